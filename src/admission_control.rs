@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::args_rets::*;
+use crate::queue::*;
+use crate::shared_rate_resource::*;
+
+/// Gates admission to a queue on the load of a downstream `SharedRateResource`,
+/// e.g. a frontend shedding requests while its database is saturated.
+pub struct AdmissionController<S: QueueSimulation + SRRSimulation + 'static> {
+    pub resource: Rc<RefCell<SharedRateResource<S>>>,
+    // enqueues are rejected while the resource's demand, counting tenancies
+    // waiting under its concurrency limit, is at or above this
+    pub max_load: f64,
+    pub metric_labels: Vec<(String, String)>,
+}
+
+impl<S: QueueSimulation + SRRSimulation + 'static> AdmissionController<S> {
+    pub fn is_admitting(&self) -> bool {
+        self.resource.borrow().get_current_demand() < self.max_load
+    }
+
+    /// Like `Queue::try_enqueue`, but rejecting the item while the controller
+    /// isn't admitting. Gated items are counted in `admissions_gated` rather than
+    /// the queue's `enqueue_rejected`.
+    pub fn try_enqueue(
+        &self,
+        queue: &mut Queue<S>,
+        simulation: &'static S,
+        timestamp: u64,
+        options: EnqueueOptions,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> (Vec<ProposedEvent<S>>, EnqueueOutcome) {
+        if !self.is_admitting() {
+            let mut metric_labels = self.metric_labels.clone();
+            metric_labels.push(("queue".to_owned(), queue.name.clone()));
+            simulation
+                .get_admissions_gated_metric()
+                .get_or_create(&metric_labels)
+                .inc();
+            return (Default::default(), EnqueueOutcome::Rejected);
+        }
+
+        queue.try_enqueue(simulation, timestamp, options, inner_handler)
+    }
+
+    /// Enqueue through the controller, handing an item that's gated or rejected
+    /// by the queue to `on_rejected` with its enqueue options, e.g. to retry it
+    /// later or record it as lost. Neither the controller nor the queue is
+    /// borrowed by then, so it may enqueue more.
+    pub fn mk_gated_enqueued_handler(
+        controller: Rc<RefCell<AdmissionController<S>>>,
+        queue: Rc<RefCell<Queue<S>>>,
+        options: EnqueueOptions,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+        on_rejected: impl FnOnce(&'static S, u64, EnqueueOptions) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let (mut proposed_events, outcome) = controller.borrow().try_enqueue(
+                &mut queue.borrow_mut(),
+                simulation,
+                timestamp,
+                options.clone(),
+                inner_handler,
+            );
+            if outcome == EnqueueOutcome::Rejected {
+                proposed_events.append(&mut on_rejected(simulation, timestamp, options));
+            }
+            proposed_events
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_distr::LogNormal;
+
+    use super::*;
+    use crate::main_loop::*;
+    use crate::simulation::*;
+    use crate::status::*;
+
+    #[test]
+    fn enqueues_are_rejected_while_the_resource_is_saturated() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let admitted: Rc<RefCell<Vec<u64>>> = Default::default();
+        let rejected: Rc<RefCell<Vec<u64>>> = Default::default();
+
+        let mut runner = SimulationRunner::new(simulation, {
+            let admitted = admitted.clone();
+            let rejected = rejected.clone();
+            Box::new(move |simulation, timestamp| {
+                let queue = Rc::new(RefCell::new(Queue::new(simulation, "gated")));
+                Worker {
+                    id: 0,
+                    subscribed_queues: vec![queue.clone()],
+                    subscription_weights: Default::default(),
+                    status: Rc::new(Status::Running.into()),
+                    allow_drop: false,
+                    rng: mk_named_rng(simulation, "worker/0"),
+                    metric_labels: Default::default(),
+                    pool: None,
+                    queue_selection: Default::default(),
+                    steal_queues: Default::default(),
+                    ext: (),
+                }
+                .listen(simulation, timestamp);
+                // one tenancy at a time, so a load of 3 is only reached by two waiting
+                let mut resource =
+                    SharedRateResource::new(simulation, 1, 1, mk_named_rng(simulation, "srr/1"))
+                        .unwrap();
+                resource.set_concurrency_limit(Some(1)).unwrap();
+                let resource = Rc::new(RefCell::new(resource));
+                let controller = Rc::new(RefCell::new(AdmissionController {
+                    resource: resource.clone(),
+                    max_load: 3.0,
+                    metric_labels: Default::default(),
+                }));
+
+                // an item every 100 ticks from 50, each admitted one requiring 980
+                // ticks of the resource once its worker has handed it over
+                Vec::from_iter((0..23).map(|i| {
+                    let resource = resource.clone();
+                    let admitted = admitted.clone();
+                    let rejected = rejected.clone();
+                    ProposedEvent::at(50 + 100 * i).handler(
+                        AdmissionController::mk_gated_enqueued_handler(
+                            controller.clone(),
+                            queue.clone(),
+                            Default::default(),
+                            move |_, timestamp, worker_token| {
+                                admitted.borrow_mut().push(timestamp);
+                                vec![ProposedEvent::at(timestamp).handler(
+                                    WorkerToken::mk_token_restoring_handler(
+                                        move |simulation, timestamp| {
+                                            let proposed_events =
+                                                SharedRateResource::mk_shared_rate_event(
+                                                    simulation,
+                                                    resource,
+                                                    timestamp,
+                                                    LogNormal::from_mean_cv(980.0, 0.0).unwrap(),
+                                                    |_, _| Default::default(),
+                                                )
+                                                .unwrap();
+                                            (proposed_events, vec![worker_token])
+                                        },
+                                    ),
+                                )]
+                            },
+                            move |_, timestamp, _| {
+                                rejected.borrow_mut().push(timestamp);
+                                Default::default()
+                            },
+                        ),
+                    )
+                }))
+            })
+        });
+
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        // three fill the resource, then each completion, at 1030 and 2010, makes
+        // room for one more
+        assert_eq!(*admitted.borrow(), [50, 150, 250, 1050, 2050]);
+        assert_eq!(rejected.borrow().len(), 18);
+        assert_eq!(rejected.borrow()[..2], [350, 450]);
+        assert_eq!(
+            simulation
+                .get_admissions_gated_metric()
+                .get_or_create(&vec![("queue".to_owned(), "gated".to_owned())])
+                .get(),
+            18
+        );
+    }
+}
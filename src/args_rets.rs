@@ -1,20 +1,198 @@
-use std::cell::RefCell;
-use std::cmp::{max, Eq, Ordering, PartialEq};
-use std::collections::BinaryHeap;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::collections::VecDeque;
-use std::hash::{Hash, Hasher};
-use std::rc::Rc;
-
-use rand::seq::SliceRandom;
-use rand::{Rng, SeedableRng};
-use rand_distr::{Distribution, LogNormal};
-use rand_xoshiro::Xoshiro256StarStar;
+use std::marker::PhantomData;
 
+use rand_distr::LogNormal;
+
+use crate::distributions::*;
+use crate::error::*;
 use crate::simulation::*;
 
+/// When a proposed event is due
+#[derive(Clone, Copy, Debug)]
+pub enum DueTime {
+    // a delay sampled when the event is scheduled, and floored at the simulation's
+    // min_delay
+    After(LogNormal<f32>),
+    // an absolute tick, taken exactly. One before the current tick is taken as the
+    // current tick, or panics if the runner has strict monotonic time set
+    At(u64),
+}
+
 pub struct ProposedEvent<S: Simulation + 'static> {
-    pub due_time: LogNormal<f32>,
+    pub due_time: DueTime,
     pub handler: Box<dyn FnOnce(&'static S, u64) -> Vec<Self>>,
+    // identifies the handler in traces, so a recorded run can be compared against a
+    // replay of the same scenario
+    pub label: Option<&'static str>,
+    // false for infrastructure events (metric collection, autoscaling) that
+    // reschedule themselves forever and so shouldn't keep a run alive on their own
+    pub keepalive: bool,
+    // among events due at the same tick, higher priorities are dispatched first,
+    // e.g. a resource being freed before a new request for it. Those sharing a
+    // priority are shuffled together, so leaving every event at 0 shuffles them all
+    pub priority: i32,
+}
+
+impl<S: Simulation + 'static> ProposedEvent<S> {
+    /// Start building an event due after a delay with mean `mean` ticks and
+    /// coefficient of variation `cv`, clamped as by `delay_from_mean_cv`
+    pub fn after(mean: f32, cv: f32) -> ProposedEventBuilder<S> {
+        ProposedEventBuilder::new(DueTime::After(delay_from_mean_cv(mean, cv)))
+    }
+
+    /// Like `after`, with the mean in seconds
+    pub fn after_seconds(simulation: &S, mean: f64, cv: f32) -> ProposedEventBuilder<S> {
+        Self::after((mean * simulation.get_ticks_per_second()) as f32, cv)
+    }
+
+    /// Start building an event due at the absolute tick `timestamp`
+    pub fn at(timestamp: u64) -> ProposedEventBuilder<S> {
+        ProposedEventBuilder::new(DueTime::At(timestamp))
+    }
+}
+
+/// Everything about a `ProposedEvent` but its handler, which finishes it. Events
+/// are unlabelled, keep the run alive and have priority 0 unless set otherwise.
+pub struct ProposedEventBuilder<S: Simulation + 'static> {
+    due_time: DueTime,
+    label: Option<&'static str>,
+    keepalive: bool,
+    priority: i32,
+    simulation: PhantomData<S>,
+}
+
+impl<S: Simulation + 'static> ProposedEventBuilder<S> {
+    fn new(due_time: DueTime) -> Self {
+        ProposedEventBuilder {
+            due_time,
+            label: None,
+            keepalive: true,
+            priority: 0,
+            simulation: PhantomData,
+        }
+    }
+
+    /// The label identifying the handler in traces
+    pub fn named(mut self, label: &'static str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    /// For infrastructure events that shouldn't keep a run alive on their own
+    pub fn infrastructure(mut self) -> Self {
+        self.keepalive = false;
+        self
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn handler(
+        self,
+        handler: impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> ProposedEvent<S> {
+        ProposedEvent {
+            due_time: self.due_time,
+            handler: Box::new(handler),
+            label: self.label,
+            keepalive: self.keepalive,
+            priority: self.priority,
+        }
+    }
+}
+
+/// Properties of one logical request that hold everywhere it goes, so that its
+/// work at a worker and at a shared resource are correlated rather than
+/// independent draws. Set at enqueue through `EnqueueOptions`, it's carried on the
+/// `WorkerToken` the item is dispatched with, from which handlers can pass it on
+/// to the tenancies they add.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ItemContext {
+    // multiplies every service and resource time sampled for the item, so positive
+    // and finite, with 1.0 for a typical item
+    size: f64,
+}
+
+impl Default for ItemContext {
+    fn default() -> Self {
+        ItemContext { size: 1.0 }
+    }
+}
+
+impl ItemContext {
+    /// Fails for a size that's zero, negative or not finite, which would otherwise
+    /// end up clamped to the minimum delay
+    pub fn new(size: f64) -> Result<Self, Error> {
+        if !(size > 0.0 && size.is_finite()) {
+            return Err(Error::InvalidItemSize { size });
+        }
+        Ok(ItemContext { size })
+    }
+
+    pub fn get_size(&self) -> f64 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_loop::*;
+    use crate::testing::*;
+
+    // events built rather than written out, traced to check their labels, timing
+    // and priority came through
+    #[test]
+    fn built_events_keep_labels_timing_and_priority() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let trace = SharedTraceBuffer::default();
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(|_, timestamp| {
+                vec![
+                    ProposedEvent::after_seconds(simulation, 2.0, 0.0)
+                        .named("event_builder_after")
+                        .handler(|_, _| Default::default()),
+                    ProposedEvent::at(timestamp + 500)
+                        .named("event_builder_request")
+                        .handler(|_, _| Default::default()),
+                    ProposedEvent::at(timestamp + 500)
+                        .named("event_builder_freed")
+                        .priority(1)
+                        .handler(|_, _| Default::default()),
+                    // an invalid mean is clamped rather than panicking
+                    ProposedEvent::after(f32::NAN, 0.0)
+                        .named("event_builder_clamped")
+                        .handler(|_, _| Default::default()),
+                    ProposedEvent::after_seconds(simulation, 60.0, 0.0)
+                        .named("event_builder_collector")
+                        .infrastructure()
+                        .handler(|_, _| unreachable!("the run ends with only this left")),
+                ]
+            }),
+        );
+        runner.set_trace_writer(Box::new(trace.clone()));
+        runner.set_end_when_work_completed(true);
+        let end_reason = runner.run();
+
+        let records = read_trace(&trace.0.borrow()[..]).unwrap();
+        let records = Vec::from_iter(
+            records
+                .into_iter()
+                .map(|record| (record.timestamp, record.label.unwrap_or_default())),
+        );
+        assert_eq!(
+            records,
+            [
+                (0, "initial"),
+                (1, "event_builder_clamped"),
+                (500, "event_builder_freed"),
+                (500, "event_builder_request"),
+                (2000, "event_builder_after"),
+            ]
+            .map(|(timestamp, label)| (timestamp, label.to_owned()))
+        );
+        assert_eq!(end_reason, EndReason::WorkCompleted);
+    }
 }
@@ -0,0 +1,197 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::args_rets::*;
+use crate::pool_manager::*;
+use crate::queue::*;
+
+/// Scales a pool serving a queue up by `step` instances, up to `max_instances`,
+/// whenever the queue's `queue_oldest_item_age_seconds` gauge is over
+/// `max_wait_seconds`. Depth alone misses a few long items holding up everything
+/// behind them, whereas their wait grows however shallow the queue is. It only
+/// ever scales up - scaling back down is left to whatever else manages the pool.
+/// Each scale-up cancels any ramp the pool has in progress. The pool's
+/// `instance_constructor` should start workers with `Worker::listen_idle`, and
+/// each check hands them the queue's parked items.
+pub struct WaitAutoscaler<S: QueueSimulation + 'static> {
+    pub queue: Rc<RefCell<Queue<S>>>,
+    pub pool_manager: Rc<RefCell<PoolManager>>,
+    pub max_wait_seconds: f64,
+    pub step: u32,
+    pub max_instances: u32,
+}
+
+impl<S: QueueSimulation + 'static> WaitAutoscaler<S> {
+    /// Refresh the queue's oldest item age gauge and scale up if it's over the
+    /// threshold, dispatching parked items to any new instances
+    pub fn check(&self, simulation: &'static S, timestamp: u64) -> Vec<ProposedEvent<S>> {
+        // released before resizing, as new instances listen on the queue
+        let (queue_name, metric_labels) = {
+            let queue = self.queue.borrow();
+            queue.refresh_oldest_item_age_metric(simulation, timestamp);
+            (queue.name.clone(), queue.metric_labels.clone())
+        };
+        let wait_seconds = simulation
+            .get_queue_oldest_item_age_metric()
+            .get_or_create(&metric_labels)
+            .get();
+        if wait_seconds <= self.max_wait_seconds {
+            return Default::default();
+        }
+
+        {
+            let mut pool_manager = self.pool_manager.borrow_mut();
+            let current = pool_manager.get_snapshot().current_instances;
+            let target = u32::min(self.max_instances, current.saturating_add(self.step));
+            if target <= current {
+                return Default::default();
+            }
+            log::info!(
+                "queue {queue_name} oldest item waited {wait_seconds:.1}s, scaling {} from {current} to {target} instances",
+                pool_manager.name
+            );
+            pool_manager.set_desired_instances_absolute(target);
+        }
+        Queue::dispatch_parked(&self.queue, simulation, timestamp)
+    }
+}
+
+/// A handler for `mk_singleton_event` checking `autoscaler` every period
+pub fn mk_wait_autoscaler_handler<S: QueueSimulation + 'static>(
+    autoscaler: Rc<WaitAutoscaler<S>>,
+) -> impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> {
+    move |simulation, timestamp| autoscaler.check(simulation, timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use rand_distr::LogNormal;
+
+    use super::*;
+    use crate::main_loop::*;
+    use crate::simulation::*;
+    use crate::status::*;
+    use crate::testing::*;
+    use crate::topology::*;
+
+    const WAIT_AUTOSCALE_ITEMS: usize = 5;
+
+    // a burst of 60s items at 10s onto a pool of one worker, parking only a few items
+    // but for longer and longer, with the pool scaled on their wait rather than the
+    // queue's depth. New instances start idle, and the autoscaler's check hands them
+    // parked items.
+    fn mk_wait_autoscale_bootstrap<S: TopologySimulation>(
+        scale_ups: Rc<RefCell<Vec<(u64, u32)>>>,
+        max_depth: Rc<Cell<usize>>,
+        served: Rc<Cell<usize>>,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let queue = Queue::new(simulation, "wait_autoscale").into_registered(simulation);
+
+            let pool_manager = PoolManager {
+                name: "wait_autoscale".into(),
+                instance_constructor: {
+                    let queue = queue.clone();
+                    let next_worker_id = Cell::new(0);
+                    Box::new(move || {
+                        let id = next_worker_id.replace(next_worker_id.get() + 1);
+                        let worker = mk_worker(simulation, id, &queue);
+                        let status = worker.status.clone();
+                        worker.listen_idle(simulation, timestamp);
+                        Box::new(move || {
+                            *status.borrow_mut() = Status::ShuttingDown;
+                        })
+                    })
+                },
+                instances: Default::default(),
+                metric_labels: vec![("pool_manager_name".into(), "wait_autoscale".into())],
+                ramp: None,
+                launch_failures: None,
+                peak_instances: 0,
+            }
+            .into_registered(simulation);
+            pool_manager.borrow_mut().set_desired_instances_absolute(1);
+
+            let autoscaler = Rc::new(WaitAutoscaler {
+                queue: queue.clone(),
+                pool_manager: pool_manager.clone(),
+                max_wait_seconds: 30.0,
+                step: 1,
+                max_instances: 4,
+            });
+            let autoscaler_handler = mk_wait_autoscaler_handler(autoscaler);
+            let period =
+                LogNormal::from_mean_cv((5.0 * simulation.get_ticks_per_second()) as f32, 0.0)
+                    .unwrap();
+            let mut proposed_events = Vec::from_iter(mk_singleton_event(
+                simulation,
+                "wait_autoscale_check",
+                period,
+                period,
+                {
+                    let queue = queue.clone();
+                    move |simulation, timestamp| {
+                        max_depth.set(usize::max(max_depth.get(), queue.borrow().deque.len()));
+                        let before = pool_manager.borrow().get_snapshot().current_instances;
+                        let proposed_events = autoscaler_handler(simulation, timestamp);
+                        let after = pool_manager.borrow().get_snapshot().current_instances;
+                        if after > before {
+                            scale_ups.borrow_mut().push((timestamp, after));
+                        }
+                        proposed_events
+                    }
+                },
+            ));
+
+            proposed_events.extend((0..WAIT_AUTOSCALE_ITEMS).map(|_| {
+                ProposedEvent::after_seconds(simulation, 10.0, 0.0)
+                    .named("wait_autoscale_arrival")
+                    .handler(Queue::mk_enqueued_handler(queue.clone(), {
+                        let served = served.clone();
+                        |_, _, worker_token| {
+                            vec![ProposedEvent::after_seconds(simulation, 60.0, 0.0)
+                                .named("wait_autoscale_service_complete")
+                                .handler(WorkerToken::mk_token_restoring_handler(move |_, _| {
+                                    served.set(served.get() + 1);
+                                    (Default::default(), vec![worker_token])
+                                }))]
+                        }
+                    }))
+            }));
+            proposed_events
+        }
+    }
+
+    #[test]
+    fn pool_is_scaled_on_the_oldest_wait() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let scale_ups: Rc<RefCell<Vec<(u64, u32)>>> = Default::default();
+        let max_depth: Rc<Cell<usize>> = Default::default();
+        let served: Rc<Cell<usize>> = Default::default();
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(mk_wait_autoscale_bootstrap(
+                scale_ups.clone(),
+                max_depth.clone(),
+                served.clone(),
+            )),
+        );
+        runner.set_end_when_work_completed(true);
+        let end_reason = runner.run();
+
+        let scale_ups = scale_ups.take();
+        // the oldest item passes 30s at 40s and is over it from the 45s check, each
+        // scale-up taking one more parked item until the pool is at its maximum. Fixed
+        // delays can come out a tick short, hence the tolerance.
+        assert_eq!(Vec::from_iter(scale_ups.iter().map(|(_, n)| *n)), [2, 3, 4]);
+        for ((timestamp, _), expected) in scale_ups.iter().zip([45_000, 50_000, 55_000]) {
+            assert!(timestamp.abs_diff(expected) <= 20);
+        }
+        assert!(max_depth.get() < WAIT_AUTOSCALE_ITEMS);
+        assert_eq!(served.get(), WAIT_AUTOSCALE_ITEMS);
+        assert_eq!(end_reason, EndReason::WorkCompleted);
+    }
+}
@@ -0,0 +1,66 @@
+use rand::distributions::{Distribution, WeightedError, WeightedIndex};
+use rand::Rng;
+use rand_distr::{LogNormal, NormalError};
+
+/// Build a `LogNormal` from its (linear-space) mean and standard deviation,
+/// equivalent to `LogNormal::from_mean_cv(mean, stddev / mean)`.
+pub fn lognormal_from_mean_stddev(mean: f32, stddev: f32) -> Result<LogNormal<f32>, NormalError> {
+    if stddev == 0.0 {
+        // avoid 0 / 0 for a fixed delay of zero
+        return LogNormal::from_mean_cv(mean, 0.0);
+    }
+    LogNormal::from_mean_cv(mean, stddev / mean)
+}
+
+/// Build a `LogNormal` from the mean `mu` and standard deviation `sigma` of the
+/// underlying normal, i.e. of the log of the delay.
+pub fn lognormal_from_mu_sigma(mu: f32, sigma: f32) -> Result<LogNormal<f32>, NormalError> {
+    LogNormal::new(mu, sigma)
+}
+
+/// A weighted choice between delay distributions, e.g. 90% fast cache hits and
+/// 10% slow misses
+#[derive(Clone, Debug)]
+pub struct DelayMixture {
+    index: WeightedIndex<f64>,
+    components: Vec<LogNormal<f32>>,
+}
+
+impl DelayMixture {
+    /// `components` are (weight, distribution) pairs; weights needn't sum to 1
+    pub fn new(
+        components: impl IntoIterator<Item = (f64, LogNormal<f32>)>,
+    ) -> Result<Self, WeightedError> {
+        let (weights, components): (Vec<f64>, Vec<LogNormal<f32>>) = components.into_iter().unzip();
+        Ok(DelayMixture {
+            index: WeightedIndex::new(weights)?,
+            components,
+        })
+    }
+
+    pub fn choose<R: Rng + ?Sized>(&self, rng: &mut R) -> &LogNormal<f32> {
+        &self.components[self.index.sample(rng)]
+    }
+}
+
+// smallest mean delay, in ticks, that delay_from_mean_cv will produce
+pub const MIN_DELAY_MEAN: f32 = 1e-3;
+
+/// Like `LogNormal::from_mean_cv(mean, cv).unwrap()`, but for parameters computed
+/// at runtime: rather than panicking, a non-positive or NaN mean is clamped to
+/// `MIN_DELAY_MEAN` and an invalid cv to 0, with a warning.
+pub fn delay_from_mean_cv(mean: f32, cv: f32) -> LogNormal<f32> {
+    let mut clamped_mean = mean;
+    if mean.is_nan() || mean < MIN_DELAY_MEAN {
+        log::warn!("delay mean {mean} is too small, clamping to {MIN_DELAY_MEAN}");
+        clamped_mean = MIN_DELAY_MEAN;
+    }
+
+    let mut clamped_cv = cv;
+    if !(cv.is_finite() && cv >= 0.0) {
+        log::warn!("delay cv {cv} is invalid, using a fixed delay");
+        clamped_cv = 0.0;
+    }
+
+    LogNormal::from_mean_cv(clamped_mean, clamped_cv).unwrap()
+}
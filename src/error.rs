@@ -0,0 +1,106 @@
+use std::fmt;
+
+/// Errors from the fallible public operations, i.e. those whose arguments a
+/// caller could get wrong. Panics are kept for violated internal invariants.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    // a relative or delta resize that would leave a pool with a negative number
+    // of instances, or more than fit in a u32
+    InvalidInstanceCount {
+        pool_manager: String,
+        requested: i64,
+    },
+    ZeroRampStep {
+        pool_manager: String,
+    },
+    // outside [0, 1], or NaN
+    InvalidLaunchFailureProbability {
+        pool_manager: String,
+        probability: f64,
+    },
+    // the resource timer would never advance and no tenancy would ever complete
+    ZeroPartitions {
+        shared_rate_resource: u64,
+    },
+    // likewise no tenancy would ever be admitted
+    ZeroConcurrencyLimit {
+        shared_rate_resource: u64,
+    },
+    // a resource being drained takes no new tenancies
+    ResourceShuttingDown {
+        shared_rate_resource: u64,
+    },
+    // a resource can't be brought back to before it was last updated
+    TimestampInPast {
+        shared_rate_resource: u64,
+        timestamp: u64,
+        last_updated: u64,
+    },
+    // a registry's text exposition couldn't be written
+    MetricEncodingFailed,
+    // a line of exposition that isn't a plain sample, e.g. one with an exemplar
+    MalformedSample {
+        line: String,
+    },
+    // zero, negative, infinite or NaN
+    InvalidItemSize {
+        size: f64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidInstanceCount {
+                pool_manager,
+                requested,
+            } => write!(
+                f,
+                "PoolManager {pool_manager} can't have {requested} instances"
+            ),
+            Error::ZeroRampStep { pool_manager } => {
+                write!(f, "PoolManager {pool_manager} ramp max_step must be positive")
+            }
+            Error::InvalidLaunchFailureProbability {
+                pool_manager,
+                probability,
+            } => write!(
+                f,
+                "PoolManager {pool_manager} launch failure probability {probability} must be between 0 and 1"
+            ),
+            Error::ZeroPartitions {
+                shared_rate_resource,
+            } => write!(
+                f,
+                "SharedRateResource {shared_rate_resource:016x} must have at least one partition"
+            ),
+            Error::ZeroConcurrencyLimit {
+                shared_rate_resource,
+            } => write!(
+                f,
+                "SharedRateResource {shared_rate_resource:016x} concurrency limit must be positive"
+            ),
+            Error::ResourceShuttingDown {
+                shared_rate_resource,
+            } => write!(
+                f,
+                "SharedRateResource {shared_rate_resource:016x} is shutting down"
+            ),
+            Error::TimestampInPast {
+                shared_rate_resource,
+                timestamp,
+                last_updated,
+            } => write!(
+                f,
+                "SharedRateResource {shared_rate_resource:016x} was last updated at {last_updated}, after {timestamp}"
+            ),
+            Error::MetricEncodingFailed => write!(f, "Metric registry couldn't be encoded"),
+            Error::MalformedSample { line } => write!(f, "Metric sample {line} couldn't be parsed"),
+            Error::InvalidItemSize { size } => {
+                write!(f, "Item size {size} must be positive and finite")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
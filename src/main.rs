@@ -1,122 +1,197 @@
-use std::borrow::Cow;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::collections::VecDeque;
-use std::hash::{Hash, Hasher};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, LineWriter};
 use std::rc::Rc;
 
 use rand::Rng;
-use rand_distr::{Distribution, LogNormal};
+use rand_distr::LogNormal;
 
+pub mod admission_control;
 pub mod args_rets;
+pub mod autoscaler;
+pub mod distributions;
+pub mod error;
 pub mod lossy_convert;
 pub mod main_loop;
+pub mod metric_samples;
+#[cfg(feature = "metrics-endpoint")]
+pub mod metrics_endpoint;
+pub mod mmpp;
+pub mod pipeline;
 pub mod pool_manager;
+pub mod processor_sharing;
 pub mod queue;
+#[cfg(feature = "remote-write")]
+pub mod remote_write;
+pub mod retry;
 pub mod shared_rate_resource;
 pub mod simulation;
 pub mod status;
+pub mod summary;
+#[cfg(test)]
+mod testing;
+pub mod topology;
+pub mod virtual_queue;
 
 use crate::args_rets::*;
-use crate::lossy_convert::*;
+use crate::distributions::*;
 use crate::main_loop::*;
+use crate::metric_samples::*;
+use crate::mmpp::*;
+use crate::pipeline::*;
 use crate::pool_manager::*;
 use crate::queue::*;
+use crate::retry::*;
 use crate::shared_rate_resource::*;
 use crate::simulation::*;
 use crate::status::*;
+use crate::topology::*;
 
 use prometheus_client::encoding::text::encode_registry;
-use prometheus_client::registry::Registry;
 use std::io::stdout;
 
-fn metric_collection_handler<S: Simulation + 'static>(
+fn metric_collection_handler<S: QueueSimulation + 'static>(
     simulation: &'static S,
     timestamp: u64,
 ) -> Vec<ProposedEvent<S>> {
+    flush_token_durations(simulation);
+    refresh_recent_checkouts(simulation, timestamp);
     let mut outstr: String = Default::default();
 
     encode_registry(&mut outstr, &simulation.borrow_metric_registry()).unwrap();
 
     std::io::Write::write_all(&mut stdout(), outstr.as_bytes()).unwrap();
 
-    vec![ProposedEvent {
-        due_time: LogNormal::from_mean_cv(
-            (S::METRICS_SAMPLING_PERIOD_SECONDS * S::TICKS_PER_SECOND) as f32,
-            0.0,
-        )
-        .unwrap(),
-        handler: Box::new(metric_collection_handler::<S>),
-    }]
+    Default::default()
 }
 
 fn mk_dummy_autoscaler_handler<S: Simulation + 'static>(
     pool_manager: Rc<RefCell<PoolManager>>,
 ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
-    move |_, _| {
-        vec![ProposedEvent {
-            due_time: LogNormal::from_mean_cv((60.0 * S::TICKS_PER_SECOND) as f32, 0.0).unwrap(),
-            handler: Box::new(mk_dummy_autoscaler_handler::<S>(pool_manager)),
-        }]
+    move |simulation, timestamp| {
+        let snapshot = pool_manager.borrow().get_snapshot();
+        log::debug!(
+            "dummy autoscaler leaving {} at {} instances @ {timestamp}",
+            snapshot.name,
+            snapshot.current_instances,
+        );
+        vec![ProposedEvent::after_seconds(simulation, 60.0, 0.0)
+            .named("dummy_autoscaler")
+            .infrastructure()
+            .handler(mk_dummy_autoscaler_handler::<S>(pool_manager))]
+    }
+}
+
+#[derive(Default)]
+struct ExampleWorkerExtension {
+    processed_count: u64,
+    last_affinity_key: Option<u64>,
+    recent_affinity_keys: RecentAffinityKeys,
+}
+
+impl AffinityTracking for ExampleWorkerExtension {
+    fn get_recent_affinity_keys(&self) -> Option<&RecentAffinityKeys> {
+        Some(&self.recent_affinity_keys)
+    }
+
+    fn get_recent_affinity_keys_mut(&mut self) -> Option<&mut RecentAffinityKeys> {
+        Some(&mut self.recent_affinity_keys)
     }
 }
 
-fn mk_foo_handler<S: QueueSimulation + 'static>(
+type ExampleSimulation = BaseQueueSimulation<ExampleWorkerExtension>;
+
+fn mk_foo_handler<S: QueueSimulation<WorkerExtension = ExampleWorkerExtension> + 'static>(
     queue: Rc<RefCell<Queue<S>>>,
 ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
-    move |_, _| {
+    move |simulation, _| {
+        let affinity_key = simulation.with_rng(|rng| rng.gen_range(0..4));
+        let service_time = queue.borrow_mut().choose_service_time().unwrap();
         vec![ProposedEvent {
-            due_time: LogNormal::from_mean_cv((0.1 * S::TICKS_PER_SECOND) as f32, 1.0).unwrap(),
-            handler: Box::new(Queue::mk_enqueued_handler(
+            due_time: DueTime::After(
+                LogNormal::from_mean_cv((0.1 * simulation.get_ticks_per_second()) as f32, 1.0)
+                    .unwrap(),
+            ),
+            handler: Box::new(Queue::mk_enqueued_handler_with_options(
                 queue,
-                |simulation, timestamp, worker_token| {
-                    std::eprintln!("checked out @ {timestamp}");
+                EnqueueOptions {
+                    affinity_key: Some(affinity_key),
+                    ..Default::default()
+                },
+                move |simulation, timestamp, mut worker_token| {
+                    let ext = worker_token.get_worker_ext_mut();
+                    ext.processed_count += 1;
+                    ext.last_affinity_key = Some(affinity_key);
+                    let processed_count = ext.processed_count;
+                    log::debug!(
+                        "worker {:016x} checked out from {} @ {timestamp} (has processed {processed_count})",
+                        worker_token.get_worker_id(),
+                        worker_token.get_originating_queue_name(),
+                    );
                     vec![ProposedEvent {
-                        due_time: LogNormal::from_mean_cv((4.0 * S::TICKS_PER_SECOND) as f32, 1.0)
-                            .unwrap(),
+                        due_time: DueTime::After(sample_service_time(simulation, &service_time)),
                         handler: Box::new(WorkerToken::mk_token_restoring_handler(
-                            move |simulation, timestamp| {
-                                std::eprintln!("restoring @ {timestamp}");
+                            move |_simulation, timestamp| {
+                                log::debug!(
+                                    "restoring @ {timestamp} after {} ticks",
+                                    timestamp - worker_token.get_checkout_timestamp(),
+                                );
                                 (Default::default(), vec![worker_token])
                             },
                         )),
+                        label: Some("foo_restore"),
+                        keepalive: true,
+                        priority: 0,
                     }]
                 },
             )),
+            label: Some("foo_enqueue"),
+            keepalive: true,
+            priority: 0,
         }]
     }
 }
 
-fn bootstrap<S: QueueSimulation + 'static>(
+fn bootstrap<
+    S: TopologySimulation + QueueSimulation<WorkerExtension = ExampleWorkerExtension> + 'static,
+>(
     simulation: &'static S,
     timestamp: u64,
 ) -> Vec<ProposedEvent<S>> {
-    let queue_foo = Rc::new(RefCell::new(Queue::<S> {
-        name: "foo".into(),
-        listening_workers: Default::default(),
-        deque: Default::default(),
-        rng: simulation.borrow_rng_mut().clone(),
-        metric_labels: vec![("queue_name".into(), "foo".into())],
-    }));
+    let queue_foo = Queue {
+        service_time: Some(
+            lognormal_from_mean_stddev(
+                (4.0 * simulation.get_ticks_per_second()) as f32,
+                (4.0 * simulation.get_ticks_per_second()) as f32,
+            )
+            .unwrap(),
+        ),
+        ..Queue::new(simulation, "foo")
+    }
+    .into_registered(simulation);
 
-    let manager_foo = Rc::new(RefCell::new(PoolManager {
+    let manager_foo = PoolManager {
         name: "foo".into(),
         instance_constructor: {
             let queue_foo_clone = queue_foo.clone();
 
             Box::new(move || {
-                let mut worker = {
-                    let mut rng = &mut queue_foo_clone.borrow_mut().rng;
+                let worker = {
+                    let rng = &mut queue_foo_clone.borrow_mut().rng;
                     let id: u64 = rng.gen();
                     Worker {
                         id,
                         status: Rc::new(Status::Running.into()),
                         allow_drop: false,
-                        metric_labels: vec![("worker_id".into(), format!("{id:016x}").into())],
-                        rng: rng.clone(),
+                        metric_labels: vec![("worker_id".into(), format!("{id:016x}"))],
+                        rng: mk_named_rng(simulation, &format!("worker/{id:016x}")),
+                        queue_selection: Default::default(),
+                        steal_queues: Default::default(),
                         ext: Default::default(),
-                        subscribed_queues: vec![queue_foo_clone.clone()].into(),
+                        subscribed_queues: vec![queue_foo_clone.clone()],
+                        subscription_weights: Default::default(),
+                        pool: None,
                     }
                 };
                 let status_clone = worker.status.clone();
@@ -130,52 +205,576 @@ fn bootstrap<S: QueueSimulation + 'static>(
         },
         instances: Default::default(),
         metric_labels: vec![("pool_manager_name".into(), "foo".into())],
-    }));
+        ramp: None,
+        launch_failures: None,
+        peak_instances: 0,
+    }
+    .into_registered(simulation);
     manager_foo.borrow_mut().set_desired_instances_absolute(2);
 
-    vec![
-        ProposedEvent {
-            due_time: LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
-            handler: Box::new(mk_dummy_autoscaler_handler::<S>(manager_foo)),
-        },
-        ProposedEvent {
-            due_time: LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
-            handler: Box::new(metric_collection_handler::<S>),
-        },
-        ProposedEvent {
-            due_time: LogNormal::from_mean_cv((240.0 * S::TICKS_PER_SECOND) as f32, 0.0).unwrap(),
-            handler: Box::new(|_, _| {
-                std::process::exit(0);
-                Default::default()
-            }),
-        },
-        ProposedEvent {
-            due_time: LogNormal::from_mean_cv((40.0 * S::TICKS_PER_SECOND) as f32, 0.01).unwrap(),
-            handler: Box::new(mk_foo_handler::<S>(queue_foo.clone())),
-        },
-        ProposedEvent {
-            due_time: LogNormal::from_mean_cv((40.1 * S::TICKS_PER_SECOND) as f32, 0.01).unwrap(),
-            handler: Box::new(mk_foo_handler::<S>(queue_foo.clone())),
-        },
-        ProposedEvent {
-            due_time: LogNormal::from_mean_cv((40.1 * S::TICKS_PER_SECOND) as f32, 0.01).unwrap(),
-            handler: Box::new(mk_foo_handler::<S>(queue_foo.clone())),
-        },
-    ]
+    let metric_collection_event = mk_singleton_event(
+        simulation,
+        "metric_collection",
+        LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+        LogNormal::from_mean_cv(
+            (simulation.get_metrics_sampling_period_seconds() * simulation.get_ticks_per_second())
+                as f32,
+            0.0,
+        )
+        .unwrap(),
+        metric_collection_handler::<S>,
+    );
+
+    Vec::from_iter(
+        metric_collection_event.into_iter().chain([
+            ProposedEvent::after(1.0, 0.0)
+                .named("dummy_autoscaler")
+                .infrastructure()
+                .handler(mk_dummy_autoscaler_handler::<S>(manager_foo)),
+            ProposedEvent::after_seconds(simulation, 40.0, 0.01)
+                .named("foo_arrival")
+                .handler(mk_foo_handler::<S>(queue_foo.clone())),
+            ProposedEvent::after_seconds(simulation, 40.1, 0.01)
+                .named("foo_arrival")
+                .handler(mk_foo_handler::<S>(queue_foo.clone())),
+            ProposedEvent::after_seconds(simulation, 40.1, 0.01)
+                .named("foo_arrival")
+                .handler(mk_foo_handler::<S>(queue_foo.clone())),
+        ]),
+    )
 }
 
-fn main() {
-    let id: u64 = 1236;
-    let mut simulation = Box::new(BaseQueueSimulation::new(
+fn mk_example_worker<S: QueueSimulation + 'static>(
+    simulation: &'static S,
+    id: u64,
+    queue: Rc<RefCell<Queue<S>>>,
+) -> Worker<S> {
+    Worker {
         id,
-        Registry::with_labels(
-            vec![(
-                Cow::from("simulation_id"),
-                Cow::from(&*Box::leak(format!("{id:016x}").into_boxed_str())),
-            )]
-            .into_iter(),
-        ),
+        status: Rc::new(Status::Running.into()),
+        allow_drop: false,
+        metric_labels: vec![("worker_id".into(), format!("{id:016x}"))],
+        rng: mk_named_rng(simulation, &format!("worker/{id:016x}")),
+        queue_selection: Default::default(),
+        steal_queues: Default::default(),
+        ext: Default::default(),
+        subscribed_queues: vec![queue],
+        subscription_weights: Default::default(),
+        pool: None,
+    }
+}
+
+// two workers each holding their item for a 5s tenancy on a single-partition
+// backend, so the four items arriving together complete in pairs 10s apart
+fn combined_example_bootstrap<S: TopologySimulation + 'static>(
+    simulation: &'static S,
+    timestamp: u64,
+    completion_timestamps: Rc<RefCell<Vec<u64>>>,
+) -> Vec<ProposedEvent<S>> {
+    let frontend = Queue::new(simulation, "frontend").into_registered(simulation);
+    let backend = SharedRateResource::new(
+        simulation,
+        1,
+        1,
+        mk_named_rng(simulation, "shared_rate_resource/backend"),
+    )
+    .unwrap()
+    .into_registered(simulation);
+
+    for id in 0..2 {
+        mk_example_worker(simulation, id, frontend.clone()).listen(simulation, timestamp);
+    }
+
+    // midway through the first pair of tenancies, with the second pair parked
+    let topology_report = ProposedEvent::after_seconds(simulation, 5.0, 0.0)
+        .named("combined_example_topology_report")
+        .handler(|simulation: &'static S, timestamp| {
+            let topology = simulation.get_topology();
+            log::info!(
+                "topology @ {timestamp}: {}",
+                serde_json::to_string(&topology).unwrap()
+            );
+            Default::default()
+        });
+
+    let mut proposed_events = Vec::from_iter((0..4).map(|_| {
+        let backend = backend.clone();
+        let completion_timestamps = completion_timestamps.clone();
+        ProposedEvent::after(1.0, 0.0)
+            .named("frontend_arrival")
+            .handler(Queue::mk_enqueued_handler(
+                frontend.clone(),
+                move |_, timestamp, worker_token| {
+                    SharedRateResource::mk_shared_rate_event(
+                        simulation,
+                        backend,
+                        timestamp,
+                        delay_from_mean_cv((5.0 * simulation.get_ticks_per_second()) as f32, 0.0),
+                        WorkerToken::mk_token_restoring_handler(move |_, timestamp| {
+                            completion_timestamps.borrow_mut().push(timestamp);
+                            (Default::default(), vec![worker_token])
+                        }),
+                    )
+                    .unwrap()
+                },
+            ))
+    }));
+    proposed_events.push(topology_report);
+    proposed_events
+}
+
+fn run_combined_example(id: u64) -> EndReason {
+    let simulation: &'static ExampleSimulation =
+        Box::leak(Box::new(SimulationBuilder::new(id).build()));
+    let completion_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+    let mut runner = SimulationRunner::new(simulation, {
+        let completion_timestamps = completion_timestamps.clone();
+        Box::new(move |simulation, timestamp| {
+            combined_example_bootstrap(simulation, timestamp, completion_timestamps)
+        })
+    });
+    let end_reason = runner.run();
+
+    log::info!(
+        "frontend items completed @ {:?}",
+        completion_timestamps.borrow()
+    );
+    let (stretch_sum, stretch_count) = simulation
+        .get_tenancy_stretch_sum_count(&[("shared_rate_resource_id".into(), format!("{:016x}", 1))])
+        .unwrap();
+    log::info!(
+        "backend tenancies took {:.2}x their required time on average",
+        stretch_sum / stretch_count as f64
+    );
+
+    end_reason
+}
+
+// quiet spells of 1 arrival/s alternating with bursts of 20/s, which make
+// interarrival times far more variable than a single rate would
+fn run_mmpp_example(id: u64) -> EndReason {
+    let simulation: &'static BaseSimulation = Box::leak(Box::new(
+        SimulationBuilder::new(id).build::<BaseSimulation>(),
     ));
+    let ticks = |seconds: f32| seconds * simulation.get_ticks_per_second() as f32;
+    let arrival_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+    let stop: Rc<Cell<bool>> = Default::default();
+    let mmpp = Rc::new(RefCell::new(
+        MarkovModulatedArrivals::new(
+            "mmpp_arrival",
+            vec![
+                MmppState {
+                    name: "quiet",
+                    interarrival: LogNormal::from_mean_cv(ticks(1.0), 1.0).unwrap(),
+                    dwell_time: LogNormal::from_mean_cv(ticks(60.0), 1.0).unwrap(),
+                    transitions: vec![(1.0, 1)],
+                },
+                MmppState {
+                    name: "burst",
+                    interarrival: LogNormal::from_mean_cv(ticks(0.05), 1.0).unwrap(),
+                    dwell_time: LogNormal::from_mean_cv(ticks(10.0), 1.0).unwrap(),
+                    transitions: vec![(1.0, 0)],
+                },
+            ],
+            0,
+            mk_named_rng(simulation, "mmpp/example"),
+            stop.clone(),
+            {
+                let arrival_timestamps = arrival_timestamps.clone();
+                move |_, timestamp| {
+                    arrival_timestamps.borrow_mut().push(timestamp);
+                    Default::default()
+                }
+            },
+        )
+        .unwrap(),
+    ));
+
+    let mut runner = SimulationRunner::new(
+        simulation,
+        Box::new(move |simulation, _| {
+            let mut proposed_events = MarkovModulatedArrivals::mk_start_events(mmpp);
+            proposed_events.push(
+                ProposedEvent::after_seconds(simulation, 3600.0, 0.0)
+                    .named("mmpp_stop")
+                    .infrastructure()
+                    .handler(move |_, _| {
+                        stop.set(true);
+                        Default::default()
+                    }),
+            );
+            proposed_events
+        }),
+    );
+    let end_reason = runner.run();
+
+    let arrival_timestamps = arrival_timestamps.borrow();
+    let gaps = Vec::from_iter(arrival_timestamps.windows(2).map(|w| (w[1] - w[0]) as f64));
+    let mean = gaps.iter().sum::<f64>() / gaps.len() as f64;
+    let variance = gaps.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / gaps.len() as f64;
+    log::info!(
+        "{} arrivals, interarrival mean {mean:.1} ticks, cv {:.2}",
+        arrival_timestamps.len(),
+        variance.sqrt() / mean,
+    );
+
+    end_reason
+}
+
+// 40 items a second apart through a main queue with two workers, half of them
+// failing there and retried through a retry queue with one, where a third fail
+fn retry_example_bootstrap<S: TopologySimulation>(
+    simulation: &'static S,
+    timestamp: u64,
+    outcomes: Rc<RefCell<Vec<RetryOutcome>>>,
+) -> Vec<ProposedEvent<S>> {
+    let mk_queue = |name: &str, service_seconds: f64| {
+        Queue {
+            service_time: Some(delay_from_mean_cv(
+                (service_seconds * simulation.get_ticks_per_second()) as f32,
+                0.5,
+            )),
+            ..Queue::new(simulation, name)
+        }
+        .into_registered(simulation)
+    };
+    let main_queue = mk_queue("main", 1.0);
+    let retry_queue = mk_queue("retry", 2.0);
+
+    for (id, queue) in [(0, &main_queue), (1, &main_queue), (2, &retry_queue)] {
+        mk_example_worker(simulation, id, queue.clone()).listen(simulation, timestamp);
+    }
+
+    let mut failure_rng = mk_named_rng(simulation, "retry_example/failures");
+    let route = Rc::new(RefCell::new(RetryRoute {
+        main_queue,
+        retry_queue,
+        max_attempts: 3,
+        backoff: delay_from_mean_cv((5.0 * simulation.get_ticks_per_second()) as f32, 0.5),
+        attempt_fails: Box::new(move |_, _, queue_name, _| {
+            failure_rng.gen_bool(if queue_name == "main" { 0.5 } else { 1.0 / 3.0 })
+        }),
+        on_outcome: Some(Box::new(move |_, _, outcome| {
+            outcomes.borrow_mut().push(outcome);
+            Default::default()
+        })),
+        pending_outcomes: None,
+    }));
+
+    Vec::from_iter((0..40).map(|i| {
+        ProposedEvent::after_seconds(simulation, (1 + i) as f64, 0.0)
+            .named("retry_example_arrival")
+            .handler(RetryRoute::mk_enqueued_handler(
+                route.clone(),
+                Default::default(),
+            ))
+    }))
+}
+
+fn run_retry_example(id: u64) -> EndReason {
+    let simulation: &'static ExampleSimulation =
+        Box::leak(Box::new(SimulationBuilder::new(id).build()));
+    let outcomes: Rc<RefCell<Vec<RetryOutcome>>> = Default::default();
+    let mut runner = SimulationRunner::new(simulation, {
+        let outcomes = outcomes.clone();
+        Box::new(move |simulation, timestamp| {
+            retry_example_bootstrap(simulation, timestamp, outcomes)
+        })
+    });
+    let end_reason = runner.run();
+
+    let outcomes = outcomes.borrow();
+    let dead_lettered = outcomes
+        .iter()
+        .filter(|o| matches!(o, RetryOutcome::DeadLettered { .. }))
+        .count();
+    let retries = get_counter_value(
+        simulation.get_item_retries_metric(),
+        &[("queue_name".to_owned(), "retry".to_owned())],
+    )
+    .unwrap();
+    log::info!(
+        "{} items, {dead_lettered} dead-lettered after {retries} retries",
+        outcomes.len()
+    );
+
+    end_reason
+}
+
+// one item through three stages of a single worker each, serving for exactly 1s,
+// returning its end-to-end latency in ticks
+fn run_pipeline(id: u64, propagation_delay: Option<LogNormal<f32>>) -> u64 {
+    let simulation: &'static ExampleSimulation =
+        Box::leak(Box::new(SimulationBuilder::new(id).build()));
+    let latency: Rc<Cell<Option<u64>>> = Default::default();
+    let mut runner = SimulationRunner::new(simulation, {
+        let latency = latency.clone();
+        Box::new(move |simulation, timestamp| {
+            let stages = Vec::from_iter((0..3u64).map(|stage| {
+                let name = format!("stage_{stage}");
+                let queue = Queue {
+                    service_time: Some(delay_from_mean_cv(
+                        simulation.get_ticks_per_second() as f32,
+                        0.0,
+                    )),
+                    ..Queue::new(simulation, &name)
+                }
+                .into_registered(simulation);
+                mk_example_worker(simulation, stage, queue.clone()).listen(simulation, timestamp);
+                queue
+            }));
+            let pipeline = Rc::new(RefCell::new(Pipeline {
+                stages,
+                propagation_delay,
+                on_complete: Some(Box::new(move |_, _, ticks| {
+                    latency.set(Some(ticks));
+                    Default::default()
+                })),
+                on_rejected: None,
+                pending_rejections: None,
+            }));
+            vec![ProposedEvent::at(timestamp)
+                .named("pipeline_example_arrival")
+                .handler(Pipeline::mk_enqueued_handler(pipeline, Default::default()))]
+        })
+    });
+    runner.run();
+    latency.get().unwrap_or_default()
+}
+
+// the same three-stage pipeline with adjacent stages and with 0.25s hand-offs
+fn run_pipeline_example(id: u64) -> EndReason {
+    let ticks_per_second = SimulationBuilder::new(id).get_ticks_per_second();
+    let adjacent = run_pipeline(id, None);
+    let propagated = run_pipeline(
+        id,
+        Some(delay_from_mean_cv((0.25 * ticks_per_second) as f32, 0.0)),
+    );
+    log::info!("pipeline latency {adjacent} ticks adjacent, {propagated} with propagation");
 
-    main_loop(Box::leak(simulation), Box::new(bootstrap));
+    EndReason::EventHeapDrained
+}
+
+fn main() {
+    // verbosity is set at runtime through RUST_LOG, e.g. RUST_LOG=debug for the
+    // example handlers' progress or RUST_LOG=trace for every dispatched timestamp
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let id: u64 = 1236;
+    let example: Option<fn(u64) -> EndReason> = match std::env::args().nth(1).as_deref() {
+        Some("--combined-example") => Some(run_combined_example),
+        Some("--mmpp-example") => Some(run_mmpp_example),
+        Some("--retry-example") => Some(run_retry_example),
+        Some("--pipeline-example") => Some(run_pipeline_example),
+        _ => None,
+    };
+    if let Some(example) = example {
+        let end_reason = example(id);
+        std::eprintln!("simulation ended: {end_reason:?}");
+        // exit without dropping the workers left listening
+        std::process::exit(end_reason.exit_code());
+    }
+
+    let simulation: &'static ExampleSimulation =
+        Box::leak(Box::new(SimulationBuilder::new(id).build()));
+
+    let mut runner = SimulationRunner::new(simulation, Box::new(bootstrap));
+    let mut reseed_at: Option<(u64, u64)> = None;
+    #[cfg(feature = "metrics-endpoint")]
+    let mut metrics_endpoint: Option<Rc<metrics_endpoint::MetricsEndpoint>> = None;
+    let mut args_iter = std::env::args().skip(1);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--record-trace" => {
+                let file = File::create(args_iter.next().unwrap()).unwrap();
+                runner.set_trace_writer(Box::new(LineWriter::new(file)));
+            }
+            "--replay-trace" => {
+                let file = File::open(args_iter.next().unwrap()).unwrap();
+                runner.set_replay_trace(read_trace(BufReader::new(file)).unwrap());
+            }
+            "--strict-time" => {
+                runner.set_strict_monotonic_time(true);
+            }
+            "--no-shuffle" => {
+                runner.set_shuffle_simultaneous_events(false);
+            }
+            "--min-delay" => {
+                runner.set_min_delay(args_iter.next().unwrap().parse().unwrap());
+            }
+            "--livelock-watchdog" => {
+                runner.set_livelock_watchdog(Some(Default::default()));
+            }
+            "--stop-after-events" => {
+                let events: u64 = args_iter.next().unwrap().parse().unwrap();
+                runner.set_stop_predicate(move |simulation, _| {
+                    simulation.get_events_dispatched_metric().get() >= events
+                });
+            }
+            "--horizon-sampling" => {
+                runner.set_horizon_sampling(Some(HorizonSampling {
+                    period: (simulation.get_metrics_sampling_period_seconds()
+                        * simulation.get_ticks_per_second()) as u64,
+                    max_events: 1000,
+                }));
+            }
+            "--fast-forward" => {
+                runner.set_fast_forward_labels(["metric_collection"]);
+            }
+            "--until-work-completed" => {
+                runner.set_end_when_work_completed(true);
+            }
+            "--lenient-worker-drop" => {
+                set_worker_drop_mode(WorkerDropMode::LogAndCount);
+            }
+            "--buffer-token-durations" => {
+                runner
+                    .get_simulation()
+                    .borrow_token_duration_buffer_mut()
+                    .enabled = true;
+            }
+            "--token-label" => {
+                let label = args_iter.next().unwrap();
+                let (name, value) = label.split_once('=').unwrap();
+                let extra_label = (name.to_owned(), value.to_owned());
+                *runner.get_simulation().borrow_token_label_enricher_mut() =
+                    Some(Box::new(move |_| vec![extra_label.clone()]));
+            }
+            "--recent-checkouts-window" => {
+                let seconds: f64 = args_iter.next().unwrap().parse().unwrap();
+                runner.get_simulation().borrow_recent_checkouts_mut().window =
+                    Some((seconds * simulation.get_ticks_per_second()) as u64);
+            }
+            "--log-worker-transitions" => {
+                runner
+                    .get_simulation()
+                    .borrow_worker_transition_log_mut()
+                    .enabled = true;
+            }
+            #[cfg(feature = "remote-write")]
+            "--remote-write" => {
+                let url = args_iter.next().unwrap();
+                let simulation = runner.get_simulation();
+                if let Some(event) = mk_singleton_event(
+                    simulation,
+                    "remote_write",
+                    LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+                    LogNormal::from_mean_cv(
+                        (simulation.get_metrics_sampling_period_seconds()
+                            * simulation.get_ticks_per_second()) as f32,
+                        0.0,
+                    )
+                    .unwrap(),
+                    remote_write::mk_remote_write_handler(remote_write::RemoteWriteCollector::new(
+                        url,
+                    )),
+                ) {
+                    runner.schedule_event(event);
+                }
+            }
+            #[cfg(feature = "metrics-endpoint")]
+            "--metrics-endpoint" => {
+                let endpoint = Rc::new(
+                    metrics_endpoint::MetricsEndpoint::bind(&args_iter.next().unwrap()).unwrap(),
+                );
+                let simulation = runner.get_simulation();
+                if let Some(event) = mk_singleton_event(
+                    simulation,
+                    "metrics_endpoint",
+                    LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+                    LogNormal::from_mean_cv(
+                        (simulation.get_metrics_sampling_period_seconds()
+                            * simulation.get_ticks_per_second()) as f32,
+                        0.0,
+                    )
+                    .unwrap(),
+                    metrics_endpoint::mk_metrics_endpoint_handler(endpoint.clone()),
+                ) {
+                    runner.schedule_event(event);
+                }
+                metrics_endpoint = Some(endpoint);
+            }
+            "--reseed-at" => {
+                let timestamp = args_iter.next().unwrap().parse().unwrap();
+                let seed = args_iter.next().unwrap().parse().unwrap();
+                reseed_at = Some((timestamp, seed));
+            }
+            _ => panic!("Unrecognized argument {arg}"),
+        }
+    }
+
+    let end_timestamp = (240.0 * simulation.get_ticks_per_second()) as u64;
+    let mut end_reason = EndReason::ReachedEndTime;
+    if let Some((timestamp, seed)) = reseed_at {
+        end_reason = runner.run_until(timestamp);
+        runner.reseed(seed);
+    }
+    if end_reason == EndReason::ReachedEndTime {
+        end_reason = runner.run_until(end_timestamp);
+    }
+
+    if let Some(divergence) = runner.get_replay_divergence() {
+        std::eprintln!("replay diverged from trace: {divergence:?}");
+    }
+    std::eprintln!("simulation ended: {end_reason:?}");
+
+    let pending_events = runner.get_pending_events();
+    if let Some(earliest) = pending_events.first() {
+        std::eprintln!(
+            "{} events pending, the earliest at t={}",
+            pending_events.len(),
+            earliest.due_time,
+        );
+        let mut pending_counts: BTreeMap<&str, usize> = Default::default();
+        for event in &pending_events {
+            *pending_counts
+                .entry(event.label.unwrap_or("<unlabelled>"))
+                .or_default() += 1;
+        }
+        for (label, count) in pending_counts {
+            std::eprintln!("  {label}: {count}");
+        }
+    }
+
+    let transition_log = runner.get_simulation().borrow_worker_transition_log_mut();
+    for (worker_id, transitions) in &transition_log.entries {
+        std::eprintln!("worker {worker_id:016x} transitions: {transitions:?}");
+    }
+
+    // the run is usually over too quickly to scrape, so the final metrics are
+    // kept up until interrupted
+    #[cfg(feature = "metrics-endpoint")]
+    if let Some(endpoint) = metrics_endpoint {
+        endpoint.collect(runner.get_simulation());
+        std::eprintln!(
+            "serving final metrics at http://{}/metrics until interrupted",
+            endpoint.local_addr
+        );
+        loop {
+            std::thread::park();
+        }
+    }
+
+    // exit without unwinding the runner, whose pending events may still own workers
+    std::process::exit(end_reason.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the id main runs examples with
+    const ID: u64 = 1236;
+
+    #[test]
+    fn examples_run_to_completion() {
+        for example in [
+            run_combined_example,
+            run_mmpp_example,
+            run_retry_example,
+            run_pipeline_example,
+        ] {
+            assert_eq!(example(ID), EndReason::EventHeapDrained);
+        }
+    }
 }
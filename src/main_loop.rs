@@ -1,22 +1,38 @@
-use std::cmp::{max, Eq, Ordering, PartialEq};
+use std::cmp::{max, Eq, Ordering, PartialEq, Reverse};
+use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
 
 use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
-use rand_distr::{Distribution, LogNormal};
+use rand_distr::Distribution;
 use rand_xoshiro::Xoshiro256StarStar;
+use serde::{Deserialize, Serialize};
 
 use crate::args_rets::*;
 use crate::simulation::*;
 
 struct ScheduledEvent<S: Simulation + 'static> {
     due_time: u64,
+    // insertion order, breaking ties between events due at the same time so that
+    // heap pop order doesn't depend on heap internals
+    sequence: u64,
     handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+    label: Option<&'static str>,
+    keepalive: bool,
+    priority: i32,
 }
 
 impl<S: Simulation + 'static> Ord for ScheduledEvent<S> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.due_time.cmp(&other.due_time).reverse()
+        self.due_time
+            .cmp(&other.due_time)
+            .then(self.sequence.cmp(&other.sequence))
+            .reverse()
     }
 }
 
@@ -28,62 +44,1746 @@ impl<S: Simulation + 'static> PartialOrd for ScheduledEvent<S> {
 
 impl<S: Simulation + 'static> PartialEq for ScheduledEvent<S> {
     fn eq(&self, other: &Self) -> bool {
-        self.due_time == other.due_time
+        self.due_time == other.due_time && self.sequence == other.sequence
     }
 }
 
 impl<S: Simulation + 'static> Eq for ScheduledEvent<S> {}
 
-pub fn main_loop<S: Simulation + 'static>(
+/// An event still in the heap, e.g. when the run ended
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEvent {
+    pub due_time: u64,
+    pub label: Option<&'static str>,
+    pub keepalive: bool,
+}
+
+/// One dispatched event, as written to (and read back from) a JSONL trace
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub timestamp: u64,
+    pub label: Option<String>,
+}
+
+/// The first point at which a replayed run stopped matching its recorded trace.
+/// `expected` is `None` if the live run dispatched more events than were recorded,
+/// `actual` is `None` if the live run ran out of events before the trace did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayDivergence {
+    pub event_index: u64,
+    pub expected: Option<TraceRecord>,
+    pub actual: Option<TraceRecord>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchdogAction {
+    Warn,
+    Abort,
+}
+
+/// How often the runner samples pending events into the `pending_event_horizon`
+/// histogram, and how many of them at most, spread evenly over the heap.
+#[derive(Clone, Copy, Debug)]
+pub struct HorizonSampling {
+    pub period: u64,
+    pub max_events: usize,
+}
+
+/// Trips when `window_events` events are dispatched while simulated time advances
+/// by no more than `min_ticks` - typically a handler rescheduling itself at the
+/// minimum delay forever.
+#[derive(Clone, Copy, Debug)]
+pub struct LivelockWatchdog {
+    pub window_events: u64,
+    pub min_ticks: u64,
+    pub action: WatchdogAction,
+}
+
+impl Default for LivelockWatchdog {
+    /// Trips on no more than one tick of progress per event on average, which is
+    /// all a handler rescheduling itself at the default 1 tick floor makes, over a
+    /// window long enough that a burst of simultaneous events won't
+    fn default() -> Self {
+        LivelockWatchdog {
+            window_events: 10_000,
+            min_ticks: 10_000,
+            action: WatchdogAction::Abort,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerPanicPolicy {
+    // let the panic unwind out of the runner
+    Propagate,
+    // drop the panicking event and carry on with the rest
+    Skip,
+    // stop the run, leaving the remaining events in the heap
+    Terminate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum EndReason {
+    ReachedEndTime,
+    // no events left at all - all work, and anything keeping the run alive, is done
+    EventHeapDrained,
+    // only non-keepalive events remained, with ending on this enabled
+    WorkCompleted,
+    LivelockDetected,
+    HandlerPanicked,
+    ReplayDiverged,
+    StopPredicateMet,
+    // the cap on events dispatched by `run_to_quiescence` was hit with work left
+    EventCapReached,
+}
+
+impl EndReason {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            EndReason::ReachedEndTime
+            | EndReason::EventHeapDrained
+            | EndReason::WorkCompleted
+            | EndReason::StopPredicateMet => 0,
+            EndReason::LivelockDetected => 2,
+            EndReason::HandlerPanicked => 3,
+            EndReason::ReplayDiverged => 4,
+            EndReason::EventCapReached => 5,
+        }
+    }
+}
+
+pub fn read_trace(reader: impl BufRead) -> std::io::Result<Vec<TraceRecord>> {
+    reader
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+        .map(|line| serde_json::from_str(&line?).map_err(std::io::Error::from))
+        .collect()
+}
+
+pub struct SimulationRunner<S: Simulation + 'static> {
     simulation: &'static S,
-    initial_handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
-) {
-    let mut event_heap: BinaryHeap<ScheduledEvent<S>> = Default::default();
-    let mut simevent_rng = simulation.borrow_rng_mut().clone();
-    let mut schedule_rng = simulation.borrow_rng_mut().clone();
-
-    event_heap.push(ScheduledEvent::<S> {
-        due_time: 0,
-        handler: initial_handler,
-    });
-
-    while !event_heap.is_empty() {
-        let mut simultaneous_events: Vec<ScheduledEvent<S>> = Default::default();
-        while let Some(event) = event_heap.peek() {
-            if let Some(ScheduledEvent::<S> {
-                due_time: existing_time,
-                ..
-            }) = simultaneous_events.first()
+    event_heap: BinaryHeap<ScheduledEvent<S>>,
+    keepalive_events: usize,
+    end_when_work_completed: bool,
+    // orders events due at the same timestamp within each priority
+    tie_break_rng: Xoshiro256StarStar,
+    // samples DueTime::After delays
+    delay_rng: Xoshiro256StarStar,
+    shuffle_simultaneous_events: bool,
+    next_sequence: u64,
+    events_dispatched: u64,
+    previous_timestamp: Option<u64>,
+    livelock_watchdog: Option<LivelockWatchdog>,
+    // (events_dispatched, timestamp) at the start of the current watchdog window
+    livelock_window_start: (u64, u64),
+    livelock_detected: bool,
+    handler_panic_policy: HandlerPanicPolicy,
+    handler_panic_terminated: bool,
+    trace_writer: Option<Box<dyn Write>>,
+    replay_trace: Option<VecDeque<TraceRecord>>,
+    replay_divergence: Option<ReplayDivergence>,
+    stop_predicate: Option<Box<dyn FnMut(&'static S, u64) -> bool>>,
+    horizon_sampling: Option<HorizonSampling>,
+    next_horizon_sample: u64,
+    fast_forward_labels: HashSet<&'static str>,
+    // of fast_forward_labels, those dispatched since the last other event was
+    fast_forward_dispatched: HashSet<&'static str>,
+    // how many events outside fast_forward_labels are pending at each due time,
+    // kept up to date as the heap changes so fast_forward needn't scan it
+    other_due_times: BTreeMap<u64, usize>,
+    strict_monotonic_time: bool,
+    // events_dispatched at which to stop, for run_to_quiescence and fork
+    event_cap: Option<u64>,
+    // stands in for the timestamp last dispatched until the first event is
+    initial_timestamp: u64,
+    // builds this run afresh from the start, for forking
+    reconstructor: Option<Rc<dyn Fn() -> SimulationRunner<S>>>,
+}
+
+impl<S: Simulation + 'static> SimulationRunner<S> {
+    const ZERO_DELAY_WATCHDOG_WINDOW_EVENTS: u64 = 100_000;
+
+    pub fn new(
+        simulation: &'static S,
+        initial_handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+    ) -> Self {
+        Self::new_at(simulation, 0, initial_handler)
+    }
+
+    /// Start the run at `initial_timestamp` rather than 0, e.g. to resume from a
+    /// checkpoint or align several simulations on a shared clock. Events are
+    /// scheduled relative to whichever timestamp they're proposed at, so those
+    /// proposed by the initial handler, including periodic collectors, follow on
+    /// from it.
+    pub fn new_at(
+        simulation: &'static S,
+        initial_timestamp: u64,
+        initial_handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+    ) -> Self {
+        let mut event_heap: BinaryHeap<ScheduledEvent<S>> = Default::default();
+        event_heap.push(ScheduledEvent::<S> {
+            due_time: initial_timestamp,
+            sequence: 0,
+            handler: initial_handler,
+            label: Some("initial"),
+            keepalive: true,
+            priority: 0,
+        });
+
+        let (tie_break_rng, delay_rng) = Self::mk_runner_rngs(simulation.get_id());
+
+        let mut runner = SimulationRunner {
+            simulation,
+            event_heap,
+            keepalive_events: 1,
+            end_when_work_completed: false,
+            tie_break_rng,
+            delay_rng,
+            shuffle_simultaneous_events: true,
+            next_sequence: 1,
+            events_dispatched: 0,
+            previous_timestamp: None,
+            livelock_watchdog: None,
+            livelock_window_start: (0, initial_timestamp),
+            livelock_detected: false,
+            handler_panic_policy: HandlerPanicPolicy::Propagate,
+            handler_panic_terminated: false,
+            trace_writer: None,
+            replay_trace: None,
+            replay_divergence: None,
+            stop_predicate: None,
+            horizon_sampling: None,
+            next_horizon_sample: initial_timestamp,
+            fast_forward_labels: Default::default(),
+            fast_forward_dispatched: Default::default(),
+            other_due_times: BTreeMap::from([(initial_timestamp, 1)]),
+            strict_monotonic_time: false,
+            event_cap: None,
+            initial_timestamp,
+            reconstructor: None,
+        };
+        // a zero floor from the simulation's builder gets the same watchdog
+        runner.set_min_delay(simulation.get_min_delay());
+        runner
+    }
+
+    pub fn get_simulation(&self) -> &'static S {
+        self.simulation
+    }
+
+    /// Events dispatched so far, over every `run_until` on this runner
+    pub fn get_events_dispatched(&self) -> u64 {
+        self.events_dispatched
+    }
+
+    /// The timestamp last dispatched, or the initial one before any have been
+    pub fn get_current_timestamp(&self) -> u64 {
+        self.previous_timestamp.unwrap_or(self.initial_timestamp)
+    }
+
+    /// The timestamp of the next event due, without dispatching it, e.g. for an
+    /// external scheduler deciding how long to wait before the next `run_until`.
+    pub fn peek_next_time(&self) -> Option<u64> {
+        self.event_heap.peek().map(|event| event.due_time)
+    }
+
+    /// Events not yet dispatched, in the order they would be dispatched with
+    /// shuffling disabled. Useful after a run ends to see what was left unfinished.
+    pub fn get_pending_events(&self) -> Vec<PendingEvent> {
+        let mut events = Vec::from_iter(self.event_heap.iter());
+        // ScheduledEvent's ordering is reversed for the max-heap
+        events.sort_by(|a, b| b.cmp(a));
+        Vec::from_iter(events.into_iter().map(|event| PendingEvent {
+            due_time: event.due_time,
+            label: event.label,
+            keepalive: event.keepalive,
+        }))
+    }
+
+    /// Schedule an event from outside any handler, relative to the timestamp last
+    /// dispatched (or the initial one), e.g. to add a collector before the run
+    /// starts.
+    pub fn schedule_event(&mut self, event: ProposedEvent<S>) {
+        self.schedule_proposed_events(self.get_current_timestamp(), vec![event]);
+    }
+
+    /// End the run once only non-keepalive events are left, rather than letting
+    /// self-rescheduling infrastructure handlers run forever.
+    pub fn set_end_when_work_completed(&mut self, end: bool) {
+        self.end_when_work_completed = end;
+    }
+
+    fn push_event(&mut self, event: ScheduledEvent<S>) {
+        if event.keepalive {
+            self.keepalive_events += 1;
+        }
+        if !self.is_fast_forwardable(&event) {
+            *self.other_due_times.entry(event.due_time).or_default() += 1;
+        }
+        self.event_heap.push(event);
+    }
+
+    fn pop_event(&mut self) -> Option<ScheduledEvent<S>> {
+        let event = self.event_heap.pop()?;
+        if event.keepalive {
+            self.keepalive_events -= 1;
+        }
+        if !self.is_fast_forwardable(&event) {
+            let count = self.other_due_times.get_mut(&event.due_time).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.other_due_times.remove(&event.due_time);
+            }
+        }
+        Some(event)
+    }
+
+    /// End the run once `predicate` returns true, e.g. once enough work has been
+    /// processed for the statistics of interest. It's called with the timestamp
+    /// just dispatched each time simulated time is about to advance, so everything
+    /// due at that timestamp has run.
+    pub fn set_stop_predicate(&mut self, predicate: impl FnMut(&'static S, u64) -> bool + 'static) {
+        self.stop_predicate = Some(Box::new(predicate));
+    }
+
+    /// Periodically observe how far in the future pending events are due, to see
+    /// whether work is piled up in the near term or spread out. Disabled by default,
+    /// as each sample iterates the heap.
+    pub fn set_horizon_sampling(&mut self, sampling: Option<HorizonSampling>) {
+        self.horizon_sampling = sampling;
+        self.next_horizon_sample = self.get_current_timestamp();
+    }
+
+    fn sample_horizon(&mut self, current_timestamp: u64) {
+        let Some(sampling) = self.horizon_sampling else {
+            return;
+        };
+        if current_timestamp < self.next_horizon_sample {
+            return;
+        }
+        self.next_horizon_sample = current_timestamp + sampling.period;
+
+        let step = self
+            .event_heap
+            .len()
+            .div_ceil(sampling.max_events.max(1))
+            .max(1);
+        for event in self.event_heap.iter().step_by(step) {
+            self.simulation.get_pending_event_horizon_metric().observe(
+                (event.due_time - current_timestamp) as f64
+                    / self.simulation.get_ticks_per_second(),
+            );
+        }
+    }
+
+    /// Skip idle periods for the labelled events, typically periodic metric
+    /// collectors: once one has been dispatched since the last event with any other
+    /// label, it's deferred to the tick before the next such event is due, as
+    /// nothing else could have changed meanwhile. So each collector still takes a
+    /// sample just after the simulation goes idle and one just before it resumes,
+    /// but none in between. Events must reschedule themselves relative to the
+    /// timestamp they're dispatched at for the samples to continue at the usual
+    /// period afterwards. Has no effect while only the labelled events remain.
+    pub fn set_fast_forward_labels(&mut self, labels: impl IntoIterator<Item = &'static str>) {
+        self.fast_forward_labels = HashSet::from_iter(labels);
+        self.fast_forward_dispatched.clear();
+        self.other_due_times.clear();
+        for event in &self.event_heap {
+            if !self.is_fast_forwardable(event) {
+                *self.other_due_times.entry(event.due_time).or_default() += 1;
+            }
+        }
+    }
+
+    fn is_fast_forwardable(&self, event: &ScheduledEvent<S>) -> bool {
+        event
+            .label
+            .is_some_and(|label| self.fast_forward_labels.contains(label))
+    }
+
+    fn fast_forward(&mut self) {
+        if self.fast_forward_dispatched.is_empty() {
+            return;
+        }
+        let Some(&resume_timestamp) = self.other_due_times.keys().next() else {
+            return;
+        };
+
+        let deferred_timestamp = resume_timestamp.saturating_sub(1);
+        while let Some(event) = self.event_heap.peek() {
+            if event.due_time >= deferred_timestamp
+                || !self
+                    .fast_forward_dispatched
+                    .contains(event.label.unwrap_or_default())
             {
-                if *existing_time != event.due_time {
-                    break;
-                }
+                break;
+            }
+            let mut event = self.pop_event().unwrap();
+            event.due_time = deferred_timestamp;
+            event.sequence = self.next_sequence;
+            self.next_sequence += 1;
+            self.push_event(event);
+        }
+    }
+
+    fn note_fast_forward_dispatch(&mut self, label: Option<&'static str>) {
+        if self.fast_forward_labels.is_empty() {
+            return;
+        }
+        match label.filter(|label| self.fast_forward_labels.contains(label)) {
+            Some(label) => {
+                self.fast_forward_dispatched.insert(label);
             }
-            simultaneous_events.push(event_heap.pop().unwrap());
+            None => self.fast_forward_dispatched.clear(),
         }
+    }
 
-        let current_timestamp = simultaneous_events.first().unwrap().due_time;
+    /// Debugging aid for scheduling-math regressions: when enabled, an event
+    /// proposed with a `DueTime::At` before the current tick panics, as does
+    /// popping an event due before the previous batch, rather than the former
+    /// being logged and dispatched at the current tick.
+    pub fn set_strict_monotonic_time(&mut self, strict: bool) {
+        self.strict_monotonic_time = strict;
+    }
 
-        #[cfg(debug_assertions)]
-        std::eprintln!("current_timestamp = {current_timestamp}");
+    /// Debugging aid: when disabled, events due at the same timestamp are dispatched
+    /// in the order they were scheduled rather than shuffled.
+    pub fn set_shuffle_simultaneous_events(&mut self, shuffle: bool) {
+        self.shuffle_simultaneous_events = shuffle;
+    }
 
-        SliceRandom::shuffle(&mut simultaneous_events[..], &mut simevent_rng);
-        let mut proposed_events: Vec<ProposedEvent<S>> = simultaneous_events
-            .drain(..)
-            .flat_map(|event| {
-                let r = (event.handler)(simulation, event.due_time);
-                simulation.get_events_dispatched_metric().inc();
-                r
-            })
-            .collect();
+    /// Floor applied to every sampled delay, 1 tick or the simulation builder's
+    /// `min_delay` by default. It's kept on the simulation, and shared rate
+    /// resources floor their tenancies' resource time at it too. With a floor of 0,
+    /// events sampled below 1 tick are due at the timestamp they were scheduled at.
+    /// Those scheduled by a batch of simultaneous events form a follow-up pass at
+    /// the same timestamp, dispatched once the whole batch has been, before time
+    /// advances. Follow-up passes are never shuffled, so a zero-delay cascade runs
+    /// in the order it was scheduled. As a handler rescheduling itself at zero
+    /// delay would then never let time advance, this also installs an aborting
+    /// livelock watchdog if none is set.
+    pub fn set_min_delay(&mut self, min_delay: u64) {
+        self.simulation.set_min_delay(min_delay);
+        if min_delay == 0 && self.livelock_watchdog.is_none() {
+            self.livelock_watchdog = Some(LivelockWatchdog {
+                window_events: Self::ZERO_DELAY_WATCHDOG_WINDOW_EVENTS,
+                min_ticks: 0,
+                action: WatchdogAction::Abort,
+            });
+        }
+    }
+
+    pub fn set_livelock_watchdog(&mut self, watchdog: Option<LivelockWatchdog>) {
+        self.livelock_watchdog = watchdog;
+    }
+
+    pub fn get_livelock_detected(&self) -> bool {
+        self.livelock_detected
+    }
+
+    // returns whether the run should be aborted
+    fn check_livelock(&mut self, current_timestamp: u64) -> bool {
+        let Some(watchdog) = self.livelock_watchdog else {
+            return false;
+        };
+
+        let (window_start_events, window_start_timestamp) = self.livelock_window_start;
+        if self.events_dispatched - window_start_events < watchdog.window_events {
+            return false;
+        }
+
+        self.livelock_window_start = (self.events_dispatched, current_timestamp);
+        if current_timestamp - window_start_timestamp > watchdog.min_ticks {
+            return false;
+        }
+
+        self.livelock_detected = true;
+        log::warn!(
+            "livelock watchdog: {} events dispatched between timestamps {window_start_timestamp} and {current_timestamp}",
+            self.events_dispatched - window_start_events,
+        );
+        watchdog.action == WatchdogAction::Abort
+    }
+
+    /// Note that under `Skip` or `Terminate` a panicking handler's captured state
+    /// (including any `WorkerToken`s) is lost, and shared state it was mutating may
+    /// be left inconsistent.
+    pub fn set_handler_panic_policy(&mut self, policy: HandlerPanicPolicy) {
+        self.handler_panic_policy = policy;
+    }
 
+    pub fn get_handler_panic_terminated(&self) -> bool {
+        self.handler_panic_terminated
+    }
+
+    // returns None if the handler panicked and wasn't propagated
+    fn call_handler(&mut self, event: ScheduledEvent<S>) -> Option<Vec<ProposedEvent<S>>> {
+        let simulation = self.simulation;
+        if self.handler_panic_policy == HandlerPanicPolicy::Propagate {
+            return Some((event.handler)(simulation, event.due_time));
+        }
+
+        let label = event.label;
+        match catch_unwind(AssertUnwindSafe(|| {
+            (event.handler)(simulation, event.due_time)
+        })) {
+            Ok(proposed_events) => Some(proposed_events),
+            Err(_) => {
+                simulation.get_handler_panics_metric().inc();
+                log::error!(
+                    "handler {} panicked at timestamp {}",
+                    label.unwrap_or("<unlabelled>"),
+                    event.due_time,
+                );
+                if self.handler_panic_policy == HandlerPanicPolicy::Terminate {
+                    self.handler_panic_terminated = true;
+                }
+                None
+            }
+        }
+    }
+
+    fn schedule_proposed_events(
+        &mut self,
+        current_timestamp: u64,
+        mut proposed_events: Vec<ProposedEvent<S>>,
+    ) {
         // TODO more efficient bulk implementation
         for proposed_event in proposed_events.drain(..) {
-            event_heap.push(ScheduledEvent::<S> {
-                due_time: current_timestamp
-                    + max(1, proposed_event.due_time.sample(&mut schedule_rng) as u64),
+            let due_time = match proposed_event.due_time {
+                DueTime::After(delay) => {
+                    current_timestamp
+                        + max(
+                            self.simulation.get_min_delay(),
+                            delay.sample(&mut self.delay_rng) as u64,
+                        )
+                }
+                DueTime::At(due_time) if due_time < current_timestamp => {
+                    let label = proposed_event.label.unwrap_or("<unlabelled>");
+                    assert!(
+                        !self.strict_monotonic_time,
+                        "Event {label} due at {due_time} proposed at {current_timestamp}, in the past",
+                    );
+                    log::warn!(
+                        "event {label} due at {due_time} proposed at {current_timestamp}, in the past; dispatching it now"
+                    );
+                    current_timestamp
+                }
+                DueTime::At(due_time) => due_time,
+            };
+            self.push_event(ScheduledEvent::<S> {
+                due_time,
+                sequence: self.next_sequence,
                 handler: proposed_event.handler,
+                label: proposed_event.label,
+                keepalive: proposed_event.keepalive,
+                priority: proposed_event.priority,
             });
+            self.next_sequence += 1;
+        }
+    }
+
+    /// Write a JSONL `TraceRecord` for every dispatched event. Lines are written as
+    /// they are dispatched, so an unbuffered or line-buffered writer survives the
+    /// process exiting from within a handler. Tracing stops, with a warning, at the
+    /// first write that fails, e.g. on a full disk, while the run carries on.
+    pub fn set_trace_writer(&mut self, writer: Box<dyn Write>) {
+        self.trace_writer = Some(writer);
+    }
+
+    /// Check every dispatched event against a previously recorded trace. Handlers
+    /// themselves can't be serialized, so they're reconstructed by re-running the
+    /// same scenario with the same seed - the trace labels identify which handler
+    /// was expected at each step. The run stops at the first divergence.
+    pub fn set_replay_trace(&mut self, trace: Vec<TraceRecord>) {
+        self.replay_trace = Some(trace.into());
+    }
+
+    pub fn get_replay_divergence(&self) -> Option<&ReplayDivergence> {
+        self.replay_divergence.as_ref()
+    }
+
+    fn check_replay(&mut self, actual: Option<TraceRecord>) {
+        if let Some(replay_trace) = &mut self.replay_trace {
+            let expected = replay_trace.pop_front();
+            if expected != actual {
+                self.replay_divergence = Some(ReplayDivergence {
+                    event_index: self.events_dispatched,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+
+    fn record_dispatch(&mut self, timestamp: u64, label: Option<&'static str>) {
+        if self.trace_writer.is_none() && self.replay_trace.is_none() {
+            return;
         }
+
+        let record = TraceRecord {
+            timestamp,
+            label: label.map(Into::into),
+        };
+
+        if let Some(writer) = &mut self.trace_writer {
+            let written = serde_json::to_writer(&mut *writer, &record)
+                .map_err(std::io::Error::from)
+                .and_then(|()| writer.write_all(b"\n"));
+            if let Err(err) = written {
+                log::warn!("stopped tracing after failing to write a trace record: {err}");
+                self.trace_writer = None;
+            }
+        }
+
+        self.check_replay(Some(record));
+    }
+
+    /// Reseed the simulation RNG and the runner's own streams, which are derived from
+    /// the new seed exactly as they were from the simulation's id at construction,
+    /// so everything dispatched afterwards is a function of it. RNGs already cloned
+    /// into subsystems (queues, workers, resources) are unaffected.
+    pub fn reseed(&mut self, seed: u64) {
+        *self.simulation.borrow_rng_mut() = Xoshiro256StarStar::seed_from_u64(seed);
+        *self.simulation.borrow_service_rng_mut() = mk_service_rng(seed);
+        (self.tie_break_rng, self.delay_rng) = Self::mk_runner_rngs(seed);
+    }
+
+    // the tie-break and delay streams, each named and seeded independently of the
+    // other and of the simulation rng, so that how many draws one feature makes
+    // never shifts another's, e.g. sampling more delays doesn't reorder ties
+    fn mk_runner_rngs(seed: u64) -> (Xoshiro256StarStar, Xoshiro256StarStar) {
+        (
+            mk_seeded_named_rng(seed, "runner/tie_break"),
+            mk_seeded_named_rng(seed, "runner/delay"),
+        )
+    }
+
+    // each priority's events are shuffled by a stream of their own, seeded from a
+    // single draw per batch, so that giving some events a priority doesn't change
+    // how those at other priorities are ordered among themselves
+    fn shuffle_within_priorities(&mut self, events: &mut [ScheduledEvent<S>]) {
+        let batch_seed: u64 = self.tie_break_rng.gen();
+        for group in events.chunk_by_mut(|a, b| a.priority == b.priority) {
+            let priority_seed = (group[0].priority as u64).wrapping_mul(0x9e3779b97f4a7c15);
+            let mut rng = Xoshiro256StarStar::seed_from_u64(batch_seed ^ priority_seed);
+            SliceRandom::shuffle(group, &mut rng);
+        }
+    }
+
+    pub fn run(&mut self) -> EndReason {
+        let end_reason = self.run_until(u64::MAX);
+
+        // a replay that finishes early has diverged just as much as one that doesn't
+        if end_reason == EndReason::EventHeapDrained
+            && self.replay_trace.as_ref().is_some_and(|t| !t.is_empty())
+        {
+            self.check_replay(None);
+            return EndReason::ReplayDiverged;
+        }
+
+        end_reason
+    }
+
+    /// Make the run forkable. `reconstruct` must build a fresh simulation and a
+    /// runner for the same scenario, with the same seed and settings, so that it
+    /// dispatches exactly the events this one does.
+    pub fn set_reconstructor(&mut self, reconstruct: impl Fn() -> Self + 'static) {
+        self.reconstructor = Some(Rc::new(reconstruct));
+    }
+
+    /// An independent runner, with its own simulation, continuing from this one's
+    /// state, e.g. to run two variants forward from an identical starting point.
+    /// Handlers can't be cloned, so as with replaying a trace the state is rebuilt
+    /// by re-running the scenario up to the same number of dispatched events. Only
+    /// what the reconstructor reproduces carries over - an event scheduled from
+    /// outside a handler, or a reseed, before forking must be applied to it too.
+    /// Panics without a reconstructor, or if the fork ends up with different
+    /// events pending.
+    pub fn fork(&self) -> Self {
+        let reconstructor = self
+            .reconstructor
+            .clone()
+            .expect("SimulationRunner::fork requires a reconstructor");
+        let mut fork = reconstructor();
+        fork.reconstructor = Some(reconstructor);
+
+        fork.event_cap = Some(self.events_dispatched);
+        fork.run_until(u64::MAX);
+        fork.event_cap = None;
+
+        assert_eq!(
+            (fork.events_dispatched, fork.previous_timestamp),
+            (self.events_dispatched, self.previous_timestamp),
+            "Fork stopped short of the run it was forked from",
+        );
+        assert_eq!(
+            fork.get_pending_events(),
+            self.get_pending_events(),
+            "Fork diverged from the run it was forked from",
+        );
+        fork
+    }
+
+    /// Testing counterpart to `run_until`: dispatch events until only non-keepalive
+    /// ones (collectors, autoscalers and the like) remain, so a scenario's work has
+    /// all been done however long it took, then leave the simulation as it is for
+    /// inspection. Gives up with `EventCapReached` once `max_events` more have been
+    /// dispatched, in case the work never finishes.
+    pub fn run_to_quiescence(&mut self, max_events: u64) -> EndReason {
+        let end_when_work_completed = self.end_when_work_completed;
+        self.end_when_work_completed = true;
+        self.event_cap = Some(self.events_dispatched + max_events);
+
+        let end_reason = self.run();
+
+        self.end_when_work_completed = end_when_work_completed;
+        self.event_cap = None;
+        end_reason
+    }
+
+    /// Dispatch events due at or before `end_timestamp`, leaving later ones in the
+    /// heap so the run can be resumed.
+    pub fn run_until(&mut self, end_timestamp: u64) -> EndReason {
+        loop {
+            self.fast_forward();
+            match self.event_heap.peek() {
+                None => return EndReason::EventHeapDrained,
+                Some(event) if event.due_time > end_timestamp => return EndReason::ReachedEndTime,
+                _ => {}
+            }
+            if self.end_when_work_completed && self.keepalive_events == 0 {
+                return EndReason::WorkCompleted;
+            }
+            if self
+                .event_cap
+                .is_some_and(|event_cap| self.events_dispatched >= event_cap)
+            {
+                return EndReason::EventCapReached;
+            }
+            if let (Some(predicate), Some(previous_timestamp)) =
+                (&mut self.stop_predicate, self.previous_timestamp)
+            {
+                // not between a batch and its zero-delay follow-up passes
+                if self.event_heap.peek().unwrap().due_time != previous_timestamp
+                    && predicate(self.simulation, previous_timestamp)
+                {
+                    return EndReason::StopPredicateMet;
+                }
+            }
+
+            let mut simultaneous_events: Vec<ScheduledEvent<S>> = Default::default();
+            while let Some(event) = self.event_heap.peek() {
+                if let Some(ScheduledEvent::<S> {
+                    due_time: existing_time,
+                    ..
+                }) = simultaneous_events.first()
+                {
+                    if *existing_time != event.due_time {
+                        break;
+                    }
+                }
+                simultaneous_events.push(self.pop_event().unwrap());
+            }
+
+            let current_timestamp = simultaneous_events.first().unwrap().due_time;
+            if self.strict_monotonic_time {
+                if let Some(previous_timestamp) = self.previous_timestamp {
+                    assert!(
+                        current_timestamp >= previous_timestamp,
+                        "Time went backwards: events due at {current_timestamp} popped after {previous_timestamp}",
+                    );
+                }
+            }
+
+            log::trace!("current_timestamp = {current_timestamp}");
+
+            // events due at the timestamp just dispatched can only have been
+            // scheduled with zero delay by that batch
+            let follow_up_pass = self.previous_timestamp == Some(current_timestamp);
+            if let Some(previous_timestamp) = self.previous_timestamp {
+                if !follow_up_pass {
+                    self.simulation.get_dispatch_gap_metric().observe(
+                        (current_timestamp - previous_timestamp) as f64
+                            / self.simulation.get_ticks_per_second(),
+                    );
+                }
+            }
+            self.previous_timestamp = Some(current_timestamp);
+            self.simulation
+                .get_simulated_seconds_metric()
+                .set(current_timestamp as f64 / self.simulation.get_ticks_per_second());
+
+            if !follow_up_pass {
+                self.sample_horizon(current_timestamp);
+            }
+
+            if self.check_livelock(current_timestamp) {
+                // leave the heap intact so the aborted state can still be inspected
+                for event in simultaneous_events {
+                    self.push_event(event);
+                }
+                return EndReason::LivelockDetected;
+            }
+
+            // stable, so each priority keeps its scheduled order unless shuffled
+            simultaneous_events.sort_by_key(|event| Reverse(event.priority));
+            if self.shuffle_simultaneous_events && !follow_up_pass {
+                self.shuffle_within_priorities(&mut simultaneous_events);
+            }
+            let mut proposed_events: Vec<ProposedEvent<S>> = Default::default();
+            let mut events_iter = simultaneous_events.into_iter();
+            while let Some(event) = events_iter.next() {
+                self.record_dispatch(event.due_time, event.label);
+                if self.replay_divergence.is_some() {
+                    // leave the diverging event and the rest of its batch pending,
+                    // so the state at the divergence can still be inspected
+                    self.push_event(event);
+                    for event in events_iter {
+                        self.push_event(event);
+                    }
+                    self.schedule_proposed_events(current_timestamp, proposed_events);
+                    return EndReason::ReplayDiverged;
+                }
+                self.note_fast_forward_dispatch(event.label);
+
+                if let Some(mut r) = self.call_handler(event) {
+                    proposed_events.append(&mut r);
+                }
+                self.simulation.get_events_dispatched_metric().inc();
+                self.events_dispatched += 1;
+
+                if self.handler_panic_terminated {
+                    for event in events_iter {
+                        self.push_event(event);
+                    }
+                    self.schedule_proposed_events(current_timestamp, proposed_events);
+                    return EndReason::HandlerPanicked;
+                }
+            }
+
+            self.schedule_proposed_events(current_timestamp, proposed_events);
+        }
+    }
+}
+
+pub fn main_loop<S: Simulation + 'static>(
+    simulation: &'static S,
+    initial_handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+) -> EndReason {
+    SimulationRunner::new(simulation, initial_handler).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use rand_distr::LogNormal;
+
+    use super::*;
+    use crate::distributions::*;
+    use crate::metric_samples::*;
+    use crate::queue::*;
+    use crate::testing::*;
+    use crate::topology::*;
+
+    fn mk_simulation() -> &'static BaseSimulation {
+        Box::leak(Box::new(BaseSimulation::new(1)))
+    }
+
+    // reschedules itself at the 1 tick floor forever
+    fn mk_runaway_handler(
+    ) -> impl FnOnce(&'static BaseSimulation, u64) -> Vec<ProposedEvent<BaseSimulation>> {
+        |_, _| {
+            vec![ProposedEvent::after(0.0, 0.0)
+                .named("runaway")
+                .handler(mk_runaway_handler())]
+        }
+    }
+
+    #[test]
+    fn default_watchdog_trips_on_runaway_handler() {
+        let mut runner = SimulationRunner::new(mk_simulation(), Box::new(mk_runaway_handler()));
+        runner.set_livelock_watchdog(Some(Default::default()));
+        assert_eq!(runner.run(), EndReason::LivelockDetected);
+        assert!(runner.get_livelock_detected());
+        assert_eq!(runner.get_events_dispatched(), 10_000);
+    }
+
+    #[test]
+    fn watchdog_trips_on_runaway_handler_started_late() {
+        let mut runner =
+            SimulationRunner::new_at(mk_simulation(), 1_000_000, Box::new(mk_runaway_handler()));
+        runner.set_livelock_watchdog(Some(Default::default()));
+        assert_eq!(runner.run(), EndReason::LivelockDetected);
+        assert_eq!(runner.get_events_dispatched(), 10_000);
+    }
+
+    // fails every write, counting them
+    struct FailingWriter(Rc<Cell<usize>>);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Err(std::io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_trace_write_stops_tracing_but_not_the_run() {
+        let writes: Rc<Cell<usize>> = Default::default();
+        let mut runner = SimulationRunner::new(mk_simulation(), Box::new(mk_runaway_handler()));
+        runner.set_trace_writer(Box::new(FailingWriter(writes.clone())));
+        assert_eq!(runner.run_until(100), EndReason::ReachedEndTime);
+        assert!(runner.get_events_dispatched() > 1);
+        assert_eq!(writes.get(), 1);
+        assert!(runner.trace_writer.is_none());
+    }
+
+    #[test]
+    fn warning_watchdog_lets_run_continue() {
+        let mut runner = SimulationRunner::new(mk_simulation(), Box::new(mk_runaway_handler()));
+        runner.set_livelock_watchdog(Some(LivelockWatchdog {
+            action: WatchdogAction::Warn,
+            ..Default::default()
+        }));
+        assert_eq!(runner.run_until(30_000), EndReason::ReachedEndTime);
+        assert!(runner.get_livelock_detected());
+    }
+
+    #[test]
+    fn default_watchdog_ignores_steady_progress() {
+        // two ticks per event is slow, but not a livelock
+        fn mk_slow_handler(
+        ) -> impl FnOnce(&'static BaseSimulation, u64) -> Vec<ProposedEvent<BaseSimulation>>
+        {
+            |_, timestamp| {
+                vec![ProposedEvent::at(timestamp + 2)
+                    .named("slow")
+                    .handler(mk_slow_handler())]
+            }
+        }
+        let mut runner = SimulationRunner::new(mk_simulation(), Box::new(mk_slow_handler()));
+        runner.set_livelock_watchdog(Some(Default::default()));
+        assert_eq!(runner.run_until(100_000), EndReason::ReachedEndTime);
+        assert!(!runner.get_livelock_detected());
+    }
+
+    #[test]
+    fn zero_min_delay_keeps_sub_tick_delays() {
+        let simulation: &'static BaseSimulation =
+            Box::leak(Box::new(SimulationBuilder::new(1).min_delay(0).build()));
+        let due_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let due_timestamps = due_timestamps.clone();
+            Box::new(move |_, _| {
+                Vec::from_iter((0..1000).map(|_| {
+                    let due_timestamps = due_timestamps.clone();
+                    ProposedEvent::after(0.5, 1.0)
+                        .named("sub_tick")
+                        .handler(move |_, timestamp| {
+                            due_timestamps.borrow_mut().push(timestamp);
+                            Default::default()
+                        })
+                }))
+            })
+        });
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+
+        // sampled with a mean of half a tick, so mostly due straight away
+        let due_timestamps = due_timestamps.borrow();
+        let immediate = due_timestamps.iter().filter(|t| **t == 0).count();
+        assert!((500..1000).contains(&immediate), "{immediate}");
+    }
+
+    #[test]
+    fn zero_delay_cascade_resolves_at_one_timestamp() {
+        // each step schedules the next with zero delay, the last fanning out into
+        // three more at once
+        fn mk_cascade_handler(
+            log: Rc<RefCell<Vec<(&'static str, u64)>>>,
+            remaining: u32,
+        ) -> impl FnOnce(&'static BaseSimulation, u64) -> Vec<ProposedEvent<BaseSimulation>>
+        {
+            move |_, timestamp| {
+                log.borrow_mut().push(("cascade", timestamp));
+                if remaining == 0 {
+                    return Vec::from_iter(["a", "b", "c"].map(|name| {
+                        let log = log.clone();
+                        ProposedEvent::after(0.0, 0.0).named("fan_out").handler(
+                            move |_, timestamp| {
+                                log.borrow_mut().push((name, timestamp));
+                                Default::default()
+                            },
+                        )
+                    }));
+                }
+                vec![ProposedEvent::after(0.0, 0.0)
+                    .named("cascade")
+                    .handler(mk_cascade_handler(log, remaining - 1))]
+            }
+        }
+
+        let simulation: &'static BaseSimulation =
+            Box::leak(Box::new(SimulationBuilder::new(1).min_delay(0).build()));
+        let log: Rc<RefCell<Vec<(&'static str, u64)>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let log = log.clone();
+            Box::new(move |_, _| {
+                let next_tick_log = log.clone();
+                vec![
+                    ProposedEvent::at(5)
+                        .named("cascade")
+                        .handler(mk_cascade_handler(log, 9)),
+                    ProposedEvent::at(6)
+                        .named("next_tick")
+                        .handler(move |_, timestamp| {
+                            next_tick_log.borrow_mut().push(("next_tick", timestamp));
+                            Default::default()
+                        }),
+                ]
+            })
+        });
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+
+        // all ten steps and the fan-out at 5, the fan-out in the order it was
+        // scheduled, before time moves on
+        let expected = Vec::from_iter(std::iter::repeat_n(("cascade", 5), 10).chain([
+            ("a", 5),
+            ("b", 5),
+            ("c", 5),
+            ("next_tick", 6),
+        ]));
+        assert_eq!(*log.borrow(), expected);
+    }
+
+    #[test]
+    fn stop_predicate_waits_for_the_whole_timestamp() {
+        let simulation: &'static BaseSimulation =
+            Box::leak(Box::new(SimulationBuilder::new(1).min_delay(0).build()));
+        let log: Rc<RefCell<Vec<(&'static str, u64)>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let log = log.clone();
+            Box::new(move |_, _| {
+                let mk_handler = |label: &'static str| {
+                    let log = log.clone();
+                    move |_: &'static BaseSimulation, timestamp| {
+                        log.borrow_mut().push((label, timestamp));
+                        if label != "follow_up" || timestamp != 3 {
+                            return Default::default();
+                        }
+                        // a zero-delay follow-up at the timestamp the predicate is met
+                        let log = log.clone();
+                        vec![ProposedEvent::after(0.0, 0.0).named("follow_up").handler(
+                            move |_, timestamp| {
+                                log.borrow_mut().push(("followed_up", timestamp));
+                                Default::default()
+                            },
+                        )]
+                    }
+                };
+                let mut events = Vec::from_iter((1..=10).map(|timestamp| {
+                    ProposedEvent::at(timestamp)
+                        .named("tick")
+                        .handler(mk_handler("tick"))
+                }));
+                events.push(
+                    ProposedEvent::at(3)
+                        .named("follow_up")
+                        .handler(mk_handler("follow_up")),
+                );
+                events
+            })
+        });
+        let predicate_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        runner.set_stop_predicate({
+            let log = log.clone();
+            let predicate_timestamps = predicate_timestamps.clone();
+            move |_, timestamp| {
+                predicate_timestamps.borrow_mut().push(timestamp);
+                log.borrow().len() >= 3
+            }
+        });
+        assert_eq!(runner.run(), EndReason::StopPredicateMet);
+
+        // checked once per timestamp, only after the follow-up has run too
+        assert_eq!(*predicate_timestamps.borrow(), [0, 1, 2, 3]);
+        assert_eq!(
+            *log.borrow(),
+            [
+                ("tick", 1),
+                ("tick", 2),
+                ("tick", 3),
+                ("follow_up", 3),
+                ("followed_up", 3)
+            ]
+        );
+        assert_eq!(runner.get_current_timestamp(), 3);
+        assert_eq!(runner.peek_next_time(), Some(4));
+    }
+
+    #[test]
+    fn horizon_sampling_observes_a_spread_of_pending_events() {
+        let simulation = mk_simulation();
+        let mut runner = SimulationRunner::new(simulation, Box::new(|_, _| Default::default()));
+        for due_time in 1000..1100 {
+            runner.schedule_event(
+                ProposedEvent::at(due_time)
+                    .named("pending")
+                    .handler(|_, _| Default::default()),
+            );
+        }
+        runner.set_horizon_sampling(Some(HorizonSampling {
+            period: 50,
+            max_events: 10,
+        }));
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+
+        // sampled at 0, with the 100 pending events, then at 1000 and 1050 with
+        // 100 and 50 left, at most 10 of them each time
+        let samples = extract_samples(&simulation.borrow_metric_registry()).unwrap();
+        let get_sample = |name: &str, le: Option<&str>| {
+            samples
+                .iter()
+                .find(|sample| {
+                    sample.name == name
+                        && le.is_none_or(|le| {
+                            sample.labels.contains(&("le".to_owned(), le.to_owned()))
+                        })
+                })
+                .unwrap()
+                .value
+        };
+        assert_eq!(get_sample("pending_event_horizon_count", None), 30.0);
+        // the first ten between 1s and 1.1s ahead, the rest under 0.1s
+        assert_eq!(
+            get_sample("pending_event_horizon_bucket", Some("0.256")),
+            20.0
+        );
+        let sum = get_sample("pending_event_horizon_sum", None);
+        assert!((10.0..10.0 * (1.1 + 0.1 + 0.05)).contains(&sum), "{sum}");
+    }
+
+    #[test]
+    fn handlers_and_runner_draw_distinct_reproducible_values() {
+        // what five handlers at one timestamp draw, and the runner's next tie-break
+        // and delay draws
+        fn run_draws() -> (Vec<u64>, [u64; 2]) {
+            let simulation = mk_simulation();
+            let draws: Rc<RefCell<Vec<u64>>> = Default::default();
+            let mut runner = SimulationRunner::new(simulation, {
+                let draws = draws.clone();
+                Box::new(move |_, _| {
+                    Vec::from_iter((0..5).map(|_| {
+                        let draws = draws.clone();
+                        ProposedEvent::at(3).named("draw").handler(
+                            move |simulation: &'static BaseSimulation, _| {
+                                draws
+                                    .borrow_mut()
+                                    .push(simulation.with_rng(|rng| rng.gen()));
+                                Default::default()
+                            },
+                        )
+                    }))
+                })
+            });
+            assert_eq!(runner.run(), EndReason::EventHeapDrained);
+            let runner_draws = [runner.tie_break_rng.gen(), runner.delay_rng.gen()];
+            (draws.take(), runner_draws)
+        }
+
+        let (draws, runner_draws) = run_draws();
+        assert_eq!(
+            HashSet::<_>::from_iter(draws.iter().chain(&runner_draws)).len(),
+            7
+        );
+        assert_eq!(run_draws(), (draws, runner_draws));
+    }
+
+    // reschedules itself every 10 ticks forever, without keeping the run alive
+    fn mk_collector_handler(
+    ) -> impl FnOnce(&'static BaseSimulation, u64) -> Vec<ProposedEvent<BaseSimulation>> {
+        |_, _| {
+            vec![ProposedEvent::after(10.0, 0.0)
+                .named("collector")
+                .infrastructure()
+                .handler(mk_collector_handler())]
+        }
+    }
+
+    #[test]
+    fn fast_forward_tracks_the_next_other_due_time() {
+        let mut runner = SimulationRunner::new(
+            mk_simulation(),
+            Box::new(|_, _| {
+                Vec::from_iter(
+                    [ProposedEvent::after(10.0, 0.0)
+                        .named("collector")
+                        .infrastructure()
+                        .handler(mk_collector_handler())]
+                    .into_iter()
+                    .chain([55, 1055, 1060].map(|due_time| {
+                        ProposedEvent::at(due_time)
+                            .named("work")
+                            .handler(|_, _| Default::default())
+                    })),
+                )
+            }),
+        );
+        // set after the initial event was scheduled, which the tracking follows
+        runner.set_fast_forward_labels(["collector"]);
+        assert_eq!(runner.other_due_times, BTreeMap::from([(0, 1)]));
+
+        assert_eq!(runner.run_until(500), EndReason::ReachedEndTime);
+        assert_eq!(
+            runner.other_due_times,
+            BTreeMap::from([(1055, 1), (1060, 1)])
+        );
+        // the collector skipped the idle gap to just before the next work
+        assert_eq!(runner.peek_next_time(), Some(1054));
+
+        runner.set_end_when_work_completed(true);
+        assert_eq!(runner.run(), EndReason::WorkCompleted);
+        assert!(runner.other_due_times.is_empty());
+    }
+
+    // a collector every second around work at 5.5s and again 10000s later, returning
+    // the timestamps it sampled at and the events dispatched
+    fn run_idle_gap(fast_forward: bool) -> (Vec<u64>, u64) {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let ticks = |seconds: f64| (seconds * simulation.get_ticks_per_second()) as u64;
+        let sample_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let sample_timestamps = sample_timestamps.clone();
+            Box::new(move |simulation, timestamp| {
+                let period = LogNormal::from_mean_cv(ticks(1.0) as f32, 0.0).unwrap();
+                let collection_event = mk_singleton_event(
+                    simulation,
+                    "idle_gap_collection",
+                    period,
+                    period,
+                    move |_, timestamp| {
+                        sample_timestamps.borrow_mut().push(timestamp);
+                        Default::default()
+                    },
+                );
+                Vec::from_iter(collection_event.into_iter().chain(
+                    [ticks(5.5), ticks(10_005.5)].map(|work_seconds| ProposedEvent {
+                        due_time: DueTime::At(timestamp + work_seconds),
+                        handler: Box::new(|_, _| Default::default()),
+                        label: Some("idle_gap_work"),
+                        keepalive: true,
+                        priority: 0,
+                    }),
+                ))
+            })
+        });
+        runner.set_end_when_work_completed(true);
+        if fast_forward {
+            runner.set_fast_forward_labels(["idle_gap_collection"]);
+        }
+        runner.run();
+
+        let sample_timestamps = sample_timestamps.borrow().clone();
+        (
+            sample_timestamps,
+            simulation.get_events_dispatched_metric().get(),
+        )
+    }
+
+    #[test]
+    fn fast_forward_skips_idle_collections() {
+        let (samples, events_dispatched) = run_idle_gap(false);
+        let (fast_forward_samples, fast_forward_events_dispatched) = run_idle_gap(true);
+
+        // a sample as the run goes idle, then just before each piece of work, and
+        // one period after the first, from which the next goes idle again
+        let ticks_per_second = SimulationBuilder::new(1).get_ticks_per_second();
+        let ticks = |seconds: f64| (seconds * ticks_per_second) as u64;
+        assert_eq!(fast_forward_samples[0], samples[0]);
+        assert_eq!(
+            fast_forward_samples[1..],
+            [ticks(5.5) - 1, ticks(6.5) - 1, ticks(10_005.5) - 1]
+        );
+        assert_eq!(*samples.last().unwrap(), ticks(10_005.0));
+        assert!(fast_forward_events_dispatched * 100 < events_dispatched);
+    }
+
+    // at each of 20 ticks, 5 events freeing a resource with priority 1 and 5 new
+    // requests for it at the default priority, scheduled interleaved
+    #[test]
+    fn higher_priority_events_dispatch_first_within_a_tick() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let dispatched: Rc<RefCell<Vec<(u64, i32, usize)>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let dispatched = dispatched.clone();
+            Box::new(move |_, timestamp| {
+                Vec::from_iter((1..=20).flat_map(|tick| {
+                    let dispatched = dispatched.clone();
+                    (0..10).map(move |index| {
+                        let priority = (index % 2 == 0) as i32;
+                        let dispatched = dispatched.clone();
+                        ProposedEvent {
+                            due_time: DueTime::At(timestamp + tick),
+                            handler: Box::new(move |_, timestamp| {
+                                dispatched.borrow_mut().push((timestamp, priority, index));
+                                Default::default()
+                            }),
+                            label: Some(if priority > 0 {
+                                "intra_tick_priority_freed"
+                            } else {
+                                "intra_tick_priority_request"
+                            }),
+                            keepalive: true,
+                            priority,
+                        }
+                    })
+                }))
+            })
+        });
+        let end_reason = runner.run();
+
+        let dispatched = dispatched.borrow();
+        assert_eq!(dispatched.len(), 200);
+        let mut shuffled = false;
+        for tick in dispatched.chunks(10) {
+            assert!(tick.iter().all(|(timestamp, _, _)| *timestamp == tick[0].0));
+            // every freed event first, still shuffled among themselves
+            assert!(tick[..5].iter().all(|(_, priority, _)| *priority == 1));
+            assert!(tick[5..].iter().all(|(_, priority, _)| *priority == 0));
+            shuffled |= !tick[..5].is_sorted_by_key(|(_, _, index)| *index);
+        }
+        assert!(shuffled);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // an event at 10 ticks that proposes one due 5 ticks earlier, returning the
+    // timestamps events were dispatched at
+    fn run_past_event(strict: bool) -> Vec<u64> {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let dispatched: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let dispatched = dispatched.clone();
+            Box::new(move |_, timestamp| {
+                vec![ProposedEvent {
+                    due_time: DueTime::At(timestamp + 10),
+                    handler: Box::new(move |_, timestamp| {
+                        dispatched.borrow_mut().push(timestamp);
+                        vec![ProposedEvent {
+                            due_time: DueTime::At(timestamp - 5),
+                            handler: Box::new(move |_, timestamp| {
+                                dispatched.borrow_mut().push(timestamp);
+                                Default::default()
+                            }),
+                            label: Some("strict_time_past"),
+                            keepalive: true,
+                            priority: 0,
+                        }]
+                    }),
+                    label: Some("strict_time_present"),
+                    keepalive: true,
+                    priority: 0,
+                }]
+            })
+        });
+        runner.set_strict_monotonic_time(strict);
+        runner.run();
+        let dispatched = dispatched.borrow().clone();
+        dispatched
+    }
+
+    #[test]
+    fn past_event_is_dispatched_late_unless_time_is_strict() {
+        // by default the past event is dispatched late, at the tick it was proposed
+        assert_eq!(run_past_event(false), [10, 10]);
+
+        let panic = std::panic::catch_unwind(|| run_past_event(true)).unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert_eq!(
+            message,
+            "Event strict_time_past due at 5 proposed at 10, in the past"
+        );
+    }
+
+    const QUIESCENCE_ITEMS: usize = 200;
+
+    // items arriving over the first 100s to two workers, alongside a collector that
+    // would keep the run going forever, counting how often each item is served
+    fn mk_quiescence_bootstrap<S: TopologySimulation>(
+        served: Rc<RefCell<Vec<u32>>>,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let queue = Queue::new(simulation, "quiescence").into_registered(simulation);
+
+            for id in 0..2 {
+                mk_worker(simulation, id, &queue).listen(simulation, timestamp);
+            }
+
+            let period =
+                LogNormal::from_mean_cv(simulation.get_ticks_per_second() as f32, 0.0).unwrap();
+            let collection_event = mk_singleton_event(
+                simulation,
+                "quiescence_collection",
+                period,
+                period,
+                |_, _| Default::default(),
+            );
+
+            let mut rng = mk_named_rng(simulation, "quiescence");
+            let arrival_events = Vec::from_iter((0..QUIESCENCE_ITEMS).map(|item| {
+                let arrival_ticks =
+                    rng.gen_range(0..(100.0 * simulation.get_ticks_per_second()) as u64);
+                let service_ticks = sample_exp_ticks(simulation, &mut rng, 2.0);
+                let served = served.clone();
+                ProposedEvent {
+                    due_time: DueTime::At(timestamp + arrival_ticks),
+                    handler: Box::new(Queue::mk_enqueued_handler(
+                        queue.clone(),
+                        move |_, timestamp, worker_token| {
+                            vec![ProposedEvent {
+                                due_time: DueTime::At(timestamp + service_ticks),
+                                handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                                    move |_, _| {
+                                        served.borrow_mut()[item] += 1;
+                                        (Default::default(), vec![worker_token])
+                                    },
+                                )),
+                                label: Some("quiescence_service_complete"),
+                                keepalive: true,
+                                priority: 0,
+                            }]
+                        },
+                    )),
+                    label: Some("quiescence_arrival"),
+                    keepalive: true,
+                    priority: 0,
+                }
+            }));
+
+            Vec::from_iter(collection_event.into_iter().chain(arrival_events))
+        }
+    }
+
+    #[test]
+    fn run_to_quiescence_ignores_collectors_and_honours_cap() {
+        let run = |max_events| {
+            let simulation: &'static BaseQueueSimulation =
+                Box::leak(Box::new(BaseQueueSimulation::new(1)));
+            let served = Rc::new(RefCell::new(vec![0; QUIESCENCE_ITEMS]));
+            let mut runner = SimulationRunner::new(
+                simulation,
+                Box::new(mk_quiescence_bootstrap(served.clone())),
+            );
+            let end_reason = runner.run_to_quiescence(max_events);
+            let served = served.borrow().clone();
+            (end_reason, served, runner)
+        };
+
+        let (end_reason, served, runner) = run(1_000_000);
+        assert_eq!(end_reason, EndReason::WorkCompleted);
+        assert!(served.iter().all(|&count| count == 1));
+        assert!(runner
+            .get_pending_events()
+            .iter()
+            .all(|event| !event.keepalive));
+
+        let (capped_end_reason, capped_served, capped_runner) = run(100);
+        assert_eq!(capped_end_reason, EndReason::EventCapReached);
+        assert!(capped_served.contains(&0));
+        // stopped with workers still checked out, which would panic on drop
+        std::mem::forget(capped_runner);
+    }
+
+    const INITIAL_TIMESTAMP_START: u64 = 1_000_000;
+
+    // a run resumed at 1_000_000 ticks, sampling metrics every 15s until a piece of
+    // work completes a minute later
+    #[test]
+    fn run_resumes_from_initial_timestamp() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let ticks = |seconds: f64| (seconds * simulation.get_ticks_per_second()) as u64;
+        let sample_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let work_timestamp: Rc<Cell<Option<u64>>> = Default::default();
+
+        let mut runner = SimulationRunner::new_at(
+            simulation,
+            INITIAL_TIMESTAMP_START,
+            Box::new({
+                let sample_timestamps = sample_timestamps.clone();
+                let work_timestamp = work_timestamp.clone();
+                move |simulation, timestamp| {
+                    assert_eq!(timestamp, INITIAL_TIMESTAMP_START);
+                    let collection_event = mk_singleton_event(
+                        simulation,
+                        "initial_timestamp_collection",
+                        LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+                        LogNormal::from_mean_cv(
+                            (simulation.get_metrics_sampling_period_seconds()
+                                * simulation.get_ticks_per_second())
+                                as f32,
+                            0.0,
+                        )
+                        .unwrap(),
+                        move |_, timestamp| {
+                            sample_timestamps.borrow_mut().push(timestamp);
+                            Default::default()
+                        },
+                    );
+                    Vec::from_iter(collection_event.into_iter().chain([ProposedEvent {
+                        due_time: DueTime::After(
+                            LogNormal::from_mean_cv(ticks(60.0) as f32, 0.0).unwrap(),
+                        ),
+                        handler: Box::new(move |_, timestamp| {
+                            work_timestamp.set(Some(timestamp));
+                            Default::default()
+                        }),
+                        label: Some("initial_timestamp_work"),
+                        keepalive: true,
+                        priority: 0,
+                    }]))
+                }
+            }),
+        );
+        let end_reason = runner.run_to_quiescence(1_000);
+
+        let sample_timestamps = sample_timestamps.borrow().clone();
+        assert_eq!(
+            work_timestamp.get(),
+            Some(INITIAL_TIMESTAMP_START + ticks(60.0))
+        );
+        assert_eq!(
+            sample_timestamps,
+            Vec::from_iter((0..4).map(|n| INITIAL_TIMESTAMP_START + 1 + n * ticks(15.0)))
+        );
+        assert_eq!(
+            simulation.get_simulated_seconds_metric().get(),
+            (INITIAL_TIMESTAMP_START + ticks(60.0)) as f64 / simulation.get_ticks_per_second()
+        );
+
+        assert_eq!(end_reason, EndReason::WorkCompleted);
+    }
+
+    const FORK_ARRIVALS: u64 = 600;
+
+    // Poisson arrivals at 3/s to two workers serving exponential service times at 1/s,
+    // falling ever further behind
+    fn fork_bootstrap<S: TopologySimulation>(
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let queue = Queue::new(simulation, "fork").into_registered(simulation);
+
+        for id in 0..2 {
+            mk_worker(simulation, id, &queue).listen(simulation, timestamp);
+        }
+
+        let rng = Rc::new(RefCell::new(mk_named_rng(simulation, "fork")));
+        let first_arrival_ticks = sample_exp_ticks(simulation, &mut rng.borrow_mut(), 3.0);
+        vec![mk_markovian_arrival_event(
+            queue,
+            rng,
+            MarkovianArrivals {
+                arrivals_per_second: 3.0,
+                services_per_second: 1.0,
+                options: Default::default(),
+                remaining: FORK_ARRIVALS,
+            },
+            timestamp + first_arrival_ticks,
+        )]
+    }
+
+    // the same backlog run on from 100s as it is and with three more workers added
+    #[test]
+    fn fork_shares_prefix_then_diverges() {
+        let traces: Rc<RefCell<Vec<SharedTraceBuffer>>> = Default::default();
+        let mk_runner = {
+            let traces = traces.clone();
+            move || {
+                let simulation: &'static BaseQueueSimulation =
+                    Box::leak(Box::new(BaseQueueSimulation::new(1)));
+                let mut runner = SimulationRunner::new(simulation, Box::new(fork_bootstrap));
+                let trace = SharedTraceBuffer::default();
+                traces.borrow_mut().push(trace.clone());
+                runner.set_trace_writer(Box::new(trace));
+                runner
+            }
+        };
+
+        let mut baseline = mk_runner();
+        baseline.set_reconstructor(mk_runner);
+        let end_timestamp = (100.0 * baseline.get_simulation().get_ticks_per_second()) as u64;
+        baseline.run_until(end_timestamp);
+        let prefix_events = baseline
+            .get_simulation()
+            .get_events_dispatched_metric()
+            .get();
+
+        let mut scaled_up = baseline.fork();
+        scaled_up.schedule_event(ProposedEvent {
+            due_time: DueTime::After(LogNormal::from_mean_cv(1.0, 0.0).unwrap()),
+            handler: Box::new(|simulation, timestamp| {
+                let queue = simulation.borrow_subsystems_mut().get_queues().remove(0);
+                // each is dispatched from the backlog straight away
+                Vec::from_iter(
+                    (2..5).flat_map(|id| {
+                        mk_worker(simulation, id, &queue).listen(simulation, timestamp)
+                    }),
+                )
+            }),
+            label: Some("fork_scale_up"),
+            keepalive: false,
+            priority: 0,
+        });
+
+        let end_reason = baseline.run();
+        scaled_up.run();
+
+        let finished_seconds = [&baseline, &scaled_up]
+            .map(|runner| runner.get_simulation().get_simulated_seconds_metric().get());
+        let traces = traces.borrow();
+        let [baseline_trace, scaled_up_trace] =
+            [&traces[0], &traces[1]].map(|trace| read_trace(&trace.0.borrow()[..]).unwrap());
+        let prefix_events = prefix_events as usize;
+        assert_eq!(
+            baseline_trace[..prefix_events],
+            scaled_up_trace[..prefix_events]
+        );
+        assert_ne!(
+            baseline_trace[prefix_events..],
+            scaled_up_trace[prefix_events..]
+        );
+        assert!(finished_seconds[1] < finished_seconds[0]);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // the order ten events due at the same tick are dispatched in, optionally
+    // alongside unrelated events with sampled delays and after draws from the
+    // simulation rng
+    fn run_tie_break(unrelated_draws: bool) -> Vec<usize> {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        if unrelated_draws {
+            let _: u64 = simulation.borrow_rng_mut().gen();
+        }
+        let dispatched: Rc<RefCell<Vec<usize>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let dispatched = dispatched.clone();
+            Box::new(move |_, timestamp| {
+                let tied_events = (0..10).map(|index| {
+                    let dispatched = dispatched.clone();
+                    ProposedEvent {
+                        due_time: DueTime::At(timestamp + 50),
+                        handler: Box::new(move |_, _| {
+                            dispatched.borrow_mut().push(index);
+                            Default::default()
+                        }),
+                        label: Some("rng_streams_tied"),
+                        keepalive: true,
+                        priority: 0,
+                    }
+                });
+                let unrelated_events = (0..50 * unrelated_draws as usize).map(|_| ProposedEvent {
+                    due_time: DueTime::After(LogNormal::from_mean_cv(10_000.0, 0.1).unwrap()),
+                    handler: Box::new(|_, _| Default::default()),
+                    label: Some("rng_streams_unrelated"),
+                    keepalive: true,
+                    priority: 0,
+                });
+                Vec::from_iter(tied_events.chain(unrelated_events))
+            })
+        });
+        runner.run();
+
+        let dispatched = dispatched.borrow().clone();
+        dispatched
+    }
+
+    #[test]
+    fn tie_break_order_is_unaffected_by_other_draws() {
+        let order = run_tie_break(false);
+        let perturbed_order = run_tie_break(true);
+        assert!(!order.is_sorted());
+        // the tie-break stream is unaffected by delays sampled or the simulation rng
+        assert_eq!(perturbed_order, order);
+    }
+
+    // two workers serving six arrivals a second apart, each for a sampled time
+    fn replay_bootstrap(
+        simulation: &'static BaseQueueSimulation,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<BaseQueueSimulation>> {
+        let queue = Queue::new(simulation, "replay").into_registered(simulation);
+        for id in 0..2 {
+            mk_worker(simulation, id, &queue).listen(simulation, timestamp);
+        }
+        let service_time = delay_from_mean_cv(simulation.get_ticks_per_second() as f32, 0.5);
+        Vec::from_iter((1..=6).map(|i| {
+            ProposedEvent::after_seconds(simulation, i as f64, 0.0)
+                .named("replay_arrival")
+                .handler(Queue::mk_enqueued_handler(
+                    queue.clone(),
+                    move |_, _, worker_token| {
+                        vec![ProposedEvent {
+                            due_time: DueTime::After(service_time),
+                            handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                                move |_, _| (Default::default(), vec![worker_token]),
+                            )),
+                            label: Some("replay_restore"),
+                            keepalive: true,
+                            priority: 0,
+                        }]
+                    },
+                ))
+        }))
+    }
+
+    fn mk_replay_runner() -> SimulationRunner<BaseQueueSimulation> {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        SimulationRunner::new(simulation, Box::new(replay_bootstrap))
+    }
+
+    fn record_replay_trace() -> Vec<TraceRecord> {
+        let buffer = SharedTraceBuffer::default();
+        let mut runner = mk_replay_runner();
+        runner.set_trace_writer(Box::new(buffer.clone()));
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        let trace = read_trace(&buffer.0.borrow()[..]).unwrap();
+        assert_eq!(trace.len() as u64, runner.get_events_dispatched());
+        trace
+    }
+
+    #[test]
+    fn replay_reproduces_recorded_trace() {
+        let trace = record_replay_trace();
+        let mut runner = mk_replay_runner();
+        runner.set_replay_trace(trace);
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        assert_eq!(runner.get_replay_divergence(), None);
+    }
+
+    #[test]
+    fn replay_flags_divergence() {
+        let mut trace = record_replay_trace();
+        let diverging_index = trace
+            .iter()
+            .position(|r| r.label.as_deref() == Some("replay_restore"))
+            .unwrap();
+        trace[diverging_index].timestamp += 1;
+        let expected = trace[diverging_index].clone();
+
+        let mut runner = mk_replay_runner();
+        runner.set_replay_trace(trace);
+        assert_eq!(runner.run(), EndReason::ReplayDiverged);
+        let divergence = runner.get_replay_divergence().unwrap();
+        assert_eq!(divergence.event_index, diverging_index as u64);
+        assert_eq!(divergence.expected, Some(expected));
+        // the run stopped with workers still checked out, which would panic on drop
+        std::mem::forget(runner);
     }
 }
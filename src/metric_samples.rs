@@ -0,0 +1,367 @@
+use std::fmt::Debug;
+
+use prometheus_client::encoding::text::encode_registry;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::{Metric, Registry};
+
+use crate::error::*;
+
+/// One sample of one series, e.g. `worker_token_duration_bucket{le="0.1",...}`,
+/// as a collector would record it
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricSample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// Snapshot every sample in the registry, by way of its text exposition so that
+/// counters, gauges and each histogram bucket, sum and count come out uniformly.
+/// Fails on exposition this can't parse, e.g. exemplars.
+pub fn extract_samples(registry: &Registry) -> Result<Vec<MetricSample>, Error> {
+    let mut exposition = String::new();
+    encode_registry(&mut exposition, registry).map_err(|_| Error::MetricEncodingFailed)?;
+
+    exposition
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            parse_sample_line(line).ok_or_else(|| Error::MalformedSample {
+                line: line.to_owned(),
+            })
+        })
+        .collect()
+}
+
+// prometheus_client only reads a series through get_or_create, which would
+// create it if missing, so a family is read by encoding it on its own as `m`
+fn extract_family_samples(family: impl Metric) -> Result<Vec<MetricSample>, Error> {
+    let mut registry = Registry::default();
+    registry.register("m", "", family);
+    extract_samples(&registry)
+}
+
+/// A counter series' labels and value
+pub type CounterSeries = (Vec<(String, String)>, u64);
+
+/// Every series of a counter family with its value, without creating any
+pub fn get_counter_series(
+    family: &Family<Vec<(String, String)>, Counter>,
+) -> Result<Vec<CounterSeries>, Error> {
+    Ok(Vec::from_iter(
+        extract_family_samples(family.clone())?
+            .into_iter()
+            .filter(|sample| sample.name == "m_total")
+            .map(|sample| (sample.labels, sample.value as u64)),
+    ))
+}
+
+/// Current value of the series with exactly `labels`, or 0 if it doesn't exist,
+/// which is left uncreated
+pub fn get_counter_value(
+    family: &Family<Vec<(String, String)>, Counter>,
+    labels: &[(String, String)],
+) -> Result<u64, Error> {
+    Ok(get_counter_series(family)?
+        .into_iter()
+        .find(|(series_labels, _)| series_labels == labels)
+        .map_or(0, |(_, value)| value))
+}
+
+/// Like `get_counter_value`, for gauges
+pub fn get_gauge_value(
+    family: &Family<Vec<(String, String)>, Gauge>,
+    labels: &[(String, String)],
+) -> Result<i64, Error> {
+    Ok(extract_family_samples(family.clone())?
+        .into_iter()
+        .find(|sample| sample.name == "m" && sample.labels == labels)
+        .map_or(0, |sample| sample.value as i64))
+}
+
+/// Sum and count of observations of the series with exactly `labels`, or zeros if
+/// it doesn't exist, which is left uncreated
+pub fn get_histogram_sum_count<
+    C: MetricConstructor<Histogram> + Clone + Send + Sync + Debug + 'static,
+>(
+    family: &Family<Vec<(String, String)>, Histogram, C>,
+    labels: &[(String, String)],
+) -> Result<(f64, u64), Error> {
+    // prometheus_client doesn't expose a histogram's sum and count either
+    let samples = extract_family_samples(family.clone())?;
+    let find = |name: &str| {
+        samples
+            .iter()
+            .find(|sample| sample.name == name && sample.labels == labels)
+            .map_or(0.0, |sample| sample.value)
+    };
+    Ok((find("m_sum"), find("m_count") as u64))
+}
+
+/// One histogram series' buckets, sum and count at a point in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramSnapshot {
+    pub labels: Vec<(String, String)>,
+    // cumulative counts by upper bound, as exposed, the last being +Inf
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+/// Snapshot every series of a histogram family and then clear it, so that its
+/// observations from here on start from empty, e.g. from a window-boundary handler
+/// for per-minute distributions. Observations can't interleave with this, but
+/// which window an observation made at the boundary's own tick lands in depends
+/// on its order relative to the boundary event: give that event a priority to
+/// settle it. Buffered observations, e.g. of worker token durations, should be
+/// flushed first. Series not observed since the last reset are omitted. Fails,
+/// leaving the family as it was, on samples this can't parse.
+pub fn take_histogram_window<
+    C: MetricConstructor<Histogram> + Clone + Send + Sync + Debug + 'static,
+>(
+    family: &Family<Vec<(String, String)>, Histogram, C>,
+) -> Result<Vec<HistogramSnapshot>, Error> {
+    // as in get_histogram_sum_count, the only way in is through encoding
+    let samples = extract_family_samples(family.clone())?;
+
+    let mut snapshots: Vec<HistogramSnapshot> = Vec::new();
+    for sample in samples {
+        let mut labels = sample.labels;
+        let le = labels
+            .iter()
+            .position(|(name, _)| name == "le")
+            .map(|i| labels.remove(i).1);
+        let snapshot = match snapshots.iter_mut().find(|s| s.labels == labels) {
+            Some(snapshot) => snapshot,
+            None => {
+                snapshots.push(HistogramSnapshot {
+                    labels,
+                    buckets: Default::default(),
+                    sum: 0.0,
+                    count: 0,
+                });
+                snapshots.last_mut().unwrap()
+            }
+        };
+        match (sample.name.as_str(), le) {
+            ("m_bucket", Some(le)) => {
+                let upper_bound = if le == "+Inf" {
+                    f64::INFINITY
+                } else {
+                    le.parse().map_err(|_| Error::MalformedSample {
+                        line: format!("{} le={le}", sample.name),
+                    })?
+                };
+                snapshot.buckets.push((upper_bound, sample.value as u64));
+            }
+            ("m_sum", None) => snapshot.sum = sample.value,
+            ("m_count", None) => snapshot.count = sample.value as u64,
+            (name, _) => {
+                return Err(Error::MalformedSample {
+                    line: name.to_owned(),
+                })
+            }
+        }
+    }
+    family.clear();
+    Ok(snapshots)
+}
+
+fn parse_sample_line(line: &str) -> Option<MetricSample> {
+    let (series, value) = line.rsplit_once(' ')?;
+    let value = match value {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        v => v.parse().ok()?,
+    };
+
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    };
+    let Some((name, mut rest)) = series.split_once('{') else {
+        if !is_name(series) {
+            return None;
+        }
+        return Some(MetricSample {
+            name: series.to_owned(),
+            labels: Default::default(),
+            value,
+        });
+    };
+
+    let mut labels = Vec::new();
+    while let Some((label_name, quoted)) = rest.split_once("=\"") {
+        let mut label_value = String::new();
+        let mut chars = quoted.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (i, '"') => break i,
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => label_value.push('\n'),
+                    c => label_value.push(c),
+                },
+                (_, c) => label_value.push(c),
+            }
+        };
+        labels.push((label_name.to_owned(), label_value));
+        rest = quoted[end + 1..].trim_start_matches(',');
+    }
+    if !is_name(name) || rest != "}" {
+        return None;
+    }
+
+    Some(MetricSample {
+        name: name.to_owned(),
+        labels,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use prometheus_client::metrics::exemplar::CounterWithExemplar;
+
+    use super::*;
+    use crate::args_rets::*;
+    use crate::main_loop::*;
+    use crate::simulation::*;
+
+    #[test]
+    fn reading_missing_series_leaves_them_uncreated() {
+        let counters: Family<Vec<(String, String)>, Counter> = Default::default();
+        let gauges: Family<Vec<(String, String)>, Gauge> = Default::default();
+        let histograms: Family<Vec<(String, String)>, Histogram> =
+            Family::new_with_constructor(|| Histogram::new([1.0].into_iter()));
+        let mut registry = Registry::default();
+        registry.register("c", "", counters.clone());
+        registry.register("g", "", gauges.clone());
+        registry.register("h", "", histograms.clone());
+
+        let present = vec![("name".to_owned(), "present".to_owned())];
+        let missing = [("name".to_owned(), "missing".to_owned())];
+        counters.get_or_create(&present).inc_by(3);
+        gauges.get_or_create(&present).set(-2);
+        histograms.get_or_create(&present).observe(0.5);
+
+        assert_eq!(get_counter_value(&counters, &present), Ok(3));
+        assert_eq!(get_gauge_value(&gauges, &present), Ok(-2));
+        assert_eq!(get_histogram_sum_count(&histograms, &present), Ok((0.5, 1)));
+        assert_eq!(get_counter_value(&counters, &missing), Ok(0));
+        assert_eq!(get_gauge_value(&gauges, &missing), Ok(0));
+        assert_eq!(get_histogram_sum_count(&histograms, &missing), Ok((0.0, 0)));
+
+        let samples = extract_samples(&registry).unwrap();
+        assert!(samples
+            .iter()
+            .all(|sample| !sample.labels.contains(&missing[0])));
+        assert_eq!(
+            get_counter_series(&counters),
+            Ok(vec![(present.clone(), 3)])
+        );
+    }
+
+    #[test]
+    fn unparseable_exposition_is_an_error() {
+        let counter: CounterWithExemplar<Vec<(String, String)>> = Default::default();
+        counter.inc_by(1, Some(vec![("trace_id".to_owned(), "abc".to_owned())]));
+        let mut registry = Registry::default();
+        registry.register("exemplary", "", counter);
+
+        assert!(matches!(
+            extract_samples(&registry),
+            Err(Error::MalformedSample { line }) if line.starts_with("exemplary_total 1 #")
+        ));
+    }
+
+    // observations of 1.0 in the first 10 ticks and of 100.0 in the next 10, with
+    // a tumbling window taken at the end of each. The boundary events have a lower
+    // priority, so an observation at a boundary's tick falls in the window it ends
+    #[test]
+    fn histogram_windows_tumble_at_boundaries() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let family: Family<Vec<(String, String)>, Histogram> =
+            Family::new_with_constructor(|| Histogram::new([10.0].into_iter()));
+        simulation.borrow_metric_registry_mut().register(
+            "histogram_window",
+            "Observations made by the histogram window test",
+            family.clone(),
+        );
+        let windows: Rc<RefCell<Vec<Vec<HistogramSnapshot>>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let family = family.clone();
+            let windows = windows.clone();
+            Box::new(move |_, timestamp| {
+                let labels = vec![("series".to_owned(), "test".to_owned())];
+                let observations = [(1, 1.0), (5, 1.0), (10, 1.0), (15, 100.0), (20, 100.0)];
+                let observation_events = observations.map(|(tick, value)| {
+                    let family = family.clone();
+                    let labels = labels.clone();
+                    ProposedEvent {
+                        due_time: DueTime::At(timestamp + tick),
+                        handler: Box::new(move |_, _| {
+                            family.get_or_create(&labels).observe(value);
+                            Default::default()
+                        }),
+                        label: Some("histogram_window_observation"),
+                        keepalive: true,
+                        priority: 0,
+                    }
+                });
+                let boundary_events = [10, 20].map(|tick| {
+                    let family = family.clone();
+                    let windows = windows.clone();
+                    ProposedEvent {
+                        due_time: DueTime::At(timestamp + tick),
+                        handler: Box::new(move |_, _| {
+                            windows
+                                .borrow_mut()
+                                .push(take_histogram_window(&family).unwrap());
+                            Default::default()
+                        }),
+                        label: Some("histogram_window_boundary"),
+                        keepalive: true,
+                        priority: -1,
+                    }
+                });
+                Vec::from_iter(observation_events.into_iter().chain(boundary_events))
+            })
+        });
+        let end_reason = runner.run();
+
+        let windows = windows.borrow();
+        let labels = vec![("series".to_owned(), "test".to_owned())];
+        assert_eq!(
+            *windows,
+            [
+                vec![HistogramSnapshot {
+                    labels: labels.clone(),
+                    buckets: vec![(10.0, 3), (f64::INFINITY, 3)],
+                    sum: 3.0,
+                    count: 3,
+                }],
+                vec![HistogramSnapshot {
+                    labels,
+                    buckets: vec![(10.0, 0), (f64::INFINITY, 2)],
+                    sum: 200.0,
+                    count: 2,
+                }],
+            ]
+        );
+        // and nothing's left for the registry to report
+        let samples = extract_samples(&simulation.borrow_metric_registry()).unwrap();
+        assert!(!samples
+            .iter()
+            .any(|sample| sample.name.starts_with("histogram_window")));
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+}
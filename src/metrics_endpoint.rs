@@ -0,0 +1,125 @@
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use prometheus_client::encoding::text::encode_registry;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::args_rets::*;
+use crate::simulation::*;
+
+/// Serves the simulation's metrics at `/metrics` for a Prometheus server to
+/// scrape. The registry is behind a `RefCell` on the simulation's thread, so it
+/// can't be encoded on demand from the server's - instead the simulation encodes
+/// it into a shared buffer each time it collects, and the server, on a thread of
+/// its own running a current-thread tokio runtime, answers every scrape with
+/// whatever was encoded last. Scrapes never hold up the simulation, at the cost
+/// of being up to a collection period stale.
+pub struct MetricsEndpoint {
+    pub local_addr: SocketAddr,
+    exposition: Arc<Mutex<String>>,
+}
+
+impl MetricsEndpoint {
+    /// Bind to `addr`, e.g. "127.0.0.1:9184" or port 0 for any free one, and start
+    /// serving from a background thread. The thread lives until the process exits.
+    pub fn bind(addr: &str) -> std::io::Result<Self> {
+        // bound here so that a bad address fails the caller rather than the thread
+        let std_listener = StdTcpListener::bind(addr)?;
+        std_listener.set_nonblocking(true)?;
+        let local_addr = std_listener.local_addr()?;
+        let exposition: Arc<Mutex<String>> = Default::default();
+
+        let served_exposition = exposition.clone();
+        thread::Builder::new()
+            .name("metrics-endpoint".into())
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_io()
+                    .enable_time()
+                    .build()
+                    .unwrap();
+                runtime.block_on(serve(std_listener, served_exposition));
+            })?;
+
+        log::info!("serving metrics at http://{local_addr}/metrics");
+        Ok(MetricsEndpoint {
+            local_addr,
+            exposition,
+        })
+    }
+
+    /// Encode the simulation's current metrics for the following scrapes
+    pub fn collect<S: Simulation>(&self, simulation: &S) {
+        let mut exposition = String::new();
+        encode_registry(&mut exposition, &simulation.borrow_metric_registry()).unwrap();
+        *self.exposition.lock().unwrap() = exposition;
+    }
+}
+
+// how long to wait before accepting again after a failure, so a persistent one,
+// e.g. running out of file descriptors, doesn't spin
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+async fn serve(std_listener: StdTcpListener, exposition: Arc<Mutex<String>>) {
+    let listener = TcpListener::from_std(std_listener).unwrap();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("metrics endpoint failed to accept a connection: {err}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        tokio::spawn(respond(stream, exposition.clone()));
+    }
+}
+
+const MAX_REQUEST_BYTES: usize = 8192;
+
+// one request per connection, which is all a scraper needs
+async fn respond(mut stream: TcpStream, exposition: Arc<Mutex<String>>) {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_BYTES {
+            return;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let (status, content_type, body) =
+        match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+            ["GET", "/metrics"] => (
+                "200 OK",
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                exposition.lock().unwrap().clone(),
+            ),
+            _ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+        };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        log::warn!("metrics endpoint failed to respond: {err}");
+    }
+}
+
+/// A handler for `mk_singleton_event` collecting to `endpoint` every period
+pub fn mk_metrics_endpoint_handler<S: Simulation + 'static>(
+    endpoint: Rc<MetricsEndpoint>,
+) -> impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> {
+    move |simulation, _| {
+        endpoint.collect(simulation);
+        Default::default()
+    }
+}
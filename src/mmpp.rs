@@ -0,0 +1,207 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use rand::distributions::{Distribution, WeightedError, WeightedIndex};
+use rand_distr::LogNormal;
+use rand_xoshiro::Xoshiro256StarStar;
+
+use crate::args_rets::*;
+use crate::simulation::*;
+
+/// One state of a `MarkovModulatedArrivals`, e.g. "quiet" or "burst"
+#[derive(Clone, Debug)]
+pub struct MmppState {
+    pub name: &'static str,
+    // between arrivals while in this state
+    pub interarrival: LogNormal<f32>,
+    // how long the process stays in this state before transitioning
+    pub dwell_time: LogNormal<f32>,
+    // (weight, index of next state) pairs; a state without any is never left
+    pub transitions: Vec<(f64, usize)>,
+}
+
+/// Arrivals whose interarrival distribution is set by a state machine stepping
+/// between `MmppState`s, so that traffic comes in correlated bursts rather than
+/// independently. Transitions are scheduled as events. Arrivals are a single
+/// `mk_modulated_arrival_process`, so a transition applies from the arrival
+/// after the one already pending, which is never superseded or left behind.
+pub struct MarkovModulatedArrivals<S: Simulation + 'static> {
+    label: &'static str,
+    states: Vec<MmppState>,
+    transition_indices: Vec<Option<WeightedIndex<f64>>>,
+    current_state: usize,
+    rng: Xoshiro256StarStar,
+    stop: Rc<Cell<bool>>,
+    arrival: Rc<dyn Fn(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+}
+
+impl<S: Simulation + 'static> MarkovModulatedArrivals<S> {
+    /// `rng` chooses transitions; setting `stop` ends both arrivals and transitions
+    pub fn new(
+        label: &'static str,
+        states: Vec<MmppState>,
+        initial_state: usize,
+        rng: Xoshiro256StarStar,
+        stop: Rc<Cell<bool>>,
+        arrival: impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> Result<Self, WeightedError> {
+        assert!(initial_state < states.len(), "No initial MMPP state");
+        let mut transition_indices = Vec::new();
+        for state in &states {
+            assert!(
+                state
+                    .transitions
+                    .iter()
+                    .all(|(_, next)| *next < states.len()),
+                "MMPP state {} transitions to a state that doesn't exist",
+                state.name,
+            );
+            transition_indices.push(if state.transitions.is_empty() {
+                None
+            } else {
+                Some(WeightedIndex::new(
+                    state.transitions.iter().map(|(weight, _)| *weight),
+                )?)
+            });
+        }
+
+        Ok(MarkovModulatedArrivals {
+            label,
+            states,
+            transition_indices,
+            current_state: initial_state,
+            rng,
+            stop,
+            arrival: Rc::new(arrival),
+        })
+    }
+
+    pub fn get_current_state(&self) -> &MmppState {
+        &self.states[self.current_state]
+    }
+
+    /// The arrival process, and the first transition unless the initial state has none
+    pub fn mk_start_events(mmpp: Rc<RefCell<Self>>) -> Vec<ProposedEvent<S>> {
+        let (label, stop, arrival) = {
+            let m = mmpp.borrow();
+            (m.label, m.stop.clone(), m.arrival.clone())
+        };
+        let arrivals = mk_modulated_arrival_process(
+            label,
+            {
+                let mmpp = mmpp.clone();
+                move || mmpp.borrow().get_current_state().interarrival
+            },
+            stop,
+            move |simulation, timestamp| arrival(simulation, timestamp),
+        );
+
+        Vec::from_iter(
+            [arrivals]
+                .into_iter()
+                .chain(Self::mk_transition_event(mmpp)),
+        )
+    }
+
+    fn mk_transition_event(mmpp: Rc<RefCell<Self>>) -> Option<ProposedEvent<S>> {
+        let m = mmpp.borrow();
+        m.transition_indices[m.current_state].as_ref()?;
+        let dwell_time = m.get_current_state().dwell_time;
+        drop(m);
+
+        Some(ProposedEvent {
+            due_time: DueTime::After(dwell_time),
+            handler: Box::new(move |_, _| {
+                {
+                    let mut m = mmpp.borrow_mut();
+                    if m.stop.get() {
+                        return Default::default();
+                    }
+                    let m = &mut *m;
+                    let choice = m.transition_indices[m.current_state]
+                        .as_ref()
+                        .unwrap()
+                        .sample(&mut m.rng);
+                    m.current_state = m.states[m.current_state].transitions[choice].1;
+                }
+
+                Vec::from_iter(Self::mk_transition_event(mmpp))
+            }),
+            label: Some("mmpp_transition"),
+            keepalive: false,
+            priority: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_loop::*;
+
+    fn get_interarrival_cv(arrival_timestamps: &[u64]) -> f64 {
+        let gaps = Vec::from_iter(arrival_timestamps.windows(2).map(|w| (w[1] - w[0]) as f64));
+        let mean = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        let variance = gaps.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / gaps.len() as f64;
+        variance.sqrt() / mean
+    }
+
+    #[test]
+    fn bursts_cluster_arrivals_with_one_arrival_pending() {
+        let simulation: &'static BaseSimulation =
+            Box::leak(Box::new(SimulationBuilder::new(1).build()));
+        let arrival_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let stop: Rc<Cell<bool>> = Default::default();
+        let mmpp = Rc::new(RefCell::new(
+            MarkovModulatedArrivals::new(
+                "mmpp_arrival",
+                vec![
+                    MmppState {
+                        name: "quiet",
+                        interarrival: LogNormal::from_mean_cv(1000.0, 1.0).unwrap(),
+                        dwell_time: LogNormal::from_mean_cv(60000.0, 1.0).unwrap(),
+                        transitions: vec![(1.0, 1)],
+                    },
+                    MmppState {
+                        name: "burst",
+                        interarrival: LogNormal::from_mean_cv(50.0, 1.0).unwrap(),
+                        dwell_time: LogNormal::from_mean_cv(10000.0, 1.0).unwrap(),
+                        transitions: vec![(1.0, 0)],
+                    },
+                ],
+                0,
+                mk_named_rng(simulation, "mmpp/test"),
+                stop.clone(),
+                {
+                    let arrival_timestamps = arrival_timestamps.clone();
+                    move |_, timestamp| {
+                        arrival_timestamps.borrow_mut().push(timestamp);
+                        Default::default()
+                    }
+                },
+            )
+            .unwrap(),
+        ));
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(move |_, _| MarkovModulatedArrivals::mk_start_events(mmpp)),
+        );
+
+        for end_timestamp in (1000..3_600_000).step_by(1000) {
+            assert_eq!(runner.run_until(end_timestamp), EndReason::ReachedEndTime);
+            let pending_arrivals = runner
+                .get_pending_events()
+                .into_iter()
+                .filter(|event| event.label == Some("mmpp_arrival"))
+                .count();
+            // transitions never leave a superseded arrival behind
+            assert_eq!(pending_arrivals, 1);
+        }
+        stop.set(true);
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+
+        // each state's own interarrivals have a cv of 1, as plain Poisson's do
+        let cv = get_interarrival_cv(&arrival_timestamps.borrow());
+        assert!(cv > 1.5, "{cv}");
+    }
+}
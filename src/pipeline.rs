@@ -0,0 +1,369 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use rand_distr::LogNormal;
+
+use crate::args_rets::*;
+use crate::queue::*;
+use crate::simulation::*;
+
+/// Called once an item has been through every stage, with the ticks since it was
+/// enqueued on the first
+pub type PipelineCompletionHandler<S> =
+    Box<dyn FnMut(&'static S, u64, u64) -> Vec<ProposedEvent<S>>>;
+
+/// Called when a stage rejects an item, with the index of the stage and the
+/// item's enqueue options, e.g. to retry it later or record it as lost
+pub type PipelineRejectionHandler<S> =
+    Box<dyn FnMut(&'static S, u64, usize, EnqueueOptions) -> Vec<ProposedEvent<S>>>;
+
+/// A chain of queues an item passes through in order, holding a worker at each
+/// stage for a service time drawn from that stage's queue. Each hand-off from one
+/// stage to the next can incur a propagation delay, e.g. network latency between
+/// services - `LogNormal::from_mean_cv(delay, 0.0)` for a fixed one. An item
+/// rejected by a stage is counted in that stage's `enqueue_rejected` and handed
+/// to `on_rejected`, if set, before being dropped.
+pub struct Pipeline<S: QueueSimulation + 'static> {
+    pub stages: Vec<Rc<RefCell<Queue<S>>>>,
+    // between one stage's completion and the next stage's enqueue, None for
+    // stages adjacent in time
+    pub propagation_delay: Option<LogNormal<f32>>,
+    // called without the pipeline borrowed, so it may enqueue more
+    pub on_complete: Option<PipelineCompletionHandler<S>>,
+    // called without the pipeline borrowed, like on_complete
+    pub on_rejected: Option<PipelineRejectionHandler<S>>,
+    // Some while on_rejected is being called, holding the rejections of items it
+    // re-enqueued that were rejected straight away, to be handed to it once it
+    // returns
+    pub pending_rejections: Option<VecDeque<(usize, EnqueueOptions)>>,
+}
+
+impl<S: QueueSimulation + 'static> Pipeline<S> {
+    /// Enqueue a new item on the first stage
+    pub fn mk_enqueued_handler(
+        pipeline: Rc<RefCell<Pipeline<S>>>,
+        options: EnqueueOptions,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            Self::enqueue_stage(pipeline, 0, options, timestamp, simulation, timestamp)
+        }
+    }
+
+    fn enqueue_stage(
+        pipeline: Rc<RefCell<Pipeline<S>>>,
+        stage: usize,
+        options: EnqueueOptions,
+        start_timestamp: u64,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let queue = pipeline.borrow().stages[stage].clone();
+        let service_time = {
+            let mut q = queue.borrow_mut();
+            q.choose_service_time()
+                .unwrap_or_else(|| panic!("Queue {} has no service_time", q.name))
+        };
+
+        let context = options.context;
+        let rejected_options = options.clone();
+        let pipeline_clone = pipeline.clone();
+        let (mut proposed_events, outcome) = queue.borrow_mut().try_enqueue(
+            simulation,
+            timestamp,
+            options.clone(),
+            move |simulation, _, worker_token| {
+                vec![ProposedEvent {
+                    due_time: DueTime::After(sample_sized_service_time(
+                        simulation,
+                        &service_time,
+                        &context,
+                    )),
+                    handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                        move |simulation, timestamp| {
+                            let proposed_events = Self::complete_stage(
+                                pipeline,
+                                stage,
+                                options,
+                                start_timestamp,
+                                simulation,
+                                timestamp,
+                            );
+                            (proposed_events, vec![worker_token])
+                        },
+                    )),
+                    label: Some("pipeline_stage_complete"),
+                    keepalive: true,
+                    priority: 0,
+                }]
+            },
+        );
+        if outcome == EnqueueOutcome::Rejected {
+            log::debug!(
+                "pipeline stage {stage} ({}) rejected an item @ {timestamp}",
+                queue.borrow().name
+            );
+            proposed_events.append(&mut Self::call_on_rejected(
+                &pipeline_clone,
+                stage,
+                rejected_options,
+                simulation,
+                timestamp,
+            ));
+        }
+        proposed_events
+    }
+
+    fn call_on_rejected(
+        pipeline: &Rc<RefCell<Pipeline<S>>>,
+        stage: usize,
+        options: EnqueueOptions,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let mut on_rejected = {
+            let mut p = pipeline.borrow_mut();
+            if let Some(pending_rejections) = &mut p.pending_rejections {
+                pending_rejections.push_back((stage, options));
+                return Default::default();
+            }
+            let Some(on_rejected) = p.on_rejected.take() else {
+                return Default::default();
+            };
+            p.pending_rejections = Some(VecDeque::from([(stage, options)]));
+            on_rejected
+        };
+
+        let mut proposed_events = Vec::new();
+        loop {
+            let rejection = pipeline
+                .borrow_mut()
+                .pending_rejections
+                .as_mut()
+                .unwrap()
+                .pop_front();
+            let Some((stage, options)) = rejection else {
+                break;
+            };
+            proposed_events.append(&mut on_rejected(simulation, timestamp, stage, options));
+        }
+        let mut p = pipeline.borrow_mut();
+        p.pending_rejections = None;
+        p.on_rejected = Some(on_rejected);
+        proposed_events
+    }
+
+    fn complete_stage(
+        pipeline: Rc<RefCell<Pipeline<S>>>,
+        stage: usize,
+        options: EnqueueOptions,
+        start_timestamp: u64,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let (stages, propagation_delay) = {
+            let p = pipeline.borrow();
+            (p.stages.len(), p.propagation_delay)
+        };
+        if stage + 1 == stages {
+            let Some(mut on_complete) = pipeline.borrow_mut().on_complete.take() else {
+                return Default::default();
+            };
+            let proposed_events = on_complete(simulation, timestamp, timestamp - start_timestamp);
+            pipeline.borrow_mut().on_complete = Some(on_complete);
+            return proposed_events;
+        }
+
+        let Some(propagation_delay) = propagation_delay else {
+            return Self::enqueue_stage(
+                pipeline,
+                stage + 1,
+                options,
+                start_timestamp,
+                simulation,
+                timestamp,
+            );
+        };
+        vec![ProposedEvent {
+            due_time: DueTime::After(propagation_delay),
+            handler: Box::new(move |simulation, timestamp| {
+                Self::enqueue_stage(
+                    pipeline,
+                    stage + 1,
+                    options,
+                    start_timestamp,
+                    simulation,
+                    timestamp,
+                )
+            }),
+            label: Some("pipeline_propagation"),
+            keepalive: true,
+            priority: 0,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::main_loop::*;
+    use crate::status::*;
+    use crate::testing::*;
+
+    #[test]
+    fn rejected_item_is_handed_to_on_rejected() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let rejected_at_stage: Rc<Cell<Option<usize>>> = Default::default();
+        let second_stage_labels = vec![("queue_name".to_owned(), "stage_1".to_owned())];
+
+        let mut runner = SimulationRunner::new(simulation, {
+            let rejected_at_stage = rejected_at_stage.clone();
+            Box::new(move |simulation, timestamp| {
+                let stages = Vec::from_iter((0..2).map(|stage| {
+                    Rc::new(RefCell::new(Queue {
+                        service_time: Some(LogNormal::from_mean_cv(1000.0, 0.0).unwrap()),
+                        ..Queue::new(simulation, &format!("stage_{stage}"))
+                    }))
+                }));
+                stages[1].borrow_mut().closed = true;
+                Worker {
+                    id: 0,
+                    subscribed_queues: vec![stages[0].clone()],
+                    subscription_weights: Default::default(),
+                    status: Rc::new(Status::Running.into()),
+                    allow_drop: false,
+                    rng: mk_named_rng(simulation, "worker/0"),
+                    metric_labels: Default::default(),
+                    pool: None,
+                    queue_selection: Default::default(),
+                    steal_queues: Default::default(),
+                    ext: (),
+                }
+                .listen(simulation, timestamp);
+
+                let pipeline = Rc::new(RefCell::new(Pipeline {
+                    stages,
+                    propagation_delay: None,
+                    on_complete: Some(Box::new(|_, _, _| unreachable!())),
+                    on_rejected: Some(Box::new(move |_, _, stage, _| {
+                        rejected_at_stage.set(Some(stage));
+                        Default::default()
+                    })),
+                    pending_rejections: None,
+                }));
+                vec![ProposedEvent::at(timestamp)
+                    .handler(Pipeline::mk_enqueued_handler(pipeline, Default::default()))]
+            })
+        });
+
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        assert_eq!(rejected_at_stage.get(), Some(1));
+        assert_eq!(
+            simulation
+                .get_enqueue_rejected_metric()
+                .get_or_create(&second_stage_labels)
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn rejection_of_an_item_enqueued_by_on_rejected_is_still_handed_over() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let pipeline = Rc::new(RefCell::new(Pipeline {
+            stages: vec![Rc::new(RefCell::new(Queue {
+                service_time: Some(LogNormal::from_mean_cv(1000.0, 0.0).unwrap()),
+                closed: true,
+                ..Queue::new(simulation, "stage_0")
+            }))],
+            propagation_delay: None,
+            on_complete: Some(Box::new(|_, _, _| unreachable!())),
+            on_rejected: None,
+            pending_rejections: None,
+        }));
+        let rejections: Rc<Cell<usize>> = Default::default();
+        pipeline.borrow_mut().on_rejected = Some(Box::new({
+            let pipeline = pipeline.clone();
+            let rejections = rejections.clone();
+            move |simulation, timestamp, _, options| {
+                rejections.set(rejections.get() + 1);
+                // the first rejection retries straight away, rejected in turn
+                if rejections.get() > 1 {
+                    return Default::default();
+                }
+                Pipeline::mk_enqueued_handler(pipeline.clone(), options)(simulation, timestamp)
+            }
+        }));
+
+        let proposed_events =
+            Pipeline::mk_enqueued_handler(pipeline.clone(), Default::default())(simulation, 0);
+        assert!(proposed_events.is_empty());
+        assert_eq!(rejections.get(), 2);
+        assert!(pipeline.borrow().on_rejected.is_some());
+        assert!(pipeline.borrow().pending_rejections.is_none());
+    }
+
+    // one item through three stages of a single worker each, serving for exactly 1s,
+    // returning its end-to-end latency in ticks
+    fn run_pipeline(propagation_delay: Option<LogNormal<f32>>) -> u64 {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let latency: Rc<Cell<Option<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let latency = latency.clone();
+            Box::new(move |simulation, timestamp| {
+                let stages = Vec::from_iter((0..3u64).map(|stage| {
+                    let name = format!("stage_{stage}");
+                    let queue = Queue {
+                        service_time: Some(
+                            LogNormal::from_mean_cv(simulation.get_ticks_per_second() as f32, 0.0)
+                                .unwrap(),
+                        ),
+                        ..Queue::new(simulation, &name)
+                    }
+                    .into_registered(simulation);
+                    mk_worker(simulation, stage, &queue).listen(simulation, timestamp);
+                    queue
+                }));
+                let pipeline = Rc::new(RefCell::new(Pipeline {
+                    stages,
+                    propagation_delay,
+                    on_complete: Some(Box::new(move |_, _, ticks| {
+                        latency.set(Some(ticks));
+                        Default::default()
+                    })),
+                    on_rejected: None,
+                    pending_rejections: None,
+                }));
+                vec![ProposedEvent {
+                    due_time: DueTime::At(timestamp),
+                    handler: Box::new(Pipeline::mk_enqueued_handler(pipeline, Default::default())),
+                    label: Some("pipeline_arrival"),
+                    keepalive: true,
+                    priority: 0,
+                }]
+            })
+        });
+        runner.run();
+        latency.get().unwrap()
+    }
+
+    #[test]
+    fn propagation_delay_adds_to_latency() {
+        // run_pipeline builds its simulations with the default tick rate
+        let ticks_per_second = SimulationBuilder::new(1).get_ticks_per_second();
+        let ticks = |seconds: f64| (seconds * ticks_per_second) as u64;
+        let adjacent = run_pipeline(None);
+        let propagated = run_pipeline(Some(
+            LogNormal::from_mean_cv(ticks(0.25) as f32, 0.0).unwrap(),
+        ));
+        // three 1s stages, plus a 0.25s delay on each of the two hand-offs, allowing
+        // for f32 precision of the sampled times
+        assert!(adjacent.abs_diff(ticks(3.0)) <= 3);
+        assert!(propagated.abs_diff(ticks(3.5)) <= 5);
+    }
+}
@@ -1,32 +1,603 @@
+use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::rc::Rc;
+
+use prometheus_client::metrics::counter::Counter;
+use rand::Rng;
+use rand_distr::LogNormal;
+use rand_xoshiro::Xoshiro256StarStar;
+use serde::Serialize;
+
+use crate::args_rets::*;
+use crate::error::*;
+use crate::simulation::*;
+
+/// A `set_desired_instances_ramped` in progress, kept while its next step is
+/// scheduled
+#[derive(Clone, Copy, Debug)]
+pub struct Ramp {
+    // None once cancelled, leaving the scheduled step to end the ramp
+    pub target: Option<u32>,
+    pub max_step: u32,
+    pub interval: LogNormal<f32>,
+    // steps in a row whose every launch failed, the ramp giving up at
+    // PoolManager::MAX_FAILED_RAMP_STEPS
+    pub failed_steps: u32,
+}
+
+/// Instance launches that fail, as with a flaky provider, set up through
+/// `PoolManager::set_launch_failures`. A failed launch doesn't count towards the
+/// pool's size. A ramp's next step, retrying it, waits for `retry_delay` rather
+/// than the ramp's interval, e.g. the provider's startup time; a direct resize
+/// leaves the pool short until it's next resized. A ramp whose every launch fails
+/// for `PoolManager::MAX_FAILED_RAMP_STEPS` steps in a row gives up, so a
+/// probability of 1.0 can't keep it retrying forever.
+#[derive(Clone, Debug)]
+pub struct LaunchFailures {
+    probability: f64,
+    retry_delay: LogNormal<f32>,
+    // a stream of the pool's own, so that enabling failures doesn't shift any
+    // other draws
+    rng: Xoshiro256StarStar,
+    // the pool's instance_launch_failures series
+    metric: Counter,
+}
+
+impl LaunchFailures {
+    pub fn get_probability(&self) -> f64 {
+        self.probability
+    }
+
+    pub fn get_retry_delay(&self) -> LogNormal<f32> {
+        self.retry_delay
+    }
+}
+
+/// Read-only view of a pool's size, e.g. for monitoring
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PoolManagerSnapshot {
+    pub name: String,
+    // the target of a ramp in progress, otherwise the current instances
+    pub desired_instances: u32,
+    pub current_instances: u32,
+}
 
 pub struct PoolManager {
     pub name: String,
     pub instances: VecDeque<Box<dyn FnOnce()>>,
     pub instance_constructor: Box<dyn FnMut() -> Box<dyn FnOnce()>>,
     pub metric_labels: Vec<(String, String)>,
+    pub ramp: Option<Ramp>,
+    pub launch_failures: Option<LaunchFailures>,
+    // the most instances the pool has had at once
+    pub peak_instances: u32,
 }
 
 impl PoolManager {
+    pub const MAX_FAILED_RAMP_STEPS: u32 = 10;
+
+    pub fn get_snapshot(&self) -> PoolManagerSnapshot {
+        let current_instances = u32::try_from(self.instances.len()).unwrap();
+        PoolManagerSnapshot {
+            name: self.name.clone(),
+            desired_instances: self
+                .ramp
+                .and_then(|ramp| ramp.target)
+                .unwrap_or(current_instances),
+            current_instances,
+        }
+    }
+
+    /// Model launch failures with `probability` from now on, replacing any set
+    /// before; set `launch_failures` to `None` to stop. Fails, leaving them as
+    /// they were, unless `probability` is between 0 and 1.
+    pub fn set_launch_failures<S: Simulation>(
+        &mut self,
+        simulation: &S,
+        probability: f64,
+        retry_delay: LogNormal<f32>,
+    ) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(Error::InvalidLaunchFailureProbability {
+                pool_manager: self.name.clone(),
+                probability,
+            });
+        }
+
+        self.launch_failures = Some(LaunchFailures {
+            probability,
+            retry_delay,
+            rng: mk_named_rng(
+                simulation,
+                &format!("pool_manager/{}/launch_failures", self.name),
+            ),
+            metric: simulation
+                .get_instance_launch_failures_metric()
+                .get_or_create(&self.metric_labels)
+                .clone(),
+        });
+        Ok(())
+    }
+
+    /// Also cancels any ramp in progress, as do the relative and delta variants.
+    /// With `launch_failures` set, launches that fail leave the pool short.
     pub fn set_desired_instances_absolute(&mut self, count: u32) {
+        if let Some(ramp) = &mut self.ramp {
+            ramp.target = None;
+        }
+        self.resize(count);
+    }
+
+    // returns how many of the launches failed
+    fn resize(&mut self, count: u32) -> u32 {
+        let current = u32::try_from(self.instances.len()).unwrap();
+        let failures = match &mut self.launch_failures {
+            Some(launch_failures) if count > current => {
+                let failures = (current..count)
+                    .filter(|_| launch_failures.rng.gen_bool(launch_failures.probability))
+                    .count() as u32;
+                launch_failures.metric.inc_by(failures as u64);
+                failures
+            }
+            _ => 0,
+        };
+        let count = count - failures;
+
         while u32::try_from(self.instances.len()).unwrap() < count {
             self.instances.push_back((self.instance_constructor)());
         }
+        self.peak_instances = u32::max(self.peak_instances, count);
 
         while u32::try_from(self.instances.len()).unwrap() > count {
             self.instances.pop_front().unwrap()();
         }
+        failures
+    }
+
+    /// Fails, leaving the pool and any ramp as they were, if the scaled count
+    /// doesn't fit in a u32, e.g. for a negative `factor`
+    pub fn set_desired_instances_relative(&mut self, factor: f32) -> Result<(), Error> {
+        self.set_desired_instances_checked((self.instances.len() as f32 * factor) as i64)
+    }
+
+    /// Fails like `set_desired_instances_relative`
+    pub fn set_desired_instances_delta(&mut self, delta: i32) -> Result<(), Error> {
+        self.set_desired_instances_checked(self.instances.len() as i64 + delta as i64)
+    }
+
+    fn set_desired_instances_checked(&mut self, requested: i64) -> Result<(), Error> {
+        let count = u32::try_from(requested).map_err(|_| Error::InvalidInstanceCount {
+            pool_manager: self.name.clone(),
+            requested,
+        })?;
+        self.set_desired_instances_absolute(count);
+        Ok(())
+    }
+
+    /// Move towards `target` by at most `max_step` instances immediately, then
+    /// again after every `interval` until it's reached, like a provider that only
+    /// launches so many instances per minute. If a ramp is already in progress
+    /// this only updates its parameters, which apply from its next step. Fails if
+    /// `max_step` is 0, as the ramp would never make progress.
+    pub fn set_desired_instances_ramped<S: Simulation + 'static>(
+        pool_manager: Rc<RefCell<Self>>,
+        target: u32,
+        max_step: u32,
+        interval: LogNormal<f32>,
+    ) -> Result<Vec<ProposedEvent<S>>, Error> {
+        if max_step == 0 {
+            return Err(Error::ZeroRampStep {
+                pool_manager: pool_manager.borrow().name.clone(),
+            });
+        }
+
+        let ramp = Ramp {
+            target: Some(target),
+            max_step,
+            interval,
+            failed_steps: 0,
+        };
+        if pool_manager.borrow_mut().ramp.replace(ramp).is_some() {
+            return Ok(Default::default());
+        }
+
+        Ok(Self::ramp_step(pool_manager))
+    }
+
+    fn ramp_step<S: Simulation + 'static>(
+        pool_manager: Rc<RefCell<Self>>,
+    ) -> Vec<ProposedEvent<S>> {
+        let delay = {
+            let mut pm = pool_manager.borrow_mut();
+            let mut ramp = pm.ramp.unwrap();
+            let Some(target) = ramp.target else {
+                pm.ramp = None;
+                return Default::default();
+            };
+
+            let current = u32::try_from(pm.instances.len()).unwrap();
+            let next = if current < target {
+                u32::min(target, current.saturating_add(ramp.max_step))
+            } else {
+                u32::max(target, current.saturating_sub(ramp.max_step))
+            };
+            let failures = pm.resize(next);
+
+            if next - failures == target {
+                pm.ramp = None;
+                return Default::default();
+            }
+            if failures > 0 && failures == next.saturating_sub(current) {
+                ramp.failed_steps += 1;
+                if ramp.failed_steps == Self::MAX_FAILED_RAMP_STEPS {
+                    log::warn!(
+                        "pool manager {} gave up ramping to {target} instances at {current} after {} steps whose every launch failed",
+                        pm.name,
+                        ramp.failed_steps,
+                    );
+                    pm.ramp = None;
+                    return Default::default();
+                }
+            } else {
+                ramp.failed_steps = 0;
+            }
+            pm.ramp = Some(ramp);
+            match &pm.launch_failures {
+                Some(launch_failures) if failures > 0 => launch_failures.retry_delay,
+                _ => ramp.interval,
+            }
+        };
+
+        vec![ProposedEvent {
+            due_time: DueTime::After(delay),
+            handler: Box::new(move |_, _| Self::ramp_step(pool_manager)),
+            label: Some("pool_manager_ramp"),
+            keepalive: false,
+            priority: 0,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use rand::RngCore;
+
+    use super::*;
+    use crate::main_loop::*;
+
+    fn mk_pool_manager() -> PoolManager {
+        PoolManager {
+            name: "test".into(),
+            instances: Default::default(),
+            instance_constructor: Box::new(|| Box::new(|| {})),
+            metric_labels: vec![("pool_manager_name".into(), "test".into())],
+            ramp: None,
+            launch_failures: None,
+            peak_instances: 0,
+        }
+    }
+
+    fn retry_delay() -> LogNormal<f32> {
+        LogNormal::from_mean_cv(1000.0, 0.0).unwrap()
+    }
+
+    #[test]
+    fn launch_failure_probability_outside_0_to_1_is_an_error() {
+        let simulation = BaseSimulation::new(1);
+        let mut pool_manager = mk_pool_manager();
+        for probability in [-0.1, 1.5, f64::NAN] {
+            let error = pool_manager
+                .set_launch_failures(&simulation, probability, retry_delay())
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                Error::InvalidLaunchFailureProbability { pool_manager, .. } if pool_manager == "test"
+            ));
+            assert!(pool_manager.launch_failures.is_none());
+        }
+        for probability in [0.0, 1.0] {
+            pool_manager
+                .set_launch_failures(&simulation, probability, retry_delay())
+                .unwrap();
+            assert_eq!(
+                pool_manager
+                    .launch_failures
+                    .as_ref()
+                    .unwrap()
+                    .get_probability(),
+                probability
+            );
+        }
     }
 
-    pub fn set_desired_instances_relative(&mut self, factor: f32) {
-        self.set_desired_instances_absolute(
-            u32::try_from((self.instances.len() as f32 * factor) as i64).unwrap(),
-        )
+    #[test]
+    fn direct_resizes_leave_the_pool_short_by_the_failures() {
+        let simulation = BaseSimulation::new(1);
+        let failures = || {
+            simulation
+                .get_instance_launch_failures_metric()
+                .get_or_create(&vec![("pool_manager_name".into(), "test".into())])
+                .get()
+        };
+        let mut pool_manager = mk_pool_manager();
+        pool_manager
+            .set_launch_failures(&simulation, 1.0, retry_delay())
+            .unwrap();
+        pool_manager.set_desired_instances_absolute(3);
+        pool_manager.set_desired_instances_delta(2).unwrap();
+        assert_eq!((pool_manager.instances.len(), failures()), (0, 5));
+
+        pool_manager
+            .set_launch_failures(&simulation, 0.5, retry_delay())
+            .unwrap();
+        pool_manager.set_desired_instances_absolute(100);
+        let (first_launched, first_failures) = (pool_manager.instances.len(), failures());
+        assert!((30..70).contains(&first_launched), "{first_launched}");
+        assert_eq!(first_failures as usize, 5 + 100 - first_launched);
+        // resizing again retries only the launches that failed
+        pool_manager.set_desired_instances_absolute(100);
+        let launched = pool_manager.instances.len();
+        assert!(launched > first_launched);
+        assert_eq!(failures() as usize, 5 + 200 - first_launched - launched);
+        assert_eq!(pool_manager.peak_instances as usize, launched);
+        // scaling in has nothing to fail
+        pool_manager.set_desired_instances_relative(0.5).unwrap();
+        assert_eq!(pool_manager.instances.len(), launched / 2);
+        assert_eq!(failures() as usize, 5 + 200 - first_launched - launched);
+    }
+
+    #[test]
+    fn ramp_gives_up_once_every_launch_keeps_failing() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let pool_manager = Rc::new(RefCell::new(mk_pool_manager()));
+        pool_manager
+            .borrow_mut()
+            .set_launch_failures(simulation, 1.0, retry_delay())
+            .unwrap();
+        let ramped_pool_manager = pool_manager.clone();
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(move |_, _| {
+                PoolManager::set_desired_instances_ramped(
+                    ramped_pool_manager,
+                    5,
+                    2,
+                    LogNormal::from_mean_cv(10.0, 0.0).unwrap(),
+                )
+                .unwrap()
+            }),
+        );
+        // drained rather than retrying every 1000 ticks forever
+        assert_eq!(runner.run_until(1_000_000), EndReason::EventHeapDrained);
+        assert_eq!(
+            runner.get_current_timestamp(),
+            1000 * (PoolManager::MAX_FAILED_RAMP_STEPS as u64 - 1)
+        );
+        let pool_manager = pool_manager.borrow();
+        assert!(pool_manager.ramp.is_none());
+        assert!(pool_manager.instances.is_empty());
+        assert_eq!(
+            simulation
+                .get_instance_launch_failures_metric()
+                .get_or_create(&pool_manager.metric_labels)
+                .get(),
+            2 * PoolManager::MAX_FAILED_RAMP_STEPS as u64
+        );
+    }
+
+    #[test]
+    fn launch_failures_draw_from_a_stream_of_their_own() {
+        let get_next_draw = |launch_failures: bool| {
+            let simulation = BaseSimulation::new(1);
+            let mut pool_manager = mk_pool_manager();
+            if launch_failures {
+                pool_manager
+                    .set_launch_failures(&simulation, 0.5, retry_delay())
+                    .unwrap();
+            }
+            pool_manager.set_desired_instances_absolute(10);
+            simulation.with_rng(|rng| rng.next_u64())
+        };
+        assert_eq!(get_next_draw(true), get_next_draw(false));
+    }
+
+    // a pool ramped from 2 to 20 instances at most 3 at a time every 10s, then from
+    // 70s back down to 4 at most 10 at a time, sampled halfway between steps
+    #[test]
+    fn ramp_steps_up_then_down() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let launched: Rc<Cell<u32>> = Default::default();
+        let shut_down: Rc<Cell<u32>> = Default::default();
+        let pool_manager = Rc::new(RefCell::new(PoolManager {
+            name: "ramped".into(),
+            instances: Default::default(),
+            instance_constructor: {
+                let launched = launched.clone();
+                let shut_down = shut_down.clone();
+                Box::new(move || {
+                    launched.set(launched.get() + 1);
+                    let shut_down = shut_down.clone();
+                    Box::new(move || shut_down.set(shut_down.get() + 1))
+                })
+            },
+            metric_labels: vec![("pool_manager_name".into(), "ramped".into())],
+            ramp: None,
+            launch_failures: None,
+            peak_instances: 0,
+        }));
+        pool_manager.borrow_mut().set_desired_instances_absolute(2);
+        let samples: Rc<RefCell<Vec<(u32, u32)>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let pool_manager = pool_manager.clone();
+            let samples = samples.clone();
+            Box::new(move |simulation, _| {
+                let interval =
+                    LogNormal::from_mean_cv((10.0 * simulation.get_ticks_per_second()) as f32, 0.0)
+                        .unwrap();
+                let mut proposed_events = PoolManager::set_desired_instances_ramped(
+                    pool_manager.clone(),
+                    20,
+                    3,
+                    interval,
+                )
+                .unwrap();
+                proposed_events.push(
+                    ProposedEvent::after_seconds(simulation, 70.0, 0.0)
+                        .named("ramp_scale_in")
+                        .handler({
+                            let pool_manager = pool_manager.clone();
+                            move |_, _| {
+                                PoolManager::set_desired_instances_ramped(
+                                    pool_manager,
+                                    4,
+                                    10,
+                                    interval,
+                                )
+                                .unwrap()
+                            }
+                        }),
+                );
+                proposed_events.extend((0..9).map(|i| {
+                    let pool_manager = pool_manager.clone();
+                    let samples = samples.clone();
+                    ProposedEvent::after_seconds(simulation, 5.0 + 10.0 * i as f64, 0.0)
+                        .named("ramp_sample")
+                        .handler(move |_, _| {
+                            let snapshot = pool_manager.borrow().get_snapshot();
+                            samples
+                                .borrow_mut()
+                                .push((snapshot.current_instances, snapshot.desired_instances));
+                            Default::default()
+                        })
+                }));
+                proposed_events
+            })
+        });
+        let end_reason = runner.run();
+
+        let samples = samples.take();
+        // up by 3 on the spot and then every 10s, until the last step caps it at 20,
+        // then down by 10 and the rest of the way 10s later
+        assert_eq!(
+            Vec::from_iter(samples.iter().map(|(current, _)| *current)),
+            [5, 8, 11, 14, 17, 20, 20, 10, 4]
+        );
+        assert_eq!(
+            Vec::from_iter(samples.iter().map(|(_, desired)| *desired)),
+            [20, 20, 20, 20, 20, 20, 20, 4, 4]
+        );
+        let pool_manager = pool_manager.borrow();
+        assert!(pool_manager.ramp.is_none());
+        assert_eq!(pool_manager.peak_instances, 20);
+        assert_eq!((launched.get(), shut_down.get()), (20, 16));
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // a pool scaled from 0 to 10 in one step, with 80% of launches failing and each
+    // retried 30s later
+    #[test]
+    fn failed_launches_are_retried_until_pool_is_full() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let launched: Rc<Cell<u32>> = Default::default();
+        let pool_manager = Rc::new(RefCell::new(PoolManager {
+            name: "flaky".into(),
+            instances: Default::default(),
+            instance_constructor: {
+                let launched = launched.clone();
+                Box::new(move || {
+                    launched.set(launched.get() + 1);
+                    Box::new(|| {})
+                })
+            },
+            metric_labels: vec![("pool_manager_name".into(), "flaky".into())],
+            ramp: None,
+            launch_failures: None,
+            peak_instances: 0,
+        }));
+        pool_manager
+            .borrow_mut()
+            .set_launch_failures(
+                simulation,
+                0.8,
+                LogNormal::from_mean_cv((30.0 * simulation.get_ticks_per_second()) as f32, 0.0)
+                    .unwrap(),
+            )
+            .unwrap();
+        let mut runner = SimulationRunner::new(simulation, {
+            let pool_manager = pool_manager.clone();
+            Box::new(move |_, _| {
+                PoolManager::set_desired_instances_ramped(
+                    pool_manager,
+                    10,
+                    u32::MAX,
+                    LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+                )
+                .unwrap()
+            })
+        });
+        let end_reason = runner.run();
+
+        let failures = simulation
+            .get_instance_launch_failures_metric()
+            .get_or_create(&vec![("pool_manager_name".into(), "flaky".into())])
+            .get();
+        let simulated_seconds = simulation.get_simulated_seconds_metric().get();
+        // only the launches that came up were constructed, and those that failed were
+        // retried, each round a retry delay after the last (give or take the ticks
+        // lost to sampling it as an f32), until there were enough
+        let retry_rounds = (simulated_seconds / 30.0).round();
+        assert!(retry_rounds > 0.0 && (simulated_seconds - retry_rounds * 30.0).abs() < 0.1);
+        let pool_manager = pool_manager.borrow();
+        assert_eq!(pool_manager.instances.len(), 10);
+        assert!(pool_manager.ramp.is_none());
+        assert_eq!(launched.get(), 10);
+        assert!(failures > 10);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
     }
 
-    pub fn set_desired_instances_delta(&mut self, delta: i32) {
-        self.set_desired_instances_absolute(
-            u32::try_from(i64::try_from(self.instances.len()).unwrap() + delta as i64).unwrap(),
-        )
+    // misusing the fallible operations returns an error and leaves the pool as it was
+    #[test]
+    fn misuse_is_an_error_and_leaves_the_pool_unchanged() {
+        let pool_manager = Rc::new(RefCell::new(mk_pool_manager()));
+        pool_manager.borrow_mut().set_desired_instances_absolute(2);
+        let invalid_instance_count = |requested| {
+            Err(Error::InvalidInstanceCount {
+                pool_manager: "test".into(),
+                requested,
+            })
+        };
+        assert_eq!(
+            pool_manager.borrow_mut().set_desired_instances_delta(-3),
+            invalid_instance_count(-1)
+        );
+        assert_eq!(
+            pool_manager
+                .borrow_mut()
+                .set_desired_instances_relative(-1.0),
+            invalid_instance_count(-2)
+        );
+        assert_eq!(
+            pool_manager
+                .borrow_mut()
+                .set_desired_instances_relative(f32::INFINITY),
+            invalid_instance_count(i64::MAX)
+        );
+        assert_eq!(pool_manager.borrow().instances.len(), 2);
+        let ramp = PoolManager::set_desired_instances_ramped::<BaseSimulation>(
+            pool_manager.clone(),
+            4,
+            0,
+            LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+        );
+        assert_eq!(
+            ramp.err(),
+            Some(Error::ZeroRampStep {
+                pool_manager: "test".into()
+            })
+        );
+        assert!(pool_manager.borrow().ramp.is_none());
     }
 }
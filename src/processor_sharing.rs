@@ -0,0 +1,358 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use rand_distr::LogNormal;
+
+use crate::args_rets::*;
+use crate::queue::*;
+use crate::shared_rate_resource::*;
+use crate::simulation::*;
+use crate::status::*;
+
+// a worker's resource, with the worker's status so the resource can be dropped
+// once the worker has gone
+struct WorkerResource<S: SRRSimulation + 'static> {
+    worker_status: Rc<RefCell<Status>>,
+    resource: Rc<RefCell<SharedRateResource<S>>>,
+}
+
+impl<S: SRRSimulation + 'static> WorkerResource<S> {
+    // a worker that's no longer running shuts down as soon as it's restored, so
+    // once its resource is idle nothing will use it again
+    fn is_abandoned(&self) -> bool {
+        *self.worker_status.borrow() != Status::Running && self.resource.borrow().is_idle()
+    }
+}
+
+/// Egalitarian processor sharing over a queue's workers, e.g. a CPU time-sharing
+/// among its active requests. Rather than holding its worker for its whole service
+/// time, an item dispatched through this releases the worker straight away and is
+/// served as a tenancy on a single-partition `SharedRateResource` belonging to that
+/// worker, so the n items on a worker each progress at 1/n of its rate. The
+/// released worker keeps listening and picks up more items, which slow those it's
+/// already serving. Items in service on a worker that leaves still complete, after
+/// which its resource is dropped. An item's size scales its service time as it
+/// would at a worker it held.
+pub struct ProcessorSharing<S: QueueSimulation + SRRSimulation + 'static> {
+    pub queue: Rc<RefCell<Queue<S>>>,
+    // kept so resources can be named while the queue is borrowed to dispatch
+    queue_name: String,
+    // by worker id, created as each worker is first dispatched an item
+    resources: BTreeMap<u64, WorkerResource<S>>,
+}
+
+impl<S: QueueSimulation + SRRSimulation + 'static> ProcessorSharing<S> {
+    pub fn new(queue: Rc<RefCell<Queue<S>>>) -> Self {
+        let queue_name = queue.borrow().name.clone();
+        ProcessorSharing {
+            queue,
+            queue_name,
+            resources: Default::default(),
+        }
+    }
+
+    /// The resource serving a worker's items, whose id is the worker's id. Its
+    /// metrics are labelled with the queue's name and the worker's id rather than
+    /// its own id, so they aren't merged with those of an ordinary resource
+    /// sharing the worker's id.
+    pub fn get_resource(
+        &mut self,
+        simulation: &'static S,
+        worker_id: u64,
+        worker_status: Rc<RefCell<Status>>,
+    ) -> Rc<RefCell<SharedRateResource<S>>> {
+        if !self.resources.contains_key(&worker_id) {
+            // workers that left without a final completion to drop theirs
+            self.resources
+                .retain(|_, worker_resource| !worker_resource.is_abandoned());
+        }
+        let queue_name = &self.queue_name;
+        self.resources
+            .entry(worker_id)
+            .or_insert_with(|| {
+                let rng = mk_named_rng(
+                    simulation,
+                    &format!("processor_sharing/{queue_name}/{worker_id:016x}"),
+                );
+                let mut resource = SharedRateResource::new(simulation, worker_id, 1, rng).unwrap();
+                resource.set_metric_labels(vec![
+                    ("processor_sharing_queue".to_owned(), queue_name.clone()),
+                    ("worker_id".to_owned(), format!("{worker_id:016x}")),
+                ]);
+                WorkerResource {
+                    worker_status,
+                    resource: Rc::new(RefCell::new(resource)),
+                }
+            })
+            .resource
+            .clone()
+    }
+
+    /// How many workers' resources are kept, those of workers that have gone
+    /// being dropped once their last item completes
+    pub fn get_resource_count(&self) -> usize {
+        self.resources.len()
+    }
+
+    // drop a worker's resource once it has gone and its last item completed
+    fn release_resource(&mut self, worker_id: u64) {
+        if self
+            .resources
+            .get(&worker_id)
+            .is_some_and(WorkerResource::is_abandoned)
+        {
+            self.resources.remove(&worker_id);
+        }
+    }
+
+    /// Enqueue an item requiring `service_time` of a worker to itself, calling
+    /// `inner_handler` once it's had it
+    pub fn mk_enqueued_handler(
+        processor_sharing: Rc<RefCell<Self>>,
+        options: EnqueueOptions,
+        service_time: LogNormal<f32>,
+        inner_handler: impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let queue = processor_sharing.borrow().queue.clone();
+            Queue::mk_enqueued_handler_with_options(
+                queue,
+                options,
+                move |simulation, timestamp, worker_token| {
+                    let worker_id = worker_token.get_worker_id();
+                    let resource = processor_sharing.borrow_mut().get_resource(
+                        simulation,
+                        worker_id,
+                        worker_token.get_worker_status(),
+                    );
+                    let context = worker_token.get_item_context();
+                    // the queue is still borrowed here, so the worker can only
+                    // listen again from an event of its own
+                    vec![ProposedEvent {
+                        due_time: DueTime::At(timestamp),
+                        handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                            move |_, timestamp| {
+                                let proposed_events =
+                                    SharedRateResource::mk_sized_shared_rate_event(
+                                        simulation,
+                                        resource,
+                                        timestamp,
+                                        service_time,
+                                        &context,
+                                        move |simulation, timestamp| {
+                                            processor_sharing
+                                                .borrow_mut()
+                                                .release_resource(worker_id);
+                                            inner_handler(simulation, timestamp)
+                                        },
+                                    )
+                                    .unwrap();
+                                (proposed_events, vec![worker_token])
+                            },
+                        )),
+                        label: Some("processor_sharing_admit"),
+                        keepalive: true,
+                        priority: 0,
+                    }]
+                },
+            )(simulation, timestamp)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_loop::*;
+    use crate::testing::*;
+    use crate::topology::*;
+
+    #[test]
+    fn resources_are_labelled_apart_and_dropped_once_their_worker_has_gone() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let processor_sharing = Rc::new(RefCell::new(ProcessorSharing::new(Rc::new(
+            RefCell::new(Queue::new(simulation, "shared")),
+        ))));
+        let status = Rc::new(RefCell::new(Status::Running));
+        let completions: Rc<RefCell<Vec<u64>>> = Default::default();
+
+        let mut runner = SimulationRunner::new(simulation, {
+            let processor_sharing = processor_sharing.clone();
+            let status = status.clone();
+            let completions = completions.clone();
+            Box::new(move |simulation, timestamp| {
+                Worker {
+                    id: 1,
+                    subscribed_queues: vec![processor_sharing.borrow().queue.clone()],
+                    subscription_weights: Default::default(),
+                    status,
+                    allow_drop: false,
+                    rng: mk_named_rng(simulation, "worker/1"),
+                    metric_labels: Default::default(),
+                    pool: None,
+                    queue_selection: Default::default(),
+                    steal_queues: Default::default(),
+                    ext: (),
+                }
+                .listen(simulation, timestamp);
+                Vec::from_iter((0..2).map(|_| {
+                    let completions = completions.clone();
+                    ProposedEvent::at(timestamp).handler(ProcessorSharing::mk_enqueued_handler(
+                        processor_sharing.clone(),
+                        Default::default(),
+                        LogNormal::from_mean_cv(1000.0, 0.0).unwrap(),
+                        move |_, timestamp| {
+                            completions.borrow_mut().push(timestamp);
+                            Default::default()
+                        },
+                    ))
+                }))
+            })
+        });
+
+        assert_eq!(runner.run_until(500), EndReason::ReachedEndTime);
+        assert_eq!(processor_sharing.borrow().get_resource_count(), 1);
+        // the worker leaves while serving both items, which still complete
+        *status.borrow_mut() = Status::ShuttingDown;
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        assert_eq!(*completions.borrow(), [2000, 2000]);
+        assert_eq!(processor_sharing.borrow().get_resource_count(), 0);
+
+        let labels = [
+            ("processor_sharing_queue".to_owned(), "shared".to_owned()),
+            ("worker_id".to_owned(), format!("{:016x}", 1)),
+        ];
+        assert_eq!(
+            simulation
+                .get_tenancy_service_time_sum_count(&labels)
+                .unwrap(),
+            (4.0, 2)
+        );
+        // not merged with an ordinary resource whose id is the worker's
+        let resource_id_labels = [("shared_rate_resource_id".to_owned(), format!("{:016x}", 1))];
+        assert_eq!(
+            simulation
+                .get_tenancy_service_time_sum_count(&resource_id_labels)
+                .unwrap(),
+            (0.0, 0)
+        );
+    }
+
+    // the same four items, requiring 10s, 10s, 20s and 20s of a worker, on a queue
+    // whose worker serves them one at a time and on one sharing its worker between
+    // them, recording the completion timestamps of each
+    fn processor_sharing_bootstrap<S: TopologySimulation>(
+        simulation: &'static S,
+        timestamp: u64,
+        completion_timestamps: Rc<RefCell<BTreeMap<&'static str, Vec<u64>>>>,
+    ) -> Vec<ProposedEvent<S>> {
+        let mk_queue = |name: &str| Queue::new(simulation, name).into_registered(simulation);
+        let sequential = mk_queue("sequential");
+        let processor_sharing = Rc::new(RefCell::new(ProcessorSharing::new(mk_queue(
+            "processor_sharing",
+        ))));
+        mk_worker(simulation, 0, &sequential).listen(simulation, timestamp);
+        mk_worker(simulation, 1, &processor_sharing.borrow().queue).listen(simulation, timestamp);
+
+        let record_completion = move |discipline: &'static str| {
+            let completion_timestamps = completion_timestamps.clone();
+            move |timestamp| {
+                completion_timestamps
+                    .borrow_mut()
+                    .entry(discipline)
+                    .or_default()
+                    .push(timestamp);
+            }
+        };
+        let mut proposed_events = Vec::new();
+        for required_seconds in [10.0, 10.0, 20.0, 20.0] {
+            let service_time = LogNormal::from_mean_cv(
+                (required_seconds * simulation.get_ticks_per_second()) as f32,
+                0.0,
+            )
+            .unwrap();
+            let record_sequential = record_completion("sequential");
+            proposed_events.push(ProposedEvent {
+                due_time: DueTime::At(timestamp),
+                handler: Box::new(Queue::mk_enqueued_handler(
+                    sequential.clone(),
+                    move |_, _, worker_token| {
+                        vec![ProposedEvent {
+                            due_time: DueTime::After(service_time),
+                            handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                                move |_, timestamp| {
+                                    record_sequential(timestamp);
+                                    (Default::default(), vec![worker_token])
+                                },
+                            )),
+                            label: Some("processor_sharing_sequential_complete"),
+                            keepalive: true,
+                            priority: 0,
+                        }]
+                    },
+                )),
+                label: Some("processor_sharing_arrival"),
+                keepalive: true,
+                priority: 0,
+            });
+            let record_shared = record_completion("processor_sharing");
+            proposed_events.push(ProposedEvent {
+                due_time: DueTime::At(timestamp),
+                handler: Box::new(ProcessorSharing::mk_enqueued_handler(
+                    processor_sharing.clone(),
+                    Default::default(),
+                    service_time,
+                    move |_, timestamp| {
+                        record_shared(timestamp);
+                        Default::default()
+                    },
+                )),
+                label: Some("processor_sharing_arrival"),
+                keepalive: true,
+                priority: 0,
+            });
+        }
+        proposed_events
+    }
+
+    #[test]
+    fn shared_worker_slows_every_item_but_finishes_together() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let completion_timestamps: Rc<RefCell<BTreeMap<&'static str, Vec<u64>>>> =
+            Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let completion_timestamps = completion_timestamps.clone();
+            Box::new(move |simulation, timestamp| {
+                processor_sharing_bootstrap(simulation, timestamp, completion_timestamps)
+            })
+        });
+        let end_reason = runner.run();
+
+        let seconds = |timestamps: &Vec<u64>| {
+            let mut seconds = Vec::from_iter(
+                timestamps
+                    .iter()
+                    .map(|t| (*t as f64 / simulation.get_ticks_per_second()).round()),
+            );
+            seconds.sort_by(f64::total_cmp);
+            seconds
+        };
+        let completion_timestamps = completion_timestamps.borrow();
+        let sequential = seconds(&completion_timestamps["sequential"]);
+        let processor_sharing = seconds(&completion_timestamps["processor_sharing"]);
+        // all four share the worker until the 10s pair have had it for 40s, leaving
+        // the 20s pair to share it for another 20s. the total work is the same, so the
+        // last completes at 60s either way, but every item is slowed by the others
+        assert_eq!(processor_sharing, [40.0, 40.0, 60.0, 60.0]);
+        assert_eq!(sequential.last(), Some(&60.0));
+        assert!(sequential
+            .iter()
+            .zip(&processor_sharing)
+            .all(|(sequential, shared)| sequential <= shared));
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+}
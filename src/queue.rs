@@ -1,54 +1,352 @@
-use std::cell::{Ref, RefCell, RefMut};
-use std::cmp::{max, Eq, Ordering, PartialEq};
-use std::collections::BinaryHeap;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::cmp::{Eq, PartialEq};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
 use std::rc::Rc;
+use std::sync::atomic::AtomicU64;
 
-use prometheus_client::metrics::counter::{Atomic, Counter};
-use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
 use prometheus_client::metrics::gauge::Gauge;
-use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::histogram::Histogram;
 use prometheus_client::registry::Registry;
 
 use rand::seq::SliceRandom;
-use rand::{Rng, SeedableRng};
+use rand::Rng;
 use rand_distr::{Distribution, LogNormal};
 use rand_xoshiro::Xoshiro256StarStar;
+use serde::Serialize;
 
 use crate::args_rets::*;
-use crate::lossy_convert::*;
+use crate::distributions::*;
+use crate::error::*;
+use crate::metric_samples::*;
+use crate::shared_rate_resource::SRRSimulation;
 use crate::simulation::*;
 use crate::status::*;
+use crate::topology::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkerTransition {
+    Listen,
+    Unlisten,
+    CheckOut,
+    Restore,
+    ShutDown,
+}
+
+/// Per-worker history of timestamped transitions, only recorded while enabled
+#[derive(Default)]
+pub struct WorkerTransitionLog {
+    pub enabled: bool,
+    pub entries: HashMap<u64, Vec<(u64, WorkerTransition)>>,
+}
+
+impl WorkerTransitionLog {
+    pub fn record(&mut self, worker_id: u64, timestamp: u64, transition: WorkerTransition) {
+        if self.enabled {
+            self.entries
+                .entry(worker_id)
+                .or_default()
+                .push((timestamp, transition));
+        }
+    }
+}
+
+/// Each worker's checkout timestamps within a sliding window, only recorded while
+/// a window is set, for spotting transient imbalance that cumulative counts hide
+#[derive(Default)]
+pub struct RecentCheckouts {
+    pub window: Option<u64>,
+    entries: HashMap<u64, WorkerCheckouts>,
+}
+
+struct WorkerCheckouts {
+    metric_labels: Vec<(String, String)>,
+    // oldest first
+    timestamps: VecDeque<u64>,
+}
+
+impl WorkerCheckouts {
+    fn prune(
+        &mut self,
+        window: u64,
+        metric: &Family<Vec<(String, String)>, Gauge>,
+        timestamp: u64,
+    ) {
+        while self
+            .timestamps
+            .front()
+            .is_some_and(|t| t + window <= timestamp)
+        {
+            self.timestamps.pop_front();
+        }
+        metric
+            .get_or_create(&self.metric_labels)
+            .set(self.timestamps.len() as i64);
+    }
+}
+
+impl RecentCheckouts {
+    pub fn record(
+        &mut self,
+        metric: &Family<Vec<(String, String)>, Gauge>,
+        worker_id: u64,
+        metric_labels: &[(String, String)],
+        timestamp: u64,
+    ) {
+        let Some(window) = self.window else {
+            return;
+        };
+        let checkouts = self
+            .entries
+            .entry(worker_id)
+            .or_insert_with(|| WorkerCheckouts {
+                metric_labels: metric_labels.to_vec(),
+                timestamps: Default::default(),
+            });
+        checkouts.timestamps.push_back(timestamp);
+        checkouts.prune(window, metric, timestamp);
+    }
+
+    /// Drop checkouts that have left the window ending at `timestamp` and update
+    /// every worker's gauge, including those that haven't been checked out since
+    pub fn refresh(&mut self, metric: &Family<Vec<(String, String)>, Gauge>, timestamp: u64) {
+        let Some(window) = self.window else {
+            return;
+        };
+        self.entries.retain(|_, checkouts| {
+            checkouts.prune(window, metric, timestamp);
+            !checkouts.timestamps.is_empty()
+        });
+    }
+}
+
+/// Histogram observations held back while enabled, so that observing doesn't look
+/// up and lock the histogram each time, to be flushed in bulk e.g. before metrics
+/// are collected. Flushing observes every value, so sums and counts are exact.
+#[derive(Default)]
+pub struct ObservationBuffer {
+    pub enabled: bool,
+    pub observations: Vec<(Vec<(String, String)>, f64)>,
+}
+
+impl ObservationBuffer {
+    /// Observe `value` now if disabled, otherwise hold it for the next flush
+    pub fn observe<C: MetricConstructor<Histogram>>(
+        &mut self,
+        family: &Family<Vec<(String, String)>, Histogram, C>,
+        labels: Vec<(String, String)>,
+        value: f64,
+    ) {
+        if self.enabled {
+            self.observations.push((labels, value));
+        } else {
+            family.get_or_create(&labels).observe(value);
+        }
+    }
+
+    pub fn flush<C: MetricConstructor<Histogram>>(
+        &mut self,
+        family: &Family<Vec<(String, String)>, Histogram, C>,
+    ) {
+        // one lookup per label set rather than per observation
+        self.observations.sort_by(|a, b| a.0.cmp(&b.0));
+        for group in self.observations.chunk_by(|a, b| a.0 == b.0) {
+            let histogram = family.get_or_create(&group[0].0);
+            for (_, value) in group {
+                histogram.observe(*value);
+            }
+        }
+        self.observations.clear();
+    }
+}
+
+/// Given a worker token's base metric labels - its worker's labels followed by
+/// `originating_queue` - returns labels to add to them, e.g. a tenant or region.
+/// Keeping the number of distinct label sets manageable is up to the enricher.
+pub type LabelEnricher = Box<dyn Fn(&[(String, String)]) -> Vec<(String, String)>>;
+
+/// Affinity keys of the items a worker handled most recently, most recent first
+#[derive(Clone, Debug, Default)]
+pub struct RecentAffinityKeys(VecDeque<u64>);
+
+impl RecentAffinityKeys {
+    const MAX_LEN: usize = 4;
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.0.contains(&key)
+    }
+
+    pub fn note(&mut self, key: u64) {
+        self.0.retain(|k| *k != key);
+        self.0.push_front(key);
+        self.0.truncate(Self::MAX_LEN);
+    }
+}
+
+/// Implemented by a worker's `ext` to have it remember the affinity keys it
+/// handles, so that queues prefer it for items sharing them. Extensions that
+/// don't keep `RecentAffinityKeys` leave the defaults, and their workers are
+/// picked as if items had no affinity key.
+pub trait AffinityTracking: Default {
+    fn get_recent_affinity_keys(&self) -> Option<&RecentAffinityKeys> {
+        None
+    }
+
+    fn get_recent_affinity_keys_mut(&mut self) -> Option<&mut RecentAffinityKeys> {
+        None
+    }
+}
+
+impl AffinityTracking for () {}
 
 pub trait QueueSimulation: Simulation {
-    type WorkerExtension: Default;
+    type WorkerExtension: AffinityTracking;
+
+    fn borrow_worker_transition_log_mut(&self) -> RefMut<'_, WorkerTransitionLog>;
+    // worker token durations, observed when tokens are restored
+    fn borrow_token_duration_buffer_mut(&self) -> RefMut<'_, ObservationBuffer>;
+    // applied to the labels of worker token metrics at checkout
+    fn borrow_token_label_enricher_mut(&self) -> RefMut<'_, Option<LabelEnricher>>;
+    fn borrow_recent_checkouts_mut(&self) -> RefMut<'_, RecentCheckouts>;
+    // shuffles workers restored together, on a stream of its own
+    fn borrow_restore_order_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar>;
 
     fn get_worker_tokens_checked_out_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
-    fn get_worker_token_duration_metric(&self) -> &Family<Vec<(String, String)>, Histogram>;
+    fn get_worker_token_duration_metric(
+        &self,
+    ) -> &Family<Vec<(String, String)>, Histogram, HistogramBuckets>;
     fn get_up_metric(&self) -> &Family<Vec<(String, String)>, Gauge>;
+    fn get_affinity_hits_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_affinity_misses_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_virtual_queue_enqueues_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_admissions_gated_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_queued_work_metric(&self) -> &Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>;
+    fn get_queue_oldest_item_age_metric(
+        &self,
+    ) -> &Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>;
+    fn get_enqueue_dispatched_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_enqueue_parked_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_enqueue_rejected_no_worker_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_enqueue_rejected_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_queue_listening_workers_metric(&self) -> &Family<Vec<(String, String)>, Gauge>;
+    fn get_workers_busy_metric(&self) -> &Family<Vec<(String, String)>, Gauge>;
+    fn get_work_steals_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_queue_stalled_metric(&self) -> &Family<Vec<(String, String)>, Gauge>;
+    fn get_workers_improperly_dropped_metric(&self) -> &Counter;
+    fn get_pick_only_shutting_down_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_worker_recent_checkouts_metric(&self) -> &Family<Vec<(String, String)>, Gauge>;
+    fn get_item_retries_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_items_dead_lettered_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
 }
 
-pub struct BaseQueueSimulation {
+/// `E` is the per-worker `WorkerExtension` state, available to handlers through
+/// the `WorkerToken`s they're given
+pub struct BaseQueueSimulation<E: AffinityTracking + 'static = ()> {
     simulation: BaseSimulation,
 
     worker_tokens_checked_out_metric: Family<Vec<(String, String)>, Counter>,
-    worker_token_duration_metric: Family<Vec<(String, String)>, Histogram>,
+    worker_token_duration_metric: Family<Vec<(String, String)>, Histogram, HistogramBuckets>,
     up_metric: Family<Vec<(String, String)>, Gauge>,
+    affinity_hits_metric: Family<Vec<(String, String)>, Counter>,
+    affinity_misses_metric: Family<Vec<(String, String)>, Counter>,
+    virtual_queue_enqueues_metric: Family<Vec<(String, String)>, Counter>,
+    admissions_gated_metric: Family<Vec<(String, String)>, Counter>,
+    queued_work_metric: Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>,
+    queue_oldest_item_age_metric: Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>,
+    enqueue_dispatched_metric: Family<Vec<(String, String)>, Counter>,
+    enqueue_parked_metric: Family<Vec<(String, String)>, Counter>,
+    enqueue_rejected_no_worker_metric: Family<Vec<(String, String)>, Counter>,
+    enqueue_rejected_metric: Family<Vec<(String, String)>, Counter>,
+    queue_listening_workers_metric: Family<Vec<(String, String)>, Gauge>,
+    workers_busy_metric: Family<Vec<(String, String)>, Gauge>,
+    work_steals_metric: Family<Vec<(String, String)>, Counter>,
+    queue_stalled_metric: Family<Vec<(String, String)>, Gauge>,
+    workers_improperly_dropped_metric: Counter,
+    pick_only_shutting_down_metric: Family<Vec<(String, String)>, Counter>,
+    worker_recent_checkouts_metric: Family<Vec<(String, String)>, Gauge>,
+    item_retries_metric: Family<Vec<(String, String)>, Counter>,
+    items_dead_lettered_metric: Family<Vec<(String, String)>, Counter>,
+
+    worker_transition_log: RefCell<WorkerTransitionLog>,
+    token_duration_buffer: RefCell<ObservationBuffer>,
+    token_label_enricher: RefCell<Option<LabelEnricher>>,
+    recent_checkouts: RefCell<RecentCheckouts>,
+    restore_order_rng: RefCell<Xoshiro256StarStar>,
+    subsystems: RefCell<SubsystemRegistry<Self>>,
+    worker_extension: PhantomData<E>,
+}
+
+impl<E: AffinityTracking + 'static> FromSimulationBuilder for BaseQueueSimulation<E> {
+    fn from_builder(builder: &SimulationBuilder) -> Self {
+        Self::new_with_simulation(
+            builder.build(),
+            builder.get_worker_token_duration_buckets().clone(),
+        )
+    }
 }
 
-impl BaseQueueSimulation {
-    pub fn new(id: u64, metric_registry: Registry) -> Self {
+impl<E: AffinityTracking + 'static> BaseQueueSimulation<E> {
+    /// Same as `SimulationBuilder::new(id).build()`, so every metric carries
+    /// the `simulation_id` label
+    pub fn new(id: u64) -> Self {
+        SimulationBuilder::new(id).build()
+    }
+
+    /// Builds with defaults like `new`, but registers metrics on the caller's
+    /// `metric_registry` as it is, without adding a `simulation_id` label
+    pub fn with_registry(id: u64, metric_registry: Registry) -> Self {
+        let builder = SimulationBuilder::new(id);
+        Self::new_with_simulation(
+            BaseSimulation::with_registry(id, metric_registry),
+            builder.get_worker_token_duration_buckets().clone(),
+        )
+    }
+
+    fn new_with_simulation(
+        simulation: BaseSimulation,
+        worker_token_duration_buckets: HistogramBuckets,
+    ) -> Self {
+        let restore_order_rng = mk_named_rng(&simulation, "worker_token/restore_order");
         let r = BaseQueueSimulation {
-            simulation: BaseSimulation::new(id, metric_registry),
+            simulation,
 
             worker_tokens_checked_out_metric: Default::default(),
-            worker_token_duration_metric: Family::new_with_constructor(|| {
-                Histogram::new(exponential_buckets(0.01, 2.0, 16))
-            }),
+            worker_token_duration_metric: Family::new_with_constructor(
+                worker_token_duration_buckets,
+            ),
             up_metric: Default::default(),
+            affinity_hits_metric: Default::default(),
+            affinity_misses_metric: Default::default(),
+            virtual_queue_enqueues_metric: Default::default(),
+            admissions_gated_metric: Default::default(),
+            queued_work_metric: Default::default(),
+            queue_oldest_item_age_metric: Default::default(),
+            enqueue_dispatched_metric: Default::default(),
+            enqueue_parked_metric: Default::default(),
+            enqueue_rejected_no_worker_metric: Default::default(),
+            enqueue_rejected_metric: Default::default(),
+            queue_listening_workers_metric: Default::default(),
+            workers_busy_metric: Default::default(),
+            work_steals_metric: Default::default(),
+            queue_stalled_metric: Default::default(),
+            workers_improperly_dropped_metric: Default::default(),
+            pick_only_shutting_down_metric: Default::default(),
+            worker_recent_checkouts_metric: Default::default(),
+            item_retries_metric: Default::default(),
+            items_dead_lettered_metric: Default::default(),
+
+            worker_transition_log: Default::default(),
+            token_duration_buffer: Default::default(),
+            token_label_enricher: Default::default(),
+            recent_checkouts: Default::default(),
+            restore_order_rng: restore_order_rng.into(),
+            subsystems: Default::default(),
+            worker_extension: PhantomData,
         };
 
         r.simulation.borrow_metric_registry_mut().register(
@@ -66,23 +364,205 @@ impl BaseQueueSimulation {
             "Whether worker is up",
             r.up_metric.clone(),
         );
+        r.simulation.borrow_metric_registry_mut().register(
+            "affinity_hits",
+            "Number of enqueues dispatched to a worker recently holding the same affinity key",
+            r.affinity_hits_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "affinity_misses",
+            "Number of enqueues with an affinity key finding no matching worker",
+            r.affinity_misses_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "virtual_queue_enqueues",
+            "Number of enqueues routed through a virtual queue to each backing queue",
+            r.virtual_queue_enqueues_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "admissions_gated",
+            "Number of enqueues rejected by admission control",
+            r.admissions_gated_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "queued_work_seconds",
+            "Total estimated service time of items parked in queue",
+            r.queued_work_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "queue_oldest_item_age_seconds",
+            "Time the longest-waiting item parked in queue has waited, as of the last refresh",
+            r.queue_oldest_item_age_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "enqueue_dispatched",
+            "Number of enqueues dispatched immediately to an idle worker",
+            r.enqueue_dispatched_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "enqueue_parked",
+            "Number of enqueues parked in the queue for lack of an idle worker",
+            r.enqueue_parked_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "enqueue_rejected_no_worker",
+            "Number of reject_if_busy enqueues rejected for lack of an idle worker",
+            r.enqueue_rejected_no_worker_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "enqueue_rejected",
+            "Number of enqueues rejected for any reason: no idle worker, too much queued work or a closed queue",
+            r.enqueue_rejected_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "queue_listening_workers",
+            "Number of idle workers listening on queue",
+            r.queue_listening_workers_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "workers_busy",
+            "Number of workers checked out, by originating queue",
+            r.workers_busy_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "work_steals",
+            "Number of items taken from queue by workers not subscribed to it",
+            r.work_steals_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "workers_improperly_dropped",
+            "Number of workers dropped without proper shutdown in log-and-count drop mode",
+            r.workers_improperly_dropped_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "queue_stalled",
+            "Whether queue has parked items and no worker has been available to take any",
+            r.queue_stalled_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "pick_only_shutting_down",
+            "Number of enqueues that found listening workers, but only ones shutting down",
+            r.pick_only_shutting_down_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "worker_recent_checkouts",
+            "Number of tokens checked out from worker within the recent checkouts window",
+            r.worker_recent_checkouts_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "item_retries",
+            "Number of failed attempts re-enqueued on a retry queue",
+            r.item_retries_metric.clone(),
+        );
+        r.simulation.borrow_metric_registry_mut().register(
+            "items_dead_lettered",
+            "Number of items abandoned after their final failed attempt, or rejected",
+            r.items_dead_lettered_metric.clone(),
+        );
 
         r
     }
-}
 
-impl Simulation for BaseQueueSimulation {
-    const TICKS_PER_SECOND: f64 = BaseSimulation::TICKS_PER_SECOND;
-    const METRICS_SAMPLING_PERIOD_SECONDS: f64 = BaseSimulation::METRICS_SAMPLING_PERIOD_SECONDS;
+    /// Tokens checked out with exactly `labels`, i.e. a worker's metric labels
+    /// followed by its `pool` if any, `originating_queue` and any enriched labels
+    pub fn get_checked_out_count(&self, labels: &[(String, String)]) -> Result<u64, Error> {
+        get_counter_value(&self.worker_tokens_checked_out_metric, labels)
+    }
+
+    /// Tokens checked out by every worker in `pool`, across all queues, e.g. for
+    /// each pool's share of the throughput of a queue they serve together
+    pub fn get_checked_out_count_by_pool(&self, pool: &str) -> Result<u64, Error> {
+        let pool_label = ("pool".to_owned(), pool.to_owned());
+        Ok(get_counter_series(&self.worker_tokens_checked_out_metric)?
+            .into_iter()
+            .filter(|(labels, _)| labels.contains(&pool_label))
+            .map(|(_, value)| value)
+            .sum())
+    }
+
+    /// Whether the worker with metric labels `labels`, followed by its `pool` if
+    /// any, is up
+    pub fn get_up_value(&self, labels: &[(String, String)]) -> Result<i64, Error> {
+        get_gauge_value(&self.up_metric, labels)
+    }
+
+    /// Total seconds and number of checkouts of restored worker tokens, including
+    /// buffered observations
+    pub fn get_worker_token_duration_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        flush_token_durations(self);
+        get_histogram_sum_count(&self.worker_token_duration_metric, labels)
+    }
+
+    pub fn get_tenancy_stretch_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        self.simulation.get_tenancy_stretch_sum_count(labels)
+    }
+
+    pub fn get_tenancy_admission_wait_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        self.simulation.get_tenancy_admission_wait_sum_count(labels)
+    }
+
+    pub fn get_tenancy_service_time_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        self.simulation.get_tenancy_service_time_sum_count(labels)
+    }
+}
 
+impl<E: AffinityTracking + 'static> Simulation for BaseQueueSimulation<E> {
     fn get_id(&self) -> u64 {
         self.simulation.get_id()
     }
 
+    fn get_ticks_per_second(&self) -> f64 {
+        self.simulation.get_ticks_per_second()
+    }
+
+    fn get_metrics_sampling_period_seconds(&self) -> f64 {
+        self.simulation.get_metrics_sampling_period_seconds()
+    }
+
+    fn get_min_delay(&self) -> u64 {
+        self.simulation.get_min_delay()
+    }
+
+    fn set_min_delay(&self, min_delay: u64) {
+        self.simulation.set_min_delay(min_delay);
+    }
+
     fn get_events_dispatched_metric(&self) -> &Counter {
         self.simulation.get_events_dispatched_metric()
     }
 
+    fn get_dispatch_gap_metric(&self) -> &Histogram {
+        self.simulation.get_dispatch_gap_metric()
+    }
+
+    fn get_pending_event_horizon_metric(&self) -> &Histogram {
+        self.simulation.get_pending_event_horizon_metric()
+    }
+
+    fn get_handler_panics_metric(&self) -> &Counter {
+        self.simulation.get_handler_panics_metric()
+    }
+
+    fn get_instance_launch_failures_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        self.simulation.get_instance_launch_failures_metric()
+    }
+
+    fn get_simulated_seconds_metric(&self) -> &Gauge<f64, AtomicU64> {
+        self.simulation.get_simulated_seconds_metric()
+    }
+
     fn borrow_metric_registry(&self) -> Ref<'_, Registry> {
         self.simulation.borrow_metric_registry()
     }
@@ -94,257 +574,2670 @@ impl Simulation for BaseQueueSimulation {
     fn borrow_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar> {
         self.simulation.borrow_rng_mut()
     }
+
+    fn borrow_service_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar> {
+        self.simulation.borrow_service_rng_mut()
+    }
+
+    fn borrow_singleton_keys_mut(&self) -> RefMut<'_, HashSet<&'static str>> {
+        self.simulation.borrow_singleton_keys_mut()
+    }
+}
+
+// so that one simulation can have both queues and shared rate resources
+impl<E: AffinityTracking + 'static> SRRSimulation for BaseQueueSimulation<E> {
+    fn get_tenancy_stretch_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+        self.simulation.get_tenancy_stretch_metric()
+    }
+
+    fn get_tenancy_admission_wait_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+        self.simulation.get_tenancy_admission_wait_metric()
+    }
+
+    fn get_tenancy_service_time_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+        self.simulation.get_tenancy_service_time_metric()
+    }
+}
+
+impl<E: AffinityTracking + 'static> TopologySimulation for BaseQueueSimulation<E> {
+    fn borrow_subsystems_mut(&self) -> RefMut<'_, SubsystemRegistry<Self>> {
+        self.subsystems.borrow_mut()
+    }
 }
 
-impl QueueSimulation for BaseQueueSimulation {
-    type WorkerExtension = ();
+impl<E: AffinityTracking + 'static> QueueSimulation for BaseQueueSimulation<E> {
+    type WorkerExtension = E;
+
+    fn borrow_worker_transition_log_mut(&self) -> RefMut<'_, WorkerTransitionLog> {
+        self.worker_transition_log.borrow_mut()
+    }
+
+    fn borrow_token_duration_buffer_mut(&self) -> RefMut<'_, ObservationBuffer> {
+        self.token_duration_buffer.borrow_mut()
+    }
+
+    fn borrow_restore_order_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar> {
+        self.restore_order_rng.borrow_mut()
+    }
+
+    fn borrow_token_label_enricher_mut(&self) -> RefMut<'_, Option<LabelEnricher>> {
+        self.token_label_enricher.borrow_mut()
+    }
+
+    fn borrow_recent_checkouts_mut(&self) -> RefMut<'_, RecentCheckouts> {
+        self.recent_checkouts.borrow_mut()
+    }
 
     fn get_worker_tokens_checked_out_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
         &self.worker_tokens_checked_out_metric
     }
 
-    fn get_worker_token_duration_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+    fn get_worker_token_duration_metric(
+        &self,
+    ) -> &Family<Vec<(String, String)>, Histogram, HistogramBuckets> {
         &self.worker_token_duration_metric
     }
 
     fn get_up_metric(&self) -> &Family<Vec<(String, String)>, Gauge> {
         &self.up_metric
     }
-}
 
-pub struct Queue<S: QueueSimulation + 'static> {
-    pub name: String,
-    pub listening_workers: HashSet<Rc<Worker<S>>>,
-    pub deque: VecDeque<Box<dyn FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>>>>,
-    pub rng: Xoshiro256StarStar,
-    pub metric_labels: Vec<(String, String)>,
-}
+    fn get_affinity_hits_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.affinity_hits_metric
+    }
 
-impl<S: QueueSimulation + 'static> Queue<S> {
-    fn pick_worker(&mut self, simulation: &'static S) -> Option<Worker<S>> {
-        while !self.listening_workers.is_empty() {
-            let chosen_worker_rc = Clone::clone(
-                self.listening_workers
-                    .iter()
-                    .nth(self.rng.gen_range(0..self.listening_workers.len()))
-                    .unwrap(),
-            );
+    fn get_affinity_misses_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.affinity_misses_metric
+    }
 
-            self.listening_workers.remove(&chosen_worker_rc);
-
-            let mut found_self = false;
-            for other_queue_rc in &chosen_worker_rc.subscribed_queues {
-                if let Ok(mut other_queue) = other_queue_rc.try_borrow_mut() {
-                    other_queue.listening_workers.remove(&chosen_worker_rc);
-                } else {
-                    // this is presumably a reference to ourselves that we failed to
-                    // borrow because we're already operating within that borrow - but
-                    // it should only happen once in that case
-                    assert!(
-                        !found_self,
-                        "Failed to borrow_mut more than one queue in subscribed_queues"
-                    );
-                    found_self = true;
-                }
-            }
-            assert!(found_self);
+    fn get_virtual_queue_enqueues_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.virtual_queue_enqueues_metric
+    }
 
-            let chosen_worker = Rc::into_inner(chosen_worker_rc).unwrap();
+    fn get_admissions_gated_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.admissions_gated_metric
+    }
 
-            if *chosen_worker.status.borrow() != Status::Running {
-                chosen_worker.shutdown(simulation);
-            } else {
-                return Some(chosen_worker);
-            }
-        }
+    fn get_queued_work_metric(&self) -> &Family<Vec<(String, String)>, Gauge<f64, AtomicU64>> {
+        &self.queued_work_metric
+    }
 
-        None
+    fn get_queue_oldest_item_age_metric(
+        &self,
+    ) -> &Family<Vec<(String, String)>, Gauge<f64, AtomicU64>> {
+        &self.queue_oldest_item_age_metric
     }
 
-    fn enqueued_handler_inner(
-        &mut self,
-        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
-        simulation: &'static S,
-        timestamp: u64,
-    ) -> Vec<ProposedEvent<S>> {
-        if self.deque.is_empty() {
-            if let Some(worker) = self.pick_worker(simulation) {
-                let mut token = WorkerToken {
-                    metric_labels: worker.metric_labels.clone(),
-                    worker: worker,
-                    checkout_timestamp: timestamp,
-                    originating_queue_name: self.name.clone(),
-                };
-                token
-                    .metric_labels
-                    .push(("originating_queue".to_owned(), self.name.clone()));
+    fn get_enqueue_dispatched_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.enqueue_dispatched_metric
+    }
 
-                simulation
-                    .get_worker_tokens_checked_out_metric()
-                    .get_or_create(&token.metric_labels)
-                    .inc();
+    fn get_enqueue_parked_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.enqueue_parked_metric
+    }
 
-                return inner_handler(simulation, timestamp, token);
-            }
-        }
+    fn get_enqueue_rejected_no_worker_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.enqueue_rejected_no_worker_metric
+    }
 
-        self.deque.push_back(Box::new(inner_handler));
+    fn get_enqueue_rejected_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.enqueue_rejected_metric
+    }
 
-        Default::default()
+    fn get_queue_listening_workers_metric(&self) -> &Family<Vec<(String, String)>, Gauge> {
+        &self.queue_listening_workers_metric
     }
 
-    pub fn mk_enqueued_handler(
-        queue: Rc<RefCell<Queue<S>>>,
-        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
-    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
-        move |simulation, timestamp| {
-            queue
-                .borrow_mut()
-                .enqueued_handler_inner(inner_handler, simulation, timestamp)
-        }
+    fn get_workers_busy_metric(&self) -> &Family<Vec<(String, String)>, Gauge> {
+        &self.workers_busy_metric
+    }
+
+    fn get_work_steals_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.work_steals_metric
+    }
+
+    fn get_queue_stalled_metric(&self) -> &Family<Vec<(String, String)>, Gauge> {
+        &self.queue_stalled_metric
+    }
+
+    fn get_workers_improperly_dropped_metric(&self) -> &Counter {
+        &self.workers_improperly_dropped_metric
+    }
+
+    fn get_pick_only_shutting_down_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.pick_only_shutting_down_metric
+    }
+
+    fn get_worker_recent_checkouts_metric(&self) -> &Family<Vec<(String, String)>, Gauge> {
+        &self.worker_recent_checkouts_metric
+    }
+
+    fn get_item_retries_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.item_retries_metric
+    }
+
+    fn get_items_dead_lettered_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.items_dead_lettered_metric
     }
 }
 
-pub struct Worker<S: QueueSimulation + 'static> {
-    pub id: u64,
-    pub subscribed_queues: Vec<Rc<RefCell<Queue<S>>>>,
-    pub status: Rc<RefCell<Status>>,
-    pub allow_drop: bool,
-    pub rng: Xoshiro256StarStar,
-    pub metric_labels: Vec<(String, String)>,
-    pub ext: S::WorkerExtension,
+pub type EnqueuedHandler<S> =
+    Box<dyn FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>>>;
+
+#[derive(Clone, Debug, Default)]
+pub struct EnqueueOptions {
+    // higher is served first
+    pub priority: i32,
+    // items sharing a key prefer a worker that recently handled that key
+    pub affinity_key: Option<u64>,
+    pub estimated_work_seconds: f64,
+    // rejected rather than parked when no worker is idle, as in a loss system
+    // (M/M/c/c) where blocked arrivals are turned away
+    pub reject_if_busy: bool,
+    pub context: ItemContext,
 }
 
-impl<S: QueueSimulation + 'static> Hash for Worker<S> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
+impl EnqueueOptions {
+    /// Set the item's `ItemContext` size, failing like `ItemContext::new`
+    pub fn with_size(mut self, size: f64) -> Result<Self, Error> {
+        self.context = ItemContext::new(size)?;
+        Ok(self)
     }
 }
 
-impl<S: QueueSimulation + 'static> PartialEq for Worker<S> {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
-    }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnqueueOutcome {
+    // handed straight to an idle worker
+    Dispatched,
+    // left in the deque for the next worker to listen
+    Parked,
+    // would have exceeded the queue's max_queued_work_seconds, found no idle
+    // worker with reject_if_busy, or the queue is closed
+    Rejected,
 }
 
-impl<S: QueueSimulation + 'static> Eq for Worker<S> {}
+pub struct QueuedItem<S: QueueSimulation + 'static> {
+    pub handler: EnqueuedHandler<S>,
+    pub enqueue_timestamp: u64,
+    pub options: EnqueueOptions,
+}
 
-impl<S: QueueSimulation + 'static> Drop for Worker<S> {
-    fn drop(&mut self) {
-        if !self.allow_drop {
-            let msg = format!("Worker {} was dropped without proper shutdown", self.id);
+/// Ticks a queue has spent at each depth, accrued whenever its depth changes, for
+/// the time-weighted (i.e. stationary) distribution of queue length rather than
+/// the mean that sampling a depth gauge gives. Counts from the first change, and
+/// only parked items count towards depth.
+#[derive(Clone, Debug, Default)]
+pub struct DepthTime {
+    // indexed by depth
+    pub ticks_at_depth: Vec<u64>,
+    pub depth: usize,
+    // when depth last changed, None before the first change
+    pub since: Option<u64>,
+}
 
-            if std::thread::panicking() {
-                std::eprintln!("{msg} (but already panicking)");
-            } else {
-                panic!("{msg}");
-            }
+impl DepthTime {
+    pub fn record(&mut self, depth: usize, timestamp: u64) {
+        if let Some(since) = self.since {
+            Self::accrue(&mut self.ticks_at_depth, self.depth, timestamp - since);
         }
+        self.depth = depth;
+        self.since = Some(timestamp);
     }
-}
 
-impl<S: QueueSimulation + 'static> Worker<S> {
-    pub fn shutdown(mut self, simulation: &'static S) {
-        simulation
-            .get_up_metric()
-            .get_or_create(&self.metric_labels)
-            .set(0);
-        self.allow_drop = true;
-        // should now drop as method took ownership
+    fn accrue(ticks_at_depth: &mut Vec<u64>, depth: usize, ticks: u64) {
+        if ticks_at_depth.len() <= depth {
+            ticks_at_depth.resize(depth + 1, 0);
+        }
+        ticks_at_depth[depth] += ticks;
     }
 
-    pub fn listen(mut self, simulation: &'static S, timestamp: u64) -> Vec<ProposedEvent<S>> {
+    /// Fraction of the time up to `timestamp` spent at each depth, indexed by
+    /// depth, or None if no time has passed since the first change
+    pub fn get_distribution(&self, timestamp: u64) -> Option<Vec<f64>> {
+        let mut ticks_at_depth = self.ticks_at_depth.clone();
+        Self::accrue(&mut ticks_at_depth, self.depth, timestamp - self.since?);
+        let total: u64 = ticks_at_depth.iter().sum();
+        (total > 0).then(|| Vec::from_iter(ticks_at_depth.iter().map(|t| *t as f64 / total as f64)))
+    }
+}
+
+/// Read-only view of a queue's state, e.g. for monitoring
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueueSnapshot {
+    pub name: String,
+    // parked items
+    pub depth: usize,
+    pub queued_work_seconds: f64,
+    // idle workers, including any shutting down that haven't yet been picked
+    pub listening_workers: usize,
+    pub closed: bool,
+    pub stalled: bool,
+}
+
+pub type StalledHandler<S> = Box<dyn FnMut(&'static S, u64) -> Vec<ProposedEvent<S>>>;
+
+/// State for `Queue::check_stalled`
+pub struct StallCheck<S: QueueSimulation + 'static> {
+    pub dequeued_since_last_check: bool,
+    pub stalled: bool,
+    // called when the queue becomes stalled, e.g. to ask an autoscaler for workers
+    pub on_stalled: Option<StalledHandler<S>>,
+}
+
+impl<S: QueueSimulation + 'static> Default for StallCheck<S> {
+    fn default() -> Self {
+        StallCheck {
+            dequeued_since_last_check: false,
+            stalled: false,
+            on_stalled: None,
+        }
+    }
+}
+
+pub struct Queue<S: QueueSimulation + 'static> {
+    pub name: String,
+    // iterated when picking a worker, so hashed deterministically
+    pub listening_workers: HashSet<Rc<Worker<S>>, DeterministicState>,
+    pub deque: VecDeque<QueuedItem<S>>,
+    pub rng: Xoshiro256StarStar,
+    pub metric_labels: Vec<(String, String)>,
+    // priority levels a parked item gains per second waited, so low priority items
+    // can't be starved indefinitely
+    pub priority_aging_rate: f64,
+    // default time to hold a worker for items enqueued without their own handler
+    pub service_time: Option<LogNormal<f32>>,
+    // takes precedence over service_time when set, each item's service time being
+    // drawn from a component chosen with the queue's rng
+    pub service_time_mixture: Option<DelayMixture>,
+    // sum of parked items' estimated_work_seconds, optionally bounded
+    pub queued_work_seconds: f64,
+    pub max_queued_work_seconds: Option<f64>,
+    // set by drain_and_close; closed queues reject enqueues and are dropped from
+    // workers' subscriptions the next time they listen
+    pub closed: bool,
+    pub stall_check: StallCheck<S>,
+    // called when an enqueue finds listening workers but all of them shutting down,
+    // e.g. mid rolling restart. The queue is borrowed at the time, so this must
+    // only act on it through the events it proposes
+    pub on_only_shutting_down: Option<StalledHandler<S>>,
+    pub depth_time: DepthTime,
+    // the simulation's workers_improperly_dropped counter, which a dropped worker
+    // can only reach through its queues
+    pub workers_improperly_dropped_metric: Counter,
+    // items handed to a worker since the queue was created, including those
+    // stolen by workers not subscribed to it
+    pub processed: u64,
+}
+
+impl<S: QueueSimulation + 'static> Queue<S> {
+    /// An open, empty queue labelled `queue_name` with its own named rng stream
+    /// and every option off. Anything else is set with struct update syntax, e.g.
+    /// `Queue { service_time: Some(..), ..Queue::new(simulation, "foo") }`.
+    pub fn new(simulation: &S, name: &str) -> Self {
+        Queue {
+            name: name.to_owned(),
+            listening_workers: HashSet::with_hasher(DeterministicState::new(simulation)),
+            deque: Default::default(),
+            rng: mk_named_rng(simulation, &format!("queue/{name}")),
+            metric_labels: vec![("queue_name".to_owned(), name.to_owned())],
+            priority_aging_rate: 0.0,
+            service_time: None,
+            service_time_mixture: None,
+            queued_work_seconds: 0.0,
+            max_queued_work_seconds: None,
+            closed: false,
+            stall_check: Default::default(),
+            on_only_shutting_down: None,
+            depth_time: Default::default(),
+            workers_improperly_dropped_metric: simulation
+                .get_workers_improperly_dropped_metric()
+                .clone(),
+            processed: 0,
+        }
+    }
+
+    pub fn get_snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            name: self.name.clone(),
+            depth: self.deque.len(),
+            queued_work_seconds: self.queued_work_seconds,
+            listening_workers: self.listening_workers.len(),
+            closed: self.closed,
+            stalled: self.stall_check.stalled,
+        }
+    }
+
+    /// Ticks the longest-waiting parked item has been parked for by `timestamp`,
+    /// or None if nothing is parked. That isn't necessarily the next item popped,
+    /// as priorities reorder them.
+    pub fn get_oldest_item_age(&self, timestamp: u64) -> Option<u64> {
+        self.deque
+            .iter()
+            .map(|item| timestamp.saturating_sub(item.enqueue_timestamp))
+            .max()
+    }
+
+    /// Bring the queue_oldest_item_age_seconds gauge up to date, 0 if nothing is
+    /// parked, e.g. before reading it. As ages grow without anything happening
+    /// to the queue, it's only set when asked.
+    pub fn refresh_oldest_item_age_metric(&self, simulation: &S, timestamp: u64) {
+        let age = self.get_oldest_item_age(timestamp).unwrap_or(0);
         simulation
-            .get_up_metric()
+            .get_queue_oldest_item_age_metric()
             .get_or_create(&self.metric_labels)
-            .set(1);
+            .set(age as f64 / simulation.get_ticks_per_second());
+    }
 
-        if *self.status.borrow() != Status::Running {
-            self.shutdown(simulation);
-            return Default::default();
+    /// Check parked items out to idle listening workers, which only happens
+    /// after a worker has joined with `Worker::listen_idle`, as otherwise any
+    /// idle worker would have been dispatched an item when it was enqueued
+    pub fn dispatch_parked(
+        queue: &Rc<RefCell<Self>>,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let mut proposed_events = Vec::new();
+        loop {
+            let worker = {
+                let mut queue = queue.borrow_mut();
+                if queue.deque.is_empty() {
+                    break;
+                }
+                let (worker, mut r) = queue.pick_worker(simulation, timestamp, None);
+                proposed_events.append(&mut r);
+                match worker {
+                    Some(worker) => worker,
+                    None => break,
+                }
+            };
+            // released, as listening checks an item out of whichever of the
+            // worker's queues it chooses
+            proposed_events.append(&mut worker.listen(simulation, timestamp));
         }
+        proposed_events
+    }
 
-        let nonempty_queues = Vec::from_iter(
-            self.subscribed_queues
+    // removes a worker from the listening set of this and every other queue it's
+    // subscribed to, taking ownership of it
+    fn take_listening_worker(
+        &mut self,
+        simulation: &'static S,
+        chosen_worker_rc: Rc<Worker<S>>,
+    ) -> Worker<S> {
+        self.listening_workers.remove(&chosen_worker_rc);
+        self.set_listening_workers_metric(simulation);
+
+        let mut found_self = false;
+        for other_queue_rc in &chosen_worker_rc.subscribed_queues {
+            if let Ok(mut other_queue) = other_queue_rc.try_borrow_mut() {
+                other_queue.listening_workers.remove(&chosen_worker_rc);
+                other_queue.set_listening_workers_metric(simulation);
+            } else {
+                // this is presumably a reference to ourselves that we failed to
+                // borrow because we're already operating within that borrow - but
+                // it should only happen once in that case
+                assert!(
+                    !found_self,
+                    "Failed to borrow_mut more than one queue in subscribed_queues"
+                );
+                found_self = true;
+            }
+        }
+        assert!(found_self);
+
+        Rc::into_inner(chosen_worker_rc).unwrap()
+    }
+
+    fn pick_worker(
+        &mut self,
+        simulation: &'static S,
+        timestamp: u64,
+        affinity_key: Option<u64>,
+    ) -> (Option<Worker<S>>, Vec<ProposedEvent<S>>) {
+        if let Some(key) = affinity_key {
+            let affine_worker_rc = self
+                .listening_workers
                 .iter()
-                .filter(|q| !q.borrow().deque.is_empty()),
-        );
-        if nonempty_queues.is_empty() {
-            // return worker to all subscribed queues
-            let worker_rc = Rc::new(self);
-            for queue in &worker_rc.subscribed_queues {
-                queue
-                    .borrow_mut()
-                    .listening_workers
-                    .insert(worker_rc.clone());
+                .find(|w| {
+                    w.ext
+                        .get_recent_affinity_keys()
+                        .is_some_and(|keys| keys.contains(key))
+                        && *w.status.borrow() == Status::Running
+                })
+                .cloned();
+
+            if let Some(worker_rc) = affine_worker_rc {
+                simulation
+                    .get_affinity_hits_metric()
+                    .get_or_create(&self.metric_labels)
+                    .inc();
+                return (
+                    Some(self.take_listening_worker(simulation, worker_rc)),
+                    Default::default(),
+                );
             }
-            return Default::default();
+
+            simulation
+                .get_affinity_misses_metric()
+                .get_or_create(&self.metric_labels)
+                .inc();
         }
 
-        // else this worker picks up a new handler from a nonempty queue
+        let had_listening_workers = !self.listening_workers.is_empty();
+        while !self.listening_workers.is_empty() {
+            let chosen_worker_rc = Clone::clone(
+                self.listening_workers
+                    .iter()
+                    .nth(self.rng.gen_range(0..self.listening_workers.len()))
+                    .unwrap(),
+            );
 
-        // choose a nonempty queue
-        let chosen_queue = SliceRandom::choose(&nonempty_queues[..], &mut self.rng).unwrap();
-        let chosen_queue_name = chosen_queue.borrow().name.clone();
-        let followon_handler = chosen_queue.borrow_mut().deque.pop_front().unwrap();
-        let mut followon_token = WorkerToken {
-            metric_labels: self.metric_labels.clone(),
-            originating_queue_name: chosen_queue_name.clone(),
-            worker: self,
-            checkout_timestamp: timestamp,
+            let chosen_worker = self.take_listening_worker(simulation, chosen_worker_rc);
+
+            if *chosen_worker.status.borrow() != Status::Running {
+                chosen_worker.shutdown(simulation, timestamp);
+            } else {
+                return (Some(chosen_worker), Default::default());
+            }
+        }
+
+        if !had_listening_workers {
+            return (None, Default::default());
+        }
+        // every worker that was listening turned out to be shutting down
+        simulation
+            .get_pick_only_shutting_down_metric()
+            .get_or_create(&self.metric_labels)
+            .inc();
+        let proposed_events = match &mut self.on_only_shutting_down {
+            Some(on_only_shutting_down) => on_only_shutting_down(simulation, timestamp),
+            None => Default::default(),
         };
-        followon_token
-            .metric_labels
-            .push(("originating_queue".to_owned(), chosen_queue_name));
+        (None, proposed_events)
+    }
 
-        // tally metric
+    fn set_listening_workers_metric(&self, simulation: &'static S) {
         simulation
-            .get_worker_tokens_checked_out_metric()
-            .get_or_create(&followon_token.metric_labels)
+            .get_queue_listening_workers_metric()
+            .get_or_create(&self.metric_labels)
+            .set(self.listening_workers.len() as i64);
+    }
+
+    fn get_effective_priority(&self, simulation: &S, item: &QueuedItem<S>, timestamp: u64) -> f64 {
+        item.options.priority as f64
+            + self.priority_aging_rate * (timestamp - item.enqueue_timestamp) as f64
+                / simulation.get_ticks_per_second()
+    }
+
+    /// Remove the parked item with the highest effective (aged) priority, the
+    /// earliest enqueued winning ties.
+    pub fn pop_next_item(
+        &mut self,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Option<QueuedItem<S>> {
+        let item = self.pop_next_item_inner(simulation, timestamp)?;
+        self.depth_time.record(self.deque.len(), timestamp);
+        self.stall_check.dequeued_since_last_check = true;
+        self.set_queued_work_seconds(
+            simulation,
+            self.queued_work_seconds - item.options.estimated_work_seconds,
+        );
+        Some(item)
+    }
+
+    fn set_queued_work_seconds(&mut self, simulation: &'static S, queued_work_seconds: f64) {
+        // don't let float error accumulate into a phantom backlog
+        self.queued_work_seconds = if self.deque.is_empty() {
+            0.0
+        } else {
+            queued_work_seconds
+        };
+        simulation
+            .get_queued_work_metric()
+            .get_or_create(&self.metric_labels)
+            .set(self.queued_work_seconds);
+    }
+
+    fn pop_next_item_inner(&mut self, simulation: &S, timestamp: u64) -> Option<QueuedItem<S>> {
+        let first_priority = self.deque.front()?.options.priority;
+        if self
+            .deque
+            .iter()
+            .all(|item| item.options.priority == first_priority)
+        {
+            // aging can't reorder items of equal base priority
+            return self.deque.pop_front();
+        }
+
+        let mut best_index = 0;
+        let mut best_priority = f64::NEG_INFINITY;
+        for (i, item) in self.deque.iter().enumerate() {
+            let effective_priority = self.get_effective_priority(simulation, item, timestamp);
+            if effective_priority > best_priority {
+                best_index = i;
+                best_priority = effective_priority;
+            }
+        }
+
+        self.deque.remove(best_index)
+    }
+
+    fn count_rejection(&self, simulation: &'static S) {
+        simulation
+            .get_enqueue_rejected_metric()
+            .get_or_create(&self.metric_labels)
             .inc();
+    }
 
-        // call follow-on handler
-        followon_handler(simulation, timestamp, followon_token)
+    /// Enqueue immediately rather than via a scheduled event, returning whether the
+    /// item was dispatched straight to an idle worker along with any events the
+    /// dispatched handler proposed. Must not be called while this queue is already
+    /// borrowed, e.g. from a handler dispatched by this queue's own enqueue path.
+    pub fn try_enqueue(
+        &mut self,
+        simulation: &'static S,
+        timestamp: u64,
+        options: EnqueueOptions,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> (Vec<ProposedEvent<S>>, EnqueueOutcome) {
+        if self.closed {
+            self.count_rejection(simulation);
+            return (Default::default(), EnqueueOutcome::Rejected);
+        }
+
+        let mut proposed_events = Vec::new();
+        if self.deque.is_empty() {
+            let (worker, mut r) = self.pick_worker(simulation, timestamp, options.affinity_key);
+            proposed_events.append(&mut r);
+            if let Some(mut worker) = worker {
+                worker.note_affinity_key(options.affinity_key);
+                self.stall_check.dequeued_since_last_check = true;
+                self.processed += 1;
+                let token = WorkerToken::check_out(
+                    worker,
+                    self.name.clone(),
+                    options.context,
+                    simulation,
+                    timestamp,
+                );
+                simulation
+                    .get_enqueue_dispatched_metric()
+                    .get_or_create(&self.metric_labels)
+                    .inc();
+
+                proposed_events.append(&mut inner_handler(simulation, timestamp, token));
+                return (proposed_events, EnqueueOutcome::Dispatched);
+            }
+        }
+
+        if options.reject_if_busy {
+            simulation
+                .get_enqueue_rejected_no_worker_metric()
+                .get_or_create(&self.metric_labels)
+                .inc();
+            self.count_rejection(simulation);
+            return (proposed_events, EnqueueOutcome::Rejected);
+        }
+
+        let queued_work_seconds = self.queued_work_seconds + options.estimated_work_seconds;
+        if self
+            .max_queued_work_seconds
+            .is_some_and(|max| queued_work_seconds > max)
+        {
+            self.count_rejection(simulation);
+            return (proposed_events, EnqueueOutcome::Rejected);
+        }
+
+        self.deque.push_back(QueuedItem {
+            handler: Box::new(inner_handler),
+            enqueue_timestamp: timestamp,
+            options,
+        });
+        self.depth_time.record(self.deque.len(), timestamp);
+        self.set_queued_work_seconds(simulation, queued_work_seconds);
+        simulation
+            .get_enqueue_parked_metric()
+            .get_or_create(&self.metric_labels)
+            .inc();
+
+        (proposed_events, EnqueueOutcome::Parked)
     }
-}
 
-pub struct WorkerToken<S: QueueSimulation + 'static> {
-    worker: Worker<S>,
-    checkout_timestamp: u64,
-    originating_queue_name: String,
-    metric_labels: Vec<(String, String)>,
-}
+    /// Enqueue a batch arrival in order, dispatching to as many idle workers as are
+    /// available and parking the rest.
+    pub fn enqueue_batch(
+        &mut self,
+        simulation: &'static S,
+        timestamp: u64,
+        options: EnqueueOptions,
+        mut inner_handlers: Vec<EnqueuedHandler<S>>,
+    ) -> Vec<ProposedEvent<S>> {
+        let mut proposed_events = Vec::new();
+        for inner_handler in inner_handlers.drain(..) {
+            let (mut r, _) =
+                self.try_enqueue(simulation, timestamp, options.clone(), inner_handler);
+            proposed_events.append(&mut r);
+        }
+        proposed_events
+    }
 
-impl<S: QueueSimulation + 'static> WorkerToken<S> {
-    pub fn mk_token_restoring_handler(
-        inner_handler: impl FnOnce(&'static S, u64) -> (Vec<ProposedEvent<S>>, Vec<WorkerToken<S>>),
+    /// Meant to be called periodically: a queue is considered stalled if it has
+    /// parked items, no idle running worker, and no item was taken from it since
+    /// the previous check - e.g. because its pool was scaled to zero. Sets the
+    /// `queue_stalled` gauge, and calls `on_stalled` when a queue becomes stalled,
+    /// returning the events it proposes. A check period shorter than typical
+    /// service times may report busy queues as stalled.
+    pub fn check_stalled(
+        queue: &Rc<RefCell<Queue<S>>>,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let mut on_stalled = {
+            let mut q = queue.borrow_mut();
+            let stalled = !q.deque.is_empty()
+                && !q.stall_check.dequeued_since_last_check
+                && q.listening_workers
+                    .iter()
+                    .all(|w| *w.status.borrow() != Status::Running);
+            let newly_stalled = stalled && !q.stall_check.stalled;
+            q.stall_check.stalled = stalled;
+            q.stall_check.dequeued_since_last_check = false;
+            simulation
+                .get_queue_stalled_metric()
+                .get_or_create(&q.metric_labels)
+                .set(stalled as i64);
+
+            if !newly_stalled {
+                return Default::default();
+            }
+            // called without the queue borrowed, so it may enqueue or add workers
+            let Some(on_stalled) = q.stall_check.on_stalled.take() else {
+                return Default::default();
+            };
+            on_stalled
+        };
+
+        let proposed_events = on_stalled(simulation, timestamp);
+        queue.borrow_mut().stall_check.on_stalled = Some(on_stalled);
+        proposed_events
+    }
+
+    /// Close a queue so it can be removed from the topology. Further enqueues are
+    /// rejected, and idle workers listening on it are unlistened and re-homed onto
+    /// their other subscribed queues, or shut down if it was their only one. Busy
+    /// workers drop the queue when they're next restored. Returns the events
+    /// proposed by re-homed workers along with the parked items, which the caller
+    /// may re-enqueue elsewhere or discard.
+    pub fn drain_and_close(
+        queue: Rc<RefCell<Queue<S>>>,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> (Vec<ProposedEvent<S>>, Vec<QueuedItem<S>>) {
+        let (parked_items, listening_workers) = {
+            let mut q = queue.borrow_mut();
+            q.closed = true;
+            let parked_items = Vec::from_iter(q.deque.drain(..));
+            q.depth_time.record(0, timestamp);
+            q.set_queued_work_seconds(simulation, 0.0);
+            let listening_workers = Vec::from_iter(q.listening_workers.drain());
+            q.set_listening_workers_metric(simulation);
+            (parked_items, listening_workers)
+        };
+
+        let mut proposed_events = Vec::new();
+        for worker_rc in listening_workers {
+            // the worker can only be referenced by the listening sets it's now
+            // been removed from
+            let worker = Worker::unlisten(worker_rc, simulation, timestamp).unwrap();
+            proposed_events.append(&mut worker.listen(simulation, timestamp));
+        }
+
+        (proposed_events, parked_items)
+    }
+
+    pub fn mk_batch_enqueued_handler(
+        queue: Rc<RefCell<Queue<S>>>,
+        options: EnqueueOptions,
+        inner_handlers: Vec<EnqueuedHandler<S>>,
     ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
-        |simulation, timestamp| {
-            // call inner handler
-            let (mut proposed_events, mut tokens_to_restore) = inner_handler(simulation, timestamp);
+        move |simulation, timestamp| {
+            queue
+                .borrow_mut()
+                .enqueue_batch(simulation, timestamp, options, inner_handlers)
+        }
+    }
 
-            // proposed events that follow-on handlers may produce
-            let mut followon_proposed_events = Vec::new();
+    pub fn mk_enqueued_handler(
+        queue: Rc<RefCell<Queue<S>>>,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        Self::mk_enqueued_handler_with_options(queue, Default::default(), inner_handler)
+    }
 
-            // handle restored tokens
-            for mut token in tokens_to_restore.drain(..) {
-                assert!(
-                    token.checkout_timestamp < timestamp,
-                    "Cannot restore WorkerToken until after time period it was checked out",
-                );
+    /// The distribution to draw an item's service time from: a component of
+    /// `service_time_mixture` if set, otherwise `service_time`
+    pub fn choose_service_time(&mut self) -> Option<LogNormal<f32>> {
+        match &self.service_time_mixture {
+            Some(mixture) => Some(*mixture.choose(&mut self.rng)),
+            None => self.service_time,
+        }
+    }
 
-                simulation
-                    .get_worker_token_duration_metric()
-                    .get_or_create(&token.metric_labels)
-                    .observe((timestamp - token.checkout_timestamp) as f64 / S::TICKS_PER_SECOND);
+    /// Enqueue a generic item that holds the worker it's dispatched to for a time
+    /// sampled from the queue's service time distribution, scaled by the item's
+    /// size, then restores it.
+    pub fn mk_serviced_enqueued_handler(
+        queue: Rc<RefCell<Queue<S>>>,
+        options: EnqueueOptions,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let service_time = {
+                let mut q = queue.borrow_mut();
+                q.choose_service_time()
+                    .unwrap_or_else(|| panic!("Queue {} has no service_time", q.name))
+            };
 
-                followon_proposed_events.append(&mut token.worker.listen(simulation, timestamp));
+            let context = options.context;
+            Self::mk_enqueued_handler_with_options(
+                queue,
+                options,
+                move |simulation, _, worker_token| {
+                    vec![ProposedEvent {
+                        due_time: DueTime::After(sample_sized_service_time(
+                            simulation,
+                            &service_time,
+                            &context,
+                        )),
+                        handler: Box::new(WorkerToken::mk_token_restoring_handler(move |_, _| {
+                            (Default::default(), vec![worker_token])
+                        })),
+                        label: Some("queue_service_complete"),
+                        keepalive: true,
+                        priority: 0,
+                    }]
+                },
+            )(simulation, timestamp)
+        }
+    }
+
+    pub fn mk_enqueued_handler_with_options(
+        queue: Rc<RefCell<Queue<S>>>,
+        options: EnqueueOptions,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let mut q = queue.borrow_mut();
+            let (proposed_events, outcome) =
+                q.try_enqueue(simulation, timestamp, options, inner_handler);
+            // already counted in enqueue_rejected by try_enqueue
+            if outcome == EnqueueOutcome::Rejected {
+                log::debug!(
+                    "queue {} rejected an item @ {timestamp}, dropping it",
+                    q.name
+                );
             }
+            proposed_events
+        }
+    }
+}
 
-            // combine proposed events from follow-ons into our ret
-            proposed_events.append(&mut followon_proposed_events);
+/// Observe any buffered worker token durations, e.g. before collecting metrics
+pub fn flush_token_durations<S: QueueSimulation>(simulation: &S) {
+    simulation
+        .borrow_token_duration_buffer_mut()
+        .flush(simulation.get_worker_token_duration_metric());
+}
+
+/// Bring every worker's recent checkouts gauge up to date, e.g. before collecting
+/// metrics, as otherwise a gauge only changes when its worker is checked out
+pub fn refresh_recent_checkouts<S: QueueSimulation>(simulation: &S, timestamp: u64) {
+    simulation
+        .borrow_recent_checkouts_mut()
+        .refresh(simulation.get_worker_recent_checkouts_metric(), timestamp);
+}
+
+/// How a worker chooses between several nonempty subscribed queues
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QueueSelectionPolicy {
+    #[default]
+    Random,
+    // lowest queue name first
+    ByName,
+    // the queue whose head item was enqueued earliest, then by name
+    OldestHead,
+}
+
+/// A worker's warm-up after it starts, e.g. JIT compilation or cache fill, during
+/// which it's up but slow: its service times are inflated by a factor decaying
+/// linearly from `initial_factor` at `started_at` to 1.0 once `period_seconds`
+/// have passed. Kept in the worker's `ext`, and consulted wherever the handlers it
+/// serves sample their service times. The default is already warm.
+#[derive(Clone, Copy, Debug)]
+pub struct WarmUp {
+    pub started_at: u64,
+    pub period_seconds: f64,
+    pub initial_factor: f64,
+}
+
+impl Default for WarmUp {
+    fn default() -> Self {
+        WarmUp {
+            started_at: 0,
+            period_seconds: 0.0,
+            initial_factor: 1.0,
+        }
+    }
+}
 
-            return proposed_events;
+impl WarmUp {
+    pub fn get_factor<S: Simulation>(&self, simulation: &S, timestamp: u64) -> f64 {
+        let elapsed_seconds =
+            timestamp.saturating_sub(self.started_at) as f64 / simulation.get_ticks_per_second();
+        if elapsed_seconds >= self.period_seconds {
+            return 1.0;
         }
+        self.initial_factor + (1.0 - self.initial_factor) * elapsed_seconds / self.period_seconds
+    }
+
+    /// Like `sample_service_time`, inflated by the factor at `timestamp`
+    pub fn sample_service_time<S: Simulation>(
+        &self,
+        simulation: &S,
+        distribution: &LogNormal<f32>,
+        timestamp: u64,
+    ) -> LogNormal<f32> {
+        let t = distribution.sample(&mut *simulation.borrow_service_rng_mut()) as f64;
+        delay_from_mean_cv((t * self.get_factor(simulation, timestamp)) as f32, 0.0)
+    }
+}
+
+pub struct Worker<S: QueueSimulation + 'static> {
+    pub id: u64,
+    pub subscribed_queues: Vec<Rc<RefCell<Queue<S>>>>,
+    // positive relative weights by queue name for QueueSelectionPolicy::Random,
+    // 1.0 for queues not listed
+    pub subscription_weights: HashMap<String, f32>,
+    pub status: Rc<RefCell<Status>>,
+    pub allow_drop: bool,
+    pub rng: Xoshiro256StarStar,
+    pub metric_labels: Vec<(String, String)>,
+    // the pool this worker belongs to, e.g. reserved or spot capacity serving the
+    // same queue, added as a `pool` label to its metrics and its queue's busy gauge
+    pub pool: Option<String>,
+    pub queue_selection: QueueSelectionPolicy,
+    // unsubscribed queues this worker may take work from when all its subscribed
+    // queues are empty, without ever listening on them
+    pub steal_queues: Vec<Rc<RefCell<Queue<S>>>>,
+    pub ext: S::WorkerExtension,
+}
+
+impl<S: QueueSimulation + 'static> Hash for Worker<S> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl<S: QueueSimulation + 'static> PartialEq for Worker<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<S: QueueSimulation + 'static> Eq for Worker<S> {}
+
+/// What happens when a `Worker` is dropped without `allow_drop` set
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WorkerDropMode {
+    // catches leaked workers early, e.g. in tests
+    #[default]
+    Panic,
+    // logs and increments the workers_improperly_dropped counter instead, so that
+    // an embedding process survives a leaked worker
+    LogAndCount,
+}
+
+// Drop can't reach the simulation, so this is per thread rather than per
+// simulation; simulations are single-threaded, so in practice it only affects
+// simulations run on the thread that sets it
+thread_local! {
+    static WORKER_DROP_MODE: Cell<WorkerDropMode> = Default::default();
+}
+
+pub fn set_worker_drop_mode(mode: WorkerDropMode) {
+    WORKER_DROP_MODE.set(mode);
+}
+
+pub fn get_worker_drop_mode() -> WorkerDropMode {
+    WORKER_DROP_MODE.get()
+}
+
+impl<S: QueueSimulation + 'static> Drop for Worker<S> {
+    fn drop(&mut self) {
+        if !self.allow_drop {
+            let msg = format!("Worker {} was dropped without proper shutdown", self.id);
+
+            if std::thread::panicking() {
+                log::error!("{msg} (but already panicking)");
+            } else if get_worker_drop_mode() == WorkerDropMode::LogAndCount {
+                log::error!("{msg}");
+                // counted against the simulation of the first subscribed queue not
+                // borrowed at the time; a worker with none goes uncounted
+                if let Some(queue) = self
+                    .subscribed_queues
+                    .iter()
+                    .find_map(|q| q.try_borrow().ok())
+                {
+                    queue.workers_improperly_dropped_metric.inc();
+                }
+            } else {
+                panic!("{msg}");
+            }
+        }
+    }
+}
+
+impl<S: QueueSimulation + 'static> Worker<S> {
+    fn note_affinity_key(&mut self, affinity_key: Option<u64>) {
+        if let (Some(key), Some(keys)) = (affinity_key, self.ext.get_recent_affinity_keys_mut()) {
+            keys.note(key);
+        }
+    }
+
+    /// Remove an idle worker from every subscribed queue's listening set without
+    /// shutting it down, e.g. to drain it or take it out for a health check.
+    /// Removing it from a queue it isn't listening on is a no-op. Returns the
+    /// worker itself, which must later be `listen`ed or shut down, unless someone
+    /// else still holds a reference to it.
+    pub fn unlisten(
+        worker_rc: Rc<Worker<S>>,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Option<Worker<S>> {
+        simulation.borrow_worker_transition_log_mut().record(
+            worker_rc.id,
+            timestamp,
+            WorkerTransition::Unlisten,
+        );
+        for queue in &worker_rc.subscribed_queues {
+            let mut queue = queue.borrow_mut();
+            queue.listening_workers.remove(&worker_rc);
+            queue.set_listening_workers_metric(simulation);
+        }
+
+        Rc::into_inner(worker_rc)
+    }
+
+    fn get_pooled_metric_labels(&self) -> Vec<(String, String)> {
+        let mut metric_labels = self.metric_labels.clone();
+        if let Some(pool) = &self.pool {
+            metric_labels.push(("pool".to_owned(), pool.clone()));
+        }
+        metric_labels
+    }
+
+    pub fn shutdown(mut self, simulation: &'static S, timestamp: u64) {
+        simulation.borrow_worker_transition_log_mut().record(
+            self.id,
+            timestamp,
+            WorkerTransition::ShutDown,
+        );
+        simulation
+            .get_up_metric()
+            .get_or_create(&self.get_pooled_metric_labels())
+            .set(0);
+        self.allow_drop = true;
+        // should now drop as method took ownership
+    }
+
+    /// Like `listen`, but joins the subscribed queues' listening workers even if
+    /// items are parked on them rather than checking one out, so it never has
+    /// events to propose, e.g. for workers started by a pool's
+    /// `instance_constructor`. `Queue::dispatch_parked` hands it parked items.
+    pub fn listen_idle(mut self, simulation: &'static S, timestamp: u64) {
+        if !self.prepare_to_listen(simulation) {
+            self.shutdown(simulation, timestamp);
+            return;
+        }
+        self.join_listening_workers(simulation, timestamp);
+    }
+
+    // marks the worker up and drops its closed queues, returning whether it
+    // should go on listening rather than shut down
+    fn prepare_to_listen(&mut self, simulation: &'static S) -> bool {
+        simulation
+            .get_up_metric()
+            .get_or_create(&self.get_pooled_metric_labels())
+            .set(1);
+
+        self.subscribed_queues.retain(|q| !q.borrow().closed);
+        *self.status.borrow() == Status::Running && !self.subscribed_queues.is_empty()
+    }
+
+    // returns the worker to all its subscribed queues
+    fn join_listening_workers(self, simulation: &'static S, timestamp: u64) {
+        simulation.borrow_worker_transition_log_mut().record(
+            self.id,
+            timestamp,
+            WorkerTransition::Listen,
+        );
+        let worker_rc = Rc::new(self);
+        for queue in &worker_rc.subscribed_queues {
+            let mut queue = queue.borrow_mut();
+            queue.listening_workers.insert(worker_rc.clone());
+            queue.set_listening_workers_metric(simulation);
+        }
+    }
+
+    pub fn listen(mut self, simulation: &'static S, timestamp: u64) -> Vec<ProposedEvent<S>> {
+        if !self.prepare_to_listen(simulation) {
+            self.shutdown(simulation, timestamp);
+            return Default::default();
+        }
+
+        let nonempty_queues = Vec::from_iter(
+            self.subscribed_queues
+                .iter()
+                .filter(|q| !q.borrow().deque.is_empty()),
+        );
+        if nonempty_queues.is_empty() {
+            // steal from the most backlogged steal queue, if any are nonempty
+            let steal_queue = self
+                .steal_queues
+                .iter()
+                .filter(|q| {
+                    let q = q.borrow();
+                    !q.closed && !q.deque.is_empty()
+                })
+                .max_by(|a, b| {
+                    let (a, b) = (a.borrow(), b.borrow());
+                    a.deque.len().cmp(&b.deque.len()).then(b.name.cmp(&a.name))
+                })
+                .cloned();
+            if let Some(steal_queue) = steal_queue {
+                simulation
+                    .get_work_steals_metric()
+                    .get_or_create(&steal_queue.borrow().metric_labels)
+                    .inc();
+                return self.check_out_next_item(&steal_queue, simulation, timestamp);
+            }
+
+            self.join_listening_workers(simulation, timestamp);
+            return Default::default();
+        }
+
+        // else this worker picks up a new handler from a nonempty queue
+
+        // choose a nonempty queue
+        let chosen_queue = match self.queue_selection {
+            QueueSelectionPolicy::Random if self.subscription_weights.is_empty() => {
+                SliceRandom::choose(&nonempty_queues[..], &mut self.rng).unwrap()
+            }
+            QueueSelectionPolicy::Random => {
+                SliceRandom::choose_weighted(&nonempty_queues[..], &mut self.rng, |q| {
+                    *self
+                        .subscription_weights
+                        .get(&q.borrow().name)
+                        .unwrap_or(&1.0)
+                })
+                .unwrap()
+            }
+            QueueSelectionPolicy::ByName => nonempty_queues
+                .iter()
+                .min_by(|a, b| a.borrow().name.cmp(&b.borrow().name))
+                .unwrap(),
+            QueueSelectionPolicy::OldestHead => nonempty_queues
+                .iter()
+                .min_by_key(|q| {
+                    let q = q.borrow();
+                    (q.deque.front().unwrap().enqueue_timestamp, q.name.clone())
+                })
+                .unwrap(),
+        };
+        let chosen_queue = Rc::clone(chosen_queue);
+        self.check_out_next_item(&chosen_queue, simulation, timestamp)
+    }
+
+    fn check_out_next_item(
+        mut self,
+        queue: &Rc<RefCell<Queue<S>>>,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let chosen_queue_name = queue.borrow().name.clone();
+        let followon_item = {
+            let mut queue = queue.borrow_mut();
+            queue.processed += 1;
+            queue.pop_next_item(simulation, timestamp).unwrap()
+        };
+        self.note_affinity_key(followon_item.options.affinity_key);
+        let followon_token = WorkerToken::check_out(
+            self,
+            chosen_queue_name,
+            followon_item.options.context,
+            simulation,
+            timestamp,
+        );
+
+        // call follow-on handler
+        (followon_item.handler)(simulation, timestamp, followon_token)
+    }
+}
+
+pub struct WorkerToken<S: QueueSimulation + 'static> {
+    worker: Worker<S>,
+    checkout_timestamp: u64,
+    originating_queue_name: String,
+    metric_labels: Vec<(String, String)>,
+    item_context: ItemContext,
+}
+
+impl<S: QueueSimulation + 'static> WorkerToken<S> {
+    fn check_out(
+        worker: Worker<S>,
+        originating_queue_name: String,
+        item_context: ItemContext,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Self {
+        simulation.borrow_worker_transition_log_mut().record(
+            worker.id,
+            timestamp,
+            WorkerTransition::CheckOut,
+        );
+        simulation.borrow_recent_checkouts_mut().record(
+            simulation.get_worker_recent_checkouts_metric(),
+            worker.id,
+            &worker.get_pooled_metric_labels(),
+            timestamp,
+        );
+
+        let mut metric_labels = worker.get_pooled_metric_labels();
+        metric_labels.push((
+            "originating_queue".to_owned(),
+            originating_queue_name.clone(),
+        ));
+        if let Some(enricher) = &*simulation.borrow_token_label_enricher_mut() {
+            let extra_labels = enricher(&metric_labels);
+            metric_labels.extend(extra_labels);
+        }
+
+        simulation
+            .get_worker_tokens_checked_out_metric()
+            .get_or_create(&metric_labels)
+            .inc();
+        simulation
+            .get_workers_busy_metric()
+            .get_or_create(&Self::get_busy_metric_labels(
+                &originating_queue_name,
+                worker.pool.as_deref(),
+            ))
+            .inc();
+
+        WorkerToken {
+            worker,
+            checkout_timestamp: timestamp,
+            originating_queue_name,
+            metric_labels,
+            item_context,
+        }
+    }
+
+    fn get_busy_metric_labels(
+        originating_queue_name: &str,
+        pool: Option<&str>,
+    ) -> Vec<(String, String)> {
+        let mut metric_labels = vec![(
+            "originating_queue".to_owned(),
+            originating_queue_name.to_owned(),
+        )];
+        if let Some(pool) = pool {
+            metric_labels.push(("pool".to_owned(), pool.to_owned()));
+        }
+        metric_labels
+    }
+
+    pub fn get_worker_id(&self) -> u64 {
+        self.worker.id
+    }
+
+    pub fn get_originating_queue_name(&self) -> &str {
+        &self.originating_queue_name
+    }
+
+    pub fn get_worker_pool(&self) -> Option<&str> {
+        self.worker.pool.as_deref()
+    }
+
+    pub fn get_worker_status(&self) -> Rc<RefCell<Status>> {
+        self.worker.status.clone()
+    }
+
+    /// The context the item this token was checked out for was enqueued with
+    pub fn get_item_context(&self) -> ItemContext {
+        self.item_context
+    }
+
+    pub fn get_checkout_timestamp(&self) -> u64 {
+        self.checkout_timestamp
+    }
+
+    pub fn get_worker_ext(&self) -> &S::WorkerExtension {
+        &self.worker.ext
+    }
+
+    /// Changes stay with the worker once the token is restored, so are seen by
+    /// whichever handler next checks out the same worker.
+    pub fn get_worker_ext_mut(&mut self) -> &mut S::WorkerExtension {
+        &mut self.worker.ext
+    }
+
+    pub fn mk_token_restoring_handler(
+        inner_handler: impl FnOnce(&'static S, u64) -> (Vec<ProposedEvent<S>>, Vec<WorkerToken<S>>),
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        |simulation, timestamp| {
+            // call inner handler
+            let (mut proposed_events, mut tokens_to_restore) = inner_handler(simulation, timestamp);
+
+            // proposed events that follow-on handlers may produce
+            let mut followon_proposed_events = Vec::new();
+
+            // workers restored together listen in random order, so the first
+            // restored isn't always first to pick up parked work. each listen
+            // returns before the next, so a large batch doesn't nest calls
+            if tokens_to_restore.len() > 1 {
+                SliceRandom::shuffle(
+                    &mut tokens_to_restore[..],
+                    &mut *simulation.borrow_restore_order_rng_mut(),
+                );
+            }
+
+            // handle restored tokens
+            for token in tokens_to_restore.drain(..) {
+                // restoring within the tick of checkout is possible with zero-delay
+                // events, and observes a zero duration
+                assert!(
+                    token.checkout_timestamp <= timestamp,
+                    "Cannot restore WorkerToken before it was checked out",
+                );
+
+                simulation.borrow_token_duration_buffer_mut().observe(
+                    simulation.get_worker_token_duration_metric(),
+                    token.metric_labels,
+                    (timestamp - token.checkout_timestamp) as f64
+                        / simulation.get_ticks_per_second(),
+                );
+                simulation
+                    .get_workers_busy_metric()
+                    .get_or_create(&Self::get_busy_metric_labels(
+                        &token.originating_queue_name,
+                        token.worker.pool.as_deref(),
+                    ))
+                    .dec();
+                simulation.borrow_worker_transition_log_mut().record(
+                    token.worker.id,
+                    timestamp,
+                    WorkerTransition::Restore,
+                );
+
+                followon_proposed_events.append(&mut token.worker.listen(simulation, timestamp));
+            }
+
+            // combine proposed events from follow-ons into our ret
+            proposed_events.append(&mut followon_proposed_events);
+
+            proposed_events
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_loop::*;
+    use crate::pool_manager::*;
+    use crate::shared_rate_resource::*;
+    use crate::testing::*;
+
+    #[derive(Default)]
+    struct TestExtension {
+        recent_affinity_keys: RecentAffinityKeys,
+        processed: u32,
+    }
+
+    impl AffinityTracking for TestExtension {
+        fn get_recent_affinity_keys(&self) -> Option<&RecentAffinityKeys> {
+            Some(&self.recent_affinity_keys)
+        }
+
+        fn get_recent_affinity_keys_mut(&mut self) -> Option<&mut RecentAffinityKeys> {
+            Some(&mut self.recent_affinity_keys)
+        }
+    }
+
+    fn mk_simulation<E: AffinityTracking + 'static>() -> &'static BaseQueueSimulation<E> {
+        Box::leak(Box::new(BaseQueueSimulation::new(1)))
+    }
+
+    fn mk_listening_queue<S: QueueSimulation + 'static>(
+        simulation: &'static S,
+        workers: u64,
+    ) -> Rc<RefCell<Queue<S>>> {
+        let queue = Rc::new(RefCell::new(Queue::new(simulation, "test")));
+        for id in 0..workers {
+            mk_worker(simulation, id, &queue).listen(simulation, 0);
+        }
+        queue
+    }
+
+    // enqueues an item onto a queue with an idle worker, restoring the worker a
+    // tick later, and returns its id
+    fn enqueue_and_restore<S: QueueSimulation + 'static>(
+        queue: &Rc<RefCell<Queue<S>>>,
+        simulation: &'static S,
+        timestamp: u64,
+        options: EnqueueOptions,
+    ) -> u64 {
+        let checked_out: Rc<RefCell<Option<WorkerToken<S>>>> = Default::default();
+        let (_, outcome) = queue
+            .borrow_mut()
+            .try_enqueue(simulation, timestamp, options, {
+                let checked_out = checked_out.clone();
+                move |_, _, token| {
+                    *checked_out.borrow_mut() = Some(token);
+                    Default::default()
+                }
+            });
+        assert_eq!(outcome, EnqueueOutcome::Dispatched);
+        let token = checked_out.take().unwrap();
+        let worker_id = token.get_worker_id();
+        WorkerToken::mk_token_restoring_handler(move |_, _| (Default::default(), vec![token]))(
+            simulation,
+            timestamp + 1,
+        );
+        worker_id
+    }
+
+    fn keyed(affinity_key: u64) -> EnqueueOptions {
+        EnqueueOptions {
+            affinity_key: Some(affinity_key),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_affinity_key_sticks_to_worker() {
+        let simulation = mk_simulation::<TestExtension>();
+        let queue = mk_listening_queue(simulation, 4);
+
+        let first = enqueue_and_restore(&queue, simulation, 0, keyed(7));
+        for i in 1..=10 {
+            assert_eq!(
+                enqueue_and_restore(&queue, simulation, 2 * i, keyed(7)),
+                first
+            );
+        }
+
+        let labels = &queue.borrow().metric_labels;
+        assert_eq!(
+            simulation
+                .get_affinity_hits_metric()
+                .get_or_create(labels)
+                .get(),
+            10
+        );
+        assert_eq!(
+            simulation
+                .get_affinity_misses_metric()
+                .get_or_create(labels)
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn untracked_extension_never_hits() {
+        let simulation = mk_simulation::<()>();
+        let queue = mk_listening_queue(simulation, 4);
+
+        for i in 0..10 {
+            enqueue_and_restore(&queue, simulation, 2 * i, keyed(7));
+        }
+
+        let labels = &queue.borrow().metric_labels;
+        assert_eq!(
+            simulation
+                .get_affinity_hits_metric()
+                .get_or_create(labels)
+                .get(),
+            0
+        );
+    }
+
+    #[test]
+    fn recent_affinity_keys_are_bounded() {
+        let mut keys = RecentAffinityKeys::default();
+        for key in 0..6 {
+            keys.note(key);
+        }
+        keys.note(3);
+        assert!(keys.contains(3) && keys.contains(5) && keys.contains(4));
+        assert!(!keys.contains(1));
+        assert_eq!(keys.0, [3, 5, 4, 2]);
+    }
+
+    #[test]
+    fn queued_work_seconds_sum_and_drain() {
+        let simulation = mk_simulation::<()>();
+        let queue = Rc::new(RefCell::new(Queue {
+            max_queued_work_seconds: Some(10.0),
+            ..Queue::new(simulation, "test")
+        }));
+        let get_gauge = || {
+            simulation
+                .get_queued_work_metric()
+                .get_or_create(&queue.borrow().metric_labels)
+                .get()
+        };
+        let enqueue = |estimated_work_seconds| {
+            queue
+                .borrow_mut()
+                .try_enqueue(
+                    simulation,
+                    0,
+                    EnqueueOptions {
+                        estimated_work_seconds,
+                        ..Default::default()
+                    },
+                    |_, _, token| {
+                        // the worker stays busy for the rest of the test
+                        std::mem::forget(token);
+                        Default::default()
+                    },
+                )
+                .1
+        };
+
+        for estimated_work_seconds in [0.5, 2.0, 4.5] {
+            assert_eq!(enqueue(estimated_work_seconds), EnqueueOutcome::Parked);
+        }
+        assert_eq!(get_gauge(), 7.0);
+        // would take the total to 11s
+        assert_eq!(enqueue(4.0), EnqueueOutcome::Rejected);
+        assert_eq!(get_gauge(), 7.0);
+        assert_eq!(
+            simulation
+                .get_enqueue_rejected_metric()
+                .get_or_create(&queue.borrow().metric_labels)
+                .get(),
+            1
+        );
+
+        // a worker listening takes the first item off the queue
+        mk_worker(simulation, 0, &queue).listen(simulation, 1);
+        assert_eq!(get_gauge(), 6.5);
+        assert_eq!(queue.borrow().queued_work_seconds, 6.5);
+    }
+
+    // sums the encoded samples of `series` over every label set including `label`
+    fn get_encoded_total<S: Simulation>(simulation: &S, series: &str, label: &str) -> f64 {
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &simulation.borrow_metric_registry())
+            .unwrap();
+        text.lines()
+            .filter(|line| line.starts_with(&format!("{series}{{")) && line.contains(label))
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<f64>().unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn sub_floor_token_durations_keep_an_exact_mean() {
+        let simulation: &'static BaseQueueSimulation<()> = Box::leak(Box::new(
+            SimulationBuilder::new(1)
+                .worker_token_duration_buckets(HistogramBuckets::exponential(0.01, 2.0, 16))
+                .build(),
+        ));
+        let queue = mk_listening_queue(simulation, 1);
+        // each held for one tick, a tenth of the first bucket's bound
+        for i in 0..1000 {
+            enqueue_and_restore(&queue, simulation, 2 * i, Default::default());
+        }
+        flush_token_durations(simulation);
+
+        let count = get_encoded_total(simulation, "worker_token_duration_count", "");
+        let sum = get_encoded_total(simulation, "worker_token_duration_sum", "");
+        assert_eq!(count, 1000.0);
+        assert!((sum / count - 0.001).abs() < 1e-9, "{sum}");
+        // every one of them in the first bucket, which is cumulative
+        assert_eq!(
+            get_encoded_total(simulation, "worker_token_duration_bucket", "le=\"0.01\""),
+            1000.0
+        );
+    }
+
+    #[test]
+    fn idle_listeners_take_parked_items_once_dispatched() {
+        let simulation = mk_simulation::<()>();
+        let queue = Rc::new(RefCell::new(Queue::new(simulation, "test")));
+        let tokens: Rc<RefCell<Vec<WorkerToken<_>>>> = Default::default();
+        for _ in 0..3 {
+            let (_, outcome) = queue
+                .borrow_mut()
+                .try_enqueue(simulation, 0, Default::default(), {
+                    let tokens = tokens.clone();
+                    move |_, _, token| {
+                        tokens.borrow_mut().push(token);
+                        Default::default()
+                    }
+                });
+            assert_eq!(outcome, EnqueueOutcome::Parked);
+        }
+        let oldest_item_age = || {
+            let labels = queue.borrow().metric_labels.clone();
+            simulation
+                .get_queue_oldest_item_age_metric()
+                .get_or_create(&labels)
+                .get()
+        };
+        queue
+            .borrow()
+            .refresh_oldest_item_age_metric(simulation, 2500);
+        assert_eq!(oldest_item_age(), 2.5);
+
+        // joining doesn't check anything out, so leaves nothing to propose
+        for id in 0..2 {
+            mk_worker(simulation, id, &queue).listen_idle(simulation, 3000);
+        }
+        assert_eq!(queue.borrow().deque.len(), 3);
+        assert_eq!(queue.borrow().listening_workers.len(), 2);
+
+        assert!(Queue::dispatch_parked(&queue, simulation, 3000).is_empty());
+        assert_eq!(queue.borrow().deque.len(), 1);
+        assert!(queue.borrow().listening_workers.is_empty());
+        assert_eq!(tokens.borrow().len(), 2);
+
+        // a restored worker takes the last one as usual
+        let restored = tokens.take();
+        WorkerToken::mk_token_restoring_handler(move |_, _| (Default::default(), restored))(
+            simulation, 4000,
+        );
+        assert!(queue.borrow().deque.is_empty());
+        let restored = tokens.take();
+        assert_eq!(restored.len(), 1);
+        WorkerToken::mk_token_restoring_handler(move |_, _| (Default::default(), restored))(
+            simulation, 5000,
+        );
+        queue
+            .borrow()
+            .refresh_oldest_item_age_metric(simulation, 5000);
+        assert_eq!(oldest_item_age(), 0.0);
+    }
+
+    #[test]
+    fn enqueue_options_reject_sizes_that_are_not_positive_and_finite() {
+        for size in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                EnqueueOptions::default().with_size(size),
+                Err(Error::InvalidItemSize { .. })
+            ));
+        }
+        let options = EnqueueOptions::default().with_size(4.0).unwrap();
+        assert_eq!(options.context.get_size(), 4.0);
+    }
+
+    #[test]
+    fn windowed_checkouts_rise_then_fall_while_cumulative_only_rises() {
+        let simulation = mk_simulation::<()>();
+        simulation.borrow_recent_checkouts_mut().window = Some(100);
+        let queue = mk_listening_queue(simulation, 1);
+        let windowed = || get_encoded_total(simulation, "worker_recent_checkouts", "");
+        let cumulative = || get_encoded_total(simulation, "worker_tokens_checked_out_total", "");
+
+        // hammered
+        for i in 0..10 {
+            enqueue_and_restore(&queue, simulation, 2 * i, Default::default());
+        }
+        assert_eq!((windowed(), cumulative()), (10.0, 10.0));
+        refresh_recent_checkouts(simulation, 50);
+        assert_eq!((windowed(), cumulative()), (10.0, 10.0));
+
+        // rested, with the early checkouts leaving the window one by one
+        refresh_recent_checkouts(simulation, 105);
+        assert_eq!((windowed(), cumulative()), (7.0, 10.0));
+        refresh_recent_checkouts(simulation, 200);
+        assert_eq!((windowed(), cumulative()), (0.0, 10.0));
+
+        enqueue_and_restore(&queue, simulation, 300, Default::default());
+        assert_eq!((windowed(), cumulative()), (1.0, 11.0));
+    }
+
+    #[test]
+    fn buffered_token_durations_are_exact_once_flushed() {
+        let simulation = mk_simulation::<()>();
+        simulation.borrow_token_duration_buffer_mut().enabled = true;
+        let queue = mk_listening_queue(simulation, 2);
+        let mut worker_ids = HashSet::new();
+        for i in 0..20 {
+            worker_ids.insert(enqueue_and_restore(
+                &queue,
+                simulation,
+                2 * i,
+                Default::default(),
+            ));
+        }
+        assert_eq!(worker_ids.len(), 2);
+
+        // held back until flushed
+        assert_eq!(
+            simulation
+                .borrow_token_duration_buffer_mut()
+                .observations
+                .len(),
+            20
+        );
+        assert_eq!(
+            get_encoded_total(simulation, "worker_token_duration_count", ""),
+            0.0
+        );
+
+        flush_token_durations(simulation);
+        assert!(simulation
+            .borrow_token_duration_buffer_mut()
+            .observations
+            .is_empty());
+        let count = get_encoded_total(simulation, "worker_token_duration_count", "");
+        let sum = get_encoded_total(simulation, "worker_token_duration_sum", "");
+        assert_eq!(count, 20.0);
+        assert!((sum - 0.02).abs() < 1e-9, "{sum}");
+        // and the accessor flushes for itself
+        enqueue_and_restore(&queue, simulation, 40, Default::default());
+        let labels = |id: u64| {
+            [
+                ("worker_id".to_owned(), id.to_string()),
+                ("originating_queue".to_owned(), "test".to_owned()),
+            ]
+        };
+        let total_count: u64 = [0, 1]
+            .map(|id| {
+                simulation
+                    .get_worker_token_duration_sum_count(&labels(id))
+                    .unwrap()
+                    .1
+            })
+            .iter()
+            .sum();
+        assert_eq!(total_count, 21);
+    }
+
+    #[test]
+    fn enriched_labels_apply_to_checkouts_and_durations() {
+        let simulation = mk_simulation::<()>();
+        *simulation.borrow_token_label_enricher_mut() = Some(Box::new(|labels| {
+            let tier = if labels.contains(&("worker_id".to_owned(), "0".to_owned())) {
+                "gold"
+            } else {
+                "silver"
+            };
+            vec![("tier".to_owned(), tier.to_owned())]
+        }));
+        let queue = mk_listening_queue(simulation, 2);
+        let mut worker_ids = Vec::new();
+        for i in 0..10 {
+            worker_ids.push(enqueue_and_restore(
+                &queue,
+                simulation,
+                2 * i,
+                Default::default(),
+            ));
+        }
+        assert!(worker_ids.contains(&0) && worker_ids.contains(&1));
+
+        for (id, tier) in [(0, "gold"), (1, "silver")] {
+            let base_labels = vec![
+                ("worker_id".to_owned(), id.to_string()),
+                ("originating_queue".to_owned(), "test".to_owned()),
+            ];
+            let mut enriched_labels = base_labels.clone();
+            enriched_labels.push(("tier".to_owned(), tier.to_owned()));
+            let checkouts = worker_ids
+                .iter()
+                .filter(|worker_id| **worker_id == id)
+                .count() as u64;
+
+            assert_eq!(
+                simulation.get_checked_out_count(&enriched_labels),
+                Ok(checkouts)
+            );
+            assert_eq!(
+                simulation
+                    .get_worker_token_duration_sum_count(&enriched_labels)
+                    .unwrap()
+                    .1,
+                checkouts
+            );
+            assert_eq!(simulation.get_checked_out_count(&base_labels), Ok(0));
+        }
+    }
+
+    #[test]
+    fn only_shutting_down_workers_park_and_notify() {
+        let simulation = mk_simulation::<()>();
+        let queue = Rc::new(RefCell::new(Queue::new(simulation, "test")));
+        for id in 0..3 {
+            let worker = mk_worker(simulation, id, &queue);
+            let status = worker.status.clone();
+            worker.listen(simulation, 0);
+            *status.borrow_mut() = Status::ShuttingDown;
+        }
+        let notified: Rc<RefCell<Vec<u64>>> = Default::default();
+        queue.borrow_mut().on_only_shutting_down = Some({
+            let notified = notified.clone();
+            Box::new(move |_, timestamp| {
+                notified.borrow_mut().push(timestamp);
+                vec![ProposedEvent::at(timestamp + 1)
+                    .named("replacement_worker")
+                    .handler(|_, _| Default::default())]
+            })
+        });
+
+        for timestamp in [5, 6] {
+            let (proposed_events, outcome) = queue.borrow_mut().try_enqueue(
+                simulation,
+                timestamp,
+                Default::default(),
+                |_, _, _| unreachable!("no worker should take the item"),
+            );
+            assert_eq!(outcome, EnqueueOutcome::Parked);
+            // only the first finds the shutting down workers, which it shuts down
+            let labels = Vec::from_iter(proposed_events.iter().map(|event| event.label));
+            let expected: &[Option<&str>] = match timestamp {
+                5 => &[Some("replacement_worker")],
+                _ => &[],
+            };
+            assert_eq!(labels, expected);
+        }
+
+        assert_eq!(*notified.borrow(), [5]);
+        let q = queue.borrow();
+        assert!(q.listening_workers.is_empty());
+        assert_eq!(q.deque.len(), 2);
+        assert_eq!(
+            get_counter_value(
+                simulation.get_pick_only_shutting_down_metric(),
+                &q.metric_labels
+            ),
+            Ok(1)
+        );
+        for id in 0..3 {
+            let up_labels = [("worker_id".to_owned(), id.to_string())];
+            assert_eq!(simulation.get_up_value(&up_labels), Ok(0));
+        }
+    }
+
+    #[test]
+    fn service_time_mixture_takes_precedence_in_proportion() {
+        let simulation = mk_simulation::<()>();
+        let fixed = |seconds: f32| LogNormal::from_mean_cv(seconds * 1000.0, 0.0).unwrap();
+        let queue = Rc::new(RefCell::new(Queue {
+            service_time: Some(fixed(100.0)),
+            service_time_mixture: Some(
+                DelayMixture::new([(0.9, fixed(1.0)), (0.1, fixed(10.0))]).unwrap(),
+            ),
+            ..Queue::new(simulation, "mixture")
+        }));
+        for id in 0..10 {
+            mk_worker(simulation, id, &queue).listen(simulation, 0);
+        }
+        let mut runner = SimulationRunner::new(simulation, {
+            let queue = queue.clone();
+            Box::new(move |_, _| {
+                Vec::from_iter((0..1000).map(|_| {
+                    ProposedEvent::at(1).named("arrival").handler(
+                        Queue::mk_serviced_enqueued_handler(queue.clone(), Default::default()),
+                    )
+                }))
+            })
+        });
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        flush_token_durations(simulation);
+
+        // every item took 1s or 10s rather than the queue's own 100s, a tenth of
+        // them 10s
+        let count = get_encoded_total(simulation, "worker_token_duration_count", "");
+        let sum = get_encoded_total(simulation, "worker_token_duration_sum", "");
+        assert_eq!(count, 1000.0);
+        let long_items = (sum - count) / 9.0;
+        assert!((long_items - 100.0).abs() < 30.0, "{long_items}");
+    }
+
+    #[test]
+    fn weighted_subscription_favours_its_queue_in_proportion() {
+        let simulation = mk_simulation::<()>();
+        let mk_queue = |name: &str| {
+            Rc::new(RefCell::new(Queue {
+                service_time: Some(LogNormal::from_mean_cv(1000.0, 0.0).unwrap()),
+                ..Queue::new(simulation, name)
+            }))
+        };
+        let (queue_a, queue_b) = (mk_queue("a"), mk_queue("b"));
+        let mut worker = mk_worker(simulation, 0, &queue_a);
+        worker.subscribed_queues.push(queue_b.clone());
+        worker.subscription_weights = HashMap::from([("a".to_owned(), 3.0)]);
+        // still serving when the runner is dropped
+        worker.allow_drop = true;
+        worker.listen(simulation, 0);
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(move |_, _| {
+                Vec::from_iter([queue_a, queue_b].into_iter().flat_map(|queue| {
+                    (0..400).map(move |_| {
+                        ProposedEvent::at(1).named("arrival").handler(
+                            Queue::mk_serviced_enqueued_handler(queue.clone(), Default::default()),
+                        )
+                    })
+                }))
+            }),
+        );
+        // 201 items checked out, the first on arrival and the rest picked while
+        // both queues are backlogged
+        assert_eq!(runner.run_until(200_500), EndReason::ReachedEndTime);
+
+        let served = |name: &str| {
+            get_encoded_total(
+                simulation,
+                "worker_tokens_checked_out_total",
+                &format!("originating_queue=\"{name}\""),
+            )
+        };
+        let (served_a, served_b) = (served("a"), served("b"));
+        assert_eq!(served_a + served_b, 201.0);
+        let ratio = served_a / served_b;
+        assert!((2.4..3.8).contains(&ratio), "{served_a} to {served_b}");
+    }
+
+    #[test]
+    fn pick_sequence_is_reproducible_across_processes() {
+        const CHILD_ENV: &str = "QUEUEING_PARTY_PICKS_CHILD";
+        const TEST_NAME: &str = "queue::tests::pick_sequence_is_reproducible_across_processes";
+
+        let simulation = mk_simulation::<()>();
+        let queue = mk_listening_queue(simulation, 16);
+        let picks = Vec::from_iter(
+            (0..50).map(|i| enqueue_and_restore(&queue, simulation, 2 * i, Default::default())),
+        );
+        if std::env::var_os(CHILD_ENV).is_some() {
+            println!("picks {picks:?}");
+            return;
+        }
+
+        // std's hashers are keyed per process, so only another process shows
+        // whether iteration order leaks into the picks
+        for _ in 0..2 {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", TEST_NAME, "--nocapture", "--test-threads", "1"])
+                .env(CHILD_ENV, "1")
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            assert!(stdout.contains(&format!("picks {picks:?}")), "{stdout}");
+        }
+    }
+
+    #[test]
+    fn pick_sequence_is_independent_of_construction_order() {
+        // the worker each of 20 items on queues "a" and "b" goes to, having built
+        // them in the given order with draws on the simulation's rng in between
+        fn get_picks(order: [&str; 2]) -> HashMap<&str, Vec<u64>> {
+            let simulation = mk_simulation::<()>();
+            let queues = HashMap::<_, _>::from_iter(order.map(|name| {
+                let _: u64 = simulation.borrow_rng_mut().gen();
+                let queue = Rc::new(RefCell::new(Queue::new(simulation, name)));
+                let first_id = if name == "a" { 0 } else { 8 };
+                for id in first_id..first_id + 8 {
+                    mk_worker(simulation, id, &queue).listen(simulation, 0);
+                }
+                (name, queue)
+            }));
+            HashMap::from_iter(queues.iter().map(|(name, queue)| {
+                let picks = (0..20)
+                    .map(|i| enqueue_and_restore(queue, simulation, 2 * i, Default::default()))
+                    .collect();
+                (*name, picks)
+            }))
+        }
+
+        let picks = get_picks(["a", "b"]);
+        assert_eq!(picks, get_picks(["b", "a"]));
+        // and the picks are actually random
+        assert!(picks["a"].iter().collect::<HashSet<_>>().len() > 1);
+    }
+
+    #[test]
+    fn token_accessors_describe_checkout() {
+        let simulation = mk_simulation::<()>();
+        let queue = Rc::new(RefCell::new(Queue::new(simulation, "accessors")));
+        // what the handler saw: worker id, queue name, checkout and current timestamps
+        type Seen = Option<(u64, String, u64, u64)>;
+        let seen: Rc<RefCell<Seen>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let seen = seen.clone();
+            Box::new(move |_, _| {
+                vec![
+                    // parked at 3, until a worker listens at 10
+                    ProposedEvent::at(3)
+                        .named("arrival")
+                        .handler(Queue::mk_enqueued_handler(
+                            queue.clone(),
+                            move |_, timestamp, token| {
+                                *seen.borrow_mut() = Some((
+                                    token.get_worker_id(),
+                                    token.get_originating_queue_name().to_owned(),
+                                    token.get_checkout_timestamp(),
+                                    timestamp,
+                                ));
+                                vec![ProposedEvent::after(2.0, 0.0).named("restore").handler(
+                                    WorkerToken::mk_token_restoring_handler(move |_, _| {
+                                        (Default::default(), vec![token])
+                                    }),
+                                )]
+                            },
+                        )),
+                    ProposedEvent::at(10)
+                        .named("listen")
+                        .handler(move |simulation, timestamp| {
+                            mk_worker(simulation, 7, &queue).listen(simulation, timestamp)
+                        }),
+                ]
+            })
+        });
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        assert_eq!(*seen.borrow(), Some((7, "accessors".to_owned(), 10, 10)));
+    }
+
+    #[test]
+    fn worker_ext_changes_persist_to_next_checkout() {
+        let simulation = mk_simulation::<TestExtension>();
+        let queue = mk_listening_queue(simulation, 1);
+
+        let mut processed = Vec::new();
+        for i in 0..3 {
+            let checked_out: Rc<RefCell<Option<WorkerToken<_>>>> = Default::default();
+            queue
+                .borrow_mut()
+                .try_enqueue(simulation, 2 * i, Default::default(), {
+                    let checked_out = checked_out.clone();
+                    move |_, _, mut token| {
+                        token.get_worker_ext_mut().processed += 1;
+                        *checked_out.borrow_mut() = Some(token);
+                        Default::default()
+                    }
+                });
+            let token = checked_out.take().unwrap();
+            processed.push(token.get_worker_ext().processed);
+            WorkerToken::mk_token_restoring_handler(move |_, _| (Default::default(), vec![token]))(
+                simulation,
+                2 * i + 1,
+            );
+        }
+        // the one worker's count carried over from each checkout to the next
+        assert_eq!(processed, [1, 2, 3]);
+    }
+
+    #[test]
+    fn same_tick_restore_observes_zero_duration() {
+        let simulation = mk_simulation::<()>();
+        let queue = mk_listening_queue(simulation, 1);
+
+        let checked_out: Rc<RefCell<Option<WorkerToken<_>>>> = Default::default();
+        queue
+            .borrow_mut()
+            .try_enqueue(simulation, 5, Default::default(), {
+                let checked_out = checked_out.clone();
+                move |_, _, token| {
+                    *checked_out.borrow_mut() = Some(token);
+                    Default::default()
+                }
+            });
+        let token = checked_out.take().unwrap();
+        WorkerToken::mk_token_restoring_handler(move |_, _| (Default::default(), vec![token]))(
+            simulation, 5,
+        );
+        flush_token_durations(simulation);
+
+        assert_eq!(
+            get_encoded_total(simulation, "worker_token_duration_count", ""),
+            1.0
+        );
+        assert_eq!(
+            get_encoded_total(simulation, "worker_token_duration_sum", ""),
+            0.0
+        );
+        // and the worker is back listening
+        assert_eq!(queue.borrow().listening_workers.len(), 1);
+    }
+
+    #[test]
+    fn batch_restore_picks_up_parked_work_fairly() {
+        let simulation = mk_simulation::<()>();
+        let queue = mk_listening_queue(simulation, 100);
+        let tokens: Rc<RefCell<Vec<WorkerToken<_>>>> = Default::default();
+        let mk_handler = || {
+            let tokens = tokens.clone();
+            move |_, _, token| {
+                tokens.borrow_mut().push(token);
+                Default::default()
+            }
+        };
+
+        // the first 100 items check out every worker, and the rest are parked
+        for _ in 0..1100 {
+            queue
+                .borrow_mut()
+                .try_enqueue(simulation, 0, Default::default(), mk_handler());
+        }
+        let restored = tokens.take();
+        let restore_order = Vec::from_iter(restored.iter().map(|token| token.get_worker_id()));
+
+        WorkerToken::mk_token_restoring_handler(move |_, _| (Default::default(), restored))(
+            simulation, 1,
+        );
+
+        // each worker picked up exactly one parked item, though not in the order
+        // they were restored
+        let pickup_order =
+            Vec::from_iter(tokens.borrow().iter().map(|token| token.get_worker_id()));
+        assert_eq!(pickup_order.len(), 100);
+        assert_eq!(
+            HashSet::<_>::from_iter(&pickup_order),
+            HashSet::from_iter(&restore_order)
+        );
+        assert_ne!(pickup_order, restore_order);
+        assert_eq!(queue.borrow().deque.len(), 900);
+        // the workers are still checked out
+        std::mem::forget(tokens.take());
+    }
+
+    #[test]
+    fn improperly_dropped_workers_count_against_their_own_simulation() {
+        let simulation = mk_simulation::<TestExtension>();
+        let other_simulation = mk_simulation::<TestExtension>();
+        let queue = Rc::new(RefCell::new(Queue::new(simulation, "test")));
+
+        set_worker_drop_mode(WorkerDropMode::LogAndCount);
+        drop(mk_worker(simulation, 0, &queue));
+        drop(mk_worker(simulation, 1, &queue));
+        set_worker_drop_mode(WorkerDropMode::Panic);
+
+        assert_eq!(simulation.get_workers_improperly_dropped_metric().get(), 2);
+        assert_eq!(
+            other_simulation
+                .get_workers_improperly_dropped_metric()
+                .get(),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Worker 0 was dropped without proper shutdown")]
+    fn improperly_dropped_worker_panics_by_default() {
+        let simulation = mk_simulation::<TestExtension>();
+        let queue = Rc::new(RefCell::new(Queue::new(simulation, "test")));
+        drop(mk_worker(simulation, 0, &queue));
+    }
+
+    // Poisson arrivals at 1/s to a single worker serving exponential service times
+    // at 2/s, so an M/M/1 queue with utilization 0.5
+    fn mm1_bootstrap<S: TopologySimulation>(
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let queue = Queue::new(simulation, "mm1").into_registered(simulation);
+        // counted from the start, rather than from the first item to be parked
+        queue.borrow_mut().depth_time.record(0, timestamp);
+
+        mk_worker(simulation, 0, &queue).listen(simulation, timestamp);
+
+        let rng = Rc::new(RefCell::new(mk_named_rng(simulation, "mm1")));
+        let first_arrival_ticks = sample_exp_ticks(simulation, &mut rng.borrow_mut(), 1.0);
+        vec![mk_markovian_arrival_event(
+            queue,
+            rng,
+            MarkovianArrivals {
+                arrivals_per_second: 1.0,
+                services_per_second: 2.0,
+                options: Default::default(),
+                remaining: 50_000,
+            },
+            timestamp + first_arrival_ticks,
+        )]
+    }
+
+    #[test]
+    fn mm1_depth_distribution_matches_theory() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let mut runner = SimulationRunner::new(simulation, Box::new(mm1_bootstrap));
+        let end_reason = runner.run();
+
+        let queue = simulation.borrow_subsystems_mut().get_queues().remove(0);
+        let queue = queue.borrow();
+        let distribution = queue
+            .depth_time
+            .get_distribution(queue.depth_time.since.unwrap())
+            .unwrap();
+        // the number waiting, excluding the one in service, is 0 with probability
+        // 1 - rho^2 and n > 0 with probability (1 - rho) rho^(n + 1)
+        let rho: f64 = 0.5;
+        for (depth, fraction) in distribution.iter().enumerate().take(4) {
+            let expected = if depth == 0 {
+                1.0 - rho.powi(2)
+            } else {
+                (1.0 - rho) * rho.powi(depth as i32 + 1)
+            };
+            assert!(
+                (fraction - expected).abs() < 0.02,
+                "depth {depth} for {fraction:.4} of the time, expected {expected:.4}",
+            );
+        }
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    const ERLANG_WORKERS: u64 = 3;
+    const ERLANG_OFFERED_LOAD: f64 = 2.0;
+    const ERLANG_ARRIVALS: u64 = 50_000;
+
+    // Poisson arrivals at 2/s to three workers serving exponential service times at
+    // 1/s, turned away when all three are busy, so an M/M/3/3 loss system
+    fn erlang_bootstrap<S: TopologySimulation>(
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let queue = Queue::new(simulation, "erlang").into_registered(simulation);
+
+        for id in 0..ERLANG_WORKERS {
+            mk_worker(simulation, id, &queue).listen(simulation, timestamp);
+        }
+
+        let rng = Rc::new(RefCell::new(mk_named_rng(simulation, "erlang")));
+        let first_arrival_ticks =
+            sample_exp_ticks(simulation, &mut rng.borrow_mut(), ERLANG_OFFERED_LOAD);
+        vec![mk_markovian_arrival_event(
+            queue,
+            rng,
+            MarkovianArrivals {
+                arrivals_per_second: ERLANG_OFFERED_LOAD,
+                services_per_second: 1.0,
+                options: EnqueueOptions {
+                    reject_if_busy: true,
+                    ..Default::default()
+                },
+                remaining: ERLANG_ARRIVALS,
+            },
+            timestamp + first_arrival_ticks,
+        )]
+    }
+
+    #[test]
+    fn loss_system_blocks_as_erlang_b() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let mut runner = SimulationRunner::new(simulation, Box::new(erlang_bootstrap));
+        let end_reason = runner.run();
+
+        let rejected = simulation
+            .get_enqueue_rejected_no_worker_metric()
+            .get_or_create(&vec![("queue_name".into(), "erlang".into())])
+            .get();
+        let blocking_probability = rejected as f64 / ERLANG_ARRIVALS as f64;
+        // Erlang B by its recurrence over the number of servers
+        let erlang_b = (1..=ERLANG_WORKERS).fold(1.0, |b, servers| {
+            ERLANG_OFFERED_LOAD * b / (servers as f64 + ERLANG_OFFERED_LOAD * b)
+        });
+        assert!((blocking_probability - erlang_b).abs() < 0.01);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    #[derive(Default)]
+    struct WarmUpExtension {
+        warm_up: WarmUp,
+    }
+
+    impl AffinityTracking for WarmUpExtension {}
+
+    type WarmUpSimulation = BaseQueueSimulation<WarmUpExtension>;
+
+    // 12 nominally 1s items parked for a worker that starts 3x slower, warming up
+    // over 10s, recording how long each took in ticks, in the order served
+    fn warm_up_bootstrap(
+        simulation: &'static WarmUpSimulation,
+        timestamp: u64,
+        service_ticks: Rc<RefCell<Vec<u64>>>,
+    ) -> Vec<ProposedEvent<WarmUpSimulation>> {
+        let queue = Queue::new(simulation, "warm_up").into_registered(simulation);
+        let nominal =
+            LogNormal::from_mean_cv(simulation.get_ticks_per_second() as f32, 0.0).unwrap();
+
+        let mut proposed_events = Vec::from_iter((0..12).map(|_| {
+            let service_ticks = service_ticks.clone();
+            ProposedEvent {
+                due_time: DueTime::At(timestamp),
+                handler: Box::new(Queue::mk_enqueued_handler(
+                    queue.clone(),
+                    move |simulation, start_timestamp, worker_token| {
+                        let service_time = worker_token
+                            .get_worker_ext()
+                            .warm_up
+                            .sample_service_time(simulation, &nominal, start_timestamp);
+                        vec![ProposedEvent {
+                            due_time: DueTime::After(service_time),
+                            handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                                move |_, timestamp| {
+                                    service_ticks.borrow_mut().push(timestamp - start_timestamp);
+                                    (Default::default(), vec![worker_token])
+                                },
+                            )),
+                            label: Some("warm_up_service_complete"),
+                            keepalive: true,
+                            priority: 0,
+                        }]
+                    },
+                )),
+                label: Some("warm_up_arrival"),
+                keepalive: true,
+                priority: 0,
+            }
+        }));
+
+        // started once the items are parked, so it serves them back to back
+        let mut worker = mk_worker(simulation, 0, &queue);
+        worker.ext.warm_up = WarmUp {
+            started_at: timestamp + 1,
+            period_seconds: 10.0,
+            initial_factor: 3.0,
+        };
+        proposed_events.push(ProposedEvent {
+            due_time: DueTime::At(timestamp + 1),
+            handler: Box::new(move |simulation, timestamp| worker.listen(simulation, timestamp)),
+            label: Some("warm_up_worker_start"),
+            keepalive: true,
+            priority: 0,
+        });
+        proposed_events
+    }
+
+    #[test]
+    fn warming_worker_speeds_up_to_nominal() {
+        let simulation: &'static WarmUpSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let service_ticks: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let service_ticks = service_ticks.clone();
+            Box::new(move |simulation, timestamp| {
+                warm_up_bootstrap(simulation, timestamp, service_ticks)
+            })
+        });
+        let end_reason = runner.run();
+
+        let service_ticks = service_ticks.borrow();
+        // 3s for the first, then each shorter than the last until 10s have passed,
+        // after which every item takes the nominal 1s
+        let ticks = |seconds: f64| (seconds * simulation.get_ticks_per_second()) as u64;
+        assert_eq!(service_ticks.len(), 12);
+        assert!(service_ticks[0].abs_diff(ticks(3.0)) <= 1);
+        let warming = service_ticks
+            .iter()
+            .take_while(|t| t.abs_diff(ticks(1.0)) > 1);
+        assert!(warming.clone().count() >= 3);
+        assert!(warming.clone().zip(warming.skip(1)).all(|(a, b)| a > b));
+        assert!(service_ticks[8..]
+            .iter()
+            .all(|t| t.abs_diff(ticks(1.0)) <= 1));
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    const POOLS_ITEMS: u64 = 300;
+
+    // two reserved workers taking 1s per item and two spot workers taking 0.5s,
+    // sharing one queue of items that all arrive at once
+    fn pools_bootstrap<S: TopologySimulation>(
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let queue = Queue::new(simulation, "shared").into_registered(simulation);
+
+        let next_worker_id: Rc<Cell<u64>> = Default::default();
+        for pool in ["reserved", "spot"] {
+            let pool_manager = mk_worker_pool_manager(
+                simulation,
+                timestamp,
+                queue.clone(),
+                pool,
+                next_worker_id.clone(),
+            )
+            .into_registered(simulation);
+            // the workers are kept by the queue once listening
+            pool_manager.borrow_mut().set_desired_instances_absolute(2);
+        }
+
+        Vec::from_iter((0..POOLS_ITEMS).map(|_| ProposedEvent {
+            due_time: DueTime::At(timestamp),
+            handler: Box::new(Queue::mk_enqueued_handler(
+                queue.clone(),
+                |_, _, worker_token| {
+                    let service_seconds = match worker_token.get_worker_pool() {
+                        Some("spot") => 0.5,
+                        _ => 1.0,
+                    };
+                    vec![ProposedEvent {
+                        due_time: DueTime::After(
+                            LogNormal::from_mean_cv(
+                                (service_seconds * simulation.get_ticks_per_second()) as f32,
+                                0.0,
+                            )
+                            .unwrap(),
+                        ),
+                        handler: Box::new(WorkerToken::mk_token_restoring_handler(move |_, _| {
+                            (Default::default(), vec![worker_token])
+                        })),
+                        label: Some("pools_service_complete"),
+                        keepalive: true,
+                        priority: 0,
+                    }]
+                },
+            )),
+            label: Some("pools_arrival"),
+            keepalive: true,
+            priority: 0,
+        }))
+    }
+
+    #[test]
+    fn pools_share_a_queue_by_service_rate() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let mut runner = SimulationRunner::new(simulation, Box::new(pools_bootstrap));
+        let end_reason = runner.run();
+
+        let reserved = simulation
+            .get_checked_out_count_by_pool("reserved")
+            .unwrap();
+        let spot = simulation.get_checked_out_count_by_pool("spot").unwrap();
+        // busy throughout, the spot workers serve twice as many
+        assert_eq!(reserved + spot, POOLS_ITEMS);
+        assert!(spot.abs_diff(2 * reserved) <= 4);
+        let busy_samples = extract_samples(&simulation.borrow_metric_registry()).unwrap();
+        assert!(busy_samples
+            .iter()
+            .any(|sample| sample.name == "workers_busy"
+                && sample
+                    .labels
+                    .contains(&("pool".to_owned(), "spot".to_owned()))));
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    const STALL_ITEMS: usize = 3;
+
+    // a single worker serving items that each take 20s, its pool scaled to zero while
+    // it's busy with the first so the others are left parked. A check every 30s
+    // finds the queue stalled and asks the pool for a worker again.
+    fn mk_stall_bootstrap<S: TopologySimulation>(
+        stalls: Rc<RefCell<Vec<(u64, i64)>>>,
+        served: Rc<Cell<usize>>,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let queue = Queue::new(simulation, "stall").into_registered(simulation);
+
+            let starting: Rc<RefCell<Vec<Worker<S>>>> = Default::default();
+            let pool_manager = PoolManager {
+                name: "stall".into(),
+                instance_constructor: {
+                    let queue = queue.clone();
+                    let starting = starting.clone();
+                    let next_worker_id = Cell::new(0);
+                    Box::new(move || {
+                        let id = next_worker_id.replace(next_worker_id.get() + 1);
+                        let worker = mk_worker(simulation, id, &queue);
+                        let status = worker.status.clone();
+                        starting.borrow_mut().push(worker);
+                        Box::new(move || {
+                            *status.borrow_mut() = Status::ShuttingDown;
+                        })
+                    })
+                },
+                instances: Default::default(),
+                metric_labels: vec![("pool_manager_name".into(), "stall".into())],
+                ramp: None,
+                launch_failures: None,
+                peak_instances: 0,
+            }
+            .into_registered(simulation);
+            pool_manager.borrow_mut().set_desired_instances_absolute(1);
+
+            queue.borrow_mut().stall_check.on_stalled = Some({
+                let pool_manager = pool_manager.clone();
+                let metric_labels = queue.borrow().metric_labels.clone();
+                Box::new(move |simulation, timestamp| {
+                    // the gauge is set before the callback runs
+                    let stalled =
+                        get_gauge_value(simulation.get_queue_stalled_metric(), &metric_labels)
+                            .unwrap();
+                    stalls.borrow_mut().push((timestamp, stalled));
+                    pool_manager
+                        .borrow_mut()
+                        .set_desired_instances_delta(1)
+                        .unwrap();
+                    Default::default()
+                })
+            });
+            let start_instances = {
+                let starting = starting.clone();
+                move |simulation, timestamp| {
+                    Vec::from_iter(
+                        starting
+                            .take()
+                            .into_iter()
+                            .flat_map(|worker: Worker<S>| worker.listen(simulation, timestamp)),
+                    )
+                }
+            };
+            let period =
+                LogNormal::from_mean_cv((30.0 * simulation.get_ticks_per_second()) as f32, 0.0)
+                    .unwrap();
+            let mut proposed_events = Vec::from_iter(mk_singleton_event(
+                simulation,
+                "stall_check",
+                period,
+                period,
+                {
+                    let queue = queue.clone();
+                    let start_instances = start_instances.clone();
+                    move |simulation, timestamp| {
+                        let mut proposed_events =
+                            Queue::check_stalled(&queue, simulation, timestamp);
+                        proposed_events.extend(start_instances(simulation, timestamp));
+                        proposed_events
+                    }
+                },
+            ));
+            proposed_events.extend(start_instances(simulation, timestamp));
+
+            proposed_events.push(
+                ProposedEvent::after_seconds(simulation, 10.0, 0.0)
+                    .named("stall_scale_in")
+                    .handler(move |_, _| {
+                        pool_manager.borrow_mut().set_desired_instances_absolute(0);
+                        Default::default()
+                    }),
+            );
+            proposed_events.extend((0..STALL_ITEMS).map(|_| {
+                ProposedEvent::after_seconds(simulation, 1.0, 0.0)
+                    .named("stall_arrival")
+                    .handler(Queue::mk_enqueued_handler(queue.clone(), {
+                        let served = served.clone();
+                        |_, _, worker_token| {
+                            vec![ProposedEvent::after_seconds(simulation, 20.0, 0.0)
+                                .named("stall_service_complete")
+                                .handler(WorkerToken::mk_token_restoring_handler(move |_, _| {
+                                    served.set(served.get() + 1);
+                                    (Default::default(), vec![worker_token])
+                                }))]
+                        }
+                    }))
+            }));
+            proposed_events
+        }
+    }
+
+    #[test]
+    fn stalled_queue_asks_pool_for_a_worker() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let stalls: Rc<RefCell<Vec<(u64, i64)>>> = Default::default();
+        let served: Rc<Cell<usize>> = Default::default();
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(mk_stall_bootstrap(stalls.clone(), served.clone())),
+        );
+        // parked items don't keep the run alive, so end once they've all been served
+        runner.set_stop_predicate({
+            let served = served.clone();
+            move |_, _| served.get() == STALL_ITEMS
+        });
+        let end_reason = runner.run();
+
+        let stalls = stalls.take();
+        // the first item is taken before the 30s check, and the worker shuts down once
+        // it's served at 21s, so the 60s check is the first to see nothing dequeued.
+        // The worker started then takes the next item, so the 90s check clears the
+        // gauge again.
+        assert_eq!(stalls.len(), 1);
+        let (timestamp, stalled) = stalls[0];
+        assert!(timestamp.abs_diff(60_000) <= 20);
+        assert_eq!(stalled, 1);
+        let queue_labels = vec![("queue_name".into(), "stall".into())];
+        assert_eq!(
+            get_gauge_value(simulation.get_queue_stalled_metric(), &queue_labels).unwrap(),
+            0
+        );
+        assert_eq!(served.get(), STALL_ITEMS);
+        assert_eq!(end_reason, EndReason::StopPredicateMet);
+    }
+
+    const ITEM_SIZE_ITEMS: usize = 12;
+
+    // small and large (4x) items alternating on one queue, each holding a worker for
+    // about 2s per unit of size and then needing about 1s per unit of a downstream
+    // service with room for every item at once, so neither stage contends
+    fn mk_item_size_bootstrap<S: TopologySimulation + SRRSimulation>(
+        served: Rc<RefCell<Vec<(f64, u64, u64)>>>,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let queue = Queue::new(simulation, "item_size").into_registered(simulation);
+            let resource = Rc::new(RefCell::new(
+                SharedRateResource::<S>::new(
+                    simulation,
+                    5,
+                    ITEM_SIZE_ITEMS as u8,
+                    mk_named_rng(simulation, "shared_rate_resource/item_size"),
+                )
+                .unwrap(),
+            ));
+
+            let mut proposed_events = Vec::new();
+            for id in 0..2 {
+                proposed_events
+                    .extend(mk_worker(simulation, id, &queue).listen(simulation, timestamp));
+            }
+            let service_time =
+                delay_from_mean_cv((2.0 * simulation.get_ticks_per_second()) as f32, 0.2);
+            let resource_time = delay_from_mean_cv(simulation.get_ticks_per_second() as f32, 0.2);
+            proposed_events.extend((0..ITEM_SIZE_ITEMS).map(|n| {
+                let served = served.clone();
+                let resource = resource.clone();
+                let options = EnqueueOptions {
+                    ..Default::default()
+                }
+                .with_size(if n % 2 == 0 { 1.0 } else { 4.0 })
+                .unwrap();
+                ProposedEvent::at(timestamp)
+                    .named("item_size_arrival")
+                    .handler(Queue::mk_enqueued_handler_with_options(
+                        queue.clone(),
+                        options,
+                        move |simulation, dispatch_timestamp, worker_token| {
+                            let context = worker_token.get_item_context();
+                            vec![ProposedEvent {
+                                due_time: DueTime::After(sample_sized_service_time(
+                                    simulation,
+                                    &service_time,
+                                    &context,
+                                )),
+                                handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                                    move |_, timestamp| {
+                                        let proposed_events =
+                                            SharedRateResource::mk_sized_shared_rate_event(
+                                                simulation,
+                                                resource,
+                                                timestamp,
+                                                resource_time,
+                                                &context,
+                                                move |_, completion_timestamp| {
+                                                    served.borrow_mut().push((
+                                                        context.get_size(),
+                                                        timestamp - dispatch_timestamp,
+                                                        completion_timestamp - timestamp,
+                                                    ));
+                                                    Default::default()
+                                                },
+                                            )
+                                            .unwrap();
+                                        (proposed_events, vec![worker_token])
+                                    },
+                                )),
+                                label: Some("item_size_service_complete"),
+                                keepalive: true,
+                                priority: 0,
+                            }]
+                        },
+                    ))
+            }));
+            proposed_events
+        }
+    }
+
+    #[test]
+    fn larger_items_take_longer_at_worker_and_resource() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let served: Rc<RefCell<Vec<(f64, u64, u64)>>> = Default::default();
+        let mut runner =
+            SimulationRunner::new(simulation, Box::new(mk_item_size_bootstrap(served.clone())));
+        let end_reason = runner.run();
+
+        let served = served.take();
+        assert_eq!(served.len(), ITEM_SIZE_ITEMS);
+        let (small, large): (Vec<_>, Vec<_>) = served.iter().partition(|(size, _, _)| *size == 1.0);
+        // every large item took longer than every small one at both the worker and the
+        // resource, and about 4x as long on average
+        for stage in [
+            |item: &(f64, u64, u64)| item.1,
+            |item: &(f64, u64, u64)| item.2,
+        ] {
+            let longest_small = small.iter().map(stage).max().unwrap();
+            let shortest_large = large.iter().map(stage).min().unwrap();
+            assert!(shortest_large > longest_small);
+            let mean = |items: &[(f64, u64, u64)]| {
+                items.iter().map(stage).sum::<u64>() as f64 / items.len() as f64
+            };
+            let ratio = mean(&large) / mean(&small);
+            assert!((3.0..5.0).contains(&ratio), "{ratio}");
+        }
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
     }
 }
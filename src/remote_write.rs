@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::cmp::min;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::args_rets::*;
+use crate::metric_samples::*;
+use crate::simulation::*;
+
+/// Pushes a snapshot of the simulation's metrics to a Prometheus remote-write
+/// endpoint each time it collects. Only plain http endpoints are supported.
+/// Collecting encodes the snapshot on the simulation's thread and hands it to a
+/// sender thread, started on the first collection, which does the slow part, so
+/// an unresponsive endpoint never holds up the simulation. Snapshots the sender
+/// can't keep up with are dropped.
+pub struct RemoteWriteCollector {
+    pub url: String,
+    // wall-clock time, in milliseconds since the epoch, that simulated time 0 is
+    // reported at
+    pub base_timestamp_ms: i64,
+    pub timeout: Duration,
+    sender: Option<SyncSender<Vec<u8>>>,
+}
+
+impl RemoteWriteCollector {
+    const MAX_PENDING_REQUESTS: usize = 16;
+
+    pub fn new(url: impl Into<String>) -> Self {
+        RemoteWriteCollector {
+            url: url.into(),
+            base_timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64,
+            timeout: Duration::from_secs(5),
+            sender: None,
+        }
+    }
+
+    pub fn collect<S: Simulation>(&mut self, simulation: &S, timestamp: u64) {
+        let timestamp_ms = self.base_timestamp_ms
+            + (timestamp as f64 * 1000.0 / simulation.get_ticks_per_second()) as i64;
+        let samples = match extract_samples(&simulation.borrow_metric_registry()) {
+            Ok(samples) => samples,
+            Err(e) => {
+                log::warn!("skipping the remote write collection @ {timestamp}: {e}");
+                return;
+            }
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&encode_write_request(&samples, timestamp_ms))
+            .unwrap();
+
+        let sender = self.sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::sync_channel(Self::MAX_PENDING_REQUESTS);
+            let url = self.url.clone();
+            let timeout = self.timeout;
+            // lives until the collector, and with it the sending half, is dropped
+            thread::Builder::new()
+                .name("remote-write".into())
+                .spawn(move || send_write_requests(url, timeout, receiver))
+                .unwrap();
+            sender
+        });
+        match sender.try_send(body) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!(
+                    "remote write to {} is behind, dropping the collection @ {timestamp}",
+                    self.url
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::warn!("remote write sender for {} has stopped", self.url);
+            }
+        }
+    }
+}
+
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+// posts each encoded request in turn, on the sender thread. While backing off
+// from a failing endpoint, requests are dropped rather than retried, so that
+// the ones sent once it recovers are current.
+fn send_write_requests(url: String, timeout: Duration, receiver: Receiver<Vec<u8>>) {
+    let mut consecutive_failures = 0;
+    let mut backoff_until: Option<Instant> = None;
+    for body in receiver {
+        if backoff_until.is_some_and(|until| Instant::now() < until) {
+            continue;
+        }
+
+        let result = ureq::post(&url)
+            .timeout(timeout)
+            .set("Content-Encoding", "snappy")
+            .set("Content-Type", "application/x-protobuf")
+            .set("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .send_bytes(&body);
+        match result {
+            Ok(_) => {
+                consecutive_failures = 0;
+                backoff_until = None;
+            }
+            Err(err) => {
+                consecutive_failures += 1;
+                let backoff =
+                    Duration::from_secs(1 << min(consecutive_failures, MAX_BACKOFF_EXPONENT));
+                log::warn!("remote write to {url} failed, backing off {backoff:?}: {err}");
+                backoff_until = Some(Instant::now() + backoff);
+            }
+        }
+    }
+}
+
+/// A handler for `mk_singleton_event` collecting to `collector` every period
+pub fn mk_remote_write_handler<S: Simulation + 'static>(
+    collector: RemoteWriteCollector,
+) -> impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> {
+    let collector = RefCell::new(collector);
+    move |simulation, timestamp| {
+        collector.borrow_mut().collect(simulation, timestamp);
+        Default::default()
+    }
+}
+
+// the WriteRequest protobuf message of the remote-write protocol, with every
+// sample as its own series
+fn encode_write_request(samples: &[MetricSample], timestamp_ms: i64) -> Vec<u8> {
+    let mut write_request = Vec::new();
+    for sample in samples {
+        let mut labels = Vec::from_iter(
+            [("__name__", sample.name.as_str())]
+                .into_iter()
+                .chain(sample.labels.iter().map(|(n, v)| (n.as_str(), v.as_str()))),
+        );
+        labels.sort();
+
+        let mut time_series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_length_delimited(&mut label, 1, name.as_bytes());
+            put_length_delimited(&mut label, 2, value.as_bytes());
+            put_length_delimited(&mut time_series, 1, &label);
+        }
+
+        let mut encoded_sample = Vec::new();
+        // field 1, 64-bit
+        encoded_sample.push(0x09);
+        encoded_sample.extend_from_slice(&sample.value.to_le_bytes());
+        // field 2, varint
+        encoded_sample.push(0x10);
+        put_varint(&mut encoded_sample, timestamp_ms as u64);
+        put_length_delimited(&mut time_series, 2, &encoded_sample);
+
+        put_length_delimited(&mut write_request, 1, &time_series);
+    }
+    write_request
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_length_delimited(buf: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    buf.push(field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    // the fields of one protobuf message, as (field number, bytes) for length
+    // delimited ones and (field number, raw little endian bytes) for 64-bit ones
+    fn decode_fields(mut buf: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = get_varint(&mut buf);
+            let value = match key & 7 {
+                0 => get_varint(&mut buf).to_le_bytes().to_vec(),
+                1 => {
+                    let (value, rest) = buf.split_at(8);
+                    buf = rest;
+                    value.to_vec()
+                }
+                2 => {
+                    let len = get_varint(&mut buf) as usize;
+                    let (value, rest) = buf.split_at(len);
+                    buf = rest;
+                    value.to_vec()
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    #[test]
+    fn write_request_decodes_to_samples() {
+        let samples = [
+            MetricSample {
+                name: "events_dispatched_total".into(),
+                labels: vec![("simulation_id".into(), "01".into())],
+                value: 42.0,
+            },
+            MetricSample {
+                name: "queue_depth".into(),
+                labels: vec![("queue_name".into(), "foo".into())],
+                value: 0.5,
+            },
+        ];
+        let timestamp_ms = 1_700_000_000_123;
+
+        let time_series = decode_fields(&encode_write_request(&samples, timestamp_ms));
+        assert_eq!(time_series.len(), 2);
+        for ((field, time_series), sample) in time_series.iter().zip(&samples) {
+            assert_eq!(*field, 1);
+            let fields = decode_fields(time_series);
+            let labels = Vec::from_iter(fields.iter().filter(|(field, _)| *field == 1).map(
+                |(_, label)| {
+                    let label = decode_fields(label);
+                    (
+                        String::from_utf8(label[0].1.clone()).unwrap(),
+                        String::from_utf8(label[1].1.clone()).unwrap(),
+                    )
+                },
+            ));
+            // sorted by name, which puts __name__ first
+            assert_eq!(
+                labels,
+                [
+                    ("__name__".to_owned(), sample.name.clone()),
+                    sample.labels[0].clone()
+                ]
+            );
+
+            let encoded_samples = Vec::from_iter(fields.iter().filter(|(field, _)| *field == 2));
+            assert_eq!(encoded_samples.len(), 1);
+            let encoded_sample = decode_fields(&encoded_samples[0].1);
+            assert_eq!(
+                f64::from_le_bytes(encoded_sample[0].1.clone().try_into().unwrap()),
+                sample.value
+            );
+            assert_eq!(
+                u64::from_le_bytes(encoded_sample[1].1.clone().try_into().unwrap()),
+                timestamp_ms as u64
+            );
+        }
+    }
+}
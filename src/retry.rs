@@ -0,0 +1,382 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use rand_distr::LogNormal;
+
+use crate::args_rets::*;
+use crate::queue::*;
+use crate::simulation::*;
+
+/// How an item enqueued through a `RetryRoute` ended up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryOutcome {
+    Succeeded { attempts: u32 },
+    // its final attempt failed, or a queue rejected it
+    DeadLettered { attempts: u32 },
+}
+
+/// Whether an attempt fails, given the name of the queue it was served from and
+/// its attempt number, counting the first as 1
+pub type AttemptFailurePredicate<S> = Box<dyn FnMut(&'static S, u64, &str, u32) -> bool>;
+
+pub type RetryOutcomeHandler<S> =
+    Box<dyn FnMut(&'static S, u64, RetryOutcome) -> Vec<ProposedEvent<S>>>;
+
+/// A dead-letter/retry pattern over two queues, typically each with its own pool:
+/// an item's first attempt is enqueued on `main_queue`, and a failed attempt is
+/// re-enqueued on `retry_queue` after a backoff, until `max_attempts` have failed
+/// and the item is dead-lettered. Each attempt holds its worker for a service time
+/// drawn from the queue it was enqueued on. A bounded retry queue that rejects an
+/// item dead-letters it straight away.
+pub struct RetryRoute<S: QueueSimulation + 'static> {
+    pub main_queue: Rc<RefCell<Queue<S>>>,
+    pub retry_queue: Rc<RefCell<Queue<S>>>,
+    // including the first attempt on main_queue
+    pub max_attempts: u32,
+    pub backoff: LogNormal<f32>,
+    pub attempt_fails: AttemptFailurePredicate<S>,
+    // called once per item, without the route borrowed, so it may enqueue more
+    pub on_outcome: Option<RetryOutcomeHandler<S>>,
+    // Some while on_outcome is being called, holding the outcomes of items it
+    // enqueued that were rejected straight away, to be delivered once it returns
+    pub pending_outcomes: Option<VecDeque<RetryOutcome>>,
+}
+
+impl<S: QueueSimulation + 'static> RetryRoute<S> {
+    /// Enqueue a new item's first attempt on the main queue
+    pub fn mk_enqueued_handler(
+        route: Rc<RefCell<RetryRoute<S>>>,
+        options: EnqueueOptions,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let main_queue = route.borrow().main_queue.clone();
+            Self::enqueue_attempt(route, main_queue, options, 1, simulation, timestamp)
+        }
+    }
+
+    fn enqueue_attempt(
+        route: Rc<RefCell<RetryRoute<S>>>,
+        queue: Rc<RefCell<Queue<S>>>,
+        options: EnqueueOptions,
+        attempt: u32,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let (service_time, metric_labels) = {
+            let mut q = queue.borrow_mut();
+            let service_time = q
+                .choose_service_time()
+                .unwrap_or_else(|| panic!("Queue {} has no service_time", q.name));
+            (service_time, q.metric_labels.clone())
+        };
+
+        let (mut proposed_events, outcome) =
+            queue
+                .borrow_mut()
+                .try_enqueue(simulation, timestamp, options.clone(), {
+                    let route = route.clone();
+                    move |simulation, _, worker_token| {
+                        vec![ProposedEvent {
+                            due_time: DueTime::After(sample_service_time(
+                                simulation,
+                                &service_time,
+                            )),
+                            handler: Box::new(WorkerToken::mk_token_restoring_handler(
+                                move |simulation, timestamp| {
+                                    let queue_name = worker_token.get_originating_queue_name();
+                                    let failed = (route.borrow_mut().attempt_fails)(
+                                        simulation, timestamp, queue_name, attempt,
+                                    );
+                                    let proposed_events = Self::complete_attempt(
+                                        route,
+                                        options,
+                                        attempt,
+                                        failed,
+                                        &metric_labels,
+                                        simulation,
+                                        timestamp,
+                                    );
+                                    (proposed_events, vec![worker_token])
+                                },
+                            )),
+                            label: Some("retry_route_attempt_complete"),
+                            keepalive: true,
+                            priority: 0,
+                        }]
+                    }
+                });
+
+        if outcome == EnqueueOutcome::Rejected {
+            simulation
+                .get_items_dead_lettered_metric()
+                .get_or_create(&queue.borrow().metric_labels)
+                .inc();
+            proposed_events.append(&mut Self::call_on_outcome(
+                &route,
+                RetryOutcome::DeadLettered { attempts: attempt },
+                simulation,
+                timestamp,
+            ));
+        }
+        proposed_events
+    }
+
+    fn complete_attempt(
+        route: Rc<RefCell<RetryRoute<S>>>,
+        options: EnqueueOptions,
+        attempt: u32,
+        failed: bool,
+        metric_labels: &[(String, String)],
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        if !failed {
+            return Self::call_on_outcome(
+                &route,
+                RetryOutcome::Succeeded { attempts: attempt },
+                simulation,
+                timestamp,
+            );
+        }
+
+        let (retry_queue, max_attempts, backoff) = {
+            let r = route.borrow();
+            (r.retry_queue.clone(), r.max_attempts, r.backoff)
+        };
+        if attempt >= max_attempts {
+            simulation
+                .get_items_dead_lettered_metric()
+                .get_or_create(&metric_labels.to_vec())
+                .inc();
+            return Self::call_on_outcome(
+                &route,
+                RetryOutcome::DeadLettered { attempts: attempt },
+                simulation,
+                timestamp,
+            );
+        }
+
+        simulation
+            .get_item_retries_metric()
+            .get_or_create(&retry_queue.borrow().metric_labels)
+            .inc();
+        vec![ProposedEvent {
+            due_time: DueTime::After(backoff),
+            handler: Box::new(move |simulation, timestamp| {
+                Self::enqueue_attempt(
+                    route,
+                    retry_queue,
+                    options,
+                    attempt + 1,
+                    simulation,
+                    timestamp,
+                )
+            }),
+            label: Some("retry_route_backoff"),
+            keepalive: true,
+            priority: 0,
+        }]
+    }
+
+    fn call_on_outcome(
+        route: &Rc<RefCell<RetryRoute<S>>>,
+        outcome: RetryOutcome,
+        simulation: &'static S,
+        timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let mut on_outcome = {
+            let mut r = route.borrow_mut();
+            if let Some(pending_outcomes) = &mut r.pending_outcomes {
+                pending_outcomes.push_back(outcome);
+                return Default::default();
+            }
+            let Some(on_outcome) = r.on_outcome.take() else {
+                return Default::default();
+            };
+            r.pending_outcomes = Some(VecDeque::from([outcome]));
+            on_outcome
+        };
+
+        let mut proposed_events = Vec::new();
+        loop {
+            let outcome = route
+                .borrow_mut()
+                .pending_outcomes
+                .as_mut()
+                .unwrap()
+                .pop_front();
+            let Some(outcome) = outcome else {
+                break;
+            };
+            proposed_events.append(&mut on_outcome(simulation, timestamp, outcome));
+        }
+        let mut r = route.borrow_mut();
+        r.pending_outcomes = None;
+        r.on_outcome = Some(on_outcome);
+        proposed_events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::main_loop::*;
+    use crate::metric_samples::*;
+    use crate::testing::*;
+    use crate::topology::*;
+
+    #[test]
+    fn outcome_of_an_item_enqueued_by_on_outcome_is_still_delivered() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let main_queue = Rc::new(RefCell::new(Queue {
+            service_time: Some(LogNormal::from_mean_cv(1000.0, 0.0).unwrap()),
+            closed: true,
+            ..Queue::new(simulation, "main")
+        }));
+        let route = Rc::new(RefCell::new(RetryRoute {
+            main_queue: main_queue.clone(),
+            retry_queue: main_queue,
+            max_attempts: 1,
+            backoff: LogNormal::from_mean_cv(1000.0, 0.0).unwrap(),
+            attempt_fails: Box::new(|_, _, _, _| unreachable!("nothing is dispatched")),
+            on_outcome: None,
+            pending_outcomes: None,
+        }));
+        let outcomes: Rc<RefCell<Vec<RetryOutcome>>> = Default::default();
+        route.borrow_mut().on_outcome = Some(Box::new({
+            let route = route.clone();
+            let outcomes = outcomes.clone();
+            move |simulation, timestamp, outcome| {
+                outcomes.borrow_mut().push(outcome);
+                // the first outcome enqueues another item, rejected in turn
+                if outcomes.borrow().len() > 1 {
+                    return Default::default();
+                }
+                RetryRoute::mk_enqueued_handler(route.clone(), Default::default())(
+                    simulation, timestamp,
+                )
+            }
+        }));
+
+        let proposed_events =
+            RetryRoute::mk_enqueued_handler(route.clone(), Default::default())(simulation, 0);
+        assert!(proposed_events.is_empty());
+        assert_eq!(
+            *outcomes.borrow(),
+            [RetryOutcome::DeadLettered { attempts: 1 }; 2]
+        );
+        assert!(route.borrow().on_outcome.is_some());
+        assert!(route.borrow().pending_outcomes.is_none());
+    }
+
+    // 40 items a second apart through a main queue, collecting each one's outcome
+    fn retry_bootstrap<S: TopologySimulation>(
+        simulation: &'static S,
+        timestamp: u64,
+        outcomes: Rc<RefCell<Vec<RetryOutcome>>>,
+    ) -> Vec<ProposedEvent<S>> {
+        let mk_queue = |name: &str, service_seconds: f64| {
+            Queue {
+                service_time: Some(
+                    LogNormal::from_mean_cv(
+                        (service_seconds * simulation.get_ticks_per_second()) as f32,
+                        0.5,
+                    )
+                    .unwrap(),
+                ),
+                ..Queue::new(simulation, name)
+            }
+            .into_registered(simulation)
+        };
+        let main_queue = mk_queue("main", 1.0);
+        let retry_queue = mk_queue("retry", 2.0);
+
+        // two workers serve the main queue, and a single one the retry queue
+        for (id, queue) in [(0, &main_queue), (1, &main_queue), (2, &retry_queue)] {
+            mk_worker(simulation, id, queue).listen(simulation, timestamp);
+        }
+
+        // half of first attempts fail, then a third of retries
+        let mut failure_rng = mk_named_rng(simulation, "retry/failures");
+        let route = Rc::new(RefCell::new(RetryRoute {
+            main_queue,
+            retry_queue,
+            max_attempts: 3,
+            backoff: LogNormal::from_mean_cv((5.0 * simulation.get_ticks_per_second()) as f32, 0.5)
+                .unwrap(),
+            attempt_fails: Box::new(move |_, _, queue_name, _| {
+                failure_rng.gen_bool(if queue_name == "main" { 0.5 } else { 1.0 / 3.0 })
+            }),
+            on_outcome: Some(Box::new(move |_, _, outcome| {
+                outcomes.borrow_mut().push(outcome);
+                Default::default()
+            })),
+            pending_outcomes: None,
+        }));
+
+        Vec::from_iter((0..40).map(|i| {
+            ProposedEvent::after_seconds(simulation, (1 + i) as f64, 0.0)
+                .named("retry_arrival")
+                .handler(RetryRoute::mk_enqueued_handler(
+                    route.clone(),
+                    Default::default(),
+                ))
+        }))
+    }
+
+    #[test]
+    fn failed_attempts_are_retried_until_dead_lettered() {
+        // seeded so that at least one item fails all three attempts
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1236)));
+        let outcomes: Rc<RefCell<Vec<RetryOutcome>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let outcomes = outcomes.clone();
+            Box::new(move |simulation, timestamp| retry_bootstrap(simulation, timestamp, outcomes))
+        });
+        let end_reason = runner.run();
+
+        let outcomes = outcomes.borrow();
+        assert_eq!(outcomes.len(), 40);
+        assert!(outcomes
+            .iter()
+            .any(|o| matches!(o, RetryOutcome::Succeeded { attempts } if *attempts > 1)));
+        assert!(outcomes
+            .iter()
+            .any(|o| matches!(o, RetryOutcome::DeadLettered { attempts: 3 })));
+
+        // every retry went through the retry queue's own pool
+        let retries: u64 = outcomes
+            .iter()
+            .map(|o| match o {
+                RetryOutcome::Succeeded { attempts } | RetryOutcome::DeadLettered { attempts } => {
+                    (attempts - 1) as u64
+                }
+            })
+            .sum();
+        let retry_queue_labels = [("queue_name".to_owned(), "retry".to_owned())];
+        assert_eq!(
+            get_counter_value(simulation.get_item_retries_metric(), &retry_queue_labels).unwrap(),
+            retries
+        );
+        let checked_out_count = |worker_id: u64, queue_name: &str| {
+            simulation
+                .get_checked_out_count(&[
+                    ("worker_id".into(), worker_id.to_string()),
+                    ("originating_queue".into(), queue_name.into()),
+                ])
+                .unwrap()
+        };
+        assert_eq!(
+            checked_out_count(0, "main") + checked_out_count(1, "main"),
+            40
+        );
+        assert_eq!(checked_out_count(2, "retry"), retries);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+}
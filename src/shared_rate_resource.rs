@@ -1,32 +1,48 @@
 use std::cell::RefCell;
 use std::cmp::{max, Eq, Ordering, PartialEq};
 use std::collections::BinaryHeap;
-use std::collections::HashMap;
-use std::collections::HashSet;
 use std::collections::VecDeque;
-use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::Histogram;
+
+use serde::Serialize;
+
 use rand::seq::SliceRandom;
-use rand::{Rng, SeedableRng};
 use rand_distr::{Distribution, LogNormal};
 use rand_xoshiro::Xoshiro256StarStar;
 
 use crate::args_rets::*;
-use crate::lossy_convert::*;
+use crate::error::*;
 use crate::simulation::*;
 use crate::status::*;
 
-pub trait SRRSimulation: Simulation {}
+pub trait SRRSimulation: Simulation {
+    fn get_tenancy_stretch_metric(&self) -> &Family<Vec<(String, String)>, Histogram>;
+    fn get_tenancy_admission_wait_metric(&self) -> &Family<Vec<(String, String)>, Histogram>;
+    fn get_tenancy_service_time_metric(&self) -> &Family<Vec<(String, String)>, Histogram>;
+}
 
 struct SharedRateTenancy<S: SRRSimulation + 'static> {
     due_timer_time: u64,
+    // insertion order, breaking ties between equal due_timer_times so that the
+    // order completed tenancies are popped in doesn't depend on the heap's layout
+    sequence: u64,
+    required_resource_time: u64,
+    // when it was added, before any wait for admission
+    added_timestamp: u64,
+    // when it was admitted and started accruing resource time
+    start_timestamp: u64,
     handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
 }
 
 impl<S: SRRSimulation + 'static> Ord for SharedRateTenancy<S> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.due_timer_time.cmp(&other.due_timer_time).reverse()
+        self.due_timer_time
+            .cmp(&other.due_timer_time)
+            .then(self.sequence.cmp(&other.sequence))
+            .reverse()
     }
 }
 
@@ -38,62 +54,348 @@ impl<S: SRRSimulation + 'static> PartialOrd for SharedRateTenancy<S> {
 
 impl<S: SRRSimulation + 'static> PartialEq for SharedRateTenancy<S> {
     fn eq(&self, other: &Self) -> bool {
-        self.due_timer_time == other.due_timer_time
+        self.due_timer_time == other.due_timer_time && self.sequence == other.sequence
     }
 }
 
 impl<S: SRRSimulation + 'static> Eq for SharedRateTenancy<S> {}
 
+// a tenancy waiting for a slot under the concurrency limit, its resource time
+// sampled once admitted
+struct WaitingTenancy<S: SRRSimulation + 'static> {
+    added_timestamp: u64,
+    required_resource_time: LogNormal<f32>,
+    context: ItemContext,
+    handler: Box<dyn FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+}
+
+/// Read-only view of a resource's scheduling state, e.g. for monitoring
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SharedRateResourceSnapshot {
+    pub id: u64,
+    pub tenancies: usize,
+    // waiting for admission under the concurrency limit
+    pub waiting_tenancies: usize,
+    pub partitions: u8,
+    pub concurrency_limit: Option<usize>,
+    pub status: Status,
+    // None while there are no tenancies
+    pub resource_timer_rate: Option<f64>,
+    pub next_wakeup_time: Option<u64>,
+    pub resource_timer: u64,
+    pub utilization_counter: u64,
+    pub load_counter: u64,
+    pub utilization_ratio: Option<f64>,
+    pub average_load: Option<f64>,
+}
+
+/// A resource shared among tenancies. Its status works like a worker's: while
+/// `Running` it takes new tenancies; otherwise it refuses them, though those
+/// already added still complete. `ShuttingDown` becomes `Terminated` once none
+/// are left, and a resource set back to `Running` takes tenancies again.
 pub struct SharedRateResource<S: SRRSimulation + 'static> {
     id: u64,
     partitions: u8,
     resource_timer: u64,
+    // the timer is only reset once it's this high, so metrics get a chance to
+    // observe it first
+    min_resource_timer_reset_val: u64,
     resource_timer_last_updated_real_time: u64,
+    // the part of a tick of resource time accrued but not yet added to the timer,
+    // so that frequent updates at a rate below 1.0 don't each round it away
+    resource_timer_fraction: f64,
     utilization_counter: u64,
     load_counter: u64,
     wakeup_event_memo: VecDeque<u64>,
     status: Rc<RefCell<Status>>,
     tenancies: BinaryHeap<SharedRateTenancy<S>>,
+    next_tenancy_sequence: u64,
     rng: Xoshiro256StarStar,
+    // when set, sub-tick remainders of sampled resource times are carried over to
+    // the next tenancy instead of every sample being rounded up to a whole tick
+    fractional_resource_time: bool,
+    resource_time_carry: f64,
+    // when set, utilization and load counters keep accumulating across the idle
+    // resets of the resource timer
+    cumulative_counters: bool,
+    // when the counters last started from zero, None until the resource is first
+    // updated
+    counters_window_start: Option<u64>,
+    // when set, at most this many tenancies share the partitions at once and the
+    // rest wait, in arrival order, for one to complete
+    concurrency_limit: Option<usize>,
+    waiting_tenancies: VecDeque<WaitingTenancy<S>>,
+    // the most tenancies admitted at once
+    peak_tenancies: usize,
+    // partition-ticks in use since the resource was first updated, never reset
+    // whether or not the counters are cumulative
+    busy_ticks_total: u64,
+    first_updated_real_time: Option<u64>,
+    // labels its tenancy histograms, by default just its id
+    metric_labels: Vec<(String, String)>,
 }
 
 impl<S: SRRSimulation + 'static> SharedRateResource<S> {
     const MAX_WAKEUP_EVENT_MEMO_LEN: u8 = 8;
-    const MIN_RESOURCE_TIMER_RESET_VAL: u64 = (S::TICKS_PER_SECOND * 120.0) as u64;
+    const MIN_RESOURCE_TIMER_RESET_SECONDS: f64 = 120.0;
+
+    /// Fails if `partitions` is 0, as the resource timer would never advance and
+    /// no tenancy would ever complete
+    pub fn new(
+        simulation: &S,
+        id: u64,
+        partitions: u8,
+        rng: Xoshiro256StarStar,
+    ) -> Result<Self, Error> {
+        if partitions == 0 {
+            return Err(Error::ZeroPartitions {
+                shared_rate_resource: id,
+            });
+        }
+
+        Ok(SharedRateResource {
+            id,
+            partitions,
+            resource_timer: 0,
+            min_resource_timer_reset_val: (Self::MIN_RESOURCE_TIMER_RESET_SECONDS
+                * simulation.get_ticks_per_second())
+                as u64,
+            resource_timer_last_updated_real_time: 0,
+            resource_timer_fraction: 0.0,
+            utilization_counter: 0,
+            load_counter: 0,
+            wakeup_event_memo: Default::default(),
+            status: Rc::new(Status::Running.into()),
+            tenancies: Default::default(),
+            next_tenancy_sequence: 0,
+            rng,
+            fractional_resource_time: false,
+            resource_time_carry: 0.0,
+            cumulative_counters: false,
+            counters_window_start: None,
+            concurrency_limit: None,
+            waiting_tenancies: Default::default(),
+            peak_tenancies: 0,
+            busy_ticks_total: 0,
+            first_updated_real_time: None,
+            metric_labels: vec![("shared_rate_resource_id".to_owned(), format!("{id:016x}"))],
+        })
+    }
+
+    pub fn set_fractional_resource_time(&mut self, fractional: bool) {
+        self.fractional_resource_time = fractional;
+        self.resource_time_carry = 0.0;
+    }
+
+    pub fn set_cumulative_counters(&mut self, cumulative: bool) {
+        self.cumulative_counters = cumulative;
+    }
+
+    /// Cap the number of tenancies sharing the partitions, e.g. a connection pool
+    /// in front of a service with a throughput ceiling. Tenancies beyond the cap
+    /// wait for an admitted one to complete and only then start accruing resource
+    /// time. Raising or removing the cap admits waiting tenancies at the next
+    /// completion. Fails if `limit` is 0, as no tenancy would ever be admitted.
+    pub fn set_concurrency_limit(&mut self, limit: Option<usize>) -> Result<(), Error> {
+        if limit == Some(0) {
+            return Err(Error::ZeroConcurrencyLimit {
+                shared_rate_resource: self.id,
+            });
+        }
+        self.concurrency_limit = limit;
+        Ok(())
+    }
+
+    /// Stop taking new tenancies, letting those already added - admitted or
+    /// waiting - complete, after which the resource is terminated and can be torn
+    /// down. Tenancy handlers may no longer chain further tenancies on it.
+    pub fn drain(&mut self) {
+        if *self.status.borrow() == Status::Running {
+            *self.status.borrow_mut() = Status::ShuttingDown;
+        }
+        self.maybe_terminate();
+    }
+
+    /// The resource's status, shared so that it can be changed from outside, e.g.
+    /// by a pool manager's shutdown closure as for a worker
+    pub fn get_status(&self) -> Rc<RefCell<Status>> {
+        self.status.clone()
+    }
+
+    /// Whether a drain has completed
+    pub fn is_drained(&self) -> bool {
+        *self.status.borrow() == Status::Terminated
+    }
+
+    fn maybe_terminate(&mut self) {
+        if *self.status.borrow() == Status::ShuttingDown
+            && self.tenancies.is_empty()
+            && self.waiting_tenancies.is_empty()
+        {
+            log::info!("shared rate resource {:016x} drained", self.id);
+            *self.status.borrow_mut() = Status::Terminated;
+        }
+    }
+
+    fn check_timestamp(&self, timestamp: u64) -> Result<(), Error> {
+        if timestamp < self.resource_timer_last_updated_real_time {
+            return Err(Error::TimestampInPast {
+                shared_rate_resource: self.id,
+                timestamp,
+                last_updated: self.resource_timer_last_updated_real_time,
+            });
+        }
+        Ok(())
+    }
 
     fn update_resource_timer(&mut self, current_timestamp: u64) {
         assert!(self.resource_timer_last_updated_real_time <= current_timestamp);
+        self.counters_window_start.get_or_insert(current_timestamp);
+        self.first_updated_real_time
+            .get_or_insert(current_timestamp);
 
         if self.tenancies.is_empty() {
             // don't reset before we've had a good chance to be observed by metrics
-            if self.resource_timer >= Self::MIN_RESOURCE_TIMER_RESET_VAL {
+            if self.resource_timer >= self.min_resource_timer_reset_val {
                 self.resource_timer = 0;
-                self.utilization_counter = 0;
-                self.load_counter = 0;
+                if !self.cumulative_counters {
+                    self.utilization_counter = 0;
+                    self.load_counter = 0;
+                    self.counters_window_start = Some(current_timestamp);
+                }
 
                 // no need to worry about adjusting due_timer_time values
                 // because there aren't any
             }
         } else if self.resource_timer_last_updated_real_time != current_timestamp {
             let real_time_delta = current_timestamp - self.resource_timer_last_updated_real_time;
-            let increment =
-                real_time_delta as f64 * self.get_current_resource_timer_rate().unwrap();
-
-            self.resource_timer += increment as u64;
+            let increment = real_time_delta as f64
+                * self.get_current_resource_timer_rate().unwrap()
+                + self.resource_timer_fraction;
 
             // should be hard for us to go past our target because float-int
-            // conversion rounds towards zero
-            assert!(self.resource_timer < self.tenancies.peek().unwrap().due_timer_time);
+            // conversion rounds towards zero, but a zero-length tenancy is already
+            // complete and only waiting for its wakeup event
+            let next_due_timer_time = self.tenancies.peek().unwrap().due_timer_time;
+            assert!(self.resource_timer <= next_due_timer_time);
+            if self.resource_timer + increment as u64 >= next_due_timer_time {
+                self.resource_timer = next_due_timer_time;
+                self.resource_timer_fraction = 0.0;
+            } else {
+                self.resource_timer += increment as u64;
+                self.resource_timer_fraction = increment.fract();
+            }
 
-            self.utilization_counter +=
+            let busy_ticks =
                 u64::min(self.partitions as u64, self.tenancies.len() as u64) * real_time_delta;
+            self.utilization_counter += busy_ticks;
+            self.busy_ticks_total += busy_ticks;
             self.load_counter += self.tenancies.len() as u64 * real_time_delta;
         }
 
         self.resource_timer_last_updated_real_time = current_timestamp;
     }
 
-    fn get_current_resource_timer_rate(&self) -> Option<f64> {
+    fn get_metric_labels(&self) -> Vec<(String, String)> {
+        self.metric_labels.clone()
+    }
+
+    /// Label the resource's tenancy histograms with `metric_labels` in place of
+    /// its id, e.g. for resources whose ids could collide with others'
+    pub fn set_metric_labels(&mut self, metric_labels: Vec<(String, String)>) {
+        self.metric_labels = metric_labels;
+    }
+
+    /// Whether no tenancies are admitted or waiting
+    pub fn is_idle(&self) -> bool {
+        self.tenancies.is_empty() && self.waiting_tenancies.is_empty()
+    }
+
+    pub fn get_snapshot(&self) -> SharedRateResourceSnapshot {
+        SharedRateResourceSnapshot {
+            id: self.id,
+            tenancies: self.tenancies.len(),
+            waiting_tenancies: self.waiting_tenancies.len(),
+            partitions: self.partitions,
+            concurrency_limit: self.concurrency_limit,
+            status: *self.status.borrow(),
+            resource_timer_rate: self.get_current_resource_timer_rate(),
+            next_wakeup_time: self.get_next_wakeup_time(),
+            resource_timer: self.resource_timer,
+            utilization_counter: self.utilization_counter,
+            load_counter: self.load_counter,
+            utilization_ratio: self.get_utilization_ratio(),
+            average_load: self.get_average_load(),
+        }
+    }
+
+    /// The most tenancies admitted at once since the resource was created, not
+    /// counting any waiting under the concurrency limit. Counted as each is added,
+    /// so one due at the same tick but not yet removed by its wakeup still counts.
+    pub fn get_peak_tenancies(&self) -> usize {
+        self.peak_tenancies
+    }
+
+    // ticks the counters have accrued over, None if none have passed yet
+    fn get_counters_window(&self) -> Option<u64> {
+        let window = self.resource_timer_last_updated_real_time - self.counters_window_start?;
+        (window > 0).then_some(window)
+    }
+
+    /// Fraction of the resource's capacity in use since the counters last started
+    /// from zero, up to when it was last updated
+    pub fn get_utilization_ratio(&self) -> Option<f64> {
+        Some(
+            self.utilization_counter as f64
+                / (self.partitions as u64 * self.get_counters_window()?) as f64,
+        )
+    }
+
+    /// Partition-ticks in use since the resource was first updated, unaffected by
+    /// the idle resets of the counters
+    pub fn get_busy_ticks_total(&self) -> u64 {
+        self.busy_ticks_total
+    }
+
+    /// Fraction of the resource's capacity in use from when it was first updated
+    /// to when it was last updated, None if no time has passed in between
+    pub fn get_total_utilization_ratio(&self) -> Option<f64> {
+        let window = self.resource_timer_last_updated_real_time - self.first_updated_real_time?;
+        (window > 0)
+            .then(|| self.busy_ticks_total as f64 / (self.partitions as u64 * window) as f64)
+    }
+
+    /// Average number of outstanding tenancies over the same window as
+    /// `get_utilization_ratio`, above `partitions` when oversubscribed
+    pub fn get_average_load(&self) -> Option<f64> {
+        Some(self.load_counter as f64 / self.get_counters_window()? as f64)
+    }
+
+    /// Bring the resource timer and counters up to `timestamp` without running
+    /// any tenancy handlers, e.g. to drive a resource directly with explicit
+    /// timestamps rather than through scheduled events. Fails if `timestamp` is
+    /// before the resource was last updated.
+    pub fn advance_to(&mut self, timestamp: u64) -> Result<(), Error> {
+        self.check_timestamp(timestamp)?;
+        self.update_resource_timer(timestamp);
+        Ok(())
+    }
+
+    /// Outstanding tenancies per partition - above 1.0 the resource is
+    /// oversubscribed and every tenancy is slowed down proportionally.
+    pub fn get_current_load(&self) -> f64 {
+        self.tenancies.len() as f64 / self.partitions as f64
+    }
+
+    /// Like `get_current_load`, but counting tenancies waiting under the
+    /// concurrency limit as well, so it can exceed `limit / partitions`
+    pub fn get_current_demand(&self) -> f64 {
+        (self.tenancies.len() + self.waiting_tenancies.len()) as f64 / self.partitions as f64
+    }
+
+    /// Rate at which each tenancy currently accrues resource time, below 1.0 when
+    /// oversubscribed
+    pub fn get_current_resource_timer_rate(&self) -> Option<f64> {
         if self.tenancies.is_empty() {
             None
         } else {
@@ -104,34 +406,115 @@ impl<S: SRRSimulation + 'static> SharedRateResource<S> {
         }
     }
 
-    fn get_next_wakeup_time(&self) -> Option<u64> {
+    /// When the next tenancy will complete if no tenancies are added meanwhile
+    pub fn get_next_wakeup_time(&self) -> Option<u64> {
         if self.tenancies.is_empty() {
             None
         } else {
+            // rounded up, so the timer has reached the due time by the wakeup
             Some(
-                ((self.tenancies.peek().unwrap().due_timer_time - self.resource_timer) as f64
-                    / self.get_current_resource_timer_rate().unwrap()) as u64
+                (((self.tenancies.peek().unwrap().due_timer_time - self.resource_timer) as f64
+                    - self.resource_timer_fraction)
+                    / self.get_current_resource_timer_rate().unwrap())
+                .ceil() as u64
                     + self.resource_timer_last_updated_real_time,
             )
         }
     }
 
+    fn has_free_slot(&self) -> bool {
+        self.concurrency_limit
+            .is_none_or(|limit| self.tenancies.len() < limit)
+    }
+
+    // admitted straight away if there's a free slot, else left waiting for one,
+    // unless the resource isn't running
+    fn add_or_wait_tenancy(
+        &mut self,
+        simulation: &S,
+        current_timestamp: u64,
+        required_resource_time: LogNormal<f32>,
+        context: ItemContext,
+        inner_handler: impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> Result<(), Error> {
+        if *self.status.borrow() != Status::Running {
+            // a status set from outside while idle is only noticed here
+            self.maybe_terminate();
+            return Err(Error::ResourceShuttingDown {
+                shared_rate_resource: self.id,
+            });
+        }
+
+        if self.has_free_slot() {
+            self.add_tenancy(
+                simulation,
+                current_timestamp,
+                current_timestamp,
+                required_resource_time,
+                context,
+                inner_handler,
+            );
+        } else {
+            self.waiting_tenancies.push_back(WaitingTenancy {
+                added_timestamp: current_timestamp,
+                required_resource_time,
+                context,
+                handler: Box::new(inner_handler),
+            });
+        }
+        Ok(())
+    }
+
+    fn admit_waiting_tenancies(&mut self, simulation: &S, current_timestamp: u64) {
+        while self.has_free_slot() {
+            let Some(waiting) = self.waiting_tenancies.pop_front() else {
+                break;
+            };
+            self.add_tenancy(
+                simulation,
+                waiting.added_timestamp,
+                current_timestamp,
+                waiting.required_resource_time,
+                waiting.context,
+                waiting.handler,
+            );
+        }
+    }
+
     fn add_tenancy(
         &mut self,
+        simulation: &S,
+        added_timestamp: u64,
         current_timestamp: u64,
         required_resource_time: LogNormal<f32>,
+        context: ItemContext,
         inner_handler: impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
     ) {
         self.update_resource_timer(current_timestamp);
-        let actual_req_resource_time = max(1, required_resource_time.sample(&mut self.rng) as u64);
+        let sampled_resource_time =
+            required_resource_time.sample(&mut self.rng) as f64 * context.get_size();
+        let actual_req_resource_time = if self.fractional_resource_time {
+            let total = sampled_resource_time + self.resource_time_carry;
+            self.resource_time_carry = total.fract();
+            total as u64
+        } else {
+            max(simulation.get_min_delay(), sampled_resource_time as u64)
+        };
         self.tenancies.push(SharedRateTenancy {
             due_timer_time: self.resource_timer + actual_req_resource_time,
+            sequence: self.next_tenancy_sequence,
+            required_resource_time: actual_req_resource_time,
+            added_timestamp,
+            start_timestamp: current_timestamp,
             handler: Box::new(inner_handler),
         });
+        self.next_tenancy_sequence += 1;
+        self.peak_tenancies = usize::max(self.peak_tenancies, self.tenancies.len());
     }
 
     fn maybe_generate_wakeup_event(
         shared_rate_resource: Rc<RefCell<Self>>,
+        current_timestamp: u64,
     ) -> Option<Vec<ProposedEvent<S>>> {
         let srr = shared_rate_resource;
 
@@ -139,7 +522,12 @@ impl<S: SRRSimulation + 'static> SharedRateResource<S> {
             return None;
         }
 
-        let t = srr.borrow().get_next_wakeup_time().unwrap();
+        // a wakeup can't be scheduled for the current tick, which may also be one
+        // whose wakeup already ran and is still in the memo
+        let t = max(
+            srr.borrow().get_next_wakeup_time().unwrap(),
+            current_timestamp + 1,
+        );
         if !srr.borrow().wakeup_event_memo.contains(&t) {
             srr.borrow_mut()
                 .wakeup_event_memo
@@ -148,24 +536,50 @@ impl<S: SRRSimulation + 'static> SharedRateResource<S> {
 
             let srrc = srr.clone();
             return Some(Vec::from([ProposedEvent {
-                due_time: LogNormal::from_mean_cv(t as f32, 0.0).unwrap(),
-                handler: Box::new(move |simulation, timestamp| {
+                due_time: DueTime::At(t),
+                handler: Box::new(move |simulation: &'static S, timestamp| {
                     let mut handlers = Vec::new();
-                    while let Some(wt) = srrc.borrow().get_next_wakeup_time() {
-                        if wt != timestamp {
-                            break;
+                    {
+                        let mut srr = srrc.borrow_mut();
+                        // bring the timer up to date at the rate that applied until
+                        // now, before completed tenancies are removed and change it
+                        srr.update_resource_timer(timestamp);
+                        while let Some(wt) = srr.get_next_wakeup_time() {
+                            if wt > timestamp {
+                                break;
+                            }
+                            handlers.push(srr.tenancies.pop().unwrap());
                         }
-                        handlers.push(srrc.borrow_mut().tenancies.pop().unwrap());
+                        srr.admit_waiting_tenancies(simulation, timestamp);
+                        srr.maybe_terminate();
                     }
 
-                    if !handlers.is_empty() {
-                        // this was a false wakeup
-
-                        // resource timer doesn't need to be updated as nothing was added
-                        // or removed from the heap. if we can avoid doing this we generally
-                        // should because it potentially adds precision error every time it
-                        // runs.
-                        srrc.borrow_mut().update_resource_timer(timestamp);
+                    let metric_labels = srrc.borrow().get_metric_labels();
+                    for tenancy in &handlers {
+                        simulation
+                            .get_tenancy_admission_wait_metric()
+                            .get_or_create(&metric_labels)
+                            .observe(
+                                (tenancy.start_timestamp - tenancy.added_timestamp) as f64
+                                    / simulation.get_ticks_per_second(),
+                            );
+                        simulation
+                            .get_tenancy_service_time_metric()
+                            .get_or_create(&metric_labels)
+                            .observe(
+                                (timestamp - tenancy.start_timestamp) as f64
+                                    / simulation.get_ticks_per_second(),
+                            );
+                        // zero-length tenancies are possible with fractional resource time
+                        if tenancy.required_resource_time > 0 {
+                            simulation
+                                .get_tenancy_stretch_metric()
+                                .get_or_create(&metric_labels)
+                                .observe(
+                                    (timestamp - tenancy.start_timestamp) as f64
+                                        / tenancy.required_resource_time as f64,
+                                );
+                        }
                     }
 
                     SliceRandom::shuffle(&mut handlers[..], &mut srrc.borrow_mut().rng);
@@ -174,11 +588,14 @@ impl<S: SRRSimulation + 'static> SharedRateResource<S> {
                         .flat_map(|tenancy| (tenancy.handler)(simulation, timestamp))
                         .collect();
 
-                    if let Some(mut mwvec) = Self::maybe_generate_wakeup_event(srrc) {
+                    if let Some(mut mwvec) = Self::maybe_generate_wakeup_event(srrc, timestamp) {
                         ret.append(&mut mwvec);
                     }
-                    return ret;
+                    ret
                 }),
+                label: Some("shared_rate_resource_wakeup"),
+                keepalive: true,
+                priority: 0,
             }]));
 
             // TODO runtime destructor guard to ensure resulting event isn't dropped?
@@ -187,18 +604,877 @@ impl<S: SRRSimulation + 'static> SharedRateResource<S> {
         Some(Default::default())
     }
 
+    /// Add a tenancy requiring `required_resource_time` of one partition, calling
+    /// `inner_handler` once it's had it. Under a concurrency limit it may first
+    /// wait for a slot. May be called from another tenancy's
+    /// handler on the same resource to chain tenancies: completed tenancies are
+    /// removed and the resource timer brought up to date before any handlers run,
+    /// and the wakeup both would schedule is deduplicated by the memo. Fails,
+    /// without adding the tenancy, if `current_timestamp` is before the resource
+    /// was last updated, e.g. one captured before an earlier event on it, or if
+    /// the resource is being drained.
     pub fn mk_shared_rate_event(
+        simulation: &S,
         shared_rate_resource: Rc<RefCell<Self>>,
         current_timestamp: u64,
         required_resource_time: LogNormal<f32>,
         inner_handler: impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
-    ) -> Vec<ProposedEvent<S>> {
-        shared_rate_resource.borrow_mut().add_tenancy(
+    ) -> Result<Vec<ProposedEvent<S>>, Error> {
+        Self::mk_sized_shared_rate_event(
+            simulation,
+            shared_rate_resource,
             current_timestamp,
             required_resource_time,
+            &Default::default(),
             inner_handler,
+        )
+    }
+
+    /// Like `mk_shared_rate_event`, with the required resource time sampled for
+    /// an item of `context`'s size, e.g. one passed on from its worker token
+    pub fn mk_sized_shared_rate_event(
+        simulation: &S,
+        shared_rate_resource: Rc<RefCell<Self>>,
+        current_timestamp: u64,
+        required_resource_time: LogNormal<f32>,
+        context: &ItemContext,
+        inner_handler: impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> Result<Vec<ProposedEvent<S>>, Error> {
+        {
+            let mut srr = shared_rate_resource.borrow_mut();
+            srr.check_timestamp(current_timestamp)?;
+            srr.add_or_wait_tenancy(
+                simulation,
+                current_timestamp,
+                required_resource_time,
+                *context,
+                inner_handler,
+            )?;
+        }
+
+        // there's always an admitted tenancy, so always a wakeup to consider
+        Ok(Self::maybe_generate_wakeup_event(shared_rate_resource, current_timestamp).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::main_loop::*;
+    use crate::metric_samples::*;
+
+    fn mk_resource(
+        simulation: &BaseSimulation,
+        partitions: u8,
+    ) -> SharedRateResource<BaseSimulation> {
+        SharedRateResource::new(simulation, 1, partitions, mk_seeded_named_rng(1, "test")).unwrap()
+    }
+
+    // resource time of every admitted tenancy, completed or not
+    fn get_total_required_resource_time(resource: &SharedRateResource<BaseSimulation>) -> u64 {
+        resource
+            .tenancies
+            .iter()
+            .map(|tenancy| tenancy.required_resource_time)
+            .sum()
+    }
+
+    fn add_tenancies(
+        simulation: &BaseSimulation,
+        resource: &mut SharedRateResource<BaseSimulation>,
+        timestamp: u64,
+        count: usize,
+        required_resource_time: LogNormal<f32>,
+    ) {
+        for _ in 0..count {
+            resource
+                .add_or_wait_tenancy(
+                    simulation,
+                    timestamp,
+                    required_resource_time,
+                    Default::default(),
+                    |_, _| Default::default(),
+                )
+                .unwrap();
+        }
+    }
+
+    // adds `count` tenancies each requiring exactly `ticks` of resource time
+    fn add_fixed_tenancies(
+        simulation: &BaseSimulation,
+        resource: &mut SharedRateResource<BaseSimulation>,
+        timestamp: u64,
+        count: usize,
+        ticks: u64,
+    ) {
+        add_tenancies(
+            simulation,
+            resource,
+            timestamp,
+            count,
+            // half a tick over, as the sampled time is truncated and mightn't be
+            // exact
+            LogNormal::from_mean_cv(ticks as f32 + 0.5, 0.0).unwrap(),
+        );
+        assert!(resource
+            .tenancies
+            .iter()
+            .all(|t| t.required_resource_time == ticks));
+    }
+
+    #[test]
+    fn zero_partitions_is_an_error() {
+        let simulation = BaseSimulation::new(1);
+        let result = SharedRateResource::new(&simulation, 0x2a, 0, mk_seeded_named_rng(1, "test"));
+        let Err(e) = result else {
+            panic!("a resource with no partitions was built");
+        };
+        assert_eq!(
+            e,
+            Error::ZeroPartitions {
+                shared_rate_resource: 0x2a
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "SharedRateResource 000000000000002a must have at least one partition"
+        );
+    }
+
+    #[test]
+    fn equal_due_times_complete_in_a_reproducible_order() {
+        // ties pop in insertion order, whatever the heap's layout
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 4);
+        add_fixed_tenancies(&simulation, &mut resource, 0, 20, 1000);
+        let popped = Vec::from_iter(
+            std::iter::from_fn(|| resource.tenancies.pop()).map(|tenancy| tenancy.sequence),
+        );
+        assert_eq!(popped, Vec::from_iter(0..20));
+
+        // so the shuffled completion order is the same in every run
+        fn run_completion_order() -> Vec<usize> {
+            let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+            let resource = Rc::new(RefCell::new(mk_resource(simulation, 4)));
+            let completed: Rc<RefCell<Vec<usize>>> = Default::default();
+            let mut runner = SimulationRunner::new(simulation, {
+                let completed = completed.clone();
+                Box::new(move |simulation, timestamp| {
+                    Vec::from_iter((0..20).flat_map(|i| {
+                        let completed = completed.clone();
+                        SharedRateResource::mk_shared_rate_event(
+                            simulation,
+                            resource.clone(),
+                            timestamp,
+                            LogNormal::from_mean_cv(1000.5, 0.0).unwrap(),
+                            move |_, _| {
+                                completed.borrow_mut().push(i);
+                                Default::default()
+                            },
+                        )
+                        .unwrap()
+                    }))
+                })
+            });
+            assert_eq!(runner.run(), EndReason::EventHeapDrained);
+            completed.take()
+        }
+        let order = run_completion_order();
+        assert_eq!(order.len(), 20);
+        // shuffled, rather than left in insertion order
+        assert_ne!(order, Vec::from_iter(0..20));
+        assert_eq!(run_completion_order(), order);
+    }
+
+    #[test]
+    fn utilization_and_load_cover_the_window_since_first_update() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 2);
+        add_fixed_tenancies(&simulation, &mut resource, 500, 1, 10_000);
+        // the window starts at the first update, and is empty so far
+        assert_eq!(resource.get_utilization_ratio(), None);
+        assert_eq!(resource.get_average_load(), None);
+
+        // one of two partitions busy for 500 ticks
+        resource.advance_to(1000).unwrap();
+        assert_eq!(resource.get_utilization_ratio(), Some(0.5));
+        assert_eq!(resource.get_average_load(), Some(1.0));
+
+        // then three tenancies oversubscribing both partitions for 200 more
+        add_fixed_tenancies(&simulation, &mut resource, 1000, 2, 10_000);
+        resource.advance_to(1200).unwrap();
+        let expected_ratio = (500.0 + 2.0 * 200.0) / (2.0 * 700.0);
+        let expected_load = (500.0 + 3.0 * 200.0) / 700.0;
+        assert_eq!(resource.get_utilization_ratio(), Some(expected_ratio));
+        assert_eq!(resource.get_average_load(), Some(expected_load));
+        let snapshot = resource.get_snapshot();
+        assert_eq!(snapshot.utilization_ratio, Some(expected_ratio));
+        assert_eq!(snapshot.average_load, Some(expected_load));
+    }
+
+    #[test]
+    fn resource_timer_runs_at_partitions_over_tenancies() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 2);
+        // four tenancies on two partitions, so the timer runs at half rate
+        add_fixed_tenancies(&simulation, &mut resource, 0, 4, 1000);
+        assert_eq!(resource.get_current_resource_timer_rate(), Some(0.5));
+        assert_eq!(resource.get_next_wakeup_time(), Some(2000));
+
+        resource.update_resource_timer(100);
+        assert_eq!(resource.resource_timer, 50);
+        // the wakeup doesn't move as time passes at the same rate
+        assert_eq!(resource.get_next_wakeup_time(), Some(2000));
+
+        resource.update_resource_timer(2000);
+        assert_eq!(resource.resource_timer, 1000);
+        assert_eq!(resource.utilization_counter, 2 * 2000);
+        assert_eq!(resource.load_counter, 4 * 2000);
+    }
+
+    #[test]
+    fn resource_timer_carries_fractions_of_a_tick() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 1);
+        // a quarter of a tick of resource time per tick
+        add_fixed_tenancies(&simulation, &mut resource, 0, 4, 10);
+        for (timestamp, expected_resource_timer) in [(1, 0), (2, 0), (3, 0), (4, 1), (6, 1), (8, 2)]
+        {
+            resource.update_resource_timer(timestamp);
+            assert_eq!(
+                resource.resource_timer, expected_resource_timer,
+                "@ {timestamp}"
+            );
+        }
+        // the half tick carried from 6 counts towards the wakeup too
+        assert_eq!(resource.get_next_wakeup_time(), Some(40));
+    }
+
+    #[test]
+    fn resource_timer_stops_at_next_due_time() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 1);
+        add_fixed_tenancies(&simulation, &mut resource, 0, 1, 10);
+        // updated late, e.g. by another tenancy being added after the wakeup was due
+        resource.update_resource_timer(25);
+        assert_eq!(resource.resource_timer, 10);
+        assert_eq!(resource.get_next_wakeup_time(), Some(25));
+    }
+
+    #[test]
+    fn idle_resource_timer_resets_after_long_enough() {
+        let simulation = BaseSimulation::new(1);
+        // either side of the 120s threshold
+        let reset_ticks = (120.0 * simulation.get_ticks_per_second()) as u64;
+        assert!((1 << 16..=1 << 17).contains(&reset_ticks));
+        for (required_resource_time, expect_reset) in [(1 << 16, false), (1 << 17, true)] {
+            let mut resource = mk_resource(&simulation, 1);
+            add_fixed_tenancies(&simulation, &mut resource, 0, 1, required_resource_time);
+            resource.update_resource_timer(required_resource_time);
+            // as the wakeup handler does with completed tenancies
+            resource.tenancies.pop();
+            assert_eq!(resource.get_next_wakeup_time(), None);
+
+            resource.update_resource_timer(1 << 18);
+            if expect_reset {
+                assert_eq!(resource.resource_timer, 0);
+                assert_eq!(resource.utilization_counter, 0);
+                assert_eq!(resource.counters_window_start, Some(1 << 18));
+            } else {
+                assert_eq!(resource.resource_timer, required_resource_time);
+                assert_eq!(resource.utilization_counter, required_resource_time);
+            }
+        }
+    }
+
+    #[test]
+    fn cumulative_counters_survive_a_reset() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 1);
+        resource.set_cumulative_counters(true);
+        add_fixed_tenancies(&simulation, &mut resource, 0, 1, 1 << 17);
+        resource.update_resource_timer(1 << 17);
+        resource.tenancies.pop();
+
+        resource.update_resource_timer(1 << 18);
+        assert_eq!(resource.resource_timer, 0);
+        assert_eq!(resource.utilization_counter, 1 << 17);
+        assert_eq!(resource.counters_window_start, Some(0));
+    }
+
+    #[test]
+    fn total_utilization_ignores_counter_resets() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 2);
+        assert_eq!(resource.get_total_utilization_ratio(), None);
+        add_fixed_tenancies(&simulation, &mut resource, 0, 1, 1 << 17);
+        resource.update_resource_timer(1 << 17);
+        resource.tenancies.pop();
+
+        // one of two partitions busy for a quarter of the time, across a reset
+        resource.update_resource_timer(1 << 19);
+        assert_eq!(resource.utilization_counter, 0);
+        assert_eq!(resource.get_busy_ticks_total(), 1 << 17);
+        assert_eq!(resource.get_total_utilization_ratio(), Some(0.125));
+    }
+
+    #[test]
+    fn snapshot_reports_rate_and_next_wakeup() {
+        let simulation = BaseSimulation::new(1);
+        let mut resource = mk_resource(&simulation, 3);
+        add_fixed_tenancies(&simulation, &mut resource, 0, 1, 1000);
+        let snapshot = resource.get_snapshot();
+        assert_eq!(snapshot.resource_timer_rate, Some(1.0));
+        assert_eq!(snapshot.next_wakeup_time, Some(1000));
+
+        // six tenancies on three partitions from 200, so the first's remaining
+        // 800 ticks take 1600
+        resource.advance_to(200).unwrap();
+        add_fixed_tenancies(&simulation, &mut resource, 200, 5, 1000);
+        let snapshot = resource.get_snapshot();
+        assert_eq!(snapshot.tenancies, 6);
+        assert_eq!(snapshot.resource_timer_rate, Some(0.5));
+        assert_eq!(snapshot.next_wakeup_time, Some(200 + 1600));
+        assert_eq!(snapshot.resource_timer, 200);
+        assert_eq!(resource.get_current_load(), 2.0);
+    }
+
+    #[test]
+    fn cumulative_counters_grow_over_idle_periods() {
+        let simulation = BaseSimulation::new(1);
+        for cumulative in [false, true] {
+            let mut resource = mk_resource(&simulation, 1);
+            resource.set_cumulative_counters(cumulative);
+            let mut utilization = Vec::new();
+            // busy for 2^17 ticks, past the reset threshold, then idle as long
+            for cycle in 0..3 {
+                let start = cycle << 18;
+                resource.advance_to(start).unwrap();
+                add_fixed_tenancies(&simulation, &mut resource, start, 1, 1 << 17);
+                resource.advance_to(start + (1 << 17)).unwrap();
+                resource.tenancies.pop();
+                resource.advance_to(start + (1 << 18) - 1).unwrap();
+                // the timer resets either way
+                assert_eq!(resource.resource_timer, 0);
+                utilization.push(resource.get_snapshot().utilization_counter);
+            }
+            if cumulative {
+                assert_eq!(utilization, [1 << 17, 2 << 17, 3 << 17]);
+                let utilization_ratio = resource.get_utilization_ratio().unwrap();
+                assert!(
+                    (utilization_ratio - 0.5).abs() < 0.001,
+                    "{utilization_ratio}"
+                );
+            } else {
+                assert_eq!(utilization, [0, 0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn fractional_resource_time_isnt_rounded_up() {
+        let simulation = BaseSimulation::new(1);
+        let sub_tick = LogNormal::from_mean_cv(0.3, 0.0).unwrap();
+
+        let mut rounded = mk_resource(&simulation, 1);
+        add_tenancies(&simulation, &mut rounded, 0, 1000, sub_tick);
+        assert_eq!(get_total_required_resource_time(&rounded), 1000);
+
+        let mut fractional = mk_resource(&simulation, 1);
+        fractional.set_fractional_resource_time(true);
+        add_tenancies(&simulation, &mut fractional, 0, 1000, sub_tick);
+        assert!(get_total_required_resource_time(&fractional).abs_diff(300) <= 1);
+    }
+
+    #[test]
+    fn zero_min_delay_lets_resource_time_round_down() {
+        let simulation: BaseSimulation = SimulationBuilder::new(1).min_delay(0).build();
+        let sub_tick = LogNormal::from_mean_cv(0.5, 1.0).unwrap();
+
+        let mut resource = mk_resource(&simulation, 1);
+        add_tenancies(&simulation, &mut resource, 0, 1000, sub_tick);
+        let required_resource_times =
+            Vec::from_iter(resource.tenancies.iter().map(|t| t.required_resource_time));
+        // mostly truncated to 0, but not all of them
+        let zero = required_resource_times.iter().filter(|t| **t == 0).count();
+        assert!((500..1000).contains(&zero), "{zero}");
+    }
+
+    #[test]
+    fn oversubscription_stretches_tenancies() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let resource = Rc::new(RefCell::new(
+            SharedRateResource::new(simulation, 1, 1, mk_seeded_named_rng(1, "test")).unwrap(),
+        ));
+        // four 10s tenancies share one partition, so each takes 40s
+        let mut runner = SimulationRunner::new(simulation, {
+            let resource = resource.clone();
+            Box::new(move |simulation, timestamp| {
+                let required_resource_time =
+                    LogNormal::from_mean_cv((10.0 * simulation.get_ticks_per_second()) as f32, 0.0)
+                        .unwrap();
+                Vec::from_iter((0..4).flat_map(|_| {
+                    SharedRateResource::mk_shared_rate_event(
+                        simulation,
+                        resource.clone(),
+                        timestamp,
+                        required_resource_time,
+                        |_, _| Default::default(),
+                    )
+                    .unwrap()
+                }))
+            })
+        });
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+
+        let (sum, count) = get_histogram_sum_count(
+            simulation.get_tenancy_stretch_metric(),
+            &resource.borrow().get_metric_labels(),
+        )
+        .unwrap();
+        assert_eq!(count, 4);
+        assert!((sum / count as f64 - 4.0).abs() < 0.01, "{sum}");
+    }
+
+    // each of the first resource's tenancies as they complete, with the resource's
+    // snapshot once the last has
+    #[derive(Default)]
+    struct SrrCompletions {
+        // required seconds, completion and expected completion timestamps
+        tenancies: Vec<(f64, u64, u64)>,
+        final_snapshot: Option<SharedRateResourceSnapshot>,
+    }
+
+    fn srr_bootstrap<S: SRRSimulation + 'static>(
+        simulation: &'static S,
+        timestamp: u64,
+        completions: Rc<RefCell<SrrCompletions>>,
+    ) -> Vec<ProposedEvent<S>> {
+        let resource = Rc::new(RefCell::new(
+            SharedRateResource::new(
+                simulation,
+                1,
+                2,
+                mk_named_rng(simulation, "shared_rate_resource/test"),
+            )
+            .unwrap(),
+        ));
+
+        // four tenancies share two partitions at half rate until the short pair
+        // complete, then the long pair have a partition each for their remaining 10s
+        let mut proposed_events = Vec::new();
+        for (required_seconds, expected_completion_seconds) in
+            [(10.0, 20.0), (10.0, 20.0), (20.0, 30.0), (20.0, 30.0)]
+        {
+            let expected_completion_timestamp = timestamp
+                + (expected_completion_seconds * simulation.get_ticks_per_second()) as u64;
+            let resource_clone = resource.clone();
+            let completions = completions.clone();
+            proposed_events.append(
+                &mut SharedRateResource::mk_shared_rate_event(
+                    simulation,
+                    resource.clone(),
+                    timestamp,
+                    LogNormal::from_mean_cv(
+                        (required_seconds * simulation.get_ticks_per_second()) as f32,
+                        0.0,
+                    )
+                    .unwrap(),
+                    move |_, completion_timestamp| {
+                        let mut completions = completions.borrow_mut();
+                        completions.tenancies.push((
+                            required_seconds,
+                            completion_timestamp,
+                            expected_completion_timestamp,
+                        ));
+                        completions.final_snapshot = Some(resource_clone.borrow().get_snapshot());
+                        Default::default()
+                    },
+                )
+                .unwrap(),
+            );
+        }
+
+        // an absolute due time is taken exactly, even where an f32 delay couldn't be
+        let far_timestamp = timestamp + (1 << 40) + 1;
+        proposed_events.push(ProposedEvent {
+            due_time: DueTime::At(far_timestamp),
+            handler: Box::new(move |_, timestamp| {
+                assert_eq!(timestamp, far_timestamp);
+                Default::default()
+            }),
+            label: Some("srr_far_event"),
+            keepalive: true,
+            priority: 0,
+        });
+
+        // three 5s tenancies run back to back on their own resource
+        let chain_resource = Rc::new(RefCell::new(
+            SharedRateResource::new(
+                simulation,
+                2,
+                1,
+                mk_named_rng(simulation, "shared_rate_resource/chain"),
+            )
+            .unwrap(),
+        ));
+        proposed_events.append(&mut mk_chained_tenancy_event(
+            simulation,
+            chain_resource,
+            timestamp,
+            3,
+            timestamp + (5.0 * simulation.get_ticks_per_second()) as u64,
+        ));
+
+        proposed_events
+    }
+
+    // a tenancy that, once complete, requires another until `remaining` have run
+    fn mk_chained_tenancy_event<S: SRRSimulation + 'static>(
+        simulation: &S,
+        resource: Rc<RefCell<SharedRateResource<S>>>,
+        timestamp: u64,
+        remaining: u64,
+        expected_completion_timestamp: u64,
+    ) -> Vec<ProposedEvent<S>> {
+        let required_ticks = 5.0 * simulation.get_ticks_per_second();
+        SharedRateResource::mk_shared_rate_event(
+            simulation,
+            resource.clone(),
+            timestamp,
+            LogNormal::from_mean_cv(required_ticks as f32, 0.0).unwrap(),
+            move |simulation, completion_timestamp| {
+                assert!(completion_timestamp.abs_diff(expected_completion_timestamp) <= 2);
+                if remaining <= 1 {
+                    return Default::default();
+                }
+                mk_chained_tenancy_event(
+                    simulation,
+                    resource,
+                    completion_timestamp,
+                    remaining - 1,
+                    completion_timestamp + required_ticks as u64,
+                )
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tenancies_share_partitions_and_chain_back_to_back() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let completions: Rc<RefCell<SrrCompletions>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let completions = completions.clone();
+            Box::new(move |simulation, timestamp| srr_bootstrap(simulation, timestamp, completions))
+        });
+        let end_reason = runner.run();
+
+        let completions = completions.borrow();
+        assert_eq!(completions.tenancies.len(), 4);
+        for (required_seconds, completion_timestamp, expected_completion_timestamp) in
+            &completions.tenancies
+        {
+            // allowing for f32 precision of the sampled times
+            assert!(
+                completion_timestamp.abs_diff(*expected_completion_timestamp) <= 2,
+                "{required_seconds}s tenancy completed @ {completion_timestamp}, expected @ {expected_completion_timestamp}",
+            );
+        }
+        // both partitions were busy throughout, with 4 tenancies for 20s then 2 for 10s
+        let snapshot = completions.final_snapshot.as_ref().unwrap();
+        assert!((snapshot.utilization_ratio.unwrap() - 1.0).abs() < 0.001);
+        assert!((snapshot.average_load.unwrap() - 10.0 / 3.0).abs() < 0.001);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // a pool of 4 connections to a service that can only work on 2 requests at a
+    // time, sent 8 requests at once each needing 10s of it, so oversubscribed
+    #[test]
+    fn concurrency_limit_parks_excess_tenancies() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let ticks = |seconds: f64| (seconds * simulation.get_ticks_per_second()) as u64;
+        let resource = Rc::new(RefCell::new(
+            SharedRateResource::<BaseSimulation>::new(
+                simulation,
+                4,
+                2,
+                mk_named_rng(simulation, "shared_rate_resource/concurrency_limit"),
+            )
+            .unwrap(),
+        ));
+        resource
+            .borrow_mut()
+            .set_concurrency_limit(Some(4))
+            .unwrap();
+        let completions: Rc<RefCell<Vec<u64>>> = Default::default();
+
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new({
+                let resource = resource.clone();
+                let completions = completions.clone();
+                move |_, timestamp| {
+                    let mut proposed_events = Vec::new();
+                    for _ in 0..8 {
+                        let completions = completions.clone();
+                        proposed_events.append(
+                            &mut SharedRateResource::mk_shared_rate_event(
+                                simulation,
+                                resource.clone(),
+                                timestamp,
+                                LogNormal::from_mean_cv(ticks(10.0) as f32, 0.0).unwrap(),
+                                move |_, timestamp| {
+                                    completions.borrow_mut().push(timestamp);
+                                    Default::default()
+                                },
+                            )
+                            .unwrap(),
+                        );
+                    }
+                    let snapshot = resource.borrow().get_snapshot();
+                    assert_eq!((snapshot.tenancies, snapshot.waiting_tenancies), (4, 4));
+                    assert_eq!(snapshot.resource_timer_rate, Some(0.5));
+                    proposed_events
+                }
+            }),
+        );
+        let end_reason = runner.run();
+
+        let completions = completions.borrow().clone();
+        // each admitted four progress at half speed, the rest waiting their turn
+        assert_eq!(completions, [[ticks(20.0); 4], [ticks(40.0); 4]].concat());
+        // the second four waited 20s to be admitted, and each took 20s from then
+        let labels = [("shared_rate_resource_id".to_owned(), format!("{:016x}", 4))];
+        assert_eq!(
+            simulation
+                .get_tenancy_admission_wait_sum_count(&labels)
+                .unwrap(),
+            (80.0, 8)
+        );
+        assert_eq!(
+            simulation
+                .get_tenancy_service_time_sum_count(&labels)
+                .unwrap(),
+            (160.0, 8)
         );
 
-        Self::maybe_generate_wakeup_event(shared_rate_resource).unwrap()
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // three 10s tenancies sharing one partition, drained 5s in
+    #[test]
+    fn drain_refuses_new_tenancies_and_completes_outstanding() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let ticks = |seconds: f64| (seconds * simulation.get_ticks_per_second()) as u64;
+        let mk_resource = |id| {
+            Rc::new(RefCell::new(
+                SharedRateResource::<BaseSimulation>::new(
+                    simulation,
+                    id,
+                    1,
+                    mk_named_rng(simulation, &format!("shared_rate_resource/{id:016x}")),
+                )
+                .unwrap(),
+            ))
+        };
+
+        // with nothing outstanding a drain completes straight away
+        let idle = mk_resource(5);
+        idle.borrow_mut().drain();
+        assert!(idle.borrow().is_drained());
+
+        let resource = mk_resource(6);
+        let completions: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new({
+                let resource = resource.clone();
+                let completions = completions.clone();
+                move |_, timestamp| {
+                    let mut proposed_events = Vec::new();
+                    for _ in 0..3 {
+                        let completions = completions.clone();
+                        proposed_events.append(
+                            &mut SharedRateResource::mk_shared_rate_event(
+                                simulation,
+                                resource.clone(),
+                                timestamp,
+                                LogNormal::from_mean_cv(ticks(10.0) as f32, 0.0).unwrap(),
+                                move |_, timestamp| {
+                                    completions.borrow_mut().push(timestamp);
+                                    Default::default()
+                                },
+                            )
+                            .unwrap(),
+                        );
+                    }
+                    proposed_events.push(ProposedEvent {
+                        due_time: DueTime::At(timestamp + ticks(5.0)),
+                        handler: Box::new(move |_, timestamp| {
+                            resource.borrow_mut().drain();
+                            let refused = SharedRateResource::mk_shared_rate_event(
+                                simulation,
+                                resource.clone(),
+                                timestamp,
+                                LogNormal::from_mean_cv(ticks(10.0) as f32, 0.0).unwrap(),
+                                |_, _| unreachable!("refused tenancy completed"),
+                            );
+                            assert_eq!(
+                                refused.err(),
+                                Some(Error::ResourceShuttingDown {
+                                    shared_rate_resource: 6
+                                })
+                            );
+                            assert!(!resource.borrow().is_drained());
+                            Default::default()
+                        }),
+                        label: Some("drain_drain"),
+                        keepalive: true,
+                        priority: 0,
+                    });
+                    proposed_events
+                }
+            }),
+        );
+        let end_reason = runner.run();
+
+        let completions = completions.borrow().clone();
+        // each progressing at a third of the rate, give or take a tick of rounding
+        assert_eq!(completions.len(), 3);
+        assert!(completions
+            .iter()
+            .all(|&completion| completion.abs_diff(ticks(30.0)) <= 1));
+        assert!(resource.borrow().is_drained());
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // a resource's status toggled from outside, as a pool manager's shutdown closure
+    // would a worker's
+    #[test]
+    fn status_toggled_from_outside_refuses_tenancies() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let resource = Rc::new(RefCell::new(
+            SharedRateResource::<BaseSimulation>::new(
+                simulation,
+                7,
+                1,
+                mk_named_rng(simulation, "shared_rate_resource/status"),
+            )
+            .unwrap(),
+        ));
+        let status = resource.borrow().get_status();
+        let completed: Rc<Cell<bool>> = Default::default();
+
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new({
+                let resource = resource.clone();
+                let completed = completed.clone();
+                move |_, timestamp| {
+                    let add_tenancy =
+                        |handler: Box<dyn FnOnce(&'static BaseSimulation, u64) -> _>| {
+                            SharedRateResource::mk_shared_rate_event(
+                                simulation,
+                                resource.clone(),
+                                timestamp,
+                                LogNormal::from_mean_cv(1000.0, 0.0).unwrap(),
+                                handler,
+                            )
+                        };
+                    let refused = Err(Error::ResourceShuttingDown {
+                        shared_rate_resource: 7,
+                    });
+
+                    // idle, so refusing the tenancy is enough to terminate it
+                    *status.borrow_mut() = Status::ShuttingDown;
+                    assert_eq!(
+                        add_tenancy(Box::new(|_, _| unreachable!())).map(|_| ()),
+                        refused
+                    );
+                    assert_eq!(resource.borrow().get_snapshot().status, Status::Terminated);
+
+                    // running again it takes tenancies, until shut down again
+                    *status.borrow_mut() = Status::Running;
+                    let proposed_events = add_tenancy(Box::new(move |_, _| {
+                        completed.set(true);
+                        Default::default()
+                    }))
+                    .unwrap();
+                    *status.borrow_mut() = Status::ShuttingDown;
+                    assert_eq!(
+                        add_tenancy(Box::new(|_, _| unreachable!())).map(|_| ()),
+                        refused
+                    );
+                    assert_eq!(resource.borrow().get_snapshot().tenancies, 1);
+                    proposed_events
+                }
+            }),
+        );
+        let end_reason = runner.run();
+
+        // the tenancy added while running still completed
+        assert!(completed.get());
+        assert!(resource.borrow().is_drained());
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // misusing the fallible operations returns an error and leaves the resource as
+    // it was
+    #[test]
+    fn misuse_is_an_error_and_leaves_the_resource_unchanged() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+
+        let zero_partitions = SharedRateResource::<BaseSimulation>::new(
+            simulation,
+            3,
+            0,
+            mk_named_rng(simulation, "shared_rate_resource/errors"),
+        );
+        assert_eq!(
+            zero_partitions.err(),
+            Some(Error::ZeroPartitions {
+                shared_rate_resource: 3
+            })
+        );
+        let resource = Rc::new(RefCell::new(
+            SharedRateResource::<BaseSimulation>::new(
+                simulation,
+                3,
+                1,
+                mk_named_rng(simulation, "shared_rate_resource/errors"),
+            )
+            .unwrap(),
+        ));
+        resource.borrow_mut().advance_to(10).unwrap();
+        let timestamp_in_past = Error::TimestampInPast {
+            shared_rate_resource: 3,
+            timestamp: 5,
+            last_updated: 10,
+        };
+        assert_eq!(
+            resource.borrow_mut().advance_to(5).err(),
+            Some(timestamp_in_past.clone())
+        );
+        let tenancy = SharedRateResource::mk_shared_rate_event(
+            simulation,
+            resource.clone(),
+            5,
+            LogNormal::from_mean_cv(1.0, 0.0).unwrap(),
+            |_, _| Default::default(),
+        );
+        assert_eq!(tenancy.err(), Some(timestamp_in_past));
+        assert_eq!(resource.borrow().get_snapshot().tenancies, 0);
+        assert_eq!(
+            resource.borrow_mut().set_concurrency_limit(Some(0)),
+            Err(Error::ZeroConcurrencyLimit {
+                shared_rate_resource: 3
+            })
+        );
     }
 }
@@ -1,65 +1,556 @@
-use std::cell::{Ref, RefCell, RefMut};
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
 use std::rc::Rc;
+use std::sync::atomic::AtomicU64;
 
-use prometheus_client::metrics::counter::{Atomic, Counter};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Registry;
 
-use rand::{Rng, SeedableRng};
+use rand::SeedableRng;
+use rand_distr::{Distribution, LogNormal};
 use rand_xoshiro::Xoshiro256StarStar;
 
-pub trait Simulation {
-    const TICKS_PER_SECOND: f64;
-    const METRICS_SAMPLING_PERIOD_SECONDS: f64;
+use crate::args_rets::*;
+use crate::distributions::*;
+use crate::error::*;
+use crate::metric_samples::*;
+use crate::shared_rate_resource::SRRSimulation;
 
+pub trait Simulation {
     fn get_id(&self) -> u64;
+    // ticks are the unit of simulated time every timestamp and delay is in
+    fn get_ticks_per_second(&self) -> f64;
+    // how often periodic metric collection is meant to run
+    fn get_metrics_sampling_period_seconds(&self) -> f64;
+    // floor in ticks for every sampled delay, and for tenancies' resource time
+    fn get_min_delay(&self) -> u64;
+    fn set_min_delay(&self, min_delay: u64);
 
     fn get_events_dispatched_metric(&self) -> &Counter;
+    fn get_dispatch_gap_metric(&self) -> &Histogram;
+    fn get_pending_event_horizon_metric(&self) -> &Histogram;
+    fn get_handler_panics_metric(&self) -> &Counter;
+    fn get_instance_launch_failures_metric(&self) -> &Family<Vec<(String, String)>, Counter>;
+    fn get_simulated_seconds_metric(&self) -> &Gauge<f64, AtomicU64>;
 
     fn borrow_metric_registry(&self) -> Ref<'_, Registry>;
     fn borrow_metric_registry_mut(&self) -> RefMut<'_, Registry>;
 
     fn borrow_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar>;
 
+    /// Draw from the simulation's shared stream, which advances with every draw,
+    /// so handlers running at the same timestamp get distinct but reproducible
+    /// values. Cloning the rng instead would replay the same values to each clone.
+    fn with_rng<T>(&self, f: impl FnOnce(&mut Xoshiro256StarStar) -> T) -> T {
+        f(&mut self.borrow_rng_mut())
+    }
+
+    // dedicated to service times, so that changes in how other events are
+    // scheduled don't perturb the sequence of service times drawn
+    fn borrow_service_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar>;
+
+    // keys of the periodic handlers scheduled through mk_singleton_event
+    fn borrow_singleton_keys_mut(&self) -> RefMut<'_, HashSet<&'static str>>;
+
     // TODO events in heap collector
 }
 
 pub struct BaseSimulation {
     id: u64,
+    ticks_per_second: f64,
+    metrics_sampling_period_seconds: f64,
+    min_delay: Cell<u64>,
     events_dispatched_metric: Counter,
+    dispatch_gap_metric: Histogram,
+    pending_event_horizon_metric: Histogram,
+    handler_panics_metric: Counter,
+    instance_launch_failures_metric: Family<Vec<(String, String)>, Counter>,
+    simulated_seconds_metric: Gauge<f64, AtomicU64>,
+    tenancy_stretch_metric: Family<Vec<(String, String)>, Histogram>,
+    tenancy_admission_wait_metric: Family<Vec<(String, String)>, Histogram>,
+    tenancy_service_time_metric: Family<Vec<(String, String)>, Histogram>,
     metric_registry: RefCell<Registry>,
     rng: RefCell<Xoshiro256StarStar>,
+    service_rng: RefCell<Xoshiro256StarStar>,
+    singleton_keys: RefCell<HashSet<&'static str>>,
+}
+
+/// A stream that doesn't overlap with `Xoshiro256StarStar::seed_from_u64(seed)`'s
+pub fn mk_service_rng(seed: u64) -> Xoshiro256StarStar {
+    let mut rng = Xoshiro256StarStar::seed_from_u64(seed);
+    rng.jump();
+    rng
+}
+
+/// A stream determined only by the simulation's id and `name`, e.g. a queue's name,
+/// so that it doesn't depend on how many draws were made from other streams before
+/// it was created
+pub fn mk_named_rng<S: Simulation>(simulation: &S, name: &str) -> Xoshiro256StarStar {
+    mk_seeded_named_rng(simulation.get_id(), name)
+}
+
+/// Like `mk_named_rng`, from an explicit seed rather than the simulation's id
+pub fn mk_seeded_named_rng(seed: u64, name: &str) -> Xoshiro256StarStar {
+    let mut hasher = Fnv1aHasher::default();
+    hasher.write(&seed.to_le_bytes());
+    hasher.write(name.as_bytes());
+    Xoshiro256StarStar::seed_from_u64(hasher.finish())
+}
+
+/// FNV-1a, which unlike std's hashers is specified to be stable across releases
+/// and isn't randomly keyed per process
+#[derive(Clone, Debug)]
+pub struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Fnv1aHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hasher state for sets and maps whose iteration order affects the simulation,
+/// so that it's reproducible across processes given the same seed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeterministicState {
+    pub seed: u64,
+}
+
+impl DeterministicState {
+    /// Seeded from the simulation's id, like its rngs
+    pub fn new<S: Simulation>(simulation: &S) -> Self {
+        DeterministicState {
+            seed: simulation.get_id(),
+        }
+    }
+}
+
+impl BuildHasher for DeterministicState {
+    type Hasher = Fnv1aHasher;
+
+    fn build_hasher(&self) -> Fnv1aHasher {
+        let mut hasher = Fnv1aHasher::default();
+        hasher.write_u64(self.seed);
+        hasher
+    }
+}
+
+/// Draw a service time from the simulation's service stream, returned as a
+/// fixed delay suitable for `DueTime::After`.
+pub fn sample_service_time<S: Simulation>(
+    simulation: &S,
+    distribution: &LogNormal<f32>,
+) -> LogNormal<f32> {
+    let t = distribution.sample(&mut *simulation.borrow_service_rng_mut());
+    delay_from_mean_cv(t, 0.0)
+}
+
+/// Like `sample_service_time`, scaled by the size of the item it's for
+pub fn sample_sized_service_time<S: Simulation>(
+    simulation: &S,
+    distribution: &LogNormal<f32>,
+    context: &ItemContext,
+) -> LogNormal<f32> {
+    let t = distribution.sample(&mut *simulation.borrow_service_rng_mut()) as f64;
+    delay_from_mean_cv((t * context.get_size()) as f32, 0.0)
+}
+
+/// Schedule `handler` to run at `first_due_time` and then every `period` for the
+/// rest of the simulation, as infrastructure that doesn't keep it alive. Returns
+/// `None` if a handler was already scheduled under `key`, so that a collector or
+/// autoscaler started twice by mistake doesn't run twice per period.
+pub fn mk_singleton_event<S: Simulation + 'static>(
+    simulation: &S,
+    key: &'static str,
+    first_due_time: LogNormal<f32>,
+    period: LogNormal<f32>,
+    handler: impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+) -> Option<ProposedEvent<S>> {
+    if !simulation.borrow_singleton_keys_mut().insert(key) {
+        return None;
+    }
+
+    Some(mk_periodic_event(
+        key,
+        first_due_time,
+        period,
+        Rc::new(handler),
+    ))
+}
+
+fn mk_periodic_event<S: Simulation + 'static>(
+    key: &'static str,
+    due_time: LogNormal<f32>,
+    period: LogNormal<f32>,
+    handler: Rc<dyn Fn(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+) -> ProposedEvent<S> {
+    ProposedEvent {
+        due_time: DueTime::After(due_time),
+        handler: Box::new(move |simulation, timestamp| {
+            let mut proposed_events = handler(simulation, timestamp);
+            proposed_events.push(mk_periodic_event(key, period, period, handler));
+            proposed_events
+        }),
+        label: Some(key),
+        keepalive: false,
+        priority: 0,
+    }
+}
+
+/// Call `arrival` after every interval drawn from `interarrival` until `stop` is
+/// set, e.g. by another handler modelling a traffic cutover, after which no
+/// further arrivals happen. Unlike a singleton event, pending arrivals keep the
+/// simulation alive.
+pub fn mk_arrival_process<S: Simulation + 'static>(
+    label: &'static str,
+    interarrival: LogNormal<f32>,
+    stop: Rc<Cell<bool>>,
+    arrival: impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+) -> ProposedEvent<S> {
+    mk_modulated_arrival_process(label, move || interarrival, stop, arrival)
+}
+
+/// Like `mk_arrival_process`, but each interval is drawn from whichever
+/// distribution `interarrival` returns at the time, e.g. one set by a state
+/// machine. A change takes effect from the next arrival scheduled, so there is
+/// only ever one arrival pending.
+pub fn mk_modulated_arrival_process<S: Simulation + 'static>(
+    label: &'static str,
+    interarrival: impl Fn() -> LogNormal<f32> + 'static,
+    stop: Rc<Cell<bool>>,
+    arrival: impl Fn(&'static S, u64) -> Vec<ProposedEvent<S>> + 'static,
+) -> ProposedEvent<S> {
+    mk_arrival_event(label, Rc::new(interarrival), stop, Rc::new(arrival))
+}
+
+fn mk_arrival_event<S: Simulation + 'static>(
+    label: &'static str,
+    interarrival: Rc<dyn Fn() -> LogNormal<f32>>,
+    stop: Rc<Cell<bool>>,
+    arrival: Rc<dyn Fn(&'static S, u64) -> Vec<ProposedEvent<S>>>,
+) -> ProposedEvent<S> {
+    ProposedEvent {
+        due_time: DueTime::After(interarrival()),
+        handler: Box::new(move |simulation, timestamp| {
+            if stop.get() {
+                return Default::default();
+            }
+            let mut proposed_events = arrival(simulation, timestamp);
+            proposed_events.push(mk_arrival_event(label, interarrival, stop, arrival));
+            proposed_events
+        }),
+        label: Some(label),
+        keepalive: true,
+        priority: 0,
+    }
+}
+
+/// Bucket bounds for histograms, usable as the constructor of a histogram `Family`
+#[derive(Clone, Debug)]
+pub struct HistogramBuckets(pub Vec<f64>);
+
+impl HistogramBuckets {
+    pub fn exponential(start: f64, factor: f64, length: u16) -> Self {
+        HistogramBuckets(Vec::from_iter(exponential_buckets(start, factor, length)))
+    }
+}
+
+impl MetricConstructor<Histogram> for HistogramBuckets {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.0.iter().copied())
+    }
+}
+
+/// Runtime configuration for constructing a simulation along with its metric
+/// registry
+pub struct SimulationBuilder {
+    id: u64,
+    labels: Vec<(String, String)>,
+    ticks_per_second: f64,
+    metrics_sampling_period_seconds: f64,
+    min_delay: u64,
+    dispatch_gap_buckets: HistogramBuckets,
+    worker_token_duration_buckets: HistogramBuckets,
+}
+
+pub trait FromSimulationBuilder {
+    fn from_builder(builder: &SimulationBuilder) -> Self;
+}
+
+impl SimulationBuilder {
+    /// `id` also seeds the simulation's rngs
+    pub fn new(id: u64) -> Self {
+        SimulationBuilder {
+            id,
+            labels: Default::default(),
+            ticks_per_second: 1000.0,
+            metrics_sampling_period_seconds: 15.0,
+            min_delay: 1,
+            dispatch_gap_buckets: HistogramBuckets::exponential(0.001, 4.0, 12),
+            // from one tick at the default tick rate; observations below the first
+            // bucket still count exactly towards the histogram's _sum and _count
+            worker_token_duration_buckets: HistogramBuckets::exponential(0.001, 2.0, 20),
+        }
+    }
+
+    /// Add a label to every metric, after `simulation_id`
+    pub fn label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+
+    /// 1000 by default, i.e. millisecond ticks. Delays are floored at whole
+    /// ticks, so a coarser rate makes short delays less precise.
+    pub fn ticks_per_second(mut self, ticks_per_second: f64) -> Self {
+        self.ticks_per_second = ticks_per_second;
+        self
+    }
+
+    /// 15s by default
+    pub fn metrics_sampling_period_seconds(mut self, seconds: f64) -> Self {
+        self.metrics_sampling_period_seconds = seconds;
+        self
+    }
+
+    /// 1 tick by default. See `SimulationRunner::set_min_delay`, which changes it
+    /// after the simulation is built.
+    pub fn min_delay(mut self, min_delay: u64) -> Self {
+        self.min_delay = min_delay;
+        self
+    }
+
+    pub fn dispatch_gap_buckets(mut self, buckets: HistogramBuckets) -> Self {
+        self.dispatch_gap_buckets = buckets;
+        self
+    }
+
+    pub fn worker_token_duration_buckets(mut self, buckets: HistogramBuckets) -> Self {
+        self.worker_token_duration_buckets = buckets;
+        self
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_ticks_per_second(&self) -> f64 {
+        self.ticks_per_second
+    }
+
+    pub fn get_metrics_sampling_period_seconds(&self) -> f64 {
+        self.metrics_sampling_period_seconds
+    }
+
+    pub fn get_min_delay(&self) -> u64 {
+        self.min_delay
+    }
+
+    pub fn get_dispatch_gap_buckets(&self) -> &HistogramBuckets {
+        &self.dispatch_gap_buckets
+    }
+
+    pub fn get_worker_token_duration_buckets(&self) -> &HistogramBuckets {
+        &self.worker_token_duration_buckets
+    }
+
+    pub fn build_registry(&self) -> Registry {
+        let simulation_id_label = ("simulation_id".to_owned(), format!("{:016x}", self.id));
+        Registry::with_labels(
+            [simulation_id_label]
+                .into_iter()
+                .chain(self.labels.iter().cloned())
+                .map(|(name, value)| (Cow::from(name), Cow::from(value))),
+        )
+    }
+
+    pub fn build<T: FromSimulationBuilder>(&self) -> T {
+        T::from_builder(self)
+    }
+}
+
+impl FromSimulationBuilder for BaseSimulation {
+    fn from_builder(builder: &SimulationBuilder) -> Self {
+        Self::new_with_builder(builder, builder.build_registry())
+    }
 }
 
 impl BaseSimulation {
-    pub fn new(id: u64, metric_registry: Registry) -> Self {
+    /// Same as `SimulationBuilder::new(id).build()`, so every metric carries
+    /// the `simulation_id` label
+    pub fn new(id: u64) -> Self {
+        SimulationBuilder::new(id).build()
+    }
+
+    /// Builds with defaults like `new`, but registers metrics on the caller's
+    /// `metric_registry` as it is, without adding a `simulation_id` label
+    pub fn with_registry(id: u64, metric_registry: Registry) -> Self {
+        Self::new_with_builder(&SimulationBuilder::new(id), metric_registry)
+    }
+
+    fn new_with_builder(builder: &SimulationBuilder, metric_registry: Registry) -> Self {
+        let id = builder.get_id();
         let r = BaseSimulation {
-            id: id,
+            id,
+            ticks_per_second: builder.get_ticks_per_second(),
+            metrics_sampling_period_seconds: builder.get_metrics_sampling_period_seconds(),
+            min_delay: builder.get_min_delay().into(),
             events_dispatched_metric: Default::default(),
+            dispatch_gap_metric: builder.get_dispatch_gap_buckets().new_metric(),
+            pending_event_horizon_metric: Histogram::new(exponential_buckets(0.001, 4.0, 12)),
+            handler_panics_metric: Default::default(),
+            instance_launch_failures_metric: Default::default(),
+            simulated_seconds_metric: Default::default(),
+            tenancy_stretch_metric: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1.0, 1.5, 12))
+            }),
+            tenancy_admission_wait_metric: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.001, 4.0, 12))
+            }),
+            tenancy_service_time_metric: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.001, 4.0, 12))
+            }),
             metric_registry: metric_registry.into(),
             rng: Xoshiro256StarStar::seed_from_u64(id).into(),
+            service_rng: mk_service_rng(id).into(),
+            singleton_keys: Default::default(),
         };
         r.borrow_metric_registry_mut().register(
             "events_dispatched",
             "Number of events dispatched in simulation",
             r.events_dispatched_metric.clone(),
         );
+        r.borrow_metric_registry_mut().register(
+            "dispatch_gap",
+            "Simulated time between consecutive timestamps with dispatched events",
+            r.dispatch_gap_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "pending_event_horizon",
+            "Simulated time until pending events are due, as sampled by the runner",
+            r.pending_event_horizon_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "handler_panics",
+            "Number of event handlers that panicked and were caught by the runner",
+            r.handler_panics_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "instance_launch_failures",
+            "Number of pool instance launches that failed, each to be retried",
+            r.instance_launch_failures_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "simulated_seconds",
+            "Simulated time elapsed",
+            r.simulated_seconds_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "tenancy_stretch",
+            "Ratio of elapsed to required time of shared rate resource tenancies",
+            r.tenancy_stretch_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "tenancy_admission_wait",
+            "Simulated time shared rate resource tenancies waited under the concurrency limit",
+            r.tenancy_admission_wait_metric.clone(),
+        );
+        r.borrow_metric_registry_mut().register(
+            "tenancy_service_time",
+            "Simulated time from admission to completion of shared rate resource tenancies",
+            r.tenancy_service_time_metric.clone(),
+        );
 
         r
     }
+
+    /// Sum and count of the stretch of completed tenancies of the resource with
+    /// metric labels `labels`
+    pub fn get_tenancy_stretch_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        get_histogram_sum_count(&self.tenancy_stretch_metric, labels)
+    }
+
+    /// Like `get_tenancy_stretch_sum_count`, in seconds waited for admission
+    pub fn get_tenancy_admission_wait_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        get_histogram_sum_count(&self.tenancy_admission_wait_metric, labels)
+    }
+
+    /// Like `get_tenancy_stretch_sum_count`, in seconds from admission to completion
+    pub fn get_tenancy_service_time_sum_count(
+        &self,
+        labels: &[(String, String)],
+    ) -> Result<(f64, u64), Error> {
+        get_histogram_sum_count(&self.tenancy_service_time_metric, labels)
+    }
 }
 
 impl Simulation for BaseSimulation {
-    const TICKS_PER_SECOND: f64 = 1000.0;
-    const METRICS_SAMPLING_PERIOD_SECONDS: f64 = 15.0;
-
     fn get_id(&self) -> u64 {
         self.id
     }
 
+    fn get_ticks_per_second(&self) -> f64 {
+        self.ticks_per_second
+    }
+
+    fn get_metrics_sampling_period_seconds(&self) -> f64 {
+        self.metrics_sampling_period_seconds
+    }
+
+    fn get_min_delay(&self) -> u64 {
+        self.min_delay.get()
+    }
+
+    fn set_min_delay(&self, min_delay: u64) {
+        self.min_delay.set(min_delay);
+    }
+
     fn get_events_dispatched_metric(&self) -> &Counter {
         &self.events_dispatched_metric
     }
 
+    fn get_dispatch_gap_metric(&self) -> &Histogram {
+        &self.dispatch_gap_metric
+    }
+
+    fn get_pending_event_horizon_metric(&self) -> &Histogram {
+        &self.pending_event_horizon_metric
+    }
+
+    fn get_handler_panics_metric(&self) -> &Counter {
+        &self.handler_panics_metric
+    }
+
+    fn get_instance_launch_failures_metric(&self) -> &Family<Vec<(String, String)>, Counter> {
+        &self.instance_launch_failures_metric
+    }
+
+    fn get_simulated_seconds_metric(&self) -> &Gauge<f64, AtomicU64> {
+        &self.simulated_seconds_metric
+    }
+
     fn borrow_metric_registry(&self) -> Ref<'_, Registry> {
         self.metric_registry.borrow()
     }
@@ -71,4 +562,175 @@ impl Simulation for BaseSimulation {
     fn borrow_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar> {
         self.rng.borrow_mut()
     }
+
+    fn borrow_service_rng_mut(&self) -> RefMut<'_, Xoshiro256StarStar> {
+        self.service_rng.borrow_mut()
+    }
+
+    fn borrow_singleton_keys_mut(&self) -> RefMut<'_, HashSet<&'static str>> {
+        self.singleton_keys.borrow_mut()
+    }
+}
+
+impl SRRSimulation for BaseSimulation {
+    fn get_tenancy_stretch_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+        &self.tenancy_stretch_metric
+    }
+
+    fn get_tenancy_admission_wait_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+        &self.tenancy_admission_wait_metric
+    }
+
+    fn get_tenancy_service_time_metric(&self) -> &Family<Vec<(String, String)>, Histogram> {
+        &self.tenancy_service_time_metric
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use prometheus_client::encoding::text::encode;
+
+    use super::*;
+    use crate::main_loop::*;
+
+    #[test]
+    fn builder_configures_tick_rate_period_and_labels() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(
+            SimulationBuilder::new(1)
+                .ticks_per_second(10.0)
+                .metrics_sampling_period_seconds(5.0)
+                .label("region", "test")
+                .build(),
+        ));
+        assert_eq!(simulation.get_metrics_sampling_period_seconds(), 5.0);
+
+        let fired_at: Rc<Cell<Option<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let fired_at = fired_at.clone();
+            Box::new(move |simulation, _| {
+                vec![ProposedEvent::after_seconds(simulation, 2.0, 0.0)
+                    .named("after_two_seconds")
+                    .handler(move |_, timestamp| {
+                        fired_at.set(Some(timestamp));
+                        Default::default()
+                    })]
+            })
+        });
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        // two seconds at ten ticks a second
+        assert_eq!(fired_at.get(), Some(20));
+        assert_eq!(simulation.get_simulated_seconds_metric().get(), 2.0);
+
+        let mut text = String::new();
+        encode(&mut text, &simulation.borrow_metric_registry()).unwrap();
+        assert!(
+            text.contains(
+                r#"simulated_seconds{simulation_id="0000000000000001",region="test"} 2.0"#
+            ),
+            "{text}"
+        );
+    }
+
+    #[test]
+    fn arrival_process_stops_once_the_flag_is_set() {
+        let simulation: &'static BaseSimulation =
+            Box::leak(Box::new(SimulationBuilder::new(1).build()));
+        let arrival_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let arrival_timestamps = arrival_timestamps.clone();
+            Box::new(move |_, _| {
+                let stop: Rc<Cell<bool>> = Default::default();
+                vec![
+                    mk_arrival_process(
+                        "arrival",
+                        LogNormal::from_mean_cv(10.0, 0.0).unwrap(),
+                        stop.clone(),
+                        move |_, timestamp| {
+                            arrival_timestamps.borrow_mut().push(timestamp);
+                            Default::default()
+                        },
+                    ),
+                    ProposedEvent::at(55).named("cutover").handler(move |_, _| {
+                        stop.set(true);
+                        Default::default()
+                    }),
+                ]
+            })
+        });
+        // the arrival pending at 60 sees the flag and doesn't reschedule
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+        assert_eq!(*arrival_timestamps.borrow(), vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn new_labels_every_metric_with_simulation_id() {
+        let get_events_dispatched_labels = |simulation: &BaseSimulation| {
+            simulation.get_events_dispatched_metric().inc();
+            extract_samples(&simulation.borrow_metric_registry())
+                .unwrap()
+                .into_iter()
+                .find(|sample| sample.name == "events_dispatched_total")
+                .unwrap()
+                .labels
+        };
+
+        // without the caller setting it
+        assert_eq!(
+            get_events_dispatched_labels(&BaseSimulation::new(42)),
+            vec![("simulation_id".to_owned(), "000000000000002a".to_owned())]
+        );
+        // while a caller's own registry is left as it is
+        assert_eq!(
+            get_events_dispatched_labels(&BaseSimulation::with_registry(42, Default::default())),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn arrival_process_stops_at_cutover() {
+        let simulation: &'static BaseSimulation = Box::leak(Box::new(BaseSimulation::new(1)));
+        let ticks = |seconds: f32| seconds * simulation.get_ticks_per_second() as f32;
+        let arrival_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let cutover_timestamp: Rc<Cell<Option<u64>>> = Default::default();
+
+        let mut runner = SimulationRunner::new(simulation, {
+            let arrival_timestamps = arrival_timestamps.clone();
+            let cutover_timestamp = cutover_timestamp.clone();
+            Box::new(move |_, _| {
+                // steady arrivals every second or so, until they're cut over at 30s
+                let stop: Rc<Cell<bool>> = Default::default();
+                let arrivals = mk_arrival_process(
+                    "steady_arrival",
+                    LogNormal::from_mean_cv(ticks(1.0), 0.2).unwrap(),
+                    stop.clone(),
+                    move |_, timestamp| {
+                        arrival_timestamps.borrow_mut().push(timestamp);
+                        Default::default()
+                    },
+                );
+                let cutover = ProposedEvent {
+                    due_time: DueTime::After(LogNormal::from_mean_cv(ticks(30.0), 0.0).unwrap()),
+                    handler: Box::new(move |_, timestamp| {
+                        cutover_timestamp.set(Some(timestamp));
+                        stop.set(true);
+                        Default::default()
+                    }),
+                    label: Some("steady_arrival_cutover"),
+                    keepalive: false,
+                    priority: 0,
+                };
+                vec![arrivals, cutover]
+            })
+        });
+        let end_reason = runner.run();
+
+        let arrival_timestamps = arrival_timestamps.borrow();
+        let cutover_timestamp = cutover_timestamp.get().unwrap();
+        assert!((25..=35).contains(&arrival_timestamps.len()));
+        assert!(arrival_timestamps.iter().all(|&ts| ts <= cutover_timestamp));
+        // the pending arrival after the cutover drops out rather than rescheduling
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
 }
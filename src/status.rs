@@ -1,4 +1,6 @@
-#[derive(PartialEq)]
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub enum Status {
     Running,
     ShuttingDown,
@@ -0,0 +1,238 @@
+use serde::Serialize;
+
+use crate::error::*;
+use crate::main_loop::*;
+use crate::metric_samples::*;
+use crate::topology::*;
+
+/// A queue's totals over the run
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueueSummary {
+    pub name: String,
+    // accepted, whether dispatched straight away or parked
+    pub enqueued: u64,
+    // handed to a worker, including items stolen by workers not subscribed to it
+    pub processed: u64,
+    pub rejected: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PoolManagerSummary {
+    pub name: String,
+    pub final_instances: u32,
+    pub peak_instances: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SharedRateResourceSummary {
+    pub id: u64,
+    pub peak_tenancies: usize,
+    // from when the resource was first updated to when it was last, whether or not
+    // it keeps cumulative counters
+    pub utilization_ratio: Option<f64>,
+}
+
+/// What a run did, at a glance, e.g. to log at its end or to compare two runs
+/// without scraping their metrics. Covers every live registered subsystem, in
+/// registration order. Subsystems are only held weakly by the registry, so any
+/// dropped before the summary is taken are left out of it altogether, e.g. a queue
+/// torn down mid run - hold on to them until then to have them summarized.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub end_reason: EndReason,
+    pub events_dispatched: u64,
+    pub final_timestamp: u64,
+    pub queues: Vec<QueueSummary>,
+    pub pool_managers: Vec<PoolManagerSummary>,
+    pub shared_rate_resources: Vec<SharedRateResourceSummary>,
+}
+
+impl<S: TopologySimulation> SimulationRunner<S> {
+    /// Summarize the run so far, given how it ended. Must not be called while any
+    /// registered subsystem is mutably borrowed. Fails if the metrics it reads
+    /// can't be.
+    pub fn get_summary(&self, end_reason: EndReason) -> Result<RunSummary, Error> {
+        let simulation = self.get_simulation();
+        let mut subsystems = simulation.borrow_subsystems_mut();
+
+        let mut queues = Vec::new();
+        for queue in subsystems.get_queues() {
+            let queue = queue.borrow();
+            queues.push(QueueSummary {
+                name: queue.name.clone(),
+                enqueued: get_counter_value(
+                    simulation.get_enqueue_dispatched_metric(),
+                    &queue.metric_labels,
+                )? + get_counter_value(
+                    simulation.get_enqueue_parked_metric(),
+                    &queue.metric_labels,
+                )?,
+                processed: queue.processed,
+                rejected: get_counter_value(
+                    simulation.get_enqueue_rejected_metric(),
+                    &queue.metric_labels,
+                )?,
+            });
+        }
+        let pool_managers = Vec::from_iter(subsystems.get_pool_managers().iter().map(|pm| {
+            let pm = pm.borrow();
+            PoolManagerSummary {
+                name: pm.name.clone(),
+                final_instances: pm.get_snapshot().current_instances,
+                peak_instances: pm.peak_instances,
+            }
+        }));
+        let shared_rate_resources =
+            Vec::from_iter(subsystems.get_shared_rate_resources().iter().map(|srr| {
+                let srr = srr.borrow();
+                SharedRateResourceSummary {
+                    id: srr.get_snapshot().id,
+                    peak_tenancies: srr.get_peak_tenancies(),
+                    utilization_ratio: srr.get_total_utilization_ratio(),
+                }
+            }));
+
+        Ok(RunSummary {
+            end_reason,
+            events_dispatched: self.get_events_dispatched(),
+            final_timestamp: self.get_current_timestamp(),
+            queues,
+            pool_managers,
+            shared_rate_resources,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::args_rets::*;
+    use crate::distributions::*;
+    use crate::queue::*;
+    use crate::shared_rate_resource::*;
+    use crate::simulation::*;
+    use crate::testing::*;
+
+    const SUMMARY_ITEMS: usize = 10;
+
+    // a pool scaled to 3 workers and back to 2 serving items that each hold a worker
+    // for 1s and then need 0.5s of a 2-partition resource, plus two reject_if_busy
+    // items arriving while both workers are busy
+    fn mk_summary_bootstrap<S: TopologySimulation>(
+        queue: Rc<RefCell<Queue<S>>>,
+        resource: Rc<RefCell<SharedRateResource<S>>>,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let mut proposed_events = Vec::from_iter((0..SUMMARY_ITEMS).map(|_| {
+                let resource = resource.clone();
+                ProposedEvent::at(timestamp)
+                    .named("summary_arrival")
+                    .handler(Queue::mk_enqueued_handler(
+                        queue.clone(),
+                        |simulation, _, worker_token| {
+                            vec![ProposedEvent::after_seconds(simulation, 1.0, 0.0)
+                                .named("summary_service_complete")
+                                .handler(WorkerToken::mk_token_restoring_handler(
+                                    move |simulation: &'static S, timestamp| {
+                                        let proposed_events =
+                                            SharedRateResource::mk_shared_rate_event(
+                                                simulation,
+                                                resource,
+                                                timestamp,
+                                                delay_from_mean_cv(
+                                                    (0.5 * simulation.get_ticks_per_second())
+                                                        as f32,
+                                                    0.0,
+                                                ),
+                                                |_, _| Default::default(),
+                                            )
+                                            .unwrap();
+                                        (proposed_events, vec![worker_token])
+                                    },
+                                ))]
+                        },
+                    ))
+            }));
+            proposed_events.extend((0..2).map(|_| {
+                ProposedEvent::after_seconds(simulation, 0.5, 0.0)
+                    .named("summary_rejected_arrival")
+                    .handler(Queue::mk_enqueued_handler_with_options(
+                        queue.clone(),
+                        EnqueueOptions {
+                            reject_if_busy: true,
+                            ..Default::default()
+                        },
+                        |_, _, _| unreachable!("both workers are busy"),
+                    ))
+            }));
+            proposed_events
+        }
+    }
+
+    #[test]
+    fn summary_covers_subsystems_still_held() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        // registered subsystems are only held weakly, so kept here to be summarized
+        let queue = Queue::new(simulation, "summary").into_registered(simulation);
+        // unlike this one, dropped before the summary and so left out of it
+        let dropped_queue = Queue::new(simulation, "dropped").into_registered(simulation);
+        let pool_manager =
+            mk_worker_pool_manager(simulation, 0, queue.clone(), "summary", Default::default())
+                .into_registered(simulation);
+        pool_manager.borrow_mut().set_desired_instances_absolute(3);
+        pool_manager.borrow_mut().set_desired_instances_absolute(2);
+        let resource = SharedRateResource::new(
+            simulation,
+            7,
+            2,
+            mk_named_rng(simulation, "shared_rate_resource/summary"),
+        )
+        .unwrap()
+        .into_registered(simulation);
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(mk_summary_bootstrap(queue, resource.clone())),
+        );
+        let end_reason = runner.run();
+
+        drop(dropped_queue);
+        let summary = runner.get_summary(end_reason).unwrap();
+        assert_eq!(summary.end_reason, EndReason::EventHeapDrained);
+        // the initial event, 12 arrivals, each item's service completion and a resource
+        // wakeup for each pair of items, which are served in step
+        assert_eq!(
+            summary.events_dispatched,
+            13 + SUMMARY_ITEMS as u64 + SUMMARY_ITEMS as u64 / 2
+        );
+        // 5 rounds of service, the last followed by 0.5s of the resource
+        assert_eq!(summary.final_timestamp, 5_500);
+        assert_eq!(
+            summary.queues,
+            [QueueSummary {
+                name: "summary".into(),
+                enqueued: SUMMARY_ITEMS as u64,
+                processed: SUMMARY_ITEMS as u64,
+                rejected: 2,
+            }]
+        );
+        assert_eq!(
+            summary.pool_managers,
+            [PoolManagerSummary {
+                name: "summary".into(),
+                final_instances: 2,
+                peak_instances: 3,
+            }]
+        );
+        let [resource] = &summary.shared_rate_resources[..] else {
+            panic!("expected one resource");
+        };
+        assert_eq!((resource.id, resource.peak_tenancies), (7, 2));
+        // both partitions busy for 0.5s of every 1s from 1s to 5.5s, the idle resets
+        // of the resource's counters notwithstanding
+        assert!((resource.utilization_ratio.unwrap() - 5.0 / 9.0).abs() < 1e-9);
+    }
+}
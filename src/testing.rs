@@ -0,0 +1,144 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use rand_distr::{Distribution, Exp};
+use rand_xoshiro::Xoshiro256StarStar;
+
+use crate::args_rets::*;
+use crate::pool_manager::*;
+use crate::queue::*;
+use crate::simulation::*;
+use crate::status::*;
+
+pub fn mk_worker<S: QueueSimulation + 'static>(
+    simulation: &'static S,
+    id: u64,
+    queue: &Rc<RefCell<Queue<S>>>,
+) -> Worker<S> {
+    Worker {
+        id,
+        subscribed_queues: vec![queue.clone()],
+        subscription_weights: Default::default(),
+        status: Rc::new(Status::Running.into()),
+        allow_drop: false,
+        rng: mk_named_rng(simulation, &format!("worker/{id}")),
+        metric_labels: vec![("worker_id".to_owned(), id.to_string())],
+        pool: None,
+        queue_selection: Default::default(),
+        steal_queues: Default::default(),
+        ext: Default::default(),
+    }
+}
+
+// a pool of workers subscribed to `queue` and listening from `timestamp`,
+// labelled with the pool's name
+pub fn mk_worker_pool_manager<S: QueueSimulation + 'static>(
+    simulation: &'static S,
+    timestamp: u64,
+    queue: Rc<RefCell<Queue<S>>>,
+    pool: &str,
+    next_worker_id: Rc<Cell<u64>>,
+) -> PoolManager {
+    PoolManager {
+        name: pool.into(),
+        instance_constructor: {
+            let pool = pool.to_owned();
+            Box::new(move || {
+                let id = next_worker_id.replace(next_worker_id.get() + 1);
+                let mut worker = mk_worker(simulation, id, &queue);
+                worker.pool = Some(pool.clone());
+                let status = worker.status.clone();
+                worker.listen(simulation, timestamp);
+                Box::new(move || {
+                    *status.borrow_mut() = Status::ShuttingDown;
+                })
+            })
+        },
+        instances: Default::default(),
+        metric_labels: vec![("pool_manager_name".into(), pool.into())],
+        ramp: None,
+        launch_failures: None,
+        peak_instances: 0,
+    }
+}
+
+// draws an exponential interval, in whole ticks
+pub fn sample_exp_ticks<S: Simulation>(
+    simulation: &S,
+    rng: &mut Xoshiro256StarStar,
+    rate_per_second: f64,
+) -> u64 {
+    (Exp::new(rate_per_second).unwrap().sample(rng) * simulation.get_ticks_per_second()).round()
+        as u64
+}
+
+// Poisson arrivals, each enqueued with `options` and holding its worker for an
+// exponential service time, until `remaining` have arrived
+#[derive(Clone)]
+pub struct MarkovianArrivals {
+    pub arrivals_per_second: f64,
+    pub services_per_second: f64,
+    pub options: EnqueueOptions,
+    pub remaining: u64,
+}
+
+pub fn mk_markovian_arrival_event<S: QueueSimulation + 'static>(
+    queue: Rc<RefCell<Queue<S>>>,
+    rng: Rc<RefCell<Xoshiro256StarStar>>,
+    arrivals: MarkovianArrivals,
+    due_timestamp: u64,
+) -> ProposedEvent<S> {
+    ProposedEvent::at(due_timestamp)
+        .named("markovian_arrival")
+        .handler(move |simulation, timestamp| {
+            let service_ticks = sample_exp_ticks(
+                simulation,
+                &mut rng.borrow_mut(),
+                arrivals.services_per_second,
+            );
+            let mut proposed_events = Queue::mk_enqueued_handler_with_options(
+                queue.clone(),
+                arrivals.options.clone(),
+                move |_, timestamp, worker_token| {
+                    vec![ProposedEvent::at(timestamp + service_ticks)
+                        .named("markovian_service_complete")
+                        .handler(WorkerToken::mk_token_restoring_handler(move |_, _| {
+                            (Default::default(), vec![worker_token])
+                        }))]
+                },
+            )(simulation, timestamp);
+
+            if arrivals.remaining > 1 {
+                let interarrival_ticks = sample_exp_ticks(
+                    simulation,
+                    &mut rng.borrow_mut(),
+                    arrivals.arrivals_per_second,
+                );
+                proposed_events.push(mk_markovian_arrival_event(
+                    queue,
+                    rng,
+                    MarkovianArrivals {
+                        remaining: arrivals.remaining - 1,
+                        ..arrivals
+                    },
+                    timestamp + interarrival_ticks,
+                ));
+            }
+            proposed_events
+        })
+}
+
+// a trace writer that can still be read while its runner owns it
+#[derive(Clone, Default)]
+pub struct SharedTraceBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedTraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
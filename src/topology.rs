@@ -0,0 +1,367 @@
+use std::cell::{RefCell, RefMut};
+use std::rc::{Rc, Weak};
+
+use serde::Serialize;
+
+use crate::pool_manager::*;
+use crate::queue::*;
+use crate::shared_rate_resource::*;
+
+/// Weak references to a simulation's queues, pools and shared rate resources,
+/// so that cross-cutting features can enumerate them without keeping them alive
+/// past the handlers that own them. Subsystems that have since been dropped are
+/// skipped, and pruned as they're found.
+pub struct SubsystemRegistry<S: QueueSimulation + SRRSimulation + 'static> {
+    queues: Vec<Weak<RefCell<Queue<S>>>>,
+    pool_managers: Vec<Weak<RefCell<PoolManager>>>,
+    shared_rate_resources: Vec<Weak<RefCell<SharedRateResource<S>>>>,
+}
+
+impl<S: QueueSimulation + SRRSimulation + 'static> Default for SubsystemRegistry<S> {
+    fn default() -> Self {
+        SubsystemRegistry {
+            queues: Default::default(),
+            pool_managers: Default::default(),
+            shared_rate_resources: Default::default(),
+        }
+    }
+}
+
+// upgrades the live references in registration order, dropping the dead ones
+fn upgrade_all<T: ?Sized>(references: &mut Vec<Weak<T>>) -> Vec<Rc<T>> {
+    references.retain(|r| r.strong_count() > 0);
+    Vec::from_iter(references.iter().filter_map(Weak::upgrade))
+}
+
+impl<S: QueueSimulation + SRRSimulation + 'static> SubsystemRegistry<S> {
+    pub fn register_queue(&mut self, queue: &Rc<RefCell<Queue<S>>>) {
+        self.queues.push(Rc::downgrade(queue));
+    }
+
+    pub fn register_pool_manager(&mut self, pool_manager: &Rc<RefCell<PoolManager>>) {
+        self.pool_managers.push(Rc::downgrade(pool_manager));
+    }
+
+    pub fn register_shared_rate_resource(
+        &mut self,
+        shared_rate_resource: &Rc<RefCell<SharedRateResource<S>>>,
+    ) {
+        self.shared_rate_resources
+            .push(Rc::downgrade(shared_rate_resource));
+    }
+
+    pub fn get_queues(&mut self) -> Vec<Rc<RefCell<Queue<S>>>> {
+        upgrade_all(&mut self.queues)
+    }
+
+    pub fn get_pool_managers(&mut self) -> Vec<Rc<RefCell<PoolManager>>> {
+        upgrade_all(&mut self.pool_managers)
+    }
+
+    pub fn get_shared_rate_resources(&mut self) -> Vec<Rc<RefCell<SharedRateResource<S>>>> {
+        upgrade_all(&mut self.shared_rate_resources)
+    }
+}
+
+impl<S: TopologySimulation> Queue<S> {
+    /// Wrap a newly constructed queue for sharing with handlers, registering it
+    /// with the simulation
+    pub fn into_registered(self, simulation: &S) -> Rc<RefCell<Self>> {
+        let queue = Rc::new(RefCell::new(self));
+        simulation.borrow_subsystems_mut().register_queue(&queue);
+        queue
+    }
+}
+
+impl PoolManager {
+    /// Like `Queue::into_registered`
+    pub fn into_registered<S: TopologySimulation>(self, simulation: &S) -> Rc<RefCell<Self>> {
+        let pool_manager = Rc::new(RefCell::new(self));
+        simulation
+            .borrow_subsystems_mut()
+            .register_pool_manager(&pool_manager);
+        pool_manager
+    }
+}
+
+impl<S: TopologySimulation> SharedRateResource<S> {
+    /// Like `Queue::into_registered`
+    pub fn into_registered(self, simulation: &S) -> Rc<RefCell<Self>> {
+        let shared_rate_resource = Rc::new(RefCell::new(self));
+        simulation
+            .borrow_subsystems_mut()
+            .register_shared_rate_resource(&shared_rate_resource);
+        shared_rate_resource
+    }
+}
+
+/// Snapshots of every live registered subsystem, in registration order
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TopologySnapshot {
+    pub queues: Vec<QueueSnapshot>,
+    pub pool_managers: Vec<PoolManagerSnapshot>,
+    pub shared_rate_resources: Vec<SharedRateResourceSnapshot>,
+}
+
+pub trait TopologySimulation: QueueSimulation + SRRSimulation + Sized + 'static {
+    fn borrow_subsystems_mut(&self) -> RefMut<'_, SubsystemRegistry<Self>>;
+
+    /// Must not be called while any registered subsystem is mutably borrowed
+    fn get_topology(&self) -> TopologySnapshot {
+        let mut subsystems = self.borrow_subsystems_mut();
+        TopologySnapshot {
+            queues: Vec::from_iter(
+                subsystems
+                    .get_queues()
+                    .iter()
+                    .map(|q| q.borrow().get_snapshot()),
+            ),
+            pool_managers: Vec::from_iter(
+                subsystems
+                    .get_pool_managers()
+                    .iter()
+                    .map(|pm| pm.borrow().get_snapshot()),
+            ),
+            shared_rate_resources: Vec::from_iter(
+                subsystems
+                    .get_shared_rate_resources()
+                    .iter()
+                    .map(|srr| srr.borrow().get_snapshot()),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_distr::LogNormal;
+
+    use super::*;
+    use crate::args_rets::*;
+    use crate::main_loop::*;
+    use crate::simulation::*;
+    use crate::testing::*;
+
+    // four items onto a frontend queue with two workers, each holding its worker
+    // for a tenancy on a single-partition backend resource
+    fn frontend_and_backend_bootstrap<S: TopologySimulation + 'static>(
+        simulation: &'static S,
+        timestamp: u64,
+        completion_timestamps: Rc<RefCell<Vec<u64>>>,
+    ) -> Vec<ProposedEvent<S>> {
+        let frontend = Queue::new(simulation, "frontend").into_registered(simulation);
+        let backend = SharedRateResource::new(
+            simulation,
+            1,
+            1,
+            mk_named_rng(simulation, "shared_rate_resource/backend"),
+        )
+        .unwrap()
+        .into_registered(simulation);
+
+        for id in 0..2 {
+            mk_worker(simulation, id, &frontend).listen(simulation, timestamp);
+        }
+
+        // midway through the first pair of tenancies, the second pair is parked
+        let topology_check = ProposedEvent {
+            due_time: DueTime::At(timestamp + (5.0 * simulation.get_ticks_per_second()) as u64),
+            handler: Box::new(|simulation: &'static S, _| {
+                let topology = simulation.get_topology();
+                let [frontend] = &topology.queues[..] else {
+                    panic!("Expected only the frontend queue in {topology:?}");
+                };
+                assert_eq!((frontend.name.as_str(), frontend.depth), ("frontend", 2));
+                assert_eq!(frontend.listening_workers, 0);
+                let [backend] = &topology.shared_rate_resources[..] else {
+                    panic!("Expected only the backend resource in {topology:?}");
+                };
+                assert_eq!(
+                    (backend.id, backend.partitions, backend.tenancies),
+                    (1, 1, 2)
+                );
+                assert_eq!(backend.resource_timer_rate, Some(0.5));
+                Default::default()
+            }),
+            label: Some("topology_check"),
+            keepalive: true,
+            priority: 0,
+        };
+
+        // each worker holds its token for a 5s tenancy on the single-partition backend,
+        // so the two items dispatched first complete after 10s and the parked pair
+        // after another 10s
+        let mut proposed_events = Vec::from_iter((0..4).map(|_| {
+            let backend = backend.clone();
+            let completion_timestamps = completion_timestamps.clone();
+            ProposedEvent {
+                due_time: DueTime::After(LogNormal::from_mean_cv(1.0, 0.0).unwrap()),
+                handler: Box::new(Queue::mk_enqueued_handler(
+                    frontend.clone(),
+                    move |_, timestamp, worker_token| {
+                        SharedRateResource::mk_shared_rate_event(
+                            simulation,
+                            backend,
+                            timestamp,
+                            LogNormal::from_mean_cv(
+                                (5.0 * simulation.get_ticks_per_second()) as f32,
+                                0.0,
+                            )
+                            .unwrap(),
+                            WorkerToken::mk_token_restoring_handler(move |_, timestamp| {
+                                completion_timestamps.borrow_mut().push(timestamp);
+                                (Default::default(), vec![worker_token])
+                            }),
+                        )
+                        .unwrap()
+                    },
+                )),
+                label: Some("frontend_arrival"),
+                keepalive: true,
+                priority: 0,
+            }
+        }));
+        proposed_events.push(topology_check);
+        proposed_events
+    }
+
+    #[test]
+    fn topology_shows_parked_items_and_shared_backend() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let completion_timestamps: Rc<RefCell<Vec<u64>>> = Default::default();
+        let mut runner = SimulationRunner::new(simulation, {
+            let completion_timestamps = completion_timestamps.clone();
+            Box::new(move |simulation, timestamp| {
+                frontend_and_backend_bootstrap(simulation, timestamp, completion_timestamps)
+            })
+        });
+        let end_reason = runner.run();
+
+        let completion_timestamps = completion_timestamps.borrow();
+        for (i, completion_timestamp) in completion_timestamps.iter().enumerate() {
+            let expected_completion_timestamp =
+                1 + (10.0 * (1 + i / 2) as f64 * simulation.get_ticks_per_second()) as u64;
+            // allowing for f32 precision of the sampled times
+            assert!(completion_timestamp.abs_diff(expected_completion_timestamp) <= 4);
+        }
+        assert_eq!(completion_timestamps.len(), 4);
+
+        let simulation = runner.get_simulation();
+        let checked_out_count: u64 = (0..2u64)
+            .map(|id| {
+                simulation
+                    .get_checked_out_count(&[
+                        ("worker_id".into(), id.to_string()),
+                        ("originating_queue".into(), "frontend".into()),
+                    ])
+                    .unwrap()
+            })
+            .sum();
+        assert_eq!(checked_out_count, 4);
+        // every pair of tenancies shares the single partition, taking twice as long
+        let (stretch_sum, stretch_count) = simulation
+            .get_tenancy_stretch_sum_count(&[(
+                "shared_rate_resource_id".into(),
+                format!("{:016x}", 1),
+            )])
+            .unwrap();
+        assert_eq!(stretch_count, 4);
+        assert!((stretch_sum - 8.0).abs() < 0.01);
+
+        assert_eq!(end_reason, EndReason::EventHeapDrained);
+    }
+
+    // subsystems register themselves as they're constructed, and drop out of the
+    // registry once the last handler holding them has gone
+    #[test]
+    fn subsystems_drop_out_of_registry_once_unheld() {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let get_registered = |simulation: &BaseQueueSimulation| {
+            let mut subsystems = simulation.borrow_subsystems_mut();
+            (
+                Vec::from_iter(
+                    subsystems
+                        .get_queues()
+                        .iter()
+                        .map(|q| q.borrow().name.clone()),
+                ),
+                Vec::from_iter(
+                    subsystems
+                        .get_pool_managers()
+                        .iter()
+                        .map(|p| p.borrow().name.clone()),
+                ),
+                Vec::from_iter(
+                    subsystems
+                        .get_shared_rate_resources()
+                        .iter()
+                        .map(|r| r.borrow().get_snapshot().id),
+                ),
+            )
+        };
+
+        let mut runner = SimulationRunner::new(
+            simulation,
+            Box::new(move |simulation, _| {
+                let main_queue = Queue::new(simulation, "main").into_registered(simulation);
+                let scratch_queue = Queue::new(simulation, "scratch").into_registered(simulation);
+                let pool_manager = PoolManager {
+                    name: "registry".into(),
+                    instances: Default::default(),
+                    instance_constructor: Box::new(|| Box::new(|| {})),
+                    metric_labels: Default::default(),
+                    ramp: None,
+                    launch_failures: None,
+                    peak_instances: 0,
+                }
+                .into_registered(simulation);
+                let shared_rate_resource = SharedRateResource::new(
+                    simulation,
+                    7,
+                    1,
+                    mk_named_rng(simulation, "shared_rate_resource/registry"),
+                )
+                .unwrap()
+                .into_registered(simulation);
+                // never held by a handler, so gone as soon as it's built
+                drop(Queue::new(simulation, "unheld").into_registered(simulation));
+                assert_eq!(
+                    get_registered(simulation),
+                    (
+                        vec!["main".to_owned(), "scratch".to_owned()],
+                        vec!["registry".to_owned()],
+                        vec![7]
+                    )
+                );
+
+                vec![
+                    // the scratch queue is only held until this handler has run
+                    ProposedEvent::after_seconds(simulation, 1.0, 0.0)
+                        .named("registry_scratch")
+                        .handler(move |_, _| {
+                            drop(scratch_queue);
+                            Default::default()
+                        }),
+                    ProposedEvent::after_seconds(simulation, 2.0, 0.0)
+                        .named("registry_check")
+                        .handler(move |simulation, _| {
+                            assert_eq!(
+                                get_registered(simulation),
+                                (
+                                    vec!["main".to_owned()],
+                                    vec!["registry".to_owned()],
+                                    vec![7]
+                                )
+                            );
+                            drop((main_queue, pool_manager, shared_rate_resource));
+                            assert_eq!(get_registered(simulation), (vec![], vec![], vec![]));
+                            Default::default()
+                        }),
+                ]
+            }),
+        );
+        assert_eq!(runner.run(), EndReason::EventHeapDrained);
+    }
+}
@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::hash::Hasher;
+use std::rc::Rc;
+
+use crate::args_rets::*;
+use crate::queue::*;
+use crate::simulation::*;
+
+pub enum ShardingPolicy {
+    RoundRobin,
+    LeastDepth,
+    // the affinity key modulo the number of backing queues, so keys sharing a
+    // stride with it pile onto the same few. Items without an affinity key fall
+    // back to round robin
+    AffinityKeyModulo,
+    // like AffinityKeyModulo, but keys are hashed rather than taken modulo the
+    // number of backing queues, so that keys sharing a stride are still spread evenly.
+    // The same seed maps the same key to the same backing queue in every run.
+    AffinityKeyUniformHash { seed: u64 },
+}
+
+/// The shard of `shards` that `key` maps to under
+/// `ShardingPolicy::AffinityKeyUniformHash { seed }`
+pub fn get_uniform_hash_shard(seed: u64, key: u64, shards: usize) -> usize {
+    // explicitly little-endian, as write_u64 would be native-endian
+    let mut hasher = Fnv1aHasher::default();
+    hasher.write(&seed.to_le_bytes());
+    hasher.write(&key.to_le_bytes());
+    // FNV-1a alone leaves keys differing in a few bits with similar hashes, so
+    // finish with MurmurHash3's fmix64 to spread them over every shard
+    let mut hash = hasher.finish();
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    (hash % shards as u64) as usize
+}
+
+/// Spreads enqueues over a set of backing `Queue`s, each typically with its own
+/// worker pool, so producers can target a single logical queue.
+pub struct VirtualQueue<S: QueueSimulation + 'static> {
+    pub name: String,
+    pub backing_queues: Vec<Rc<RefCell<Queue<S>>>>,
+    pub policy: ShardingPolicy,
+    pub next_round_robin_index: usize,
+    pub metric_labels: Vec<(String, String)>,
+}
+
+impl<S: QueueSimulation + 'static> VirtualQueue<S> {
+    fn pick_round_robin(&mut self) -> usize {
+        let i = self.next_round_robin_index % self.backing_queues.len();
+        self.next_round_robin_index = i + 1;
+        i
+    }
+
+    fn pick_backing_queue(&mut self, options: &EnqueueOptions) -> Rc<RefCell<Queue<S>>> {
+        assert!(
+            !self.backing_queues.is_empty(),
+            "VirtualQueue {} has no backing queues",
+            self.name
+        );
+
+        let i = match (&self.policy, options.affinity_key) {
+            (ShardingPolicy::AffinityKeyModulo, Some(key)) => {
+                (key % self.backing_queues.len() as u64) as usize
+            }
+            (ShardingPolicy::AffinityKeyUniformHash { seed }, Some(key)) => {
+                get_uniform_hash_shard(*seed, key, self.backing_queues.len())
+            }
+            (ShardingPolicy::LeastDepth, _) => {
+                self.backing_queues
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, q)| q.borrow().deque.len())
+                    .unwrap()
+                    .0
+            }
+            _ => self.pick_round_robin(),
+        };
+
+        self.backing_queues[i].clone()
+    }
+
+    pub fn mk_enqueued_handler(
+        virtual_queue: Rc<RefCell<VirtualQueue<S>>>,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        Self::mk_enqueued_handler_with_options(virtual_queue, Default::default(), inner_handler)
+    }
+
+    pub fn mk_enqueued_handler_with_options(
+        virtual_queue: Rc<RefCell<VirtualQueue<S>>>,
+        options: EnqueueOptions,
+        inner_handler: impl FnOnce(&'static S, u64, WorkerToken<S>) -> Vec<ProposedEvent<S>> + 'static,
+    ) -> impl FnOnce(&'static S, u64) -> Vec<ProposedEvent<S>> {
+        move |simulation, timestamp| {
+            let (backing_queue, mut metric_labels) = {
+                let mut vq = virtual_queue.borrow_mut();
+                (vq.pick_backing_queue(&options), vq.metric_labels.clone())
+            };
+
+            metric_labels.push((
+                "backing_queue".to_owned(),
+                backing_queue.borrow().name.clone(),
+            ));
+            simulation
+                .get_virtual_queue_enqueues_metric()
+                .get_or_create(&metric_labels)
+                .inc();
+
+            Queue::mk_enqueued_handler_with_options(backing_queue, options, inner_handler)(
+                simulation, timestamp,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARDS: usize = 4;
+
+    // parks 100 items through a virtual queue over backing queues with no workers,
+    // returning how many each backing queue got
+    fn get_shard_depths(
+        policy: ShardingPolicy,
+        options: impl Fn(u64) -> EnqueueOptions,
+    ) -> Vec<usize> {
+        let simulation: &'static BaseQueueSimulation =
+            Box::leak(Box::new(BaseQueueSimulation::new(1)));
+        let backing_queues = Vec::from_iter((0..SHARDS).map(|shard| {
+            Rc::new(RefCell::new(Queue::new(
+                simulation,
+                &format!("shard_{shard}"),
+            )))
+        }));
+        let virtual_queue = Rc::new(RefCell::new(VirtualQueue {
+            name: "virtual".into(),
+            backing_queues: backing_queues.clone(),
+            policy,
+            next_round_robin_index: 0,
+            metric_labels: vec![("virtual_queue_name".into(), "virtual".into())],
+        }));
+        for i in 0..100 {
+            VirtualQueue::mk_enqueued_handler_with_options(
+                virtual_queue.clone(),
+                options(i),
+                |_, _, _| unreachable!(),
+            )(simulation, i);
+        }
+        Vec::from_iter(backing_queues.iter().map(|q| q.borrow().deque.len()))
+    }
+
+    #[test]
+    fn round_robin_spreads_evenly() {
+        assert_eq!(
+            get_shard_depths(ShardingPolicy::RoundRobin, |_| Default::default()),
+            [25; SHARDS]
+        );
+    }
+
+    #[test]
+    fn strided_keys_only_spread_when_hashed() {
+        let strided = |i| EnqueueOptions {
+            affinity_key: Some(i * SHARDS as u64),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_shard_depths(ShardingPolicy::AffinityKeyModulo, strided),
+            [100, 0, 0, 0]
+        );
+        let hashed = get_shard_depths(ShardingPolicy::AffinityKeyUniformHash { seed: 0 }, strided);
+        assert!(
+            hashed.iter().all(|depth| (10..=40).contains(depth)),
+            "{hashed:?}"
+        );
+    }
+
+    // golden shards, which have to hold in every process and on every platform
+    #[test]
+    fn uniform_hash_shards_are_the_same_everywhere() {
+        let shards = Vec::from_iter((0..8).map(|key| get_uniform_hash_shard(0, key * 8, 8)));
+        assert_eq!(shards, [2, 5, 0, 6, 7, 6, 2, 3]);
+    }
+}